@@ -0,0 +1,105 @@
+use chrono::{DateTime, FixedOffset, Local};
+use std::future::Future;
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+
+pub type Sleep<'a> = Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
+
+/// Where the player, monitor and scheduler get the time and wait from, so a simulated clock
+/// can replace the real one.
+pub trait Clock: Send + Sync {
+    /// Monotonic time, for pacing and intervals.
+    fn now(&self) -> Instant;
+    /// Local wall-clock time, for anything tied to the time of day.
+    fn local_now(&self) -> DateTime<FixedOffset>;
+    fn sleep(&self, duration: Duration) -> Sleep<'_>;
+
+    fn elapsed(&self, since: Instant) -> Duration {
+        self.now().saturating_duration_since(since)
+    }
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn local_now(&self) -> DateTime<FixedOffset> {
+        Local::now().fixed_offset()
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep<'_> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// Virtual time for tests, read off tokio's clock. Under a paused runtime
+/// (`#[tokio::test(start_paused = true)]`) time only moves when every task is waiting, and
+/// then jumps straight to the earliest sleeper's wake-up, so an hour of playback runs as
+/// fast as the work in it and the timeline is the same on every run.
+#[cfg(test)]
+pub struct SimulatedClock {
+    start: tokio::time::Instant,
+    start_local: DateTime<FixedOffset>,
+}
+
+#[cfg(test)]
+impl SimulatedClock {
+    pub fn new(start_local: DateTime<FixedOffset>) -> Self {
+        Self {
+            start: tokio::time::Instant::now(),
+            start_local,
+        }
+    }
+
+    /// Virtual time since the clock was created.
+    pub fn elapsed_total(&self) -> Duration {
+        tokio::time::Instant::now() - self.start
+    }
+}
+
+#[cfg(test)]
+impl Clock for SimulatedClock {
+    fn now(&self) -> Instant {
+        tokio::time::Instant::now().into_std()
+    }
+
+    fn local_now(&self) -> DateTime<FixedOffset> {
+        self.start_local + self.elapsed_total()
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep<'_> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clock() -> SimulatedClock {
+        SimulatedClock::new(DateTime::parse_from_rfc3339("2024-03-10T08:00:00+01:00").unwrap())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_sleeps_overlap() {
+        let clock = clock();
+        let start = clock.now();
+        tokio::join!(clock.sleep(Duration::from_secs(5)), clock.sleep(Duration::from_secs(3)));
+        assert_eq!(clock.elapsed(start), Duration::from_secs(5));
+        assert_eq!(clock.local_now().format("%H:%M:%S").to_string(), "08:00:05");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn losing_a_select_does_not_move_time() {
+        let clock = clock();
+        let start = clock.now();
+        tokio::select! {
+            _ = clock.sleep(Duration::from_secs(3600)) => panic!("the shorter sleep should win"),
+            _ = clock.sleep(Duration::from_millis(250)) => {}
+        }
+        assert_eq!(clock.elapsed(start), Duration::from_millis(250));
+    }
+}
@@ -1,2 +1,4 @@
 pub mod settings;
+pub mod playback;
 pub use settings::Settings;
+pub use playback::PlaybackPositions;
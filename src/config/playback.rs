@@ -0,0 +1,158 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::Result;
+
+/// Oldest entries are dropped beyond this many wallpapers.
+const MAX_ENTRIES: usize = 200;
+
+/// Resuming closer than this to the end would just show the loop point, so start over instead.
+const END_MARGIN_SECS: f64 = 2.0;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SavedPosition {
+    pub position: f64,
+    pub duration: Option<f64>,
+    /// Unix seconds, for pruning.
+    pub updated: u64,
+}
+
+/// Where each wallpaper was last left, kept in `playback.json` next to the settings.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct PlaybackPositions {
+    #[serde(default)]
+    positions: HashMap<String, SavedPosition>,
+    #[serde(skip)]
+    dirty: bool,
+}
+
+impl PlaybackPositions {
+    pub fn load() -> Result<Self> {
+        Self::load_from(&Self::state_path()?)
+    }
+
+    fn load_from(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let content = std::fs::read_to_string(path)?;
+        let positions = serde_json::from_str(&content)?;
+        Ok(positions)
+    }
+
+    /// Write the file if anything changed since the last save.
+    pub fn save(&mut self) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        self.save_to(&Self::state_path()?)
+    }
+
+    fn save_to(&mut self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let content = serde_json::to_string_pretty(self)?;
+        // Write then rename so a crash mid-write can't leave a truncated file behind
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, content)?;
+        std::fs::rename(tmp, path)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Position to resume `path` from, if it's worth resuming.
+    pub fn resume_position(&self, path: &str) -> Option<f64> {
+        let saved = self.positions.get(path)?;
+        let near_end = saved.duration.is_some_and(|d| saved.position > d - END_MARGIN_SECS);
+        (saved.position > 0.0 && !near_end).then_some(saved.position)
+    }
+
+    pub fn record(&mut self, path: &str, position: f64, duration: Option<f64>) {
+        let updated = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        self.positions.insert(path.to_string(), SavedPosition { position, duration, updated });
+        if self.positions.len() > MAX_ENTRIES {
+            if let Some(oldest) = self.positions.iter().min_by_key(|(_, p)| p.updated).map(|(k, _)| k.clone()) {
+                self.positions.remove(&oldest);
+            }
+        }
+        self.dirty = true;
+    }
+
+    pub fn forget(&mut self, path: &str) {
+        if self.positions.remove(path).is_some() {
+            self.dirty = true;
+        }
+    }
+
+    fn state_path() -> Result<PathBuf> {
+        let mut path = dirs::config_dir().ok_or_else(|| anyhow::anyhow!("Could not find config directory"))?;
+        path.push("Mew");
+        path.push("playback.json");
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wallpaper::fixtures::TempFile;
+
+    #[test]
+    fn positions_survive_a_save_and_load() {
+        let file = TempFile::new("playback.json");
+        let mut positions = PlaybackPositions::default();
+        positions.record("a.mp4", 12.5, Some(60.0));
+        positions.record("b.mp4", 3.0, None);
+        positions.save_to(&file).unwrap();
+        assert!(!positions.dirty);
+
+        let loaded = PlaybackPositions::load_from(&file).unwrap();
+        assert_eq!(loaded.resume_position("a.mp4"), Some(12.5));
+        assert_eq!(loaded.resume_position("b.mp4"), Some(3.0));
+        assert_eq!(loaded.resume_position("c.mp4"), None);
+    }
+
+    #[test]
+    fn a_missing_file_loads_empty() {
+        let loaded = PlaybackPositions::load_from(&TempFile::new("no-playback.json")).unwrap();
+        assert!(loaded.positions.is_empty());
+    }
+
+    #[test]
+    fn only_positions_worth_resuming_are_returned() {
+        let mut positions = PlaybackPositions::default();
+        positions.record("start.mp4", 0.0, Some(60.0));
+        positions.record("end.mp4", 59.0, Some(60.0));
+        positions.record("middle.mp4", 30.0, Some(60.0));
+        assert_eq!(positions.resume_position("start.mp4"), None);
+        assert_eq!(positions.resume_position("end.mp4"), None);
+        assert_eq!(positions.resume_position("middle.mp4"), Some(30.0));
+    }
+
+    #[test]
+    fn forgetting_marks_the_store_dirty_only_when_something_went() {
+        let mut positions = PlaybackPositions::default();
+        positions.forget("a.mp4");
+        assert!(!positions.dirty);
+        positions.record("a.mp4", 10.0, Some(60.0));
+        positions.dirty = false;
+        positions.forget("a.mp4");
+        assert!(positions.dirty);
+        assert_eq!(positions.resume_position("a.mp4"), None);
+    }
+
+    #[test]
+    fn the_oldest_entry_goes_past_the_limit() {
+        let mut positions = PlaybackPositions::default();
+        for i in 0..MAX_ENTRIES {
+            positions.record(&format!("{}.mp4", i), 10.0, Some(60.0));
+        }
+        positions.positions.get_mut("0.mp4").unwrap().updated = 0;
+        positions.record("new.mp4", 10.0, Some(60.0));
+        assert_eq!(positions.positions.len(), MAX_ENTRIES);
+        assert_eq!(positions.resume_position("0.mp4"), None);
+        assert_eq!(positions.resume_position("new.mp4"), Some(10.0));
+    }
+}
@@ -8,6 +8,29 @@ pub struct WallpaperSettings {
     pub resolution: String,
     pub fps_preset: String,
     pub scaling_mode: String,
+    /// Shown when `path` can't be decoded.
+    #[serde(default)]
+    pub fallback_path: String,
+    /// "#RRGGBB", used when the fallback can't be played either.
+    #[serde(default = "default_fallback_color")]
+    pub fallback_color: String,
+    /// Name of the playlist to rotate through instead of `path`; empty plays `path` alone.
+    #[serde(default)]
+    pub playlist: String,
+}
+
+fn default_fallback_color() -> String {
+    "#000000".to_string()
+}
+
+impl WallpaperSettings {
+    pub fn fallback_rgb(&self) -> [u8; 3] {
+        let hex = self.fallback_color.trim_start_matches('#');
+        match u32::from_str_radix(hex, 16) {
+            Ok(v) if hex.len() == 6 => [(v >> 16) as u8, (v >> 8) as u8, v as u8],
+            _ => [0, 0, 0],
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -15,10 +38,17 @@ pub struct PerformanceSettings {
     pub pause_on_battery: bool,
     pub battery_threshold: i32,
     pub pause_on_fullscreen: bool,
+    /// Pause after this long without keyboard or mouse input; 0 never does.
+    #[serde(default = "default_idle_pause_minutes")]
+    pub idle_pause_minutes: u32,
     pub enable_glassmorphism: bool,
     pub show_icon_shortcuts: bool,
 }
 
+fn default_idle_pause_minutes() -> u32 {
+    10
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct StartupSettings {
     pub launch_with_windows: bool,
@@ -26,12 +56,363 @@ pub struct StartupSettings {
     pub minimize_to_tray: bool,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AudioSettings {
+    pub enabled: bool,
+    pub volume: u32,
+    pub muted: bool,
+}
+
+impl Default for AudioSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            volume: 50,
+            muted: false,
+        }
+    }
+}
+
+/// Applied when the wallpaper path is a URL (camera feed, HLS, ...).
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct NetworkSettings {
+    pub timeout_secs: u32,
+    pub buffer_kb: u32,
+    pub rtsp_over_tcp: bool,
+    pub max_reconnect_delay_secs: u32,
+}
+
+impl Default for NetworkSettings {
+    fn default() -> Self {
+        Self {
+            timeout_secs: 10,
+            buffer_kb: 1024,
+            rtsp_over_tcp: true,
+            max_reconnect_delay_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ProxySettings {
+    /// Transcode heavy sources (8K, ProRes, HEVC) once into a screen-sized proxy.
+    pub enabled: bool,
+}
+
+/// Short loops are decoded once into RAM and replayed without touching the decoder.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FrameCacheSettings {
+    pub enabled: bool,
+    pub max_clip_secs: f64,
+    pub memory_budget_mb: u32,
+    pub compress: bool,
+}
+
+impl Default for FrameCacheSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_clip_secs: 5.0,
+            memory_budget_mb: 512,
+            compress: false,
+        }
+    }
+}
+
+/// Resume each wallpaper where it was left.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PlaybackSettings {
+    pub resume: bool,
+    /// Wallpapers that always start from frame 0 even with resume on.
+    #[serde(default)]
+    pub always_from_start: Vec<String>,
+    /// Wallpapers whose frame follows the time of day instead of looping.
+    #[serde(default)]
+    pub day_cycle: Vec<DayCycleSettings>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DayCycleSettings {
+    pub path: String,
+    /// Time of day the video starts at, in minutes after midnight.
+    #[serde(default)]
+    pub offset_minutes: i32,
+    /// Real seconds per second of video; 0 fits the video to one day.
+    #[serde(default)]
+    pub stretch: f64,
+}
+
+impl Default for PlaybackSettings {
+    fn default() -> Self {
+        Self {
+            resume: true,
+            always_from_start: Vec::new(),
+            day_cycle: Vec::new(),
+        }
+    }
+}
+
+impl PlaybackSettings {
+    pub fn day_cycle(&self, path: &str) -> Option<&DayCycleSettings> {
+        self.day_cycle.iter().find(|d| d.path == path)
+    }
+
+    /// Turn day-cycle mode on or off for `path`, keeping any offset/stretch already set.
+    pub fn set_day_cycle(&mut self, path: &str, enabled: bool) {
+        if !enabled {
+            self.day_cycle.retain(|d| d.path != path);
+        } else if self.day_cycle(path).is_none() {
+            self.day_cycle.push(DayCycleSettings {
+                path: path.to_string(),
+                offset_minutes: 0,
+                stretch: 0.0,
+            });
+        }
+    }
+
+    pub fn set_always_from_start(&mut self, path: &str, enabled: bool) {
+        self.always_from_start.retain(|p| p != path);
+        if enabled {
+            self.always_from_start.push(path.to_string());
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PlaylistItemSettings {
+    pub path: String,
+    /// Seconds this item plays for, overriding the playlist's interval.
+    #[serde(default)]
+    pub interval_secs: Option<u32>,
+    /// Relative chance of being picked in "weighted" order.
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+impl PlaylistItemSettings {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            interval_secs: None,
+            weight: default_weight(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PlaylistSettings {
+    pub name: String,
+    pub items: Vec<PlaylistItemSettings>,
+    /// "sequential", "shuffle" or "weighted".
+    #[serde(default = "default_order")]
+    pub order: String,
+    /// Seconds per item; 0 never rotates on a timer.
+    #[serde(default = "default_interval")]
+    pub interval_secs: u32,
+    /// Rotate after this many loops of the item instead of on the interval; 0 uses the interval.
+    #[serde(default)]
+    pub loops: u32,
+}
+
+fn default_order() -> String {
+    "sequential".to_string()
+}
+
+fn default_interval() -> u32 {
+    300
+}
+
+impl PlaylistSettings {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            items: Vec::new(),
+            order: default_order(),
+            interval_secs: default_interval(),
+            loops: 0,
+        }
+    }
+}
+
+/// One range of the day: from `start` until the next entry's start.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ScheduleEntrySettings {
+    /// "HH:MM", or "sunrise"/"sunset" with an optional offset in minutes, e.g. "sunset-30".
+    pub start: String,
+    /// What plays in this range: a wallpaper path, a playlist name, or nothing (paused).
+    #[serde(default)]
+    pub wallpaper: String,
+    #[serde(default)]
+    pub playlist: String,
+    #[serde(default)]
+    pub pause: bool,
+}
+
+/// Switch wallpapers by time of day. The location is only needed for sunrise/sunset entries.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ScheduleSettings {
+    pub enabled: bool,
+    #[serde(default)]
+    pub latitude: Option<f64>,
+    #[serde(default)]
+    pub longitude: Option<f64>,
+    #[serde(default)]
+    pub entries: Vec<ScheduleEntrySettings>,
+}
+
+/// Act on the wallpaper while a particular app runs, or has a window in front. Every
+/// criterion that's set has to hold.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AppRuleSettings {
+    /// Executable name ("UnityEditor.exe") or the end of its path; empty matches any app.
+    #[serde(default)]
+    pub process: String,
+    /// Only while the app has a window of this class; empty matches any window.
+    #[serde(default)]
+    pub window_class: String,
+    /// Only while one of the app's windows has focus.
+    #[serde(default)]
+    pub focused: bool,
+    /// "pause", "cap_fps", "mute" or "wallpaper".
+    pub action: String,
+    /// Frame rate for "cap_fps".
+    #[serde(default)]
+    pub fps: u32,
+    /// What to play instead for "wallpaper".
+    #[serde(default)]
+    pub wallpaper: String,
+}
+
+/// One row of the power policy table; the first row matching the current conditions
+/// decides how the wallpaper plays.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PowerTierSettings {
+    /// "ac", "battery" or "any".
+    #[serde(default = "default_source")]
+    pub source: String,
+    /// Only while the battery is below this percentage.
+    #[serde(default)]
+    pub below_percent: Option<u32>,
+    /// Only while a fullscreen app is (true) or isn't (false) in front.
+    #[serde(default)]
+    pub fullscreen: Option<bool>,
+    /// Only while the OS power saver is (true) or isn't (false) on.
+    #[serde(default)]
+    pub power_saver: Option<bool>,
+    /// "full", "reduced_fps", "reduced_resolution", "static" or "hidden".
+    pub mode: String,
+}
+
+fn default_source() -> String {
+    "any".to_string()
+}
+
+impl PowerTierSettings {
+    pub fn new(source: &str, below_percent: Option<u32>, mode: &str) -> Self {
+        Self {
+            source: source.to_string(),
+            below_percent,
+            fullscreen: None,
+            power_saver: None,
+            mode: mode.to_string(),
+        }
+    }
+}
+
+/// Degrade playback step by step to save power, rather than only pausing.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PowerPolicySettings {
+    pub tiers: Vec<PowerTierSettings>,
+    /// Frame rate cap in the reduced modes.
+    pub reduced_fps: u32,
+    /// Fraction of the screen size decoded at in reduced resolution.
+    pub reduced_scale: f32,
+    /// Close the decoder once the wallpaper has been still this long; 0 keeps it open.
+    pub release_after_secs: u32,
+}
+
+impl Default for PowerPolicySettings {
+    fn default() -> Self {
+        Self {
+            // Pausing below `battery_threshold` is added in front of these by `pause_on_battery`
+            tiers: vec![PowerTierSettings::new("battery", None, "reduced_fps")],
+            reduced_fps: 15,
+            reduced_scale: 0.5,
+            release_after_secs: 120,
+        }
+    }
+}
+
+/// Lower the frame rate and internal resolution when decoding can't keep up.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct QualitySettings {
+    pub adaptive: bool,
+    /// The governor never goes below these.
+    pub min_fps: u32,
+    pub min_scale: f32,
+}
+
+impl Default for QualitySettings {
+    fn default() -> Self {
+        Self {
+            adaptive: true,
+            min_fps: 10,
+            min_scale: 0.5,
+        }
+    }
+}
+
+/// How the picture changes between wallpapers, and on pause/resume.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TransitionSettings {
+    /// "cut", "fade", "crossfade", "dissolve" or "slide".
+    pub kind: String,
+    pub duration_ms: u32,
+    pub fade_on_pause: bool,
+}
+
+impl Default for TransitionSettings {
+    fn default() -> Self {
+        Self {
+            kind: "crossfade".to_string(),
+            duration_ms: 800,
+            fade_on_pause: true,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Settings {
     pub version: String,
     pub wallpaper: WallpaperSettings,
     pub performance: PerformanceSettings,
     pub startup: StartupSettings,
+    #[serde(default)]
+    pub audio: AudioSettings,
+    #[serde(default)]
+    pub network: NetworkSettings,
+    #[serde(default)]
+    pub proxy: ProxySettings,
+    #[serde(default)]
+    pub frame_cache: FrameCacheSettings,
+    #[serde(default)]
+    pub playback: PlaybackSettings,
+    #[serde(default)]
+    pub transition: TransitionSettings,
+    #[serde(default)]
+    pub playlists: Vec<PlaylistSettings>,
+    #[serde(default)]
+    pub schedule: ScheduleSettings,
+    #[serde(default)]
+    pub power: PowerPolicySettings,
+    #[serde(default)]
+    pub quality: QualitySettings,
+    #[serde(default)]
+    pub app_rules: Vec<AppRuleSettings>,
 }
 
 impl Default for Settings {
@@ -43,11 +424,15 @@ impl Default for Settings {
                 resolution: "1080p".to_string(),
                 fps_preset: "balanced".to_string(),
                 scaling_mode: "fill".to_string(),
+                fallback_path: String::new(),
+                fallback_color: default_fallback_color(),
+                playlist: String::new(),
             },
             performance: PerformanceSettings {
                 pause_on_battery: true,
                 battery_threshold: 20,
                 pause_on_fullscreen: true,
+                idle_pause_minutes: default_idle_pause_minutes(),
                 enable_glassmorphism: true,
                 show_icon_shortcuts: true,
             },
@@ -56,11 +441,38 @@ impl Default for Settings {
                 start_minimized: true,
                 minimize_to_tray: true,
             },
+            audio: AudioSettings::default(),
+            network: NetworkSettings::default(),
+            proxy: ProxySettings::default(),
+            frame_cache: FrameCacheSettings::default(),
+            playback: PlaybackSettings::default(),
+            transition: TransitionSettings::default(),
+            playlists: Vec::new(),
+            schedule: ScheduleSettings::default(),
+            power: PowerPolicySettings::default(),
+            quality: QualitySettings::default(),
+            app_rules: Vec::new(),
         }
     }
 }
 
 impl Settings {
+    /// The playlist selected for the wallpaper, if any.
+    pub fn active_playlist(&self) -> Option<&PlaylistSettings> {
+        if self.wallpaper.playlist.is_empty() {
+            return None;
+        }
+        self.playlists.iter().find(|p| p.name == self.wallpaper.playlist)
+    }
+
+    /// Replace the playlist with the same name, or add it.
+    pub fn set_playlist(&mut self, playlist: PlaylistSettings) {
+        match self.playlists.iter_mut().find(|p| p.name == playlist.name) {
+            Some(existing) => *existing = playlist,
+            None => self.playlists.push(playlist),
+        }
+    }
+
     pub fn load() -> Result<Self> {
         let path = Self::config_path()?;
         if !path.exists() {
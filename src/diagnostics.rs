@@ -1,6 +1,12 @@
+use crate::wallpaper::command::{Percentiles, PlayerStats};
+use crate::wallpaper::watchdog::Incident;
+use anyhow::Result;
+use chrono::{DateTime, Local};
 use windows::Win32::Foundation::*;
 use windows::Win32::UI::WindowsAndMessaging::*;
 use std::ffi::c_void;
+use std::fmt::Write;
+use std::path::PathBuf;
 
 pub fn dump_desktop_hierarchy() {
     tracing::info!("--- DESKTOP HIERARCHY DUMP START (ENUM MODE) ---");
@@ -58,3 +64,97 @@ unsafe fn log_window(name: &str, hwnd: HWND) {
         name, hwnd, title, width, height, rect.left, rect.top, style, ex_style
     );
 }
+
+/// Everything written to a diagnostics export, gathered when the user asks for one.
+pub struct Report {
+    pub generated: DateTime<Local>,
+    pub stats: PlayerStats,
+    /// Recent watchdog stalls, oldest first.
+    pub incidents: Vec<Incident>,
+}
+
+impl Report {
+    pub fn render(&self) -> String {
+        let stats = &self.stats;
+        let mut out = String::new();
+        let _ = writeln!(out, "Mew {} diagnostics, {}", env!("CARGO_PKG_VERSION"), self.generated.format("%Y-%m-%d %H:%M:%S %:z"));
+        let _ = writeln!(out);
+        let _ = writeln!(out, "[player]");
+        let _ = writeln!(out, "presented fps: {:.1}", stats.presented_fps);
+        let _ = writeln!(out, "dropped frames: {}", stats.dropped_frames);
+        let _ = writeln!(out, "late frames: {}", stats.late_frames);
+        let _ = writeln!(out, "decode ms: {}", percentiles(&stats.decode_ms));
+        let _ = writeln!(out, "scale ms: {}", percentiles(&stats.scale_ms));
+        let _ = writeln!(out, "present ms: {}", percentiles(&stats.present_ms));
+        let _ = writeln!(out, "queue depth: {}", stats.queue_depth);
+        let _ = writeln!(out, "loops: {}", stats.loops);
+        let _ = writeln!(out, "corrupt packets: {}", stats.corrupt_packets);
+        let _ = writeln!(out);
+        let _ = writeln!(out, "[incidents]");
+        if self.incidents.is_empty() {
+            let _ = writeln!(out, "none");
+        }
+        for incident in &self.incidents {
+            let _ = writeln!(out, "{}", incident);
+        }
+        out
+    }
+}
+
+fn percentiles(p: &Percentiles) -> String {
+    format!("p50 {:.2}, p95 {:.2}, p99 {:.2}, max {:.2}", p.p50, p.p95, p.p99, p.max)
+}
+
+/// Write `report` next to the settings file, returning where it went.
+pub fn export(report: &Report) -> Result<PathBuf> {
+    let mut path = dirs::config_dir().ok_or_else(|| anyhow::anyhow!("Could not find config directory"))?;
+    path.push("Mew");
+    std::fs::create_dir_all(&path)?;
+    path.push(format!("diagnostics-{}.txt", report.generated.format("%Y%m%d-%H%M%S")));
+    std::fs::write(&path, report.render())?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wallpaper::watchdog::{Component, Recovery};
+    use std::time::Duration;
+
+    #[test]
+    fn report_lists_every_stat() {
+        let report = Report {
+            generated: Local::now(),
+            stats: PlayerStats {
+                presented_fps: 29.97,
+                dropped_frames: 3,
+                late_frames: 1,
+                decode_ms: Percentiles { p50: 4.0, p95: 6.5, p99: 9.25, max: 12.0 },
+                queue_depth: 2,
+                loops: 7,
+                corrupt_packets: 5,
+                ..Default::default()
+            },
+            incidents: vec![Incident {
+                at: DateTime::parse_from_rfc3339("2026-03-14T09:26:53+01:00").unwrap(),
+                component: Component::Decoder,
+                stalled_for: Duration::from_millis(6500),
+                recovery: Recovery::Rebuild(Component::Decoder),
+            }],
+        };
+        let text = report.render();
+        for line in [
+            "presented fps: 30.0",
+            "dropped frames: 3",
+            "late frames: 1",
+            "decode ms: p50 4.00, p95 6.50, p99 9.25, max 12.00",
+            "scale ms: p50 0.00",
+            "queue depth: 2",
+            "loops: 7",
+            "corrupt packets: 5",
+            "[incidents]\n2026-03-14 09:26:53 decoder stalled for 6.5s, rebuilt the decoder",
+        ] {
+            assert!(text.contains(line), "{:?} missing from\n{}", line, text);
+        }
+    }
+}
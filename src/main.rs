@@ -1,13 +1,34 @@
+mod clock;
 mod config;
 mod performance;
 mod ui;
 mod utils;
 mod wallpaper;
 mod diagnostics;
+mod schedule;
+mod shutdown;
 
-use crate::config::Settings;
-use crate::wallpaper::WallpaperPlayer;
-use crate::performance::PerformanceMonitor;
+use crate::config::{PlaybackPositions, Settings};
+use crate::config::settings::{AppRuleSettings, PlaylistItemSettings, PlaylistSettings};
+use crate::wallpaper::{WallpaperPlayer, WallpaperRenderer};
+use crate::wallpaper::command::{PlayerCommand, PlayerEvent};
+use crate::wallpaper::day_cycle::DayCycle;
+use crate::wallpaper::pause::PauseReason;
+use crate::wallpaper::playlist::Playlist;
+use crate::wallpaper::frame_cache::FrameCacheOptions;
+use crate::wallpaper::governor::QualityBounds;
+use crate::wallpaper::player::PlayerState;
+use crate::wallpaper::proxy::ProxyState;
+use crate::wallpaper::stream::StreamOptions;
+use crate::wallpaper::transition::TransitionKind;
+use crate::wallpaper::watchdog::Watchdog;
+use crate::performance::{MonitorSettings, PerformanceMonitor};
+use crate::performance::app_rules::AppRule;
+use crate::schedule::Scheduler;
+use crate::clock::{Clock, SystemClock};
+use std::sync::Arc;
+use crate::schedule::scheduler::Schedule;
+use slint::Model;
 
 slint::include_modules!();
 
@@ -24,37 +45,99 @@ async fn main() -> anyhow::Result<()> {
     let settings = Settings::load().unwrap_or_default();
     
     // 2. Initialize Core Components
-    let player = WallpaperPlayer::new();
-    let player_state = player.get_state();
-    
-    // Set initial state from settings
-    {
-        let mut s = player_state.lock().unwrap();
-        s.path = settings.wallpaper.path.clone();
-        s.resolution = settings.wallpaper.resolution.clone();
-        s.fps = match settings.wallpaper.fps_preset.as_str() {
-            "Power Saver" => 15,
-            "Balanced" => 30,
-            "Performance" => 60,
-            _ => 30,
-        };
+    // Initial state from settings; after this the player is only driven by commands
+    let state = PlayerState {
+        path: settings.wallpaper.path.clone(),
+        resolution: settings.wallpaper.resolution.clone(),
+        fps: fps_for_preset(&settings.wallpaper.fps_preset),
+        audio_enabled: settings.audio.enabled,
+        volume: settings.audio.volume,
+        muted: settings.audio.muted,
+        stream_options: StreamOptions {
+            timeout: std::time::Duration::from_secs(settings.network.timeout_secs as u64),
+            buffer_kb: settings.network.buffer_kb,
+            rtsp_over_tcp: settings.network.rtsp_over_tcp,
+        },
+        max_reconnect_delay: std::time::Duration::from_secs(settings.network.max_reconnect_delay_secs.max(1) as u64),
+        fallback_path: settings.wallpaper.fallback_path.clone(),
+        fallback_color: settings.wallpaper.fallback_rgb(),
+        proxy_enabled: settings.proxy.enabled,
+        frame_cache: FrameCacheOptions {
+            enabled: settings.frame_cache.enabled,
+            max_clip_secs: settings.frame_cache.max_clip_secs,
+            memory_budget: settings.frame_cache.memory_budget_mb as usize * 1024 * 1024,
+            compress: settings.frame_cache.compress,
+        },
+        resume: settings.playback.resume,
+        always_from_start: settings.playback.always_from_start.clone(),
+        positions: PlaybackPositions::load().unwrap_or_default(),
+        day_cycles: settings.playback.day_cycle.iter().map(|d| (d.path.clone(), DayCycle::from_settings(d))).collect(),
+        transition: TransitionKind::from_name(&settings.transition.kind),
+        transition_duration: std::time::Duration::from_millis(settings.transition.duration_ms as u64),
+        fade_on_pause: settings.transition.fade_on_pause,
+        reduced_fps: settings.power.reduced_fps,
+        reduced_scale: settings.power.reduced_scale,
+        release_after: std::time::Duration::from_secs(settings.power.release_after_secs as u64),
+        quality: QualityBounds::from_settings(&settings.quality),
+        ..Default::default()
+    };
+    let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+    let mut player = WallpaperPlayer::new(state, clock.clone());
+    let player_handle = player.handle();
+
+    if let Some(playlist) = settings.active_playlist() {
+        player_handle.send(PlayerCommand::SetPlaylist(Some(Playlist::from_settings(playlist))));
     }
 
-    let monitor = PerformanceMonitor::new(player_state.clone());
+    let (monitor_settings, monitor_updates) = tokio::sync::watch::channel(MonitorSettings::from_settings(&settings));
+    let monitor = PerformanceMonitor::new(player_handle.clone(), clock.clone(), monitor_updates);
+    let watchdog = Watchdog::new(player.progress(), player_handle.clone(), clock.clone());
+    let incidents = watchdog.incidents();
 
     // 3. Spwan Tasks
-    let _player_task = tokio::spawn(async move {
-        if let Err(e) = player.run().await {
-            tracing::error!("Player error: {}", e);
+    let player_clock = clock.clone();
+    let player_task = tokio::spawn(async move {
+        let result = match WallpaperRenderer::new(player_clock) {
+            Ok(renderer) => player.run(renderer).await,
+            Err(e) => Err(e.context("Failed to initialize the renderer")),
+        };
+        if let Err(e) = result {
+            tracing::error!("Player error: {:#}", e);
+        }
+    });
+
+    let watchdog_task = tokio::spawn(async move {
+        if let Err(e) = watchdog.run().await {
+            tracing::error!("Watchdog error: {}", e);
         }
     });
 
-    let _monitor_task = tokio::spawn(async move {
+    let monitor_task = tokio::spawn(async move {
         if let Err(e) = monitor.run().await {
             tracing::error!("Monitor error: {}", e);
         }
     });
 
+    let mut background = vec![watchdog_task, monitor_task];
+    if settings.schedule.enabled {
+        let schedule = Schedule::from_settings(&settings.schedule, &settings.playlists);
+        let scheduler = Scheduler::new(schedule, clock.clone(), player_handle.clone());
+        background.push(tokio::spawn(async move {
+            if let Err(e) = scheduler.run().await {
+                tracing::error!("Scheduler error: {}", e);
+            }
+        }));
+    }
+
+    // A signal closes the UI, and shutdown carries on below once the event loop returns
+    tokio::spawn(async {
+        shutdown::signalled().await;
+        tracing::info!("Signal received, exiting.");
+        let _ = slint::invoke_from_event_loop(|| {
+            let _ = slint::quit_event_loop();
+        });
+    });
+
     // 4. UI Setup
     let ui = AppWindow::new()?;
     ui.set_wallpaper_path(settings.wallpaper.path.clone().into());
@@ -68,6 +151,37 @@ async fn main() -> anyhow::Result<()> {
     ui.set_enable_glassmorphism(settings.performance.enable_glassmorphism);
     ui.set_show_icon_shortcuts(settings.performance.show_icon_shortcuts);
     ui.set_pause_on_battery(settings.performance.pause_on_battery);
+    ui.set_audio_enabled(settings.audio.enabled);
+    ui.set_audio_volume(settings.audio.volume as i32);
+    ui.set_audio_muted(settings.audio.muted);
+    ui.set_proxy_enabled(settings.proxy.enabled);
+    ui.set_transition_kind(settings.transition.kind.clone().into());
+    ui.set_transition_ms(settings.transition.duration_ms as i32);
+    ui.set_fade_on_pause(settings.transition.fade_on_pause);
+    // Edit the active playlist, or the first saved one
+    let edited_playlist = settings.active_playlist().or(settings.playlists.first()).cloned();
+    let playlist_items = std::rc::Rc::new(slint::VecModel::<slint::SharedString>::default());
+    ui.set_use_playlist(settings.active_playlist().is_some());
+    if let Some(ref playlist) = edited_playlist {
+        ui.set_playlist_name(playlist.name.clone().into());
+        ui.set_playlist_order(playlist.order.clone().into());
+        ui.set_playlist_interval(playlist.interval_secs as i32);
+        ui.set_playlist_loops(playlist.loops as i32);
+        for item in &playlist.items {
+            playlist_items.push(item.path.clone().into());
+        }
+    }
+    ui.set_playlist_items(playlist_items.clone().into());
+
+    let edited_rules = std::rc::Rc::new(std::cell::RefCell::new(settings.app_rules.clone()));
+    let app_rules = std::rc::Rc::new(slint::VecModel::<slint::SharedString>::default());
+    for rule in &settings.app_rules {
+        app_rules.push(describe_app_rule(rule).into());
+    }
+    ui.set_app_rules(app_rules.clone().into());
+
+    ui.set_start_from_beginning(settings.playback.always_from_start.contains(&settings.wallpaper.path));
+    ui.set_day_cycle(settings.playback.day_cycle(&settings.wallpaper.path).is_some());
 
     let ui_handle = ui.as_weak();
     ui.on_browse_clicked(move || {
@@ -78,23 +192,118 @@ async fn main() -> anyhow::Result<()> {
         {
             let path_str = path.to_string_lossy().to_string();
             ui.set_wallpaper_path(path_str.clone().into());
+            let playback = Settings::load().unwrap_or_default().playback;
+            ui.set_start_from_beginning(playback.always_from_start.contains(&path_str));
+            ui.set_day_cycle(playback.day_cycle(&path_str).is_some());
         }
     });
 
-    let state_for_ui = player_state.clone();
-    ui.on_apply_clicked(move |path, fps_preset, resolution, threshold, launch, pause_fs, tray, glass, icons, pause_bat| {
-        let mut s = state_for_ui.lock().unwrap();
-        s.path = path.to_string();
-        s.resolution = resolution.to_string();
-        s.fps = match fps_preset.as_str() {
-            "Power Saver" => 15,
-            "Balanced" => 30,
-            "Performance" => 60,
-            _ => 30,
+    let add_items = playlist_items.clone();
+    ui.on_playlist_add_clicked(move || {
+        if let Some(paths) = rfd::FileDialog::new()
+            .add_filter("Videos", &["mp4", "webm", "avi", "mkv"])
+            .pick_files()
+        {
+            for path in paths {
+                add_items.push(path.to_string_lossy().to_string().into());
+            }
+        }
+    });
+
+    let remove_items = playlist_items.clone();
+    ui.on_playlist_remove(move |index| {
+        if index >= 0 && (index as usize) < remove_items.row_count() {
+            remove_items.remove(index as usize);
+        }
+    });
+
+    let next_player = player_handle.clone();
+    ui.on_playlist_next(move || next_player.send(PlayerCommand::Next));
+    let previous_player = player_handle.clone();
+    ui.on_playlist_previous(move || previous_player.send(PlayerCommand::Previous));
+
+    let add_rule_ui = ui.as_weak();
+    let add_rules = (edited_rules.clone(), app_rules.clone());
+    ui.on_app_rule_add(move || {
+        let Some(ui) = add_rule_ui.upgrade() else { return };
+        let value = ui.get_app_rule_value().trim().to_string();
+        let action = ui.get_app_rule_action().to_string();
+        let rule = AppRuleSettings {
+            process: ui.get_app_rule_process().trim().to_string(),
+            window_class: ui.get_app_rule_window_class().trim().to_string(),
+            focused: ui.get_app_rule_focused(),
+            fps: if action == "cap_fps" { value.parse().unwrap_or(0) } else { 0 },
+            wallpaper: if action == "wallpaper" { value } else { String::new() },
+            action,
         };
-        
+        if let Err(e) = AppRule::from_settings(&rule) {
+            tracing::warn!("Not adding app rule: {}", e);
+            return;
+        }
+        add_rules.1.push(describe_app_rule(&rule).into());
+        add_rules.0.borrow_mut().push(rule);
+        ui.set_app_rule_process("".into());
+        ui.set_app_rule_window_class("".into());
+        ui.set_app_rule_value("".into());
+    });
+
+    let remove_rules = (edited_rules.clone(), app_rules.clone());
+    ui.on_app_rule_remove(move |index| {
+        if index >= 0 && (index as usize) < remove_rules.1.row_count() {
+            remove_rules.1.remove(index as usize);
+            remove_rules.0.borrow_mut().remove(index as usize);
+        }
+    });
+
+    let ui_player = player_handle.clone();
+    let apply_ui = ui.as_weak();
+    let apply_items = playlist_items.clone();
+    let apply_rules = edited_rules.clone();
+    ui.on_apply_clicked(move |path, fps_preset, resolution, threshold, launch, pause_fs, tray, glass, icons, pause_bat, audio_on, volume, muted, proxy_on, from_start, transition, transition_ms, fade_on_pause, day_cycle| {
+        ui_player.send(PlayerCommand::SetAlwaysFromStart { path: path.to_string(), enabled: from_start });
+        ui_player.send(PlayerCommand::SetResolution(resolution.to_string()));
+        ui_player.send(PlayerCommand::SetFps(fps_for_preset(&fps_preset)));
+        ui_player.send(PlayerCommand::SetAudio { enabled: audio_on, volume: volume.clamp(0, 100) as u32, muted });
+        ui_player.send(PlayerCommand::SetProxyEnabled(proxy_on));
+        let transition_ms = transition_ms.clamp(0, 5000) as u32;
+        ui_player.send(PlayerCommand::SetTransition {
+            kind: TransitionKind::from_name(&transition),
+            duration: std::time::Duration::from_millis(transition_ms as u64),
+            fade_on_pause,
+        });
+
         // Save to settings
         let mut settings = Settings::load().unwrap_or_default();
+        let playlist = apply_ui.upgrade().filter(|ui| ui.get_use_playlist() && apply_items.row_count() > 0).map(|ui| {
+            let name = ui.get_playlist_name().trim().to_string();
+            let mut playlist = PlaylistSettings::new(if name.is_empty() { "My Playlist" } else { &name });
+            // Per-item intervals and weights are only edited in the file; keep them for items still listed
+            let saved = settings.playlists.iter().find(|p| p.name == playlist.name);
+            playlist.items = apply_items
+                .iter()
+                .map(|path| {
+                    saved
+                        .and_then(|p| p.items.iter().find(|item| item.path == path.as_str()))
+                        .cloned()
+                        .unwrap_or_else(|| PlaylistItemSettings::new(&path))
+                })
+                .collect();
+            playlist.order = ui.get_playlist_order().to_string();
+            playlist.interval_secs = ui.get_playlist_interval().max(0) as u32;
+            playlist.loops = ui.get_playlist_loops().max(0) as u32;
+            playlist
+        });
+        match playlist {
+            Some(playlist) => {
+                ui_player.send(PlayerCommand::SetPlaylist(Some(Playlist::from_settings(&playlist))));
+                settings.wallpaper.playlist = playlist.name.clone();
+                settings.set_playlist(playlist);
+            }
+            None => {
+                ui_player.send(PlayerCommand::Load(path.to_string()));
+                settings.wallpaper.playlist.clear();
+            }
+        }
         settings.wallpaper.path = path.to_string();
         settings.wallpaper.fps_preset = fps_preset.to_string();
         settings.wallpaper.resolution = resolution.to_string();
@@ -106,17 +315,122 @@ async fn main() -> anyhow::Result<()> {
         settings.performance.enable_glassmorphism = glass;
         settings.performance.show_icon_shortcuts = icons;
         settings.performance.pause_on_battery = pause_bat;
+        settings.audio.enabled = audio_on;
+        settings.audio.volume = volume.clamp(0, 100) as u32;
+        settings.audio.muted = muted;
+        settings.proxy.enabled = proxy_on;
+        settings.playback.set_always_from_start(&path, from_start);
+        settings.playback.set_day_cycle(&path, day_cycle);
+        ui_player.send(PlayerCommand::SetDayCycle {
+            path: path.to_string(),
+            cycle: settings.playback.day_cycle(&path).map(DayCycle::from_settings),
+        });
+        settings.transition.kind = transition.to_string();
+        settings.transition.duration_ms = transition_ms;
+        settings.transition.fade_on_pause = fade_on_pause;
+        settings.app_rules = apply_rules.borrow().clone();
 
         let _ = settings.save();
+        monitor_settings.send_replace(MonitorSettings::from_settings(&settings));
         
         tracing::info!("Applied settings: {} at {}", path, resolution);
     });
 
+    let export_ui = ui.as_weak();
+    let export_player = player_handle.clone();
+    ui.on_export_diagnostics(move || {
+        let Some(ui) = export_ui.upgrade() else { return };
+        let report = diagnostics::Report {
+            generated: chrono::Local::now(),
+            stats: export_player.stats(),
+            incidents: incidents.lock().unwrap().iter().cloned().collect(),
+        };
+        let status = match diagnostics::export(&report) {
+            Ok(path) => format!("Saved to {}", path.display()),
+            Err(e) => {
+                tracing::warn!("Diagnostics export failed: {}", e);
+                format!("Export failed: {}", e)
+            }
+        };
+        ui.set_diagnostics_status(status.into());
+    });
+
+    let pause_player = player_handle.clone();
+    ui.on_pause_clicked(move |paused| {
+        pause_player.send(if paused { PlayerCommand::Pause(PauseReason::User) } else { PlayerCommand::Resume(PauseReason::User) });
+    });
+
+    let seek_player = player_handle.clone();
+    ui.on_seek(move |position| seek_player.send(PlayerCommand::Seek(position as f64)));
+
+    // Surface playback failures (corrupt file, dead stream), what's playing and proxy progress in the Library tab
+    let status_ui = ui.as_weak();
+    let mut events = player_handle.subscribe();
+    let status_timer = slint::Timer::default();
+    status_timer.start(slint::TimerMode::Repeated, std::time::Duration::from_millis(250), move || {
+        let Some(ui) = status_ui.upgrade() else { return };
+        loop {
+            match events.try_recv() {
+                Ok(PlayerEvent::Error(error)) => ui.set_player_error(error.into()),
+                Ok(PlayerEvent::Status(status)) => ui.set_playback_status(status.label().into()),
+                Ok(PlayerEvent::Loaded(info)) => {
+                    ui.set_player_error("".into());
+                    ui.set_proxy_status("".into());
+                    ui.set_media_info(info.to_string().into());
+                    ui.set_playback_duration(if info.is_live { 0.0 } else { info.duration.unwrap_or(0.0) as f32 });
+                }
+                Ok(PlayerEvent::Position { position, duration }) => {
+                    ui.set_playback_position(position as f32);
+                    ui.set_position_text(format!("{} / {}", minutes_seconds(position), duration.map_or("--:--".to_string(), minutes_seconds)).into());
+                }
+                Ok(PlayerEvent::PauseReasons(reasons)) => {
+                    ui.set_user_paused(reasons.contains(PauseReason::User));
+                    ui.set_pause_status(reasons.to_string().into());
+                }
+                Ok(PlayerEvent::Stats(stats)) => ui.set_player_stats(stats.to_string().into()),
+                Ok(PlayerEvent::ProxyProgress(progress)) => {
+                    let status = match progress.state {
+                        ProxyState::Running(fraction) => format!("Optimising video for this screen... {:.0}%", fraction * 100.0),
+                        _ => String::new(),
+                    };
+                    ui.set_proxy_status(status.into());
+                }
+                Err(tokio::sync::broadcast::error::TryRecvError::Lagged(_)) => {}
+                Err(_) => break,
+            }
+        }
+    });
+
     ui.on_exit_clicked(move || {
-        std::process::exit(0);
+        let _ = slint::quit_event_loop();
     });
 
-    ui.run()?;
+    let result = ui.run();
+    shutdown::shutdown(&player_handle, player_task, background).await;
+    result?;
 
     Ok(())
 }
+
+/// One line for the rules list; rules that can't be used say why.
+fn describe_app_rule(rule: &AppRuleSettings) -> String {
+    match AppRule::from_settings(rule) {
+        Ok(rule) => rule.to_string(),
+        Err(e) => format!("(ignored) {}", e),
+    }
+}
+
+/// `m:ss`, for the seek bar.
+fn minutes_seconds(secs: f64) -> String {
+    let secs = secs.max(0.0) as u64;
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+fn fps_for_preset(preset: &str) -> u32 {
+    match preset {
+        "Power Saver" => 15,
+        "Balanced" => 30,
+        "Performance" => 60,
+        _ => 30,
+    }
+}
@@ -0,0 +1,324 @@
+use crate::config::settings::AppRuleSettings;
+use crate::performance::fullscreen::WindowInfo;
+use anyhow::Result;
+use std::fmt;
+use sysinfo::{ProcessRefreshKind, System, UpdateKind};
+
+/// A process as sysinfo reported it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunningProcess {
+    pub pid: u32,
+    pub name: String,
+    pub path: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AppAction {
+    Pause,
+    /// Play at no more than this frame rate.
+    CapFps(u32),
+    Mute,
+    /// Play this wallpaper instead, going back to the usual one afterwards.
+    Wallpaper(String),
+}
+
+impl fmt::Display for AppAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppAction::Pause => write!(f, "pause"),
+            AppAction::CapFps(fps) => write!(f, "cap at {} fps", fps),
+            AppAction::Mute => write!(f, "mute"),
+            AppAction::Wallpaper(path) => write!(f, "switch to {}", path),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AppRule {
+    /// Lower case, with `/` for separators. Compared with the executable name, or with the
+    /// end of its path when it contains a separator.
+    pub process: Option<String>,
+    pub window_class: Option<String>,
+    pub focused: bool,
+    pub action: AppAction,
+}
+
+impl AppRule {
+    pub fn from_settings(settings: &AppRuleSettings) -> Result<Self> {
+        let process = Some(settings.process.trim().to_lowercase().replace('\\', "/")).filter(|p| !p.is_empty());
+        let window_class = Some(settings.window_class.trim().to_string()).filter(|c| !c.is_empty());
+        if process.is_none() && window_class.is_none() {
+            anyhow::bail!("App rule needs a process or a window class");
+        }
+        let action = match settings.action.to_ascii_lowercase().replace(['-', ' '], "_").as_str() {
+            "pause" => AppAction::Pause,
+            "cap_fps" if settings.fps > 0 => AppAction::CapFps(settings.fps),
+            "cap_fps" => anyhow::bail!("App rule caps the frame rate but has no fps"),
+            "mute" => AppAction::Mute,
+            "wallpaper" if !settings.wallpaper.is_empty() => AppAction::Wallpaper(settings.wallpaper.clone()),
+            "wallpaper" => anyhow::bail!("App rule switches wallpaper but has none set"),
+            other => anyhow::bail!("Unknown app rule action {:?}", other),
+        };
+        Ok(Self {
+            process,
+            window_class,
+            focused: settings.focused,
+            action,
+        })
+    }
+
+    fn matches_process(&self, process: &RunningProcess) -> bool {
+        let Some(pattern) = &self.process else {
+            return true;
+        };
+        if pattern.contains('/') {
+            // Whole path components only, so "common/game.exe" doesn't match "uncommon/game.exe"
+            return process.path.as_ref().is_some_and(|path| {
+                let path = path.to_lowercase().replace('\\', "/");
+                path.strip_suffix(pattern.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.ends_with('/') || pattern.starts_with('/'))
+            });
+        }
+        let name = process.name.to_lowercase();
+        name == *pattern || name.trim_end_matches(".exe") == pattern.trim_end_matches(".exe")
+    }
+
+    /// Whether the rule holds for these processes and top-level windows.
+    pub fn matches(&self, processes: &[RunningProcess], windows: &[WindowInfo]) -> bool {
+        let pids: Option<Vec<u32>> = self
+            .process
+            .as_ref()
+            .map(|_| processes.iter().filter(|p| self.matches_process(p)).map(|p| p.pid).collect());
+        if pids.as_ref().is_some_and(|pids| pids.is_empty()) {
+            return false;
+        }
+        if self.window_class.is_none() && !self.focused {
+            return true;
+        }
+        windows.iter().any(|window| {
+            window.visible
+                && (!self.focused || window.focused)
+                && self.window_class.as_ref().is_none_or(|class| class.eq_ignore_ascii_case(&window.class))
+                && pids.as_ref().is_none_or(|pids| window.pid.is_some_and(|pid| pids.contains(&pid)))
+        })
+    }
+}
+
+impl fmt::Display for AppRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} while ", self.action)?;
+        match (&self.process, &self.window_class) {
+            (Some(process), Some(class)) => write!(f, "{} ({})", process, class)?,
+            (Some(process), None) => write!(f, "{}", process)?,
+            (None, Some(class)) => write!(f, "a {} window", class)?,
+            (None, None) => write!(f, "anything")?,
+        }
+        write!(f, " {}", if self.focused { "is focused" } else { "runs" })
+    }
+}
+
+/// What the matching rules add up to.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AppEffects {
+    /// Indices of the rules that matched, in order.
+    pub matched: Vec<usize>,
+    pub pause: bool,
+    /// The lowest cap of any matching rule.
+    pub fps_cap: Option<u32>,
+    pub mute: bool,
+    /// The first matching rule's wallpaper.
+    pub wallpaper: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AppRules {
+    pub rules: Vec<AppRule>,
+}
+
+impl AppRules {
+    /// Build from settings, skipping (and logging) rules that can't be used.
+    pub fn from_settings(settings: &[AppRuleSettings]) -> Self {
+        let rules = settings
+            .iter()
+            .filter_map(|rule| match AppRule::from_settings(rule) {
+                Ok(rule) => Some(rule),
+                Err(e) => {
+                    tracing::warn!("Skipping app rule: {}", e);
+                    None
+                }
+            })
+            .collect();
+        Self { rules }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn evaluate(&self, processes: &[RunningProcess], windows: &[WindowInfo]) -> AppEffects {
+        let mut effects = AppEffects::default();
+        for (index, rule) in self.rules.iter().enumerate() {
+            if !rule.matches(processes, windows) {
+                continue;
+            }
+            effects.matched.push(index);
+            match &rule.action {
+                AppAction::Pause => effects.pause = true,
+                AppAction::CapFps(fps) => effects.fps_cap = Some(effects.fps_cap.map_or(*fps, |cap| cap.min(*fps))),
+                AppAction::Mute => effects.mute = true,
+                AppAction::Wallpaper(path) => {
+                    effects.wallpaper.get_or_insert_with(|| path.clone());
+                }
+            }
+        }
+        effects
+    }
+}
+
+/// Running processes, refreshed on demand. Only names and paths are read.
+pub struct ProcessList {
+    system: System,
+}
+
+impl ProcessList {
+    pub fn new() -> Self {
+        Self { system: System::new() }
+    }
+
+    pub fn snapshot(&mut self) -> Vec<RunningProcess> {
+        self.system
+            .refresh_processes_specifics(ProcessRefreshKind::new().with_exe(UpdateKind::OnlyIfNotSet));
+        self.system
+            .processes()
+            .iter()
+            .map(|(pid, process)| RunningProcess {
+                pid: pid.as_u32(),
+                name: process.name().to_string(),
+                path: process.exe().map(|path| path.to_string_lossy().into_owned()),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::performance::fullscreen::Rect;
+
+    fn settings(process: &str, window_class: &str, action: &str) -> AppRuleSettings {
+        AppRuleSettings {
+            process: process.to_string(),
+            window_class: window_class.to_string(),
+            focused: false,
+            action: action.to_string(),
+            fps: 0,
+            wallpaper: String::new(),
+        }
+    }
+
+    fn rule(process: &str, window_class: &str, action: &str) -> AppRule {
+        AppRule::from_settings(&settings(process, window_class, action)).unwrap()
+    }
+
+    fn process(pid: u32, name: &str, path: Option<&str>) -> RunningProcess {
+        RunningProcess { pid, name: name.to_string(), path: path.map(str::to_string) }
+    }
+
+    fn window(class: &str, pid: u32, focused: bool) -> WindowInfo {
+        WindowInfo {
+            class: class.to_string(),
+            pid: Some(pid),
+            bounds: Rect::new(0, 0, 800, 600),
+            visible: true,
+            focused,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn unusable_rules_are_refused() {
+        let error = |settings: AppRuleSettings| AppRule::from_settings(&settings).unwrap_err().to_string();
+        assert!(error(settings(" ", "", "pause")).contains("process or a window class"));
+        assert!(error(settings("game.exe", "", "cap_fps")).contains("no fps"));
+        assert!(error(settings("game.exe", "", "wallpaper")).contains("none set"));
+        assert!(error(settings("game.exe", "", "explode")).contains("Unknown app rule action"));
+
+        let capped = AppRule::from_settings(&AppRuleSettings { fps: 15, ..settings("game.exe", "", "Cap FPS") }).unwrap();
+        assert_eq!(capped.action, AppAction::CapFps(15));
+        assert_eq!(rule("", "UnityWndClass", "mute").action, AppAction::Mute);
+    }
+
+    #[test]
+    fn process_names_match_with_or_without_exe() {
+        let processes = [process(1, "UnityEditor.exe", None)];
+        assert!(rule("unityeditor.exe", "", "pause").matches(&processes, &[]));
+        assert!(rule("UnityEditor", "", "pause").matches(&processes, &[]));
+        assert!(!rule("unity", "", "pause").matches(&processes, &[]));
+
+        let linux = [process(2, "blender", None)];
+        assert!(rule("Blender.exe", "", "pause").matches(&linux, &[]));
+    }
+
+    #[test]
+    fn paths_match_whole_trailing_components() {
+        let processes = [process(1, "Game.exe", Some("C:\\Games\\SteamApps\\common\\Game.exe"))];
+        assert!(rule("steamapps\\common\\game.exe", "", "pause").matches(&processes, &[]));
+        assert!(rule("common/Game.exe", "", "pause").matches(&processes, &[]));
+        assert!(!rule("mmon\\game.exe", "", "pause").matches(&processes, &[]));
+        // A path pattern never falls back to the bare name
+        assert!(!rule("other\\game.exe", "", "pause").matches(&processes, &[]));
+        assert!(!rule("common\\game.exe", "", "pause").matches(&[process(1, "Game.exe", None)], &[]));
+    }
+
+    #[test]
+    fn every_criterion_has_to_hold_on_the_same_window() {
+        let processes = [process(10, "code.exe", None), process(20, "game.exe", None)];
+        let editor = window("Chrome_WidgetWin_1", 10, false);
+        let game = window("UnityWndClass", 20, true);
+
+        // The class has to belong to one of the process's own windows
+        assert!(rule("game.exe", "UnityWndClass", "pause").matches(&processes, &[editor.clone(), game.clone()]));
+        assert!(!rule("code.exe", "UnityWndClass", "pause").matches(&processes, &[editor.clone(), game.clone()]));
+        assert!(rule("", "unitywndclass", "pause").matches(&processes, std::slice::from_ref(&game)));
+
+        let focused = |process: &str| AppRule { focused: true, ..rule(process, "", "pause") };
+        assert!(focused("game.exe").matches(&processes, &[editor.clone(), game.clone()]));
+        assert!(!focused("code.exe").matches(&processes, &[editor.clone(), game.clone()]));
+
+        // Hidden windows don't count, and neither do windows without a pid when there's a process to match
+        let hidden = WindowInfo { visible: false, ..game.clone() };
+        assert!(!rule("game.exe", "UnityWndClass", "pause").matches(&processes, &[hidden]));
+        let anonymous = WindowInfo { pid: None, ..game.clone() };
+        assert!(!rule("game.exe", "UnityWndClass", "pause").matches(&processes, std::slice::from_ref(&anonymous)));
+        assert!(rule("", "UnityWndClass", "pause").matches(&processes, &[anonymous]));
+
+        // A process rule with nothing else to check only needs the process running
+        assert!(rule("game.exe", "", "pause").matches(&processes, &[]));
+        assert!(!rule("missing.exe", "", "pause").matches(&processes, &[game]));
+    }
+
+    #[test]
+    fn matching_rules_combine() {
+        let rules = AppRules::from_settings(&[
+            AppRuleSettings { fps: 30, ..settings("code.exe", "", "cap_fps") },
+            AppRuleSettings { wallpaper: "focus.mp4".into(), ..settings("code.exe", "", "wallpaper") },
+            settings("", "", "pause"),
+            AppRuleSettings { fps: 15, ..settings("game.exe", "", "cap_fps") },
+            AppRuleSettings { wallpaper: "game.mp4".into(), ..settings("game.exe", "", "wallpaper") },
+            settings("game.exe", "", "mute"),
+            settings("missing.exe", "", "pause"),
+        ]);
+        // The rule with neither a process nor a class was skipped
+        assert_eq!(rules.rules.len(), 6);
+
+        let processes = [process(10, "code.exe", None), process(20, "game.exe", None)];
+        let effects = rules.evaluate(&processes, &[]);
+        assert_eq!(effects.matched, [0, 1, 2, 3, 4]);
+        assert_eq!(effects.fps_cap, Some(15));
+        assert_eq!(effects.wallpaper.as_deref(), Some("focus.mp4"));
+        assert!(effects.mute);
+        assert!(!effects.pause);
+
+        assert_eq!(rules.evaluate(&[], &[]), AppEffects::default());
+    }
+}
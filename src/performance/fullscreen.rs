@@ -0,0 +1,540 @@
+/// Screen rectangle in desktop coordinates, right and bottom exclusive.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Rect {
+    pub left: i32,
+    pub top: i32,
+    pub right: i32,
+    pub bottom: i32,
+}
+
+impl Rect {
+    pub fn new(left: i32, top: i32, width: i32, height: i32) -> Self {
+        Self { left, top, right: left + width, bottom: top + height }
+    }
+
+    pub fn intersects(&self, other: &Rect) -> bool {
+        self.left < other.right && other.left < self.right && self.top < other.bottom && other.top < self.bottom
+    }
+
+    /// Covers all of `other`, give or take `slack` pixels on each edge for borders.
+    pub fn covers(&self, other: &Rect, slack: i32) -> bool {
+        self.left <= other.left + slack
+            && self.top <= other.top + slack
+            && self.right >= other.right - slack
+            && self.bottom >= other.bottom - slack
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MonitorInfo {
+    pub name: String,
+    pub bounds: Rect,
+    /// The wallpaper is drawn on this monitor.
+    pub shows_wallpaper: bool,
+}
+
+/// A top-level window as the backend saw it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WindowInfo {
+    /// Win32 window class, or the class half of X11 `WM_CLASS`.
+    pub class: String,
+    pub pid: Option<u32>,
+    pub bounds: Rect,
+    pub visible: bool,
+    pub minimized: bool,
+    pub maximized: bool,
+    pub focused: bool,
+    /// The window manager's own say, where there is one (X11 `_NET_WM_STATE_FULLSCREEN`).
+    pub fullscreen_hint: Option<bool>,
+    /// Part of the desktop or shell, e.g. an X11 desktop or dock window.
+    pub shell: bool,
+}
+
+/// Monitors and top-level windows at one moment.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DesktopSnapshot {
+    pub monitors: Vec<MonitorInfo>,
+    pub windows: Vec<WindowInfo>,
+}
+
+/// What the policy found on one monitor.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MonitorVerdict {
+    pub monitor: MonitorInfo,
+    /// The fullscreen window covering it, if any.
+    pub covered_by: Option<WindowInfo>,
+}
+
+/// The verdict for every monitor in a snapshot.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FullscreenReport {
+    pub monitors: Vec<MonitorVerdict>,
+}
+
+impl FullscreenReport {
+    /// A fullscreen window hides the wallpaper on at least one monitor it's drawn on.
+    pub fn covers_wallpaper(&self) -> bool {
+        self.monitors.iter().any(|verdict| verdict.monitor.shows_wallpaper && verdict.covered_by.is_some())
+    }
+
+    /// "firefox on DP-1, Game on HDMI-1", for the log.
+    pub fn describe(&self) -> String {
+        let covered: Vec<String> = self
+            .monitors
+            .iter()
+            .filter_map(|verdict| {
+                let window = verdict.covered_by.as_ref()?;
+                Some(format!("{} on {}", window.class, verdict.monitor.name))
+            })
+            .collect();
+        if covered.is_empty() {
+            "no fullscreen windows".to_string()
+        } else {
+            covered.join(", ")
+        }
+    }
+}
+
+/// Decides, monitor by monitor, whether a fullscreen window is in the way. Windows that
+/// belong to the desktop, the shell or Mew itself never count, and neither do maximised
+/// ones, which only fill the work area even when that happens to be the whole screen.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FullscreenPolicy {
+    /// Window classes that never count, compared case-insensitively.
+    pub excluded_classes: Vec<String>,
+    /// Our own process, whose windows never count.
+    pub own_pid: Option<u32>,
+    /// Pixels a window may fall short of each monitor edge by.
+    pub slack: i32,
+}
+
+impl Default for FullscreenPolicy {
+    fn default() -> Self {
+        Self {
+            excluded_classes: [
+                // Windows desktop and taskbars
+                "Progman",
+                "WorkerW",
+                "Shell_TrayWnd",
+                "Shell_SecondaryTrayWnd",
+                // The wallpaper itself
+                "MewWallpaperClass",
+            ]
+            .into_iter()
+            .map(String::from)
+            .collect(),
+            own_pid: Some(std::process::id()),
+            slack: 2,
+        }
+    }
+}
+
+impl FullscreenPolicy {
+    pub fn evaluate(&self, snapshot: &DesktopSnapshot) -> FullscreenReport {
+        let monitors = snapshot
+            .monitors
+            .iter()
+            .map(|monitor| MonitorVerdict {
+                monitor: monitor.clone(),
+                covered_by: snapshot.windows.iter().find(|window| self.covers(window, monitor)).cloned(),
+            })
+            .collect();
+        FullscreenReport { monitors }
+    }
+
+    fn excluded(&self, window: &WindowInfo) -> bool {
+        window.shell
+            || (self.own_pid.is_some() && window.pid == self.own_pid)
+            || self.excluded_classes.iter().any(|class| class.eq_ignore_ascii_case(&window.class))
+    }
+
+    fn covers(&self, window: &WindowInfo, monitor: &MonitorInfo) -> bool {
+        if !window.visible || window.minimized || self.excluded(window) || !window.bounds.intersects(&monitor.bounds) {
+            return false;
+        }
+        match window.fullscreen_hint {
+            Some(fullscreen) => fullscreen,
+            None => !window.maximized && window.bounds.covers(&monitor.bounds, self.slack),
+        }
+    }
+}
+
+/// Somewhere to take desktop snapshots from.
+pub trait WindowSource: Send {
+    fn name(&self) -> &'static str;
+    fn snapshot(&mut self) -> anyhow::Result<DesktopSnapshot>;
+}
+
+/// The source for the platform we're running on, if it has one.
+pub fn system() -> Option<Box<dyn WindowSource>> {
+    #[cfg(windows)]
+    {
+        Some(Box::new(win32::Win32WindowSource))
+    }
+    #[cfg(target_os = "linux")]
+    {
+        Some(Box::new(x11::X11WindowSource::default()))
+    }
+    #[cfg(not(any(windows, target_os = "linux")))]
+    {
+        None
+    }
+}
+
+#[cfg(windows)]
+mod win32 {
+    use super::{DesktopSnapshot, MonitorInfo, Rect, WindowInfo, WindowSource};
+    use windows::Win32::Foundation::{BOOL, HWND, LPARAM, RECT};
+    use windows::Win32::Graphics::Dwm::{DwmGetWindowAttribute, DWMWA_CLOAKED};
+    use windows::Win32::Graphics::Gdi::{EnumDisplayMonitors, GetMonitorInfoW, HDC, HMONITOR, MONITORINFO, MONITORINFOEXW};
+    use windows::Win32::UI::WindowsAndMessaging::{
+        EnumWindows, GetClassNameW, GetForegroundWindow, GetWindowRect, GetWindowThreadProcessId, IsIconic,
+        IsWindowVisible, IsZoomed, MONITORINFOF_PRIMARY,
+    };
+
+    fn rect(r: &RECT) -> Rect {
+        Rect::new(r.left, r.top, r.right - r.left, r.bottom - r.top)
+    }
+
+    unsafe extern "system" fn collect_monitor(monitor: HMONITOR, _: HDC, _: *mut RECT, data: LPARAM) -> BOOL {
+        let monitors = &mut *(data.0 as *mut Vec<MonitorInfo>);
+        let mut info = MONITORINFOEXW::default();
+        info.monitorInfo.cbSize = std::mem::size_of::<MONITORINFOEXW>() as u32;
+        if GetMonitorInfoW(monitor, &mut info as *mut MONITORINFOEXW as *mut MONITORINFO).as_bool() {
+            let name = String::from_utf16_lossy(&info.szDevice);
+            monitors.push(MonitorInfo {
+                name: name.trim_end_matches('\0').to_string(),
+                bounds: rect(&info.monitorInfo.rcMonitor),
+                // The wallpaper window is sized to the primary screen
+                shows_wallpaper: info.monitorInfo.dwFlags & MONITORINFOF_PRIMARY != 0,
+            });
+        }
+        true.into()
+    }
+
+    unsafe extern "system" fn collect_window(hwnd: HWND, data: LPARAM) -> BOOL {
+        (*(data.0 as *mut Vec<HWND>)).push(hwnd);
+        true.into()
+    }
+
+    unsafe fn describe(hwnd: HWND, foreground: HWND) -> WindowInfo {
+        let mut class = [0u16; 256];
+        let len = GetClassNameW(hwnd, &mut class).max(0) as usize;
+        let mut pid = 0u32;
+        GetWindowThreadProcessId(hwnd, Some(&mut pid));
+        let mut bounds = RECT::default();
+        let _ = GetWindowRect(hwnd, &mut bounds);
+        // UWP apps and windows on other virtual desktops are "visible" but cloaked
+        let mut cloaked = 0u32;
+        let _ = DwmGetWindowAttribute(
+            hwnd,
+            DWMWA_CLOAKED,
+            &mut cloaked as *mut u32 as *mut _,
+            std::mem::size_of::<u32>() as u32,
+        );
+        WindowInfo {
+            class: String::from_utf16_lossy(&class[..len]),
+            pid: (pid != 0).then_some(pid),
+            bounds: rect(&bounds),
+            visible: IsWindowVisible(hwnd).as_bool() && cloaked == 0,
+            minimized: IsIconic(hwnd).as_bool(),
+            maximized: IsZoomed(hwnd).as_bool(),
+            focused: hwnd == foreground,
+            fullscreen_hint: None,
+            shell: false,
+        }
+    }
+
+    pub struct Win32WindowSource;
+
+    impl WindowSource for Win32WindowSource {
+        fn name(&self) -> &'static str {
+            "Win32"
+        }
+
+        fn snapshot(&mut self) -> anyhow::Result<DesktopSnapshot> {
+            let mut monitors: Vec<MonitorInfo> = Vec::new();
+            let mut handles: Vec<HWND> = Vec::new();
+            unsafe {
+                if !EnumDisplayMonitors(HDC::default(), None, Some(collect_monitor), LPARAM(&mut monitors as *mut _ as isize))
+                    .as_bool()
+                {
+                    anyhow::bail!("EnumDisplayMonitors failed");
+                }
+                EnumWindows(Some(collect_window), LPARAM(&mut handles as *mut _ as isize))?;
+                let foreground = GetForegroundWindow();
+                let windows = handles.into_iter().map(|hwnd| describe(hwnd, foreground)).collect();
+                Ok(DesktopSnapshot { monitors, windows })
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod x11 {
+    use super::{DesktopSnapshot, MonitorInfo, Rect, WindowInfo, WindowSource};
+    use x11rb::connection::Connection;
+    use x11rb::protocol::randr::ConnectionExt as _;
+    use x11rb::protocol::xproto::{Atom, AtomEnum, ConnectionExt as _, MapState, Window};
+    use x11rb::rust_connection::RustConnection;
+
+    struct Atoms {
+        client_list: Atom,
+        active_window: Atom,
+        state: Atom,
+        fullscreen: Atom,
+        hidden: Atom,
+        maximized_vert: Atom,
+        maximized_horz: Atom,
+        window_type: Atom,
+        type_desktop: Atom,
+        type_dock: Atom,
+        pid: Atom,
+    }
+
+    struct Session {
+        conn: RustConnection,
+        root: Window,
+        atoms: Atoms,
+    }
+
+    /// Reads the EWMH properties of the windows the window manager lists. Connects on
+    /// first use and again after an error, e.g. when the X server went away.
+    #[derive(Default)]
+    pub struct X11WindowSource {
+        session: Option<Session>,
+    }
+
+    fn connect() -> anyhow::Result<Session> {
+        let (conn, screen) = x11rb::connect(None)?;
+        let root = conn.setup().roots[screen].root;
+        let atom = |name: &str| -> anyhow::Result<Atom> { Ok(conn.intern_atom(false, name.as_bytes())?.reply()?.atom) };
+        let atoms = Atoms {
+            client_list: atom("_NET_CLIENT_LIST_STACKING")?,
+            active_window: atom("_NET_ACTIVE_WINDOW")?,
+            state: atom("_NET_WM_STATE")?,
+            fullscreen: atom("_NET_WM_STATE_FULLSCREEN")?,
+            hidden: atom("_NET_WM_STATE_HIDDEN")?,
+            maximized_vert: atom("_NET_WM_STATE_MAXIMIZED_VERT")?,
+            maximized_horz: atom("_NET_WM_STATE_MAXIMIZED_HORZ")?,
+            window_type: atom("_NET_WM_WINDOW_TYPE")?,
+            type_desktop: atom("_NET_WM_WINDOW_TYPE_DESKTOP")?,
+            type_dock: atom("_NET_WM_WINDOW_TYPE_DOCK")?,
+            pid: atom("_NET_WM_PID")?,
+        };
+        Ok(Session { conn, root, atoms })
+    }
+
+    impl Session {
+        fn property32(&self, window: Window, property: Atom, kind: AtomEnum) -> anyhow::Result<Vec<u32>> {
+            let reply = self.conn.get_property(false, window, property, kind, 0, 4096)?.reply()?;
+            Ok(reply.value32().map(|values| values.collect()).unwrap_or_default())
+        }
+
+        fn monitors(&self) -> anyhow::Result<Vec<MonitorInfo>> {
+            let reply = self.conn.randr_get_monitors(self.root, true)?.reply()?;
+            let mut monitors = Vec::new();
+            for monitor in reply.monitors {
+                let name = self.conn.get_atom_name(monitor.name)?.reply()?;
+                monitors.push(MonitorInfo {
+                    name: String::from_utf8_lossy(&name.name).into_owned(),
+                    bounds: Rect::new(monitor.x as i32, monitor.y as i32, monitor.width as i32, monitor.height as i32),
+                    shows_wallpaper: true,
+                });
+            }
+            if monitors.is_empty() {
+                // No RandR monitors configured; treat the whole screen as one
+                let geometry = self.conn.get_geometry(self.root)?.reply()?;
+                monitors.push(MonitorInfo {
+                    name: "screen".to_string(),
+                    bounds: Rect::new(0, 0, geometry.width as i32, geometry.height as i32),
+                    shows_wallpaper: true,
+                });
+            }
+            Ok(monitors)
+        }
+
+        fn window(&self, window: Window, active: Option<Window>) -> anyhow::Result<WindowInfo> {
+            let a = &self.atoms;
+            let state = self.property32(window, a.state, AtomEnum::ATOM)?;
+            let types = self.property32(window, a.window_type, AtomEnum::ATOM)?;
+            let class = self.conn.get_property(false, window, AtomEnum::WM_CLASS, AtomEnum::STRING, 0, 256)?.reply()?;
+            // WM_CLASS is "instance\0class\0"
+            let class = class.value.split(|b| *b == 0).nth(1).map(|c| String::from_utf8_lossy(c).into_owned());
+            let geometry = self.conn.get_geometry(window)?.reply()?;
+            let origin = self.conn.translate_coordinates(window, self.root, 0, 0)?.reply()?;
+            let attributes = self.conn.get_window_attributes(window)?.reply()?;
+            Ok(WindowInfo {
+                class: class.unwrap_or_default(),
+                pid: self.property32(window, a.pid, AtomEnum::CARDINAL)?.first().copied(),
+                bounds: Rect::new(origin.dst_x as i32, origin.dst_y as i32, geometry.width as i32, geometry.height as i32),
+                visible: attributes.map_state == MapState::VIEWABLE,
+                minimized: state.contains(&a.hidden),
+                maximized: state.contains(&a.maximized_vert) && state.contains(&a.maximized_horz),
+                focused: active == Some(window),
+                fullscreen_hint: Some(state.contains(&a.fullscreen)),
+                shell: types.contains(&a.type_desktop) || types.contains(&a.type_dock),
+            })
+        }
+
+        fn snapshot(&self) -> anyhow::Result<DesktopSnapshot> {
+            let monitors = self.monitors()?;
+            let active = self.property32(self.root, self.atoms.active_window, AtomEnum::WINDOW)?.first().copied();
+            let mut windows = Vec::new();
+            // Bottom to top; topmost first like Win32
+            for window in self.property32(self.root, self.atoms.client_list, AtomEnum::WINDOW)?.into_iter().rev() {
+                // Windows can close between listing and asking about them
+                if let Ok(info) = self.window(window, active) {
+                    windows.push(info);
+                }
+            }
+            Ok(DesktopSnapshot { monitors, windows })
+        }
+    }
+
+    impl WindowSource for X11WindowSource {
+        fn name(&self) -> &'static str {
+            "X11"
+        }
+
+        fn snapshot(&mut self) -> anyhow::Result<DesktopSnapshot> {
+            let session = match self.session.take() {
+                Some(session) => session,
+                None => connect()?,
+            };
+            let snapshot = session.snapshot()?;
+            self.session = Some(session);
+            Ok(snapshot)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OWN_PID: u32 = 4242;
+
+    /// A 1920x1080 primary with the wallpaper, and a 1280x1024 secondary to its right without.
+    fn monitors() -> Vec<MonitorInfo> {
+        vec![
+            MonitorInfo { name: "primary".to_string(), bounds: Rect::new(0, 0, 1920, 1080), shows_wallpaper: true },
+            MonitorInfo { name: "secondary".to_string(), bounds: Rect::new(1920, 0, 1280, 1024), shows_wallpaper: false },
+        ]
+    }
+
+    fn window(class: &str, bounds: Rect) -> WindowInfo {
+        WindowInfo { class: class.to_string(), pid: Some(1000), bounds, visible: true, ..Default::default() }
+    }
+
+    fn policy() -> FullscreenPolicy {
+        FullscreenPolicy { own_pid: Some(OWN_PID), ..Default::default() }
+    }
+
+    fn evaluate(windows: Vec<WindowInfo>) -> FullscreenReport {
+        policy().evaluate(&DesktopSnapshot { monitors: monitors(), windows })
+    }
+
+    /// The class covering each monitor, in monitor order.
+    fn covering(report: &FullscreenReport) -> Vec<Option<&str>> {
+        report.monitors.iter().map(|verdict| verdict.covered_by.as_ref().map(|w| w.class.as_str())).collect()
+    }
+
+    #[test]
+    fn a_fullscreen_window_covers_the_wallpaper() {
+        let report = evaluate(vec![window("Game", Rect::new(0, 0, 1920, 1080))]);
+
+        assert_eq!(covering(&report), [Some("Game"), None]);
+        assert!(report.covers_wallpaper());
+        assert_eq!(report.describe(), "Game on primary");
+    }
+
+    #[test]
+    fn fullscreen_on_a_monitor_without_the_wallpaper_doesnt_count() {
+        let report = evaluate(vec![window("mpv", Rect::new(1920, 0, 1280, 1024))]);
+
+        assert_eq!(covering(&report), [None, Some("mpv")]);
+        assert!(!report.covers_wallpaper());
+        assert_eq!(report.describe(), "mpv on secondary");
+    }
+
+    #[test]
+    fn a_window_spanning_both_monitors_covers_each() {
+        let report = evaluate(vec![window("Spanned", Rect::new(0, 0, 3200, 1080))]);
+        assert_eq!(covering(&report), [Some("Spanned"), Some("Spanned")]);
+
+        // Tall enough for the secondary, too short for the primary
+        let report = evaluate(vec![window("Spanned", Rect::new(0, 0, 3200, 1024))]);
+        assert_eq!(covering(&report), [None, Some("Spanned")]);
+    }
+
+    #[test]
+    fn borders_within_the_slack_still_cover() {
+        let report = evaluate(vec![window("Bordered", Rect::new(2, 1, 1918 - 2, 1078))]);
+        assert!(report.covers_wallpaper());
+
+        let report = evaluate(vec![window("Small", Rect::new(0, 0, 1920, 1040))]);
+        assert!(!report.covers_wallpaper());
+    }
+
+    #[test]
+    fn maximised_windows_are_not_fullscreen() {
+        // Taskbar on auto-hide: the work area is the whole screen
+        let maximised = WindowInfo { maximized: true, ..window("Notepad", Rect::new(0, 0, 1920, 1080)) };
+        assert!(!evaluate(vec![maximised]).covers_wallpaper());
+
+        let fullscreen = WindowInfo { maximized: false, ..window("Notepad", Rect::new(0, 0, 1920, 1080)) };
+        assert!(evaluate(vec![fullscreen]).covers_wallpaper());
+    }
+
+    #[test]
+    fn excluded_classes_and_our_own_windows_never_count() {
+        let screen = Rect::new(0, 0, 1920, 1080);
+        let ours = WindowInfo { pid: Some(OWN_PID), ..window("MewSettings", screen) };
+        let shell = WindowInfo { shell: true, ..window("xfdesktop", screen) };
+        let report = evaluate(vec![window("Progman", screen), window("workerw", screen), ours, shell]);
+
+        assert_eq!(covering(&report), [None, None]);
+        assert_eq!(report.describe(), "no fullscreen windows");
+    }
+
+    #[test]
+    fn hidden_cloaked_and_minimised_windows_never_count() {
+        let screen = Rect::new(0, 0, 1920, 1080);
+        // Cloaked windows, e.g. on another virtual desktop, come through as not visible
+        let cloaked = WindowInfo { visible: false, ..window("Cloaked", screen) };
+        let minimised = WindowInfo { minimized: true, ..window("Minimised", screen) };
+
+        assert!(!evaluate(vec![cloaked, minimised]).covers_wallpaper());
+    }
+
+    #[test]
+    fn the_first_covering_window_is_named() {
+        let screen = Rect::new(0, 0, 1920, 1080);
+        let report = evaluate(vec![window("Progman", screen), window("Top", screen), window("Below", screen)]);
+
+        assert_eq!(covering(&report), [Some("Top"), None]);
+    }
+
+    #[test]
+    fn the_window_manager_hint_overrides_geometry() {
+        // Asked for fullscreen but not resized yet
+        let hinted = WindowInfo { fullscreen_hint: Some(true), ..window("firefox", Rect::new(100, 100, 800, 600)) };
+        assert_eq!(covering(&evaluate(vec![hinted])), [Some("firefox"), None]);
+
+        // Screen-sized, but the window manager says it isn't fullscreen
+        let sized = WindowInfo { fullscreen_hint: Some(false), ..window("borderless", Rect::new(0, 0, 1920, 1080)) };
+        assert!(!evaluate(vec![sized]).covers_wallpaper());
+
+        // The hint only applies to the monitors the window is on
+        let elsewhere = WindowInfo { fullscreen_hint: Some(true), ..window("mpv", Rect::new(1920, 0, 1280, 1024)) };
+        assert_eq!(covering(&evaluate(vec![elsewhere])), [None, Some("mpv")]);
+    }
+
+    #[test]
+    fn rects_touching_at_an_edge_dont_intersect() {
+        let primary = Rect::new(0, 0, 1920, 1080);
+        assert!(!primary.intersects(&Rect::new(1920, 0, 1280, 1024)));
+        assert!(primary.intersects(&Rect::new(1919, 0, 1280, 1024)));
+    }
+}
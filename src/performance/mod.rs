@@ -1,3 +1,8 @@
+pub mod app_rules;
+pub mod fullscreen;
 pub mod monitor;
+pub mod policy;
+pub mod power;
+pub mod presence;
 
-pub use monitor::PerformanceMonitor;
+pub use monitor::{MonitorSettings, PerformanceMonitor};
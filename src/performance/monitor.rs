@@ -1,65 +1,235 @@
-use battery::Manager;
-use windows::Win32::UI::WindowsAndMessaging::{GetForegroundWindow, GetWindowRect};
-use std::sync::{Arc, Mutex};
-use crate::wallpaper::player::PlayerState;
-use tokio::time::{sleep, Duration};
+use crate::clock::Clock;
+use crate::config::Settings;
+use crate::performance::app_rules::{AppEffects, AppRules, ProcessList};
+use crate::performance::fullscreen::{self, DesktopSnapshot, FullscreenPolicy, WindowSource};
+use crate::performance::policy::{PolicyTier, PowerConditions, PowerMode, PowerPolicy};
+use crate::performance::power::{self, PowerSource, PowerWatch};
+use crate::performance::presence::{self, Presence, PresenceSource};
+use crate::wallpaper::command::{PlayerCommand, PlayerHandle};
+use crate::wallpaper::pause::PauseReason;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(3);
+
+/// The parts of the settings the monitor acts on. Sent again on every Apply.
+#[derive(Debug, Clone)]
+pub struct MonitorSettings {
+    pub policy: PowerPolicy,
+    pub pause_on_fullscreen: bool,
+    /// How long without input counts as idle; `None` never pauses for it.
+    pub idle_after: Option<Duration>,
+    pub app_rules: AppRules,
+}
+
+impl MonitorSettings {
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self {
+            policy: PowerPolicy::from_settings(&settings.power, &settings.performance),
+            pause_on_fullscreen: settings.performance.pause_on_fullscreen,
+            idle_after: match settings.performance.idle_pause_minutes {
+                0 => None,
+                minutes => Some(Duration::from_secs(minutes as u64 * 60)),
+            },
+            app_rules: AppRules::from_settings(&settings.app_rules),
+        }
+    }
+}
 
 pub struct PerformanceMonitor {
-    state: Arc<Mutex<PlayerState>>,
+    player: PlayerHandle,
+    clock: Arc<dyn Clock>,
+    settings: watch::Receiver<MonitorSettings>,
+    power: PowerWatch,
+    windows: Option<Box<dyn WindowSource>>,
+    fullscreen: FullscreenPolicy,
+    presence: Option<Box<dyn PresenceSource>>,
+    processes: ProcessList,
 }
 
 impl PerformanceMonitor {
-    pub fn new(state: Arc<Mutex<PlayerState>>) -> Self {
-        Self { state }
+    pub fn new(player: PlayerHandle, clock: Arc<dyn Clock>, settings: watch::Receiver<MonitorSettings>) -> Self {
+        Self::with_power_source(player, clock, settings, power::system())
+    }
+
+    pub fn with_power_source(
+        player: PlayerHandle,
+        clock: Arc<dyn Clock>,
+        settings: watch::Receiver<MonitorSettings>,
+        source: Box<dyn PowerSource>,
+    ) -> Self {
+        Self {
+            player,
+            clock,
+            settings,
+            power: PowerWatch::new(source),
+            windows: fullscreen::system(),
+            fullscreen: FullscreenPolicy::default(),
+            presence: presence::system(),
+            processes: ProcessList::new(),
+        }
+    }
+
+    /// Monitors and windows right now; empty if they can't be listed.
+    fn desktop(&mut self, failing: &mut bool) -> DesktopSnapshot {
+        let Some(source) = self.windows.as_mut() else {
+            return DesktopSnapshot::default();
+        };
+        match source.snapshot() {
+            Ok(snapshot) => {
+                *failing = false;
+                snapshot
+            }
+            Err(e) => {
+                if !*failing {
+                    tracing::warn!("Failed to list windows through {}: {}", source.name(), e);
+                    *failing = true;
+                }
+                DesktopSnapshot::default()
+            }
+        }
+    }
+
+    /// Input idle time and session lock right now; present and unlocked if they can't be read.
+    fn presence(&mut self, failing: &mut bool) -> Presence {
+        let Some(source) = self.presence.as_mut() else {
+            return Presence::default();
+        };
+        match source.read() {
+            Ok(presence) => {
+                *failing = false;
+                presence
+            }
+            Err(e) => {
+                if !*failing {
+                    tracing::warn!("Failed to read input idle time through {}: {}", source.name(), e);
+                    *failing = true;
+                }
+                Presence::default()
+            }
+        }
     }
 
-    pub async fn run(&self) -> anyhow::Result<()> {
+    /// Send the player whatever changed between the last and the current app rule effects.
+    fn apply_app_rules(&self, settings: &MonitorSettings, effects: AppEffects, last: &mut AppEffects, last_pause: &mut Option<bool>) {
+        if effects.matched != last.matched {
+            let rules: Vec<String> = effects.matched.iter().map(|&i| settings.app_rules.rules[i].to_string()).collect();
+            if rules.is_empty() {
+                tracing::info!("App rules: none match");
+            } else {
+                tracing::info!("App rules: {}", rules.join("; "));
+            }
+        }
+        self.report(PauseReason::AppRule, effects.pause, last_pause);
+        if (effects.fps_cap, effects.mute) != (last.fps_cap, last.mute) {
+            self.player.send(PlayerCommand::SetAppLimits { fps_cap: effects.fps_cap, mute: effects.mute });
+        }
+        if effects.wallpaper != last.wallpaper {
+            // The player goes back to whatever is underneath by then, scheduled or not
+            self.player.send(PlayerCommand::SetAppWallpaper(effects.wallpaper.clone()));
+        }
+        *last = effects;
+    }
+
+    /// Set or clear `reason` on the player, only when it differs from what was last sent.
+    fn report(&self, reason: PauseReason, active: bool, last: &mut Option<bool>) {
+        if *last != Some(active) {
+            self.player.send(if active { PlayerCommand::Pause(reason) } else { PlayerCommand::Resume(reason) });
+            *last = Some(active);
+        }
+    }
+
+    pub async fn run(mut self) -> anyhow::Result<()> {
+        let mut last_mode: Option<(PowerMode, PauseReason)> = None;
+        let mut last_fullscreen: Option<bool> = None;
+        let mut last_covered = String::new();
+        let mut windows_failing = false;
+        let mut last_effects = AppEffects::default();
+        let mut last_app_pause: Option<bool> = None;
+        let mut last_idle: Option<bool> = None;
+        let mut last_locked: Option<bool> = None;
+        let mut presence_failing = false;
         loop {
-            let mut should_pause = false;
-
-            // 1. Check Battery
-            if let Ok(battery_manager) = Manager::new() {
-                if let Ok(mut batteries) = battery_manager.batteries() {
-                    if let Some(Ok(battery)) = batteries.next() {
-                        let state = battery.state();
-                        let percentage = battery.state_of_charge().value * 100.0;
-                        
-                        // TODO: Use threshold from settings
-                        if state == battery::State::Discharging && percentage < 20.0 {
-                            should_pause = true;
-                        }
-                    }
+            let settings = self.settings.borrow_and_update().clone();
+
+            // 1. Check power
+            let (status, events) = self.power.poll();
+            for event in events {
+                tracing::info!("Power: {}", event);
+            }
+            let mut conditions = PowerConditions {
+                on_battery: status.on_battery,
+                battery_percent: status.battery_percent,
+                power_saver: status.power_saver,
+                ..Default::default()
+            };
+
+            // 2. Check fullscreen, monitor by monitor
+            let desktop = self.desktop(&mut windows_failing);
+            let report = self.fullscreen.evaluate(&desktop);
+            let covered = report.describe();
+            if covered != last_covered {
+                tracing::info!("Fullscreen: {}", covered);
+                last_covered = covered;
+            }
+            let fullscreen = report.covers_wallpaper();
+
+            conditions.fullscreen = fullscreen;
+            let tier = settings.policy.matching(&conditions);
+            let mode = tier.map_or(PowerMode::Full, |tier| tier.mode);
+            let reason = tier.map_or(PauseReason::Power, PolicyTier::pause_reason);
+            if last_mode != Some((mode, reason)) {
+                match tier {
+                    Some(tier) => tracing::info!("Power policy: {} ({}), by tier \"{}\"", mode.label(), conditions, tier),
+                    None => tracing::info!("Power policy: {} ({}), no tier matches", mode.label(), conditions),
                 }
+                self.player.send(PlayerCommand::SetPowerMode { mode, reason });
+                last_mode = Some((mode, reason));
             }
-            // battery_manager is dropped here
-
-            // 2. Check Fullscreen (simple heuristic)
-            if !should_pause {
-                unsafe {
-                    let hwnd = GetForegroundWindow();
-                    if !hwnd.0.is_null() {
-                        let mut rect = windows::Win32::Foundation::RECT::default();
-                        if GetWindowRect(hwnd, &mut rect).is_ok() {
-                            let screen_w = windows::Win32::UI::WindowsAndMessaging::GetSystemMetrics(windows::Win32::UI::WindowsAndMessaging::SM_CXSCREEN);
-                            let screen_h = windows::Win32::UI::WindowsAndMessaging::GetSystemMetrics(windows::Win32::UI::WindowsAndMessaging::SM_CYSCREEN);
-                            
-                            if (rect.right - rect.left).abs() >= screen_w - 5 && 
-                               (rect.bottom - rect.top).abs() >= screen_h - 5 {
-                                // Probably fullscreen
-                                // Note: Need to exclude the wallpaper window itself and desktop
-                                should_pause = true;
-                            }
-                        }
-                    }
+            let pause_fullscreen = settings.pause_on_fullscreen && fullscreen;
+            if last_fullscreen != Some(pause_fullscreen) {
+                if pause_fullscreen {
+                    tracing::info!("Pausing for a fullscreen app (pause on fullscreen is on)");
+                } else if fullscreen {
+                    tracing::info!("Fullscreen app in front, but pause on fullscreen is off");
                 }
             }
+            self.report(PauseReason::Fullscreen, pause_fullscreen, &mut last_fullscreen);
 
-            {
-                let mut s = self.state.lock().unwrap();
-                s.is_paused = should_pause; 
+            // 3. Check whether anyone is there to see it
+            let presence = self.presence(&mut presence_failing);
+            let idle = presence.is_idle(settings.idle_after);
+            if last_idle != Some(idle) && idle {
+                tracing::info!("Pausing, no input for {}s", presence.idle_for.as_secs());
             }
+            self.report(PauseReason::Idle, idle, &mut last_idle);
+            if last_locked != Some(presence.locked) && presence.locked {
+                tracing::info!("Pausing, the session is locked");
+            }
+            self.report(PauseReason::Locked, presence.locked, &mut last_locked);
+
+            // 4. Check app rules; processes are only listed when there's a rule to match
+            let effects = if settings.app_rules.is_empty() {
+                AppEffects::default()
+            } else {
+                settings.app_rules.evaluate(&self.processes.snapshot(), &desktop.windows)
+            };
+            self.apply_app_rules(&settings, effects, &mut last_effects, &mut last_app_pause);
 
-            sleep(Duration::from_secs(3)).await;
+            // Re-check straight away when the settings change
+            let clock = self.clock.clone();
+            tokio::select! {
+                _ = clock.sleep(CHECK_INTERVAL) => {}
+                changed = self.settings.changed() => {
+                    if changed.is_err() {
+                        // Nobody can send new settings any more; keep going with these
+                        clock.sleep(CHECK_INTERVAL).await;
+                    } else {
+                        tracing::info!("Monitor settings changed, re-evaluating");
+                    }
+                }
+            }
         }
     }
 }
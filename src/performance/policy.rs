@@ -0,0 +1,198 @@
+use crate::config::settings::{PerformanceSettings, PowerPolicySettings, PowerTierSettings};
+use crate::wallpaper::pause::PauseReason;
+use std::fmt;
+
+/// How much of the wallpaper runs. Each level also applies the savings of the ones
+/// before it, so reduced resolution runs at the reduced frame rate too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PowerMode {
+    Full,
+    ReducedFps,
+    ReducedResolution,
+    /// Hold the last frame.
+    Static,
+    /// Take the wallpaper off the desktop altogether.
+    Hidden,
+}
+
+impl PowerMode {
+    /// Parse the name stored in settings; anything unknown plays in full.
+    pub fn from_name(name: &str) -> Self {
+        match name.to_ascii_lowercase().replace(['-', ' '], "_").as_str() {
+            "reduced_fps" => PowerMode::ReducedFps,
+            "reduced_resolution" => PowerMode::ReducedResolution,
+            "static" => PowerMode::Static,
+            "hidden" => PowerMode::Hidden,
+            _ => PowerMode::Full,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            PowerMode::Full => "full",
+            PowerMode::ReducedFps => "reduced frame rate",
+            PowerMode::ReducedResolution => "reduced resolution",
+            PowerMode::Static => "static",
+            PowerMode::Hidden => "hidden",
+        }
+    }
+
+    /// Playback is held in this mode.
+    pub fn is_held(self) -> bool {
+        self >= PowerMode::Static
+    }
+}
+
+/// The signals a tier can key on, as last measured by the monitor.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PowerConditions {
+    pub on_battery: bool,
+    /// Charge in percent, if there is a battery.
+    pub battery_percent: Option<f32>,
+    pub fullscreen: bool,
+    pub power_saver: bool,
+}
+
+impl fmt::Display for PowerConditions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", if self.on_battery { "on battery" } else { "on AC" })?;
+        if let Some(percent) = self.battery_percent {
+            write!(f, " at {:.0}%", percent)?;
+        }
+        if self.fullscreen {
+            write!(f, ", fullscreen app")?;
+        }
+        if self.power_saver {
+            write!(f, ", power saver on")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceMatch {
+    Any,
+    Ac,
+    Battery,
+}
+
+/// One row of the policy table. Unset conditions match anything.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PolicyTier {
+    pub source: SourceMatch,
+    /// Matches while the charge is below this percentage. Never matches without a battery.
+    pub below_percent: Option<f32>,
+    pub fullscreen: Option<bool>,
+    pub power_saver: Option<bool>,
+    pub mode: PowerMode,
+}
+
+impl PolicyTier {
+    pub fn from_settings(settings: &PowerTierSettings) -> Self {
+        Self {
+            source: match settings.source.to_ascii_lowercase().as_str() {
+                "ac" => SourceMatch::Ac,
+                "battery" => SourceMatch::Battery,
+                _ => SourceMatch::Any,
+            },
+            below_percent: settings.below_percent.map(|p| p as f32),
+            fullscreen: settings.fullscreen,
+            power_saver: settings.power_saver,
+            mode: PowerMode::from_name(&settings.mode),
+        }
+    }
+
+    /// The tier `pause_on_battery` stands for: hold the wallpaper below the threshold.
+    pub fn pause_on_battery(threshold: i32) -> Self {
+        Self {
+            source: SourceMatch::Battery,
+            below_percent: Some(threshold.clamp(0, 100) as f32),
+            fullscreen: None,
+            power_saver: None,
+            mode: PowerMode::Static,
+        }
+    }
+
+    /// What a wallpaper held by this tier is paused for: the battery when the tier is keyed
+    /// on it, power saving otherwise.
+    pub fn pause_reason(&self) -> PauseReason {
+        if self.source == SourceMatch::Battery || self.below_percent.is_some() {
+            PauseReason::Battery
+        } else {
+            PauseReason::Power
+        }
+    }
+
+    pub fn matches(&self, conditions: &PowerConditions) -> bool {
+        let source = match self.source {
+            SourceMatch::Any => true,
+            SourceMatch::Ac => !conditions.on_battery,
+            SourceMatch::Battery => conditions.on_battery,
+        };
+        let charge = self
+            .below_percent
+            .is_none_or(|limit| conditions.battery_percent.is_some_and(|percent| percent < limit));
+        let fullscreen = self.fullscreen.is_none_or(|wanted| wanted == conditions.fullscreen);
+        let power_saver = self.power_saver.is_none_or(|wanted| wanted == conditions.power_saver);
+        source && charge && fullscreen && power_saver
+    }
+}
+
+impl fmt::Display for PolicyTier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ", self.mode.label())?;
+        let mut conditions = Vec::new();
+        match self.source {
+            SourceMatch::Any => {}
+            SourceMatch::Ac => conditions.push("on AC".to_string()),
+            SourceMatch::Battery => conditions.push("on battery".to_string()),
+        }
+        if let Some(percent) = self.below_percent {
+            conditions.push(format!("below {:.0}%", percent));
+        }
+        match self.fullscreen {
+            Some(true) => conditions.push("with a fullscreen app".to_string()),
+            Some(false) => conditions.push("without a fullscreen app".to_string()),
+            None => {}
+        }
+        match self.power_saver {
+            Some(true) => conditions.push("in power saver".to_string()),
+            Some(false) => conditions.push("outside power saver".to_string()),
+            None => {}
+        }
+        if conditions.is_empty() {
+            write!(f, "always")
+        } else {
+            write!(f, "{}", conditions.join(" "))
+        }
+    }
+}
+
+/// Picks a `PowerMode` from the conditions: the first tier that matches wins, and with
+/// none matching the wallpaper plays in full.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PowerPolicy {
+    pub tiers: Vec<PolicyTier>,
+}
+
+impl PowerPolicy {
+    /// The table from `power`, behind the pause-on-battery tier when that's switched on.
+    pub fn from_settings(power: &PowerPolicySettings, performance: &PerformanceSettings) -> Self {
+        let pause = performance
+            .pause_on_battery
+            .then(|| PolicyTier::pause_on_battery(performance.battery_threshold));
+        Self {
+            tiers: pause.into_iter().chain(power.tiers.iter().map(PolicyTier::from_settings)).collect(),
+        }
+    }
+
+    /// The tier that decides under `conditions`, if any.
+    pub fn matching(&self, conditions: &PowerConditions) -> Option<&PolicyTier> {
+        self.tiers.iter().find(|tier| tier.matches(conditions))
+    }
+
+    #[cfg(test)]
+    pub fn evaluate(&self, conditions: &PowerConditions) -> PowerMode {
+        self.matching(conditions).map_or(PowerMode::Full, |tier| tier.mode)
+    }
+}
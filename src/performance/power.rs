@@ -0,0 +1,426 @@
+#[cfg(test)]
+use std::collections::VecDeque;
+use std::fmt;
+
+/// What the machine is running on, summed over every battery it has.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PowerStatus {
+    pub on_battery: bool,
+    /// Combined charge in percent; `None` without a battery.
+    pub battery_percent: Option<f32>,
+    /// The OS battery saver / power saver profile is on.
+    pub power_saver: bool,
+}
+
+/// A change between two readings worth telling the policy about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerEvent {
+    UnpluggedFromAc,
+    PluggedIntoAc,
+    PowerSaver(bool),
+}
+
+impl fmt::Display for PowerEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PowerEvent::UnpluggedFromAc => write!(f, "switched to battery"),
+            PowerEvent::PluggedIntoAc => write!(f, "switched to AC"),
+            PowerEvent::PowerSaver(true) => write!(f, "power saver turned on"),
+            PowerEvent::PowerSaver(false) => write!(f, "power saver turned off"),
+        }
+    }
+}
+
+impl PowerEvent {
+    /// The events that lead from `before` to `after`.
+    pub fn between(before: &PowerStatus, after: &PowerStatus) -> Vec<PowerEvent> {
+        let mut events = Vec::new();
+        if before.on_battery != after.on_battery {
+            events.push(if after.on_battery { PowerEvent::UnpluggedFromAc } else { PowerEvent::PluggedIntoAc });
+        }
+        if before.power_saver != after.power_saver {
+            events.push(PowerEvent::PowerSaver(after.power_saver));
+        }
+        events
+    }
+}
+
+/// Somewhere to read the power status from: the OS, or a script for tests.
+pub trait PowerSource: Send {
+    fn name(&self) -> &'static str;
+    fn read(&mut self) -> anyhow::Result<PowerStatus>;
+}
+
+/// Polls a `PowerSource` and turns successive readings into events.
+pub struct PowerWatch {
+    source: Box<dyn PowerSource>,
+    last: Option<PowerStatus>,
+    failing: bool,
+}
+
+impl PowerWatch {
+    pub fn new(source: Box<dyn PowerSource>) -> Self {
+        tracing::info!("Reading power status from {}", source.name());
+        Self { source, last: None, failing: false }
+    }
+
+    /// The current status and what changed since the last poll. A failed read keeps the
+    /// last known status, and is only logged the first time in a row.
+    pub fn poll(&mut self) -> (PowerStatus, Vec<PowerEvent>) {
+        let status = match self.source.read() {
+            Ok(status) => {
+                self.failing = false;
+                status
+            }
+            Err(e) => {
+                if !self.failing {
+                    tracing::warn!("Failed to read power status from {}: {}", self.source.name(), e);
+                    self.failing = true;
+                }
+                return (self.last.unwrap_or_default(), Vec::new());
+            }
+        };
+        let events = self.last.map(|last| PowerEvent::between(&last, &status)).unwrap_or_default();
+        self.last = Some(status);
+        (status, events)
+    }
+}
+
+/// The source for the platform we're running on.
+pub fn system() -> Box<dyn PowerSource> {
+    #[cfg(windows)]
+    {
+        Box::new(windows_source::WindowsPowerSource)
+    }
+    #[cfg(target_os = "linux")]
+    {
+        Box::new(linux::SysfsPowerSource::new())
+    }
+    #[cfg(not(any(windows, target_os = "linux")))]
+    {
+        Box::new(BatteryCrateSource::new())
+    }
+}
+
+/// Plays back a fixed list of readings, then keeps repeating the last one.
+#[cfg(test)]
+pub struct ScriptedPowerSource {
+    steps: VecDeque<anyhow::Result<PowerStatus>>,
+    last: PowerStatus,
+}
+
+#[cfg(test)]
+impl ScriptedPowerSource {
+    pub fn new(steps: impl IntoIterator<Item = PowerStatus>) -> Self {
+        Self {
+            steps: steps.into_iter().map(Ok).collect(),
+            last: PowerStatus::default(),
+        }
+    }
+
+    /// Queue a reading that fails, to check the last status is kept.
+    pub fn then_fail(mut self, message: &str) -> Self {
+        self.steps.push_back(Err(anyhow::anyhow!("{}", message)));
+        self
+    }
+
+    pub fn then(mut self, status: PowerStatus) -> Self {
+        self.steps.push_back(Ok(status));
+        self
+    }
+}
+
+#[cfg(test)]
+impl PowerSource for ScriptedPowerSource {
+    fn name(&self) -> &'static str {
+        "a script"
+    }
+
+    fn read(&mut self) -> anyhow::Result<PowerStatus> {
+        match self.steps.pop_front() {
+            Some(Ok(status)) => {
+                self.last = status;
+                Ok(status)
+            }
+            Some(Err(e)) => Err(e),
+            None => Ok(self.last),
+        }
+    }
+}
+
+#[cfg(windows)]
+mod windows_source {
+    use super::{PowerSource, PowerStatus};
+    use windows::Win32::System::Power::{GetSystemPowerStatus, SYSTEM_POWER_STATUS};
+
+    const AC_OFFLINE: u8 = 0;
+    const NO_BATTERY: u8 = 128;
+    const UNKNOWN: u8 = 255;
+    const BATTERY_SAVER_ON: u8 = 1;
+
+    /// `GetSystemPowerStatus`, which already combines every battery.
+    pub struct WindowsPowerSource;
+
+    impl PowerSource for WindowsPowerSource {
+        fn name(&self) -> &'static str {
+            "GetSystemPowerStatus"
+        }
+
+        fn read(&mut self) -> anyhow::Result<PowerStatus> {
+            let mut status = SYSTEM_POWER_STATUS::default();
+            unsafe { GetSystemPowerStatus(&mut status)? };
+            let has_battery = status.BatteryFlag != NO_BATTERY && status.BatteryFlag != UNKNOWN;
+            Ok(PowerStatus {
+                on_battery: status.ACLineStatus == AC_OFFLINE,
+                battery_percent: (has_battery && status.BatteryLifePercent != UNKNOWN)
+                    .then_some(status.BatteryLifePercent as f32),
+                power_saver: status.SystemStatusFlag == BATTERY_SAVER_ON,
+            })
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::{PowerSource, PowerStatus};
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    const POWER_SUPPLY: &str = "/sys/class/power_supply";
+    /// Set to "low-power" by power-profiles-daemon (and tuned) in power saver mode.
+    const PLATFORM_PROFILE: &str = "/sys/firmware/acpi/platform_profile";
+
+    /// Reads `/sys/class/power_supply`, the same files UPower reads, without needing D-Bus.
+    pub struct SysfsPowerSource {
+        root: PathBuf,
+        profile: PathBuf,
+    }
+
+    impl SysfsPowerSource {
+        pub fn new() -> Self {
+            Self::at(POWER_SUPPLY, PLATFORM_PROFILE)
+        }
+
+        /// Read from other directories, e.g. a copy of a real machine's tree.
+        pub fn at(root: impl Into<PathBuf>, profile: impl Into<PathBuf>) -> Self {
+            Self { root: root.into(), profile: profile.into() }
+        }
+    }
+
+    fn read_trimmed(path: &Path) -> Option<String> {
+        fs::read_to_string(path).ok().map(|s| s.trim().to_string())
+    }
+
+    fn read_number(dir: &Path, name: &str) -> Option<f64> {
+        read_trimmed(&dir.join(name))?.parse().ok()
+    }
+
+    /// Remaining and full charge in the battery's own unit, preferring energy, then
+    /// charge, then falling back to the percentage with a weight of one.
+    fn battery_charge(dir: &Path) -> Option<(f64, f64)> {
+        for (now, full) in [("energy_now", "energy_full"), ("charge_now", "charge_full")] {
+            if let (Some(now), Some(full)) = (read_number(dir, now), read_number(dir, full)) {
+                if full > 0.0 {
+                    return Some((now, full));
+                }
+            }
+        }
+        read_number(dir, "capacity").map(|percent| (percent / 100.0, 1.0))
+    }
+
+    impl PowerSource for SysfsPowerSource {
+        fn name(&self) -> &'static str {
+            "/sys/class/power_supply"
+        }
+
+        fn read(&mut self) -> anyhow::Result<PowerStatus> {
+            let mut mains: Option<bool> = None;
+            let mut discharging = false;
+            let (mut now, mut full) = (0.0, 0.0);
+            for entry in fs::read_dir(&self.root)? {
+                let dir = entry?.path();
+                match read_trimmed(&dir.join("type")).as_deref() {
+                    Some("Mains") | Some("USB") => {
+                        let online = read_trimmed(&dir.join("online")).as_deref() == Some("1");
+                        mains = Some(mains.unwrap_or(false) || online);
+                    }
+                    Some("Battery") => {
+                        // Mice, keyboards and headsets report their batteries here too
+                        if read_trimmed(&dir.join("scope")).as_deref() == Some("Device") {
+                            continue;
+                        }
+                        if read_trimmed(&dir.join("status")).as_deref() == Some("Discharging") {
+                            discharging = true;
+                        }
+                        if let Some((battery_now, battery_full)) = battery_charge(&dir) {
+                            now += battery_now;
+                            full += battery_full;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            Ok(PowerStatus {
+                // Without a mains supply listed, go by whether any battery is draining
+                on_battery: mains.map_or(discharging, |online| !online),
+                battery_percent: (full > 0.0).then(|| (now / full * 100.0).clamp(0.0, 100.0) as f32),
+                power_saver: read_trimmed(&self.profile).as_deref() == Some("low-power"),
+            })
+        }
+    }
+}
+
+/// The `battery` crate, for platforms without a source of their own. It can't see power
+/// saver modes.
+#[cfg(not(any(windows, target_os = "linux")))]
+pub struct BatteryCrateSource {
+    manager: Option<battery::Manager>,
+}
+
+#[cfg(not(any(windows, target_os = "linux")))]
+impl BatteryCrateSource {
+    pub fn new() -> Self {
+        Self { manager: battery::Manager::new().ok() }
+    }
+}
+
+#[cfg(not(any(windows, target_os = "linux")))]
+impl PowerSource for BatteryCrateSource {
+    fn name(&self) -> &'static str {
+        "the battery crate"
+    }
+
+    fn read(&mut self) -> anyhow::Result<PowerStatus> {
+        let manager = self.manager.as_ref().ok_or_else(|| anyhow::anyhow!("No battery manager"))?;
+        let (mut discharging, mut now, mut full) = (false, 0.0, 0.0);
+        for battery in manager.batteries()? {
+            let battery = battery?;
+            discharging |= battery.state() == battery::State::Discharging;
+            now += battery.energy().value;
+            full += battery.energy_full().value;
+        }
+        Ok(PowerStatus {
+            on_battery: discharging,
+            battery_percent: (full > 0.0).then(|| now / full * 100.0),
+            power_saver: false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::settings::{PerformanceSettings, PowerPolicySettings, PowerTierSettings};
+    use crate::performance::policy::{PowerConditions, PowerMode, PowerPolicy};
+    use crate::wallpaper::pause::PauseReason;
+
+    fn ac(percent: f32) -> PowerStatus {
+        PowerStatus { on_battery: false, battery_percent: Some(percent), power_saver: false }
+    }
+
+    fn battery(percent: f32) -> PowerStatus {
+        PowerStatus { on_battery: true, battery_percent: Some(percent), power_saver: false }
+    }
+
+    /// The default table, with pausing below 20% in front of it and power saver tiers after.
+    fn policy() -> PowerPolicy {
+        let mut power = PowerPolicySettings::default();
+        power.tiers.push(PowerTierSettings { power_saver: Some(true), ..PowerTierSettings::new("ac", None, "reduced_resolution") });
+        let performance = PerformanceSettings {
+            pause_on_battery: true,
+            battery_threshold: 20,
+            pause_on_fullscreen: false,
+            idle_pause_minutes: 0,
+            enable_glassmorphism: false,
+            show_icon_shortcuts: false,
+        };
+        PowerPolicy::from_settings(&power, &performance)
+    }
+
+    /// Poll once and run the reading through `policy` the way the monitor does.
+    fn step(watch: &mut PowerWatch, policy: &PowerPolicy) -> (PowerMode, Option<PauseReason>, Vec<PowerEvent>) {
+        let (status, events) = watch.poll();
+        let conditions = PowerConditions {
+            on_battery: status.on_battery,
+            battery_percent: status.battery_percent,
+            power_saver: status.power_saver,
+            ..Default::default()
+        };
+        let tier = policy.matching(&conditions);
+        (policy.evaluate(&conditions), tier.map(|tier| tier.pause_reason()), events)
+    }
+
+    #[test]
+    fn unplugging_and_plugging_in_change_the_mode() {
+        let policy = policy();
+        let saver = PowerStatus { power_saver: true, ..ac(100.0) };
+        let mut watch = PowerWatch::new(Box::new(ScriptedPowerSource::new([ac(100.0), battery(90.0), ac(90.0), saver])));
+
+        assert_eq!(step(&mut watch, &policy), (PowerMode::Full, None, vec![]));
+        assert_eq!(
+            step(&mut watch, &policy),
+            (PowerMode::ReducedFps, Some(PauseReason::Battery), vec![PowerEvent::UnpluggedFromAc])
+        );
+        assert_eq!(step(&mut watch, &policy), (PowerMode::Full, None, vec![PowerEvent::PluggedIntoAc]));
+        assert_eq!(
+            step(&mut watch, &policy),
+            (PowerMode::ReducedResolution, Some(PauseReason::Power), vec![PowerEvent::PowerSaver(true)])
+        );
+        // The script has run out, so the last reading repeats with nothing new to report
+        assert_eq!(step(&mut watch, &policy), (PowerMode::ReducedResolution, Some(PauseReason::Power), vec![]));
+    }
+
+    #[test]
+    fn a_failed_read_keeps_the_last_status() {
+        let policy = policy();
+        let source = ScriptedPowerSource::new([battery(15.0)])
+            .then_fail("device gone")
+            .then_fail("still gone")
+            .then(ac(15.0));
+        let mut watch = PowerWatch::new(Box::new(source));
+
+        assert_eq!(step(&mut watch, &policy).0, PowerMode::Static);
+        for _ in 0..2 {
+            assert_eq!(watch.poll(), (battery(15.0), vec![]));
+        }
+        // Events are reported against the last good reading, not the failures
+        assert_eq!(step(&mut watch, &policy), (PowerMode::Full, None, vec![PowerEvent::PluggedIntoAc]));
+    }
+
+    #[test]
+    fn a_failed_first_read_reports_no_battery() {
+        let mut watch = PowerWatch::new(Box::new(ScriptedPowerSource::new([]).then_fail("no such file").then(battery(50.0))));
+
+        assert_eq!(watch.poll(), (PowerStatus::default(), vec![]));
+        // Nothing was known before, so the first good reading isn't a change
+        assert_eq!(watch.poll(), (battery(50.0), vec![]));
+    }
+
+    #[test]
+    fn draining_the_battery_crosses_the_threshold_tier() {
+        let policy = policy();
+        let levels = [80.0, 40.0, 20.0, 19.5, 5.0];
+        let mut watch = PowerWatch::new(Box::new(ScriptedPowerSource::new(levels.map(battery))));
+
+        let modes: Vec<(PowerMode, Option<PauseReason>)> = levels
+            .iter()
+            .map(|_| {
+                let (mode, reason, _) = step(&mut watch, &policy);
+                (mode, reason)
+            })
+            .collect();
+        let reduced = (PowerMode::ReducedFps, Some(PauseReason::Battery));
+        let held = (PowerMode::Static, Some(PauseReason::Battery));
+        assert_eq!(modes, [reduced, reduced, reduced, held, held]);
+        assert!(modes[3].0.is_held());
+    }
+
+    #[test]
+    fn a_machine_without_a_battery_never_reaches_the_threshold() {
+        let policy = policy();
+        let desktop = PowerStatus { battery_percent: None, ..ac(0.0) };
+        let mut watch = PowerWatch::new(Box::new(ScriptedPowerSource::new([desktop])));
+
+        assert_eq!(step(&mut watch, &policy), (PowerMode::Full, None, vec![]));
+    }
+}
@@ -0,0 +1,186 @@
+use std::time::Duration;
+
+/// Whether anyone is at the desktop to see the wallpaper.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Presence {
+    /// Time since the last keyboard or mouse input.
+    pub idle_for: Duration,
+    /// The session is locked, so the lock screen covers the desktop.
+    pub locked: bool,
+}
+
+impl Presence {
+    /// Idle for at least `after`; never with `None`.
+    pub fn is_idle(&self, after: Option<Duration>) -> bool {
+        after.is_some_and(|after| self.idle_for >= after)
+    }
+}
+
+/// Somewhere to read presence from.
+pub trait PresenceSource: Send {
+    fn name(&self) -> &'static str;
+    fn read(&mut self) -> anyhow::Result<Presence>;
+}
+
+/// The source for the platform we're running on, if it has one.
+pub fn system() -> Option<Box<dyn PresenceSource>> {
+    #[cfg(windows)]
+    {
+        Some(Box::new(win32::Win32PresenceSource::new()))
+    }
+    #[cfg(target_os = "linux")]
+    {
+        Some(Box::new(x11::X11PresenceSource::default()))
+    }
+    #[cfg(not(any(windows, target_os = "linux")))]
+    {
+        None
+    }
+}
+
+#[cfg(windows)]
+mod win32 {
+    use super::{Presence, PresenceSource};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+    use windows::Win32::Foundation::{HWND, LPARAM, LRESULT, WPARAM};
+    use windows::Win32::System::LibraryLoader::GetModuleHandleW;
+    use windows::Win32::System::RemoteDesktop::{WTSRegisterSessionNotification, NOTIFY_FOR_THIS_SESSION};
+    use windows::Win32::System::SystemInformation::GetTickCount;
+    use windows::Win32::UI::Input::KeyboardAndMouse::{GetLastInputInfo, LASTINPUTINFO};
+    use windows::Win32::UI::WindowsAndMessaging::{
+        CreateWindowExW, DefWindowProcW, DispatchMessageW, GetMessageW, RegisterClassW, TranslateMessage, HMENU,
+        HWND_MESSAGE, MSG, WINDOW_EX_STYLE, WINDOW_STYLE, WM_WTSSESSION_CHANGE, WNDCLASSW, WTS_SESSION_LOCK,
+        WTS_SESSION_UNLOCK,
+    };
+
+    /// Set and cleared by the session watcher's window; one per process.
+    static LOCKED: AtomicBool = AtomicBool::new(false);
+
+    unsafe extern "system" fn session_proc(hwnd: HWND, msg: u32, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
+        if msg == WM_WTSSESSION_CHANGE {
+            match wparam.0 as u32 {
+                WTS_SESSION_LOCK => LOCKED.store(true, Ordering::Relaxed),
+                WTS_SESSION_UNLOCK => LOCKED.store(false, Ordering::Relaxed),
+                _ => {}
+            }
+            return LRESULT(0);
+        }
+        DefWindowProcW(hwnd, msg, wparam, lparam)
+    }
+
+    /// A message-only window on its own thread, registered for lock and unlock notifications.
+    fn watch_session() -> anyhow::Result<()> {
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || unsafe {
+            let created = (|| -> anyhow::Result<HWND> {
+                let instance = GetModuleHandleW(None)?;
+                let class = windows::core::w!("MewSessionWatch");
+                let wc = WNDCLASSW { lpfnWndProc: Some(session_proc), hInstance: instance.into(), lpszClassName: class, ..Default::default() };
+                RegisterClassW(&wc);
+                let hwnd = CreateWindowExW(
+                    WINDOW_EX_STYLE::default(),
+                    class,
+                    windows::core::w!("Mew Session Watch"),
+                    WINDOW_STYLE::default(),
+                    0, 0, 0, 0,
+                    HWND_MESSAGE,
+                    HMENU::default(),
+                    instance,
+                    None,
+                )?;
+                WTSRegisterSessionNotification(hwnd, NOTIFY_FOR_THIS_SESSION)?;
+                Ok(hwnd)
+            })();
+            let watching = created.is_ok();
+            let _ = tx.send(created.map(|_| ()));
+            if watching {
+                let mut msg = MSG::default();
+                while GetMessageW(&mut msg, HWND::default(), 0, 0).as_bool() {
+                    let _ = TranslateMessage(&msg);
+                    DispatchMessageW(&msg);
+                }
+            }
+        });
+        rx.recv()?
+    }
+
+    /// Idle time from `GetLastInputInfo`, locking from `WTSRegisterSessionNotification`.
+    pub struct Win32PresenceSource;
+
+    impl Win32PresenceSource {
+        pub fn new() -> Self {
+            if let Err(e) = watch_session() {
+                tracing::warn!("Can't watch for the session locking: {}", e);
+            }
+            Self
+        }
+    }
+
+    impl PresenceSource for Win32PresenceSource {
+        fn name(&self) -> &'static str {
+            "Win32"
+        }
+
+        fn read(&mut self) -> anyhow::Result<Presence> {
+            let mut info = LASTINPUTINFO { cbSize: std::mem::size_of::<LASTINPUTINFO>() as u32, dwTime: 0 };
+            unsafe {
+                if !GetLastInputInfo(&mut info).as_bool() {
+                    anyhow::bail!("GetLastInputInfo failed");
+                }
+                // Both tick counts wrap after 49.7 days
+                let idle_ms = GetTickCount().wrapping_sub(info.dwTime);
+                Ok(Presence { idle_for: Duration::from_millis(idle_ms as u64), locked: LOCKED.load(Ordering::Relaxed) })
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod x11 {
+    use super::{Presence, PresenceSource};
+    use std::time::Duration;
+    use x11rb::connection::Connection;
+    use x11rb::protocol::screensaver::ConnectionExt as _;
+    use x11rb::rust_connection::RustConnection;
+
+    /// Idle time from the MIT-SCREEN-SAVER extension. Locking goes through the desktop's
+    /// own screen locker, which X can't see, so it's never reported.
+    #[derive(Default)]
+    pub struct X11PresenceSource {
+        session: Option<(RustConnection, u32)>,
+    }
+
+    impl PresenceSource for X11PresenceSource {
+        fn name(&self) -> &'static str {
+            "X11"
+        }
+
+        fn read(&mut self) -> anyhow::Result<Presence> {
+            let (conn, root) = match self.session.take() {
+                Some(session) => session,
+                None => {
+                    let (conn, screen) = x11rb::connect(None)?;
+                    let root = conn.setup().roots[screen].root;
+                    (conn, root)
+                }
+            };
+            let info = conn.screensaver_query_info(root)?.reply()?;
+            self.session = Some((conn, root));
+            Ok(Presence { idle_for: Duration::from_millis(info.ms_since_user_input as u64), locked: false })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn idle_only_counts_once_a_threshold_is_set_and_reached() {
+        let presence = Presence { idle_for: Duration::from_secs(600), locked: false };
+        assert!(!presence.is_idle(None));
+        assert!(presence.is_idle(Some(Duration::from_secs(600))));
+        assert!(!presence.is_idle(Some(Duration::from_secs(601))));
+    }
+}
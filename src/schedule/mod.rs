@@ -0,0 +1,4 @@
+pub mod scheduler;
+pub mod solar;
+
+pub use scheduler::Scheduler;
@@ -0,0 +1,306 @@
+use crate::config::settings::{PlaylistSettings, ScheduleSettings};
+use crate::clock::Clock;
+use crate::schedule::solar;
+use crate::wallpaper::command::{PlayerCommand, PlayerHandle};
+use crate::wallpaper::pause::PauseReason;
+use crate::wallpaper::playlist::Playlist;
+use anyhow::Result;
+use chrono::{DateTime, Duration as ChronoDuration, FixedOffset, NaiveDate, NaiveTime};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often the schedule is re-checked. Boundaries are minute-granular, so this is plenty.
+const TICK: Duration = Duration::from_secs(20);
+
+/// When a schedule range starts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Boundary {
+    At(NaiveTime),
+    /// Minutes relative to the event, negative for before.
+    Sunrise(i64),
+    Sunset(i64),
+}
+
+impl Boundary {
+    pub fn parse(text: &str) -> Result<Self> {
+        let text = text.trim().to_ascii_lowercase();
+        if let Some(offset) = text.strip_prefix("sunrise") {
+            return Ok(Boundary::Sunrise(parse_offset(offset)?));
+        }
+        if let Some(offset) = text.strip_prefix("sunset") {
+            return Ok(Boundary::Sunset(parse_offset(offset)?));
+        }
+        NaiveTime::parse_from_str(&text, "%H:%M")
+            .map(Boundary::At)
+            .map_err(|e| anyhow::anyhow!("Invalid schedule time {:?}: {}", text, e))
+    }
+
+    fn is_solar(&self) -> bool {
+        !matches!(self, Boundary::At(_))
+    }
+
+    /// When this boundary falls on `date`, in the clock's timezone. `None` for a solar
+    /// boundary on a day without that event, or without a location.
+    fn resolve(&self, date: NaiveDate, tz: FixedOffset, location: Option<(f64, f64)>) -> Option<DateTime<FixedOffset>> {
+        match *self {
+            Boundary::At(time) => date.and_time(time).and_local_timezone(tz).single(),
+            Boundary::Sunrise(minutes) | Boundary::Sunset(minutes) => {
+                let (latitude, longitude) = location?;
+                let (sunrise, sunset) = solar::sun_times(date, latitude, longitude)?;
+                let event = if matches!(self, Boundary::Sunrise(_)) { sunrise } else { sunset };
+                Some(event.with_timezone(&tz) + ChronoDuration::minutes(minutes))
+            }
+        }
+    }
+}
+
+/// "+30", "-15" or "" as minutes.
+fn parse_offset(text: &str) -> Result<i64> {
+    let text = text.replace(' ', "");
+    if text.is_empty() {
+        return Ok(0);
+    }
+    text.trim_start_matches('+')
+        .parse()
+        .map_err(|e| anyhow::anyhow!("Invalid sunrise/sunset offset {:?}: {}", text, e))
+}
+
+#[derive(Debug, Clone)]
+pub enum ScheduleTarget {
+    Wallpaper(String),
+    Playlist(Playlist),
+    /// Hold playback for the whole range.
+    Pause,
+}
+
+#[derive(Debug, Clone)]
+pub struct ScheduleEntry {
+    pub start: Boundary,
+    pub target: ScheduleTarget,
+}
+
+/// Ranges of the day, each running from its start until the next one's.
+#[derive(Debug, Clone, Default)]
+pub struct Schedule {
+    pub entries: Vec<ScheduleEntry>,
+    pub location: Option<(f64, f64)>,
+}
+
+impl Schedule {
+    /// Build from settings, skipping (and logging) entries that can't be used.
+    pub fn from_settings(settings: &ScheduleSettings, playlists: &[PlaylistSettings]) -> Self {
+        let location = settings.latitude.zip(settings.longitude);
+        let mut entries = Vec::new();
+        for entry in &settings.entries {
+            let start = match Boundary::parse(&entry.start) {
+                Ok(start) => start,
+                Err(e) => {
+                    tracing::warn!("Skipping schedule entry: {}", e);
+                    continue;
+                }
+            };
+            if start.is_solar() && location.is_none() {
+                tracing::warn!("Skipping schedule entry {:?}: sunrise/sunset needs a latitude and longitude", entry.start);
+                continue;
+            }
+            let target = if entry.pause {
+                ScheduleTarget::Pause
+            } else if !entry.playlist.is_empty() {
+                match playlists.iter().find(|p| p.name == entry.playlist) {
+                    Some(playlist) => ScheduleTarget::Playlist(Playlist::from_settings(playlist)),
+                    None => {
+                        tracing::warn!("Skipping schedule entry {:?}: no playlist named {}", entry.start, entry.playlist);
+                        continue;
+                    }
+                }
+            } else if !entry.wallpaper.is_empty() {
+                ScheduleTarget::Wallpaper(entry.wallpaper.clone())
+            } else {
+                tracing::warn!("Skipping schedule entry {:?}: nothing to play", entry.start);
+                continue;
+            };
+            entries.push(ScheduleEntry { start, target });
+        }
+        Self { entries, location }
+    }
+
+    /// Index of the entry whose range contains `now`: the one that started most recently,
+    /// looking back into yesterday for the range that runs past midnight.
+    pub fn active_at(&self, now: DateTime<FixedOffset>) -> Option<usize> {
+        let today = now.date_naive();
+        let days = [today, today.pred_opt()?];
+        self.entries
+            .iter()
+            .enumerate()
+            .flat_map(|(index, entry)| {
+                days.iter()
+                    .filter_map(move |&date| entry.start.resolve(date, *now.offset(), self.location))
+                    .filter(move |start| *start <= now)
+                    .map(move |start| (start, index))
+            })
+            .max_by_key(|(start, _)| *start)
+            .map(|(_, index)| index)
+    }
+}
+
+/// Watches the clock and tells the player what to play whenever the schedule moves into a
+/// new range.
+pub struct Scheduler {
+    schedule: Schedule,
+    clock: Arc<dyn Clock>,
+    player: PlayerHandle,
+    active: Option<usize>,
+}
+
+impl Scheduler {
+    pub fn new(schedule: Schedule, clock: Arc<dyn Clock>, player: PlayerHandle) -> Self {
+        Self {
+            schedule,
+            clock,
+            player,
+            active: None,
+        }
+    }
+
+    /// Check the clock once and send the player the new range's wallpaper if a boundary was
+    /// crossed since the last check. Returns the active entry.
+    pub fn tick(&mut self) -> Option<usize> {
+        let active = self.schedule.active_at(self.clock.local_now());
+        if active == self.active {
+            return active;
+        }
+        let was_paused = self
+            .active
+            .is_some_and(|index| matches!(self.schedule.entries[index].target, ScheduleTarget::Pause));
+        self.active = active;
+        let index = active?;
+
+        match &self.schedule.entries[index].target {
+            ScheduleTarget::Pause => {
+                tracing::info!("Schedule: pausing from {:?}", self.schedule.entries[index].start);
+                self.player.send(PlayerCommand::Pause(PauseReason::Schedule));
+                return active;
+            }
+            ScheduleTarget::Wallpaper(path) => {
+                tracing::info!("Schedule: switching to {}", path);
+                self.player.send(PlayerCommand::Load(path.clone()));
+            }
+            ScheduleTarget::Playlist(playlist) => {
+                tracing::info!("Schedule: switching to playlist {}", playlist.name);
+                self.player.send(PlayerCommand::SetPlaylist(Some(playlist.clone())));
+            }
+        }
+        if was_paused {
+            self.player.send(PlayerCommand::Resume(PauseReason::Schedule));
+        }
+        active
+    }
+
+    pub async fn run(mut self) -> Result<()> {
+        tracing::info!("Schedule running with {} entries", self.schedule.entries.len());
+        loop {
+            self.tick();
+            self.clock.sleep(TICK).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SimulatedClock;
+    use crate::wallpaper::command;
+
+    fn at(hour: u32, minute: u32) -> Boundary {
+        Boundary::At(NaiveTime::from_hms_opt(hour, minute, 0).unwrap())
+    }
+
+    fn schedule(starts: &[Boundary], location: Option<(f64, f64)>) -> Schedule {
+        let entries = starts
+            .iter()
+            .enumerate()
+            .map(|(index, &start)| ScheduleEntry { start, target: ScheduleTarget::Wallpaper(format!("{}.mp4", index)) })
+            .collect();
+        Schedule { entries, location }
+    }
+
+    fn local(date: NaiveDate, hour: u32, minute: u32) -> DateTime<FixedOffset> {
+        date.and_hms_opt(hour, minute, 0).unwrap().and_local_timezone(FixedOffset::east_opt(3600).unwrap()).unwrap()
+    }
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    #[test]
+    fn parses_times_and_offsets() {
+        assert_eq!(Boundary::parse("07:30").unwrap(), at(7, 30));
+        assert_eq!(Boundary::parse("Sunrise").unwrap(), Boundary::Sunrise(0));
+        assert_eq!(Boundary::parse("sunset + 30").unwrap(), Boundary::Sunset(30));
+        assert_eq!(Boundary::parse("sunrise-15").unwrap(), Boundary::Sunrise(-15));
+        assert!(Boundary::parse("25:00").is_err());
+        assert!(Boundary::parse("sunset+soon").is_err());
+    }
+
+    #[test]
+    fn evening_range_runs_past_midnight() {
+        let schedule = schedule(&[at(22, 0), at(6, 0)], None);
+        let day = date(2024, 3, 10);
+        assert_eq!(schedule.active_at(local(day, 2, 0)), Some(0));
+        assert_eq!(schedule.active_at(local(day, 5, 59)), Some(0));
+        assert_eq!(schedule.active_at(local(day, 6, 0)), Some(1));
+        assert_eq!(schedule.active_at(local(day, 21, 59)), Some(1));
+        assert_eq!(schedule.active_at(local(day, 23, 0)), Some(0));
+        assert_eq!(Schedule::default().active_at(local(day, 12, 0)), None);
+    }
+
+    #[test]
+    fn solar_boundaries_apply_their_offsets() {
+        let location = (48.85, 2.35);
+        let schedule = schedule(&[Boundary::Sunrise(30), Boundary::Sunset(-60)], Some(location));
+        let day = date(2024, 6, 21);
+        let (sunrise, sunset) = solar::sun_times(day, location.0, location.1).unwrap();
+        let offset = FixedOffset::east_opt(3600).unwrap();
+        let minutes = |time: DateTime<chrono::Utc>, by: i64| (time + ChronoDuration::minutes(by)).with_timezone(&offset);
+
+        assert_eq!(schedule.active_at(minutes(sunrise, 29)), Some(1));
+        assert_eq!(schedule.active_at(minutes(sunrise, 31)), Some(0));
+        assert_eq!(schedule.active_at(minutes(sunset, -61)), Some(0));
+        assert_eq!(schedule.active_at(minutes(sunset, -59)), Some(1));
+    }
+
+    #[test]
+    fn polar_day_has_no_solar_range() {
+        let svalbard = Some((78.2, 15.6));
+        let midsummer = local(date(2024, 6, 21), 12, 0);
+        let solar_only = schedule(&[Boundary::Sunrise(0), Boundary::Sunset(0)], svalbard);
+        assert_eq!(solar_only.active_at(midsummer), None);
+        // A fixed time still applies
+        let mixed = schedule(&[Boundary::Sunrise(0), at(8, 0)], svalbard);
+        assert_eq!(mixed.active_at(midsummer), Some(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn leaving_a_pause_range_resumes_after_switching() {
+        let mut schedule = schedule(&[at(8, 0), at(9, 0)], None);
+        schedule.entries[0].target = ScheduleTarget::Pause;
+        let clock = Arc::new(SimulatedClock::new(local(date(2024, 3, 10), 8, 30)));
+        let (handle, mut ends) = command::channel();
+        let mut scheduler = Scheduler::new(schedule, clock.clone(), handle);
+
+        assert_eq!(scheduler.tick(), Some(0));
+        assert!(matches!(ends.commands.try_recv(), Ok(PlayerCommand::Pause(PauseReason::Schedule))));
+        assert!(ends.commands.try_recv().is_err());
+
+        // Nothing new within the same range
+        tokio::time::advance(Duration::from_secs(20 * 60)).await;
+        assert_eq!(scheduler.tick(), Some(0));
+        assert!(ends.commands.try_recv().is_err());
+
+        tokio::time::advance(Duration::from_secs(20 * 60)).await;
+        assert_eq!(scheduler.tick(), Some(1));
+        assert!(matches!(ends.commands.try_recv(), Ok(PlayerCommand::Load(path)) if path == "1.mp4"));
+        assert!(matches!(ends.commands.try_recv(), Ok(PlayerCommand::Resume(PauseReason::Schedule))));
+        assert!(ends.commands.try_recv().is_err());
+    }
+}
@@ -0,0 +1,75 @@
+use chrono::{DateTime, NaiveDate, Utc};
+
+/// Julian day of the J2000 epoch (2000-01-01 12:00 UTC).
+const J2000: f64 = 2451545.0;
+/// Julian day of the Unix epoch.
+const UNIX_EPOCH_JD: f64 = 2440587.5;
+/// Sun centre below the horizon at rise/set, allowing for refraction and the disc's radius.
+const HORIZON_DEG: f64 = -0.833;
+const EARTH_TILT_DEG: f64 = 23.4397;
+
+/// Sunrise and sunset on `date` at `latitude`/`longitude` (degrees, north and east positive),
+/// using the standard sunrise equation; good to a minute or two, which is plenty for
+/// switching wallpapers. `None` while the sun stays up or down all day.
+pub fn sun_times(date: NaiveDate, latitude: f64, longitude: f64) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    let epoch = NaiveDate::from_ymd_opt(2000, 1, 1)?;
+    let days = (date - epoch).num_days() as f64;
+
+    // Mean solar noon, then the sun's position at that moment
+    let noon = days - longitude / 360.0;
+    let anomaly = (357.5291 + 0.98560028 * noon).rem_euclid(360.0).to_radians();
+    let center = 1.9148 * anomaly.sin() + 0.02 * (2.0 * anomaly).sin() + 0.0003 * (3.0 * anomaly).sin();
+    let ecliptic = (anomaly.to_degrees() + center + 180.0 + 102.9372).rem_euclid(360.0).to_radians();
+    let transit = J2000 + noon + 0.0053 * anomaly.sin() - 0.0069 * (2.0 * ecliptic).sin();
+    let declination = (ecliptic.sin() * EARTH_TILT_DEG.to_radians().sin()).asin();
+
+    let lat = latitude.to_radians();
+    let cos_hour_angle =
+        (HORIZON_DEG.to_radians().sin() - lat.sin() * declination.sin()) / (lat.cos() * declination.cos());
+    if !(-1.0..=1.0).contains(&cos_hour_angle) {
+        return None;
+    }
+    let half_day = cos_hour_angle.acos().to_degrees() / 360.0;
+
+    Some((julian_to_utc(transit - half_day)?, julian_to_utc(transit + half_day)?))
+}
+
+fn julian_to_utc(julian: f64) -> Option<DateTime<Utc>> {
+    let millis = ((julian - UNIX_EPOCH_JD) * 86_400_000.0).round() as i64;
+    DateTime::from_timestamp_millis(millis)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveDateTime, TimeDelta};
+
+    fn assert_near(actual: DateTime<Utc>, expected: &str) {
+        let expected = NaiveDateTime::parse_from_str(expected, "%Y-%m-%d %H:%M").unwrap().and_utc();
+        assert!((actual - expected).abs() <= TimeDelta::minutes(3), "{} is not near {}", actual, expected);
+    }
+
+    #[test]
+    fn greenwich_midsummer() {
+        // Published times for the Royal Observatory: 04:43 and 21:21 BST
+        let (sunrise, sunset) = sun_times(NaiveDate::from_ymd_opt(2024, 6, 21).unwrap(), 51.4769, 0.0).unwrap();
+        assert_near(sunrise, "2024-06-21 03:43");
+        assert_near(sunset, "2024-06-21 20:21");
+    }
+
+    #[test]
+    fn equinox_day_at_the_equator_is_about_twelve_hours() {
+        let (sunrise, sunset) = sun_times(NaiveDate::from_ymd_opt(2024, 3, 20).unwrap(), 0.0, 0.0).unwrap();
+        assert_near(sunrise, "2024-03-20 06:04");
+        let day = sunset - sunrise;
+        assert!(day > TimeDelta::hours(12) && day < TimeDelta::minutes(12 * 60 + 10), "{}", day);
+    }
+
+    #[test]
+    fn no_times_during_polar_day_or_night() {
+        assert_eq!(sun_times(NaiveDate::from_ymd_opt(2024, 6, 21).unwrap(), 78.2, 15.6), None);
+        assert_eq!(sun_times(NaiveDate::from_ymd_opt(2024, 12, 21).unwrap(), 78.2, 15.6), None);
+        // Midwinter in the south is its polar day
+        assert_eq!(sun_times(NaiveDate::from_ymd_opt(2024, 12, 21).unwrap(), -78.2, 15.6), None);
+    }
+}
@@ -0,0 +1,61 @@
+use crate::wallpaper::command::{PlayerCommand, PlayerHandle};
+use crate::wallpaper::renderer;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// How long the player gets to save and take the window down before we give up on it.
+const PLAYER_GRACE: Duration = Duration::from_secs(3);
+
+/// Resolves on Ctrl+C, SIGTERM, or the console window being closed.
+pub async fn signalled() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+            }
+            Err(e) => {
+                tracing::warn!("Cannot listen for SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(windows)]
+    {
+        match tokio::signal::windows::ctrl_close() {
+            Ok(mut close) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = close.recv() => {}
+                }
+            }
+            Err(e) => {
+                tracing::warn!("Cannot listen for the console closing: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+}
+
+/// Stop everything in order: the tasks that drive the player first, so nothing pauses or
+/// reloads mid-teardown, then the player, which saves its position and takes the wallpaper
+/// window down. Settings are written as they're applied, so there's nothing else to flush.
+pub async fn shutdown(player: &PlayerHandle, player_task: JoinHandle<()>, background: Vec<JoinHandle<()>>) {
+    tracing::info!("Shutting down.");
+    for task in background {
+        task.abort();
+        let _ = task.await;
+    }
+
+    player.send(PlayerCommand::Shutdown);
+    if tokio::time::timeout(PLAYER_GRACE, player_task).await.is_err() {
+        // Stuck mid-frame; at least don't leave the icons on a transparent background
+        tracing::error!("Player didn't stop within {:?}, restoring the desktop without it.", PLAYER_GRACE);
+        renderer::restore_desktop();
+    }
+    tracing::info!("Shutdown complete.");
+}
@@ -0,0 +1,677 @@
+use anyhow::Result;
+use ffmpeg_next as ffmpeg;
+use std::collections::VecDeque;
+use windows::Win32::Media::Audio::*;
+use windows::Win32::Media::{MMTIME, TIME_SAMPLES};
+
+/// How far ahead of the video clock audio is handed to the sink (seconds).
+const AUDIO_LEAD: f64 = 0.2;
+/// Audio older than this relative to the video clock is dropped instead of played.
+const AUDIO_DRIFT_TOLERANCE: f64 = 0.08;
+/// Buffers handed to waveOut and not yet played back; about a second of audio at the
+/// decoder's usual packet sizes. Writes beyond it are dropped and resync fills the hole.
+const MAX_QUEUED_BUFFERS: usize = 48;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioFormat {
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+/// Destination for decoded, interleaved 16-bit PCM.
+/// Sync and gain are handled upstream, so a sink only has to play what it is given.
+pub trait AudioSink: Send {
+    fn open(&mut self, format: AudioFormat) -> Result<()>;
+    fn write(&mut self, samples: &[i16]) -> Result<()>;
+    fn set_paused(&mut self, _paused: bool) {}
+    /// Drop anything queued (seek / loop).
+    fn reset(&mut self) {}
+    /// Frames the device has actually played since `open` or the last `reset`, by its own
+    /// clock. `None` when it can't say, which leaves sync to the video clock alone.
+    fn played_frames(&mut self) -> Option<u64> {
+        None
+    }
+}
+
+/// Discards audio. Used when no output device is available.
+#[derive(Default)]
+pub struct NullSink;
+
+impl NullSink {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl AudioSink for NullSink {
+    fn open(&mut self, _format: AudioFormat) -> Result<()> {
+        Ok(())
+    }
+
+    fn write(&mut self, _samples: &[i16]) -> Result<()> {
+        Ok(())
+    }
+}
+
+struct WaveBuffer {
+    header: WAVEHDR,
+    _data: Vec<i16>,
+}
+
+/// Default output device through the WinMM waveOut API.
+pub struct WaveOutSink {
+    handle: HWAVEOUT,
+    queued: VecDeque<Box<WaveBuffer>>,
+    /// Frames played before the last position read, and that read, which wraps at 2^32.
+    played: u64,
+    last_position: u32,
+}
+
+// Safety: HWAVEOUT is a process-wide handle; the sink is only driven by the player thread.
+unsafe impl Send for WaveOutSink {}
+
+impl WaveOutSink {
+    pub fn new() -> Self {
+        Self {
+            handle: HWAVEOUT::default(),
+            queued: VecDeque::new(),
+            played: 0,
+            last_position: 0,
+        }
+    }
+
+    fn is_done(buffer: &WaveBuffer) -> bool {
+        // The driver updates dwFlags asynchronously, so the read has to be volatile. WAVEHDR is
+        // packed and the field may be misaligned, so read it as bytes, which are never misaligned.
+        let flags = unsafe { std::ptr::read_volatile(std::ptr::addr_of!(buffer.header.dwFlags) as *const [u8; 4]) };
+        u32::from_ne_bytes(flags) & WHDR_DONE != 0
+    }
+
+    fn reclaim(&mut self, force: bool) {
+        while let Some(front) = self.queued.front_mut() {
+            if !force && !Self::is_done(front) {
+                break;
+            }
+            unsafe {
+                waveOutUnprepareHeader(self.handle, &mut front.header, std::mem::size_of::<WAVEHDR>() as u32);
+            }
+            self.queued.pop_front();
+        }
+    }
+}
+
+impl AudioSink for WaveOutSink {
+    fn open(&mut self, format: AudioFormat) -> Result<()> {
+        let block_align = format.channels * 2;
+        let wfx = WAVEFORMATEX {
+            wFormatTag: WAVE_FORMAT_PCM as u16,
+            nChannels: format.channels,
+            nSamplesPerSec: format.sample_rate,
+            nAvgBytesPerSec: format.sample_rate * block_align as u32,
+            nBlockAlign: block_align,
+            wBitsPerSample: 16,
+            cbSize: 0,
+        };
+
+        let mut handle = HWAVEOUT::default();
+        let res = unsafe { waveOutOpen(Some(&mut handle), WAVE_MAPPER, &wfx, 0, 0, CALLBACK_NULL) };
+        if res != 0 {
+            return Err(anyhow::anyhow!("waveOutOpen failed (MMRESULT {})", res));
+        }
+        self.handle = handle;
+        tracing::info!("Audio device opened: {} Hz, {} ch", format.sample_rate, format.channels);
+        Ok(())
+    }
+
+    fn write(&mut self, samples: &[i16]) -> Result<()> {
+        if self.handle.is_invalid() || samples.is_empty() {
+            return Ok(());
+        }
+        self.reclaim(false);
+        if self.queued.len() >= MAX_QUEUED_BUFFERS {
+            tracing::debug!("Audio device is {} buffers behind, dropping {} samples", self.queued.len(), samples.len());
+            return Ok(());
+        }
+
+        let mut data = samples.to_vec();
+        let mut buffer = Box::new(WaveBuffer {
+            header: WAVEHDR {
+                lpData: windows::core::PSTR(data.as_mut_ptr() as *mut u8),
+                dwBufferLength: (data.len() * 2) as u32,
+                ..Default::default()
+            },
+            _data: data,
+        });
+
+        let size = std::mem::size_of::<WAVEHDR>() as u32;
+        unsafe {
+            let res = waveOutPrepareHeader(self.handle, &mut buffer.header, size);
+            if res != 0 {
+                return Err(anyhow::anyhow!("waveOutPrepareHeader failed (MMRESULT {})", res));
+            }
+            let res = waveOutWrite(self.handle, &mut buffer.header, size);
+            if res != 0 {
+                waveOutUnprepareHeader(self.handle, &mut buffer.header, size);
+                return Err(anyhow::anyhow!("waveOutWrite failed (MMRESULT {})", res));
+            }
+        }
+        self.queued.push_back(buffer);
+        Ok(())
+    }
+
+    fn set_paused(&mut self, paused: bool) {
+        if self.handle.is_invalid() {
+            return;
+        }
+        unsafe {
+            if paused {
+                waveOutPause(self.handle);
+            } else {
+                waveOutRestart(self.handle);
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        if self.handle.is_invalid() {
+            return;
+        }
+        unsafe {
+            waveOutReset(self.handle);
+        }
+        self.reclaim(true);
+        // waveOutReset puts the position back to zero
+        self.played = 0;
+        self.last_position = 0;
+    }
+
+    fn played_frames(&mut self) -> Option<u64> {
+        if self.handle.is_invalid() {
+            return None;
+        }
+        let mut time = MMTIME { wType: TIME_SAMPLES, ..Default::default() };
+        let res = unsafe { waveOutGetPosition(self.handle, &mut time, std::mem::size_of::<MMTIME>() as u32) };
+        // Drivers that can't count samples switch wType to a format they can
+        if res != 0 || time.wType != TIME_SAMPLES {
+            return None;
+        }
+        let position = unsafe { time.u.sample };
+        self.played += position.wrapping_sub(self.last_position) as u64;
+        self.last_position = position;
+        Some(self.played)
+    }
+}
+
+impl Drop for WaveOutSink {
+    fn drop(&mut self) {
+        if self.handle.is_invalid() {
+            return;
+        }
+        self.reset();
+        unsafe {
+            waveOutClose(self.handle);
+        }
+    }
+}
+
+struct AudioChunk {
+    pts: f64,
+    samples: Vec<i16>,
+}
+
+/// Holds decoded audio until the video clock reaches it, then applies gain and forwards it to the sink.
+pub struct AudioOutput {
+    sink: Box<dyn AudioSink>,
+    format: AudioFormat,
+    volume: u32,
+    muted: bool,
+    pending: VecDeque<AudioChunk>,
+    /// PTS (seconds) right after the last sample handed to the sink.
+    written_until: Option<f64>,
+    /// Frames handed to the sink since it was opened or reset, silence included.
+    written_frames: u64,
+}
+
+impl AudioOutput {
+    pub fn new(mut sink: Box<dyn AudioSink>, format: AudioFormat, volume: u32, muted: bool) -> Result<Self> {
+        sink.open(format)?;
+        Ok(Self {
+            sink,
+            format,
+            volume: volume.min(100),
+            muted,
+            pending: VecDeque::new(),
+            written_until: None,
+            written_frames: 0,
+        })
+    }
+
+    pub fn set_volume(&mut self, volume: u32) {
+        self.volume = volume.min(100);
+    }
+
+    pub fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.sink.set_paused(paused);
+    }
+
+    pub fn push(&mut self, pts: f64, samples: Vec<i16>) {
+        if !samples.is_empty() {
+            self.pending.push_back(AudioChunk { pts, samples });
+        }
+    }
+
+    /// Drop queued audio and restart sync; called after any seek.
+    pub fn flush(&mut self) {
+        self.pending.clear();
+        self.written_until = None;
+        self.written_frames = 0;
+        self.sink.reset();
+    }
+
+    fn frames_to_secs(&self, frames: usize) -> f64 {
+        frames as f64 / self.format.sample_rate.max(1) as f64
+    }
+
+    fn secs_to_frames(&self, secs: f64) -> usize {
+        (secs.max(0.0) * self.format.sample_rate as f64).round() as usize
+    }
+
+    /// How far what the device is playing right now is ahead of `video_pts`, going by the
+    /// device's own position. The sound card's clock never quite matches the one pacing
+    /// video, so left alone the two drift apart over a long loop.
+    fn drift(&mut self, written_until: f64, video_pts: f64) -> Option<f64> {
+        let played = self.sink.played_frames()?;
+        let queued = self.written_frames.saturating_sub(played);
+        Some(written_until - self.frames_to_secs(queued as usize) - video_pts)
+    }
+
+    /// Release every queued chunk that falls inside the window around `video_pts`.
+    pub fn sync(&mut self, video_pts: f64) -> Result<()> {
+        let channels = self.format.channels.max(1) as usize;
+        let gain = if self.muted { 0.0 } else { self.volume as f32 / 100.0 };
+
+        while let Some(chunk) = self.pending.front() {
+            let frames = chunk.samples.len() / channels;
+            let end = chunk.pts + self.frames_to_secs(frames);
+
+            if end < video_pts - AUDIO_DRIFT_TOLERANCE {
+                // Video has already moved past this audio.
+                self.pending.pop_front();
+                continue;
+            }
+            if chunk.pts > video_pts + AUDIO_LEAD {
+                break;
+            }
+
+            let chunk = self.pending.pop_front().unwrap();
+            let mut start_frame = 0;
+            let mut silence_frames = 0;
+            match self.written_until {
+                None => {
+                    // First chunk after open/flush: line it up with the video clock.
+                    if chunk.pts < video_pts - AUDIO_DRIFT_TOLERANCE {
+                        start_frame = self.secs_to_frames(video_pts - chunk.pts).min(frames);
+                    } else if chunk.pts > video_pts {
+                        silence_frames = self.secs_to_frames(chunk.pts - video_pts);
+                    }
+                }
+                Some(until) => {
+                    if chunk.pts > until + AUDIO_DRIFT_TOLERANCE {
+                        silence_frames = self.secs_to_frames(chunk.pts - until);
+                    } else if chunk.pts < until - AUDIO_DRIFT_TOLERANCE {
+                        start_frame = self.secs_to_frames(until - chunk.pts).min(frames);
+                    }
+                    // Pull the device back in line: skip audio it's late with, or hold it
+                    // back with silence when it has run ahead
+                    match self.drift(until, video_pts) {
+                        Some(drift) if drift < -AUDIO_DRIFT_TOLERANCE => {
+                            start_frame = (start_frame + self.secs_to_frames(-drift)).min(frames);
+                        }
+                        Some(drift) if drift > AUDIO_DRIFT_TOLERANCE => silence_frames += self.secs_to_frames(drift),
+                        _ => {}
+                    }
+                }
+            }
+
+            if silence_frames > 0 {
+                self.sink.write(&vec![0i16; silence_frames * channels])?;
+                self.written_frames += silence_frames as u64;
+            }
+
+            let out: Vec<i16> = chunk.samples[start_frame * channels..]
+                .iter()
+                .map(|s| (*s as f32 * gain) as i16)
+                .collect();
+            self.sink.write(&out)?;
+            self.written_frames += (out.len() / channels) as u64;
+            self.written_until = Some(end);
+        }
+        Ok(())
+    }
+}
+
+/// The audio stream of an open input, decoded and resampled to packed 16-bit stereo.
+pub struct AudioTrack {
+    stream_index: usize,
+    time_base: f64,
+    decoder: ffmpeg::decoder::Audio,
+    resampler: ffmpeg::software::resampling::Context,
+    output: AudioOutput,
+}
+
+impl AudioTrack {
+    /// Returns `Ok(None)` when the input has no audio stream.
+    pub fn open(
+        ictx: &ffmpeg::format::context::Input,
+        sink: Box<dyn AudioSink>,
+        volume: u32,
+        muted: bool,
+    ) -> Result<Option<Self>> {
+        let input = match ictx.streams().best(ffmpeg::media::Type::Audio) {
+            Some(s) => s,
+            None => return Ok(None),
+        };
+
+        let stream_index = input.index();
+        let time_base = f64::from(input.time_base());
+        let context_decoder = ffmpeg::codec::context::Context::from_parameters(input.parameters())?;
+        let decoder = context_decoder.decoder().audio()?;
+
+        let src_layout = if decoder.channel_layout().is_empty() {
+            ffmpeg::ChannelLayout::default(decoder.channels() as i32)
+        } else {
+            decoder.channel_layout()
+        };
+
+        let resampler = ffmpeg::software::resampling::Context::get(
+            decoder.format(),
+            src_layout,
+            decoder.rate(),
+            ffmpeg::format::Sample::I16(ffmpeg::format::sample::Type::Packed),
+            ffmpeg::ChannelLayout::STEREO,
+            decoder.rate(),
+        )?;
+
+        let format = AudioFormat { sample_rate: decoder.rate(), channels: 2 };
+        let output = AudioOutput::new(sink, format, volume, muted)?;
+
+        Ok(Some(Self {
+            stream_index,
+            time_base,
+            decoder,
+            resampler,
+            output,
+        }))
+    }
+
+    pub fn stream_index(&self) -> usize {
+        self.stream_index
+    }
+
+    pub fn output_mut(&mut self) -> &mut AudioOutput {
+        &mut self.output
+    }
+
+    pub fn decode_packet(&mut self, packet: &ffmpeg::Packet) -> Result<()> {
+        self.decoder.send_packet(packet)?;
+
+        let mut frame = ffmpeg::util::frame::Audio::empty();
+        while self.decoder.receive_frame(&mut frame).is_ok() {
+            let pts = frame.timestamp().or(frame.pts()).unwrap_or(0) as f64 * self.time_base;
+
+            let mut converted = ffmpeg::util::frame::Audio::empty();
+            self.resampler.run(&frame, &mut converted)?;
+
+            let count = converted.samples() * 2;
+            let samples: Vec<i16> = converted.data(0)[..count * 2]
+                .chunks_exact(2)
+                .map(|b| i16::from_le_bytes([b[0], b[1]]))
+                .collect();
+            self.output.push(pts, samples);
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) {
+        self.decoder.flush();
+        self.output.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+
+    const RATE: u32 = 1000;
+    const MONO: AudioFormat = AudioFormat { sample_rate: RATE, channels: 1 };
+
+    /// Writes everything it receives to a PCM WAV file, so what the sink was handed can be
+    /// read back and checked against the video clock.
+    struct WavSink {
+        writer: Option<BufWriter<File>>,
+        path: PathBuf,
+        data_bytes: u32,
+    }
+
+    impl WavSink {
+        fn new(path: PathBuf) -> Self {
+            Self { writer: None, path, data_bytes: 0 }
+        }
+    }
+
+    impl AudioSink for WavSink {
+        fn open(&mut self, format: AudioFormat) -> Result<()> {
+            let mut writer = BufWriter::new(File::create(&self.path)?);
+            let block_align = format.channels as u32 * 2;
+            writer.write_all(b"RIFF")?;
+            writer.write_all(&36u32.to_le_bytes())?;
+            writer.write_all(b"WAVEfmt ")?;
+            writer.write_all(&16u32.to_le_bytes())?;
+            writer.write_all(&1u16.to_le_bytes())?;
+            writer.write_all(&format.channels.to_le_bytes())?;
+            writer.write_all(&format.sample_rate.to_le_bytes())?;
+            writer.write_all(&(format.sample_rate * block_align).to_le_bytes())?;
+            writer.write_all(&(block_align as u16).to_le_bytes())?;
+            writer.write_all(&16u16.to_le_bytes())?;
+            writer.write_all(b"data")?;
+            writer.write_all(&0u32.to_le_bytes())?;
+            self.writer = Some(writer);
+            Ok(())
+        }
+
+        fn write(&mut self, samples: &[i16]) -> Result<()> {
+            let writer = self.writer.as_mut().ok_or_else(|| anyhow::anyhow!("WAV sink not opened"))?;
+            for s in samples {
+                writer.write_all(&s.to_le_bytes())?;
+            }
+            self.data_bytes += (samples.len() * 2) as u32;
+            Ok(())
+        }
+    }
+
+    impl Drop for WavSink {
+        fn drop(&mut self) {
+            if let Some(mut writer) = self.writer.take() {
+                let _ = writer.flush();
+                let file = writer.get_mut();
+                let _ = file.seek(SeekFrom::Start(4)).and_then(|_| file.write_all(&(36 + self.data_bytes).to_le_bytes()));
+                let _ = file.seek(SeekFrom::Start(40)).and_then(|_| file.write_all(&self.data_bytes.to_le_bytes()));
+            }
+        }
+    }
+
+    fn wav_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("mew-audio-{}-{}.wav", name, std::process::id()))
+    }
+
+    /// Samples in the file's data chunk, checking the header sizes add up.
+    fn read_wav(path: &PathBuf) -> Vec<i16> {
+        let mut bytes = Vec::new();
+        File::open(path).unwrap().read_to_end(&mut bytes).unwrap();
+        std::fs::remove_file(path).unwrap();
+        let data_bytes = u32::from_le_bytes(bytes[40..44].try_into().unwrap()) as usize;
+        assert_eq!(u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize, 36 + data_bytes);
+        assert_eq!(bytes.len(), 44 + data_bytes);
+        bytes[44..].chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect()
+    }
+
+    /// Feed `chunks` (pts, frames of a constant sample) and sync at each video pts in turn.
+    fn play(name: &str, volume: u32, muted: bool, chunks: &[(f64, usize, i16)], video: &[f64]) -> Vec<i16> {
+        let path = wav_path(name);
+        {
+            let mut output = AudioOutput::new(Box::new(WavSink::new(path.clone())), MONO, volume, muted).unwrap();
+            for &(pts, frames, value) in chunks {
+                output.push(pts, vec![value; frames]);
+            }
+            for &pts in video {
+                output.sync(pts).unwrap();
+            }
+        }
+        read_wav(&path)
+    }
+
+    #[test]
+    fn in_sync_audio_passes_through_unchanged() {
+        let out = play("in-sync", 100, false, &[(0.0, 100, 7), (0.1, 100, 9)], &[0.0]);
+        assert_eq!(out.len(), 200);
+        assert!(out[..100].iter().all(|&s| s == 7));
+        assert!(out[100..].iter().all(|&s| s == 9));
+    }
+
+    #[test]
+    fn late_first_chunk_is_trimmed_to_the_video_clock() {
+        let out = play("late", 100, false, &[(0.0, 1000, 5)], &[0.5]);
+        assert_eq!(out.len(), 500);
+    }
+
+    #[test]
+    fn early_first_chunk_is_padded_with_silence() {
+        let out = play("early", 100, false, &[(0.1, 100, 5)], &[0.0]);
+        assert_eq!(out.len(), 200);
+        assert!(out[..100].iter().all(|&s| s == 0));
+        assert!(out[100..].iter().all(|&s| s == 5));
+    }
+
+    #[test]
+    fn gaps_become_silence_and_overlaps_are_cut() {
+        // 0.0-0.1, then a 0.1s gap, then a chunk overlapping the previous by 0.1s
+        let out = play("gaps", 100, false, &[(0.0, 100, 1), (0.2, 100, 2), (0.2, 200, 3)], &[0.0, 0.2]);
+        assert_eq!(out.len(), 100 + 100 + 100 + 100);
+        assert!(out[100..200].iter().all(|&s| s == 0));
+        assert!(out[200..300].iter().all(|&s| s == 2));
+        assert!(out[300..].iter().all(|&s| s == 3));
+    }
+
+    #[test]
+    fn audio_beyond_the_lead_waits_and_stale_audio_is_dropped() {
+        let path = wav_path("lead");
+        {
+            let mut output = AudioOutput::new(Box::new(WavSink::new(path.clone())), MONO, 100, false).unwrap();
+            output.push(0.0, vec![1; 100]);
+            output.push(1.0, vec![2; 100]);
+            output.sync(0.0).unwrap();
+            // The first chunk went out; the second is a second ahead and must wait
+            assert_eq!(output.pending.len(), 1);
+            output.flush();
+            output.push(0.0, vec![3; 100]);
+            output.sync(5.0).unwrap();
+            assert!(output.pending.is_empty());
+        }
+        assert_eq!(read_wav(&path), vec![1; 100]);
+    }
+
+    /// A device whose clock runs `skew` times as fast as the video's, and that keeps every
+    /// sample it was handed so what it's playing at any moment can be looked up.
+    #[derive(Clone, Default)]
+    struct SkewedSink {
+        state: Arc<Mutex<Skewed>>,
+    }
+
+    #[derive(Default)]
+    struct Skewed {
+        written: Vec<i16>,
+        /// Device time, in seconds since it opened.
+        clock: f64,
+    }
+
+    impl AudioSink for SkewedSink {
+        fn open(&mut self, _format: AudioFormat) -> Result<()> {
+            Ok(())
+        }
+
+        fn write(&mut self, samples: &[i16]) -> Result<()> {
+            self.state.lock().unwrap().written.extend_from_slice(samples);
+            Ok(())
+        }
+
+        fn played_frames(&mut self) -> Option<u64> {
+            let state = self.state.lock().unwrap();
+            // An underrun stalls the position at what was written
+            Some(((state.clock * RATE as f64) as u64).min(state.written.len() as u64))
+        }
+    }
+
+    /// Play half an hour of continuous audio against a `skew`ed device. Each sample holds its
+    /// own pts in ms (plus one, so it's told apart from silence). Returns the worst gap
+    /// between the pts audible and the video pts, and the most audio ever queued, in seconds.
+    fn play_skewed(skew: f64) -> (f64, f64) {
+        const STEP: f64 = 0.02;
+        const CHUNK: usize = (STEP * RATE as f64) as usize;
+        const WRAP: i64 = 30_000;
+        let sink = SkewedSink::default();
+        let mut output = AudioOutput::new(Box::new(sink.clone()), MONO, 100, false).unwrap();
+        let (mut worst_gap, mut worst_queue) = (0.0f64, 0.0f64);
+        let mut next_chunk = 0usize;
+        for step in 0..(1800.0 / STEP) as usize {
+            let video = step as f64 * STEP;
+            sink.state.lock().unwrap().clock = video * skew;
+            while next_chunk as f64 * STEP <= video + 2.0 * AUDIO_LEAD {
+                let start = (next_chunk * CHUNK) as i64;
+                output.push(next_chunk as f64 * STEP, (0..CHUNK as i64).map(|i| (1 + (start + i) % WRAP) as i16).collect());
+                next_chunk += 1;
+            }
+            output.sync(video).unwrap();
+
+            let played = output.sink.played_frames().unwrap() as usize;
+            let state = sink.state.lock().unwrap();
+            worst_queue = worst_queue.max((state.written.len() - played) as f64 / RATE as f64);
+            let Some(&sample) = played.checked_sub(1).and_then(|at| state.written.get(at)) else { continue };
+            if sample == 0 {
+                continue;
+            }
+            let audible = (sample as i64 - 1) as f64 / 1000.0;
+            let gap = (audible - video).rem_euclid(WRAP as f64 / 1000.0);
+            worst_gap = worst_gap.max(gap.min(WRAP as f64 / 1000.0 - gap));
+        }
+        (worst_gap, worst_queue)
+    }
+
+    #[test]
+    fn a_slow_device_is_caught_up_by_skipping_audio() {
+        // 0.5% slow would leave it 9 s behind by the end
+        let (gap, queue) = play_skewed(0.995);
+        assert!(gap <= AUDIO_DRIFT_TOLERANCE + 0.03, "audio {:.3}s off the video", gap);
+        assert!(queue <= AUDIO_LEAD + AUDIO_DRIFT_TOLERANCE + 0.03, "{:.3}s queued", queue);
+    }
+
+    #[test]
+    fn a_fast_device_is_held_back_with_silence() {
+        let (gap, queue) = play_skewed(1.005);
+        assert!(gap <= AUDIO_DRIFT_TOLERANCE + 0.03, "audio {:.3}s off the video", gap);
+        assert!(queue <= AUDIO_LEAD + AUDIO_DRIFT_TOLERANCE + 0.03, "{:.3}s queued", queue);
+    }
+
+    #[test]
+    fn gain_follows_volume_and_mute() {
+        assert!(play("half", 50, false, &[(0.0, 10, 1000)], &[0.0]).iter().all(|&s| s == 500));
+        assert!(play("muted", 100, true, &[(0.0, 10, 1000)], &[0.0]).iter().all(|&s| s == 0));
+    }
+}
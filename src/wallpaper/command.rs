@@ -0,0 +1,223 @@
+use crate::performance::policy::PowerMode;
+use crate::wallpaper::day_cycle::DayCycle;
+use crate::wallpaper::frame::PixelFormat;
+use crate::wallpaper::pause::{PauseReason, PauseReasons};
+use crate::wallpaper::playlist::Playlist;
+use crate::wallpaper::proxy::ProxyProgress;
+use crate::wallpaper::transition::TransitionKind;
+use crate::wallpaper::watchdog::Recovery;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, watch};
+
+/// Events kept per subscriber before the slowest one starts missing them.
+const EVENT_CAPACITY: usize = 64;
+
+/// Requests to the player task. They are applied in order, as soon as the player
+/// wakes, including while it is paused or idle.
+#[derive(Debug, Clone)]
+pub enum PlayerCommand {
+    /// Play a file or stream URL, leaving any playlist. Loading the path that's already
+    /// playing is a no-op.
+    Load(String),
+    /// Rotate through a playlist, starting from its first item in play order. `None` stays
+    /// on the current item as a single wallpaper.
+    SetPlaylist(Option<Playlist>),
+    /// Skip to the next or back to the previous playlist item.
+    Next,
+    Previous,
+    /// Hold playback for this reason; it stays held until every reason is resumed.
+    Pause(PauseReason),
+    Resume(PauseReason),
+    /// Jump to this many seconds into the current wallpaper.
+    Seek(f64),
+    SetFps(u32),
+    SetResolution(String),
+    SetAudio { enabled: bool, volume: u32, muted: bool },
+    SetProxyEnabled(bool),
+    /// Ignore the saved position for `path` and always start it from frame 0.
+    SetAlwaysFromStart { path: String, enabled: bool },
+    /// Make `path` follow the time of day instead of looping, or play normally with `None`.
+    SetDayCycle { path: String, cycle: Option<DayCycle> },
+    /// How wallpaper changes blend, and whether pausing and resuming crossfade too.
+    SetTransition { kind: TransitionKind, duration: Duration, fade_on_pause: bool },
+    /// Degrade playback as the power policy decided. `Static` and `Hidden` hold playback
+    /// with `reason`, which is `Battery` or `Power`.
+    SetPowerMode { mode: PowerMode, reason: PauseReason },
+    /// Limits from the app rules that currently match, on top of the user's settings.
+    SetAppLimits { fps_cap: Option<u32>, mute: bool },
+    /// Play this instead while an app rule wants it. `None` goes back to the wallpaper or
+    /// playlist underneath, including any `Load` or `SetPlaylist` that came in meanwhile.
+    SetAppWallpaper(Option<String>),
+    /// Rebuild a stalled component, or restart the whole pipeline; sent by the watchdog.
+    Recover(Recovery),
+    /// Save the position, take the wallpaper off the desktop and end the player task.
+    Shutdown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaybackStatus {
+    Idle,
+    Playing,
+    Paused,
+    /// Waiting to reconnect to a dropped stream.
+    Reconnecting,
+    /// The wallpaper failed; a fallback or solid colour is showing.
+    Failed,
+}
+
+impl PlaybackStatus {
+    pub fn label(self) -> &'static str {
+        match self {
+            PlaybackStatus::Idle => "Idle",
+            PlaybackStatus::Playing => "Playing",
+            PlaybackStatus::Paused => "Paused",
+            PlaybackStatus::Reconnecting => "Reconnecting",
+            PlaybackStatus::Failed => "Failed",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MediaInfo {
+    pub path: String,
+    /// Size frames are decoded at, i.e. the screen size.
+    pub width: u32,
+    pub height: u32,
+    pub duration: Option<f64>,
+    pub is_live: bool,
+    pub output_format: PixelFormat,
+    /// Playing a transcoded proxy rather than the source itself.
+    pub proxy: bool,
+    pub cached: bool,
+}
+
+impl std::fmt::Display for MediaInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = std::path::Path::new(&self.path).file_name().map_or(self.path.as_str(), |n| n.to_str().unwrap_or(&self.path));
+        write!(f, "{}, {}x{}", name, self.width, self.height)?;
+        match self.duration {
+            _ if self.is_live => write!(f, ", live")?,
+            Some(duration) => write!(f, ", {:.1}s", duration)?,
+            None => write!(f, ", unknown length")?,
+        }
+        write!(f, ", {:?}", self.output_format)?;
+        if self.proxy {
+            write!(f, ", proxy")?;
+        }
+        if self.cached {
+            write!(f, ", cached in memory")?;
+        }
+        Ok(())
+    }
+}
+
+/// Milliseconds at each percentile of the stats window.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Percentiles {
+    pub p50: f64,
+    pub p95: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+/// Rolling pipeline metrics over the last few seconds of playback.
+#[derive(Debug, Clone, Default)]
+pub struct PlayerStats {
+    /// Frames actually presented per second.
+    pub presented_fps: f64,
+    /// Presentation slots skipped because the player fell behind.
+    pub dropped_frames: u64,
+    /// Frames that went up noticeably after their slot.
+    pub late_frames: u64,
+    pub decode_ms: Percentiles,
+    /// Pixel format conversion and scaling to the screen.
+    pub scale_ms: Percentiles,
+    pub present_ms: Percentiles,
+    /// Decoded frames waiting to be shown.
+    pub queue_depth: usize,
+    /// Times the current wallpaper has looped since it was loaded.
+    pub loops: u32,
+    /// Damaged packets skipped since the wallpaper was opened.
+    pub corrupt_packets: u64,
+}
+
+impl std::fmt::Display for PlayerStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:.1} fps, {} dropped, {} late, decode p95 {:.1} ms, scale p95 {:.1} ms, present p95 {:.1} ms, {} loops",
+            self.presented_fps,
+            self.dropped_frames,
+            self.late_frames,
+            self.decode_ms.p95,
+            self.scale_ms.p95,
+            self.present_ms.p95,
+            self.loops
+        )
+    }
+}
+
+/// What the player reports back. Broadcast, so the UI and the monitor can each subscribe.
+#[derive(Debug, Clone)]
+pub enum PlayerEvent {
+    Status(PlaybackStatus),
+    /// The active pause reasons changed.
+    PauseReasons(PauseReasons),
+    Loaded(MediaInfo),
+    Error(String),
+    Position { position: f64, duration: Option<f64> },
+    Stats(PlayerStats),
+    ProxyProgress(ProxyProgress),
+}
+
+/// The sending half of the player's channels; cheap to clone and hand to the UI and the monitor.
+#[derive(Clone)]
+pub struct PlayerHandle {
+    commands: mpsc::UnboundedSender<PlayerCommand>,
+    events: broadcast::Sender<PlayerEvent>,
+    stats: watch::Receiver<PlayerStats>,
+}
+
+impl PlayerHandle {
+    /// Queue a command. Does not block, so it's safe from the UI thread.
+    pub fn send(&self, command: PlayerCommand) {
+        if self.commands.send(command).is_err() {
+            tracing::warn!("Player task is gone, command dropped.");
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<PlayerEvent> {
+        self.events.subscribe()
+    }
+
+    /// The latest stats snapshot, refreshed every couple of seconds while playing.
+    pub fn stats(&self) -> PlayerStats {
+        self.stats.borrow().clone()
+    }
+}
+
+/// The ends of the channels the player task keeps.
+pub struct PlayerEnds {
+    pub commands: mpsc::UnboundedReceiver<PlayerCommand>,
+    pub events: broadcast::Sender<PlayerEvent>,
+    pub stats: watch::Sender<PlayerStats>,
+}
+
+/// Create the command, event and stats channels: the handle for callers, and the ends the player keeps.
+pub fn channel() -> (PlayerHandle, PlayerEnds) {
+    let (commands, receiver) = mpsc::unbounded_channel();
+    let (events, _) = broadcast::channel(EVENT_CAPACITY);
+    let (stats, stats_receiver) = watch::channel(PlayerStats::default());
+    (
+        PlayerHandle {
+            commands,
+            events: events.clone(),
+            stats: stats_receiver,
+        },
+        PlayerEnds {
+            commands: receiver,
+            events,
+            stats,
+        },
+    )
+}
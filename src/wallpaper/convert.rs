@@ -0,0 +1,237 @@
+use crate::wallpaper::frame::{ColorInfo, ColorRange, ColorSpace, Frame, PixelFormat};
+use anyhow::Result;
+
+/// Reference YUV -> BGRA conversion, one pixel at a time in floating point.
+/// It backs the headless test surface and is what `to_bgra_fast` and the GPU path are
+/// checked against, so it favours being obviously right over being fast.
+#[cfg(test)]
+pub fn to_bgra(src: &Frame, dst: &mut Frame) -> Result<()> {
+    if copy_bgra(src, dst)? {
+        return Ok(());
+    }
+    let (width, height) = (src.width() as usize, src.height() as usize);
+    let matrix = YuvMatrix::new(src.color(), src.format() == PixelFormat::P010);
+    let dst_stride = dst.stride(0);
+    let out = dst.plane_mut(0);
+    for y in 0..height {
+        for x in 0..width {
+            let (luma, cb, cr) = sample(src, x, y);
+            let [r, g, b] = matrix.to_rgb(luma, cb, cr);
+            let offset = y * dst_stride + x * 4;
+            out[offset..offset + 4].copy_from_slice(&[b, g, r, 255]);
+        }
+    }
+    Ok(())
+}
+
+/// YUV -> BGRA in integers through per-code lookup tables, a row at a time, for CPU work
+/// that has to keep up with playback such as blending transitions. Within a code value
+/// of `to_bgra`.
+pub fn to_bgra_fast(src: &Frame, dst: &mut Frame) -> Result<()> {
+    if copy_bgra(src, dst)? {
+        return Ok(());
+    }
+    let (width, height) = (src.width() as usize, src.height() as usize);
+    let tables = YuvTables::new(src.color(), src.format() == PixelFormat::P010);
+    let dst_stride = dst.stride(0);
+    let out = dst.plane_mut(0);
+    for y in 0..height {
+        let row = out[y * dst_stride..][..width * 4].chunks_exact_mut(4);
+        let luma = &src.plane(0)[y * src.stride(0)..];
+        let chroma = |plane: usize| &src.plane(plane)[y / 2 * src.stride(plane)..];
+        match src.format() {
+            PixelFormat::Nv12 => {
+                let uv = chroma(1);
+                for (x, pixel) in row.enumerate() {
+                    let c = x / 2 * 2;
+                    pixel.copy_from_slice(&tables.bgra(luma[x] as usize, uv[c] as usize, uv[c + 1] as usize));
+                }
+            }
+            PixelFormat::Yuv420p => {
+                let (u, v) = (chroma(1), chroma(2));
+                for (x, pixel) in row.enumerate() {
+                    pixel.copy_from_slice(&tables.bgra(luma[x] as usize, u[x / 2] as usize, v[x / 2] as usize));
+                }
+            }
+            PixelFormat::P010 => {
+                let uv = chroma(1);
+                let word = |data: &[u8], at: usize| (u16::from_le_bytes([data[at], data[at + 1]]) >> 6) as usize;
+                for (x, pixel) in row.enumerate() {
+                    let c = x / 2 * 4;
+                    pixel.copy_from_slice(&tables.bgra(word(luma, x * 2), word(uv, c), word(uv, c + 2)));
+                }
+            }
+            PixelFormat::Bgra => unreachable!("BGRA frames are copied, not converted"),
+        }
+    }
+    Ok(())
+}
+
+/// Size `dst` for `src` and copy a BGRA `src` straight across. Returns whether that was all
+/// there was to do, or fails when a YUV `src` is missing planes.
+fn copy_bgra(src: &Frame, dst: &mut Frame) -> Result<bool> {
+    let (width, height) = (src.width() as usize, src.height() as usize);
+    dst.prepare(PixelFormat::Bgra, src.width(), src.height());
+    dst.set_pts(src.pts());
+    dst.set_color(src.color());
+
+    if src.format() == PixelFormat::Bgra {
+        let (src_stride, dst_stride) = (src.stride(0), dst.stride(0));
+        let data = src.plane(0);
+        let out = dst.plane_mut(0);
+        for y in 0..height {
+            out[y * dst_stride..y * dst_stride + width * 4].copy_from_slice(&data[y * src_stride..y * src_stride + width * 4]);
+        }
+        return Ok(true);
+    }
+
+    if src.planes() < 2 || (src.format() == PixelFormat::Yuv420p && src.planes() < 3) {
+        return Err(anyhow::anyhow!("{:?} frame is missing planes", src.format()));
+    }
+    Ok(false)
+}
+
+/// Raw Y, Cb, Cr code values at (x, y); chroma is shared by each 2x2 block.
+#[cfg(test)]
+fn sample(frame: &Frame, x: usize, y: usize) -> (u16, u16, u16) {
+    let (cx, cy) = (x / 2, y / 2);
+    match frame.format() {
+        PixelFormat::Nv12 => {
+            let uv = &frame.plane(1)[cy * frame.stride(1) + cx * 2..];
+            (frame.plane(0)[y * frame.stride(0) + x] as u16, uv[0] as u16, uv[1] as u16)
+        }
+        PixelFormat::Yuv420p => (
+            frame.plane(0)[y * frame.stride(0) + x] as u16,
+            frame.plane(1)[cy * frame.stride(1) + cx] as u16,
+            frame.plane(2)[cy * frame.stride(2) + cx] as u16,
+        ),
+        PixelFormat::P010 => {
+            // 10 significant bits stored in the top of each little-endian word.
+            let word = |plane: usize, offset: usize| u16::from_le_bytes([frame.plane(plane)[offset], frame.plane(plane)[offset + 1]]) >> 6;
+            let uv = cy * frame.stride(1) + cx * 4;
+            (word(0, y * frame.stride(0) + x * 2), word(1, uv), word(1, uv + 2))
+        }
+        PixelFormat::Bgra => unreachable!("BGRA frames are copied, not sampled"),
+    }
+}
+
+struct YuvMatrix {
+    kr: f32,
+    kb: f32,
+    y_offset: f32,
+    y_scale: f32,
+    c_offset: f32,
+    c_scale: f32,
+}
+
+impl YuvMatrix {
+    fn new(color: ColorInfo, ten_bit: bool) -> Self {
+        let (kr, kb) = match color.space {
+            ColorSpace::Bt601 => (0.299, 0.114),
+            ColorSpace::Bt709 => (0.2126, 0.0722),
+            ColorSpace::Bt2020 => (0.2627, 0.0593),
+        };
+        // Limited range codes are 16-235 (Y) and 16-240 (C) at 8 bits, scaled by 4 at 10 bits.
+        let depth = if ten_bit { 4.0 } else { 1.0 };
+        let max = if ten_bit { 1023.0 } else { 255.0 };
+        let (y_offset, y_scale, c_scale) = match color.range {
+            ColorRange::Limited => (16.0 * depth, 219.0 * depth, 224.0 * depth),
+            ColorRange::Full => (0.0, max, max),
+        };
+        Self {
+            kr,
+            kb,
+            y_offset,
+            y_scale,
+            c_offset: 128.0 * depth,
+            c_scale,
+        }
+    }
+
+    #[cfg(test)]
+    fn to_rgb(&self, y: u16, cb: u16, cr: u16) -> [u8; 3] {
+        let y = (y as f32 - self.y_offset) / self.y_scale;
+        let cb = (cb as f32 - self.c_offset) / self.c_scale;
+        let cr = (cr as f32 - self.c_offset) / self.c_scale;
+
+        let r = y + 2.0 * (1.0 - self.kr) * cr;
+        let b = y + 2.0 * (1.0 - self.kb) * cb;
+        let g = (y - self.kr * r - self.kb * b) / (1.0 - self.kr - self.kb);
+        [r, g, b].map(|c| (c * 255.0).round().clamp(0.0, 255.0) as u8)
+    }
+}
+
+/// Each code value's contribution to R, G and B, in 1/256ths of an 8-bit level.
+struct YuvTables {
+    luma: Vec<i32>,
+    r_cr: Vec<i32>,
+    g_cb: Vec<i32>,
+    g_cr: Vec<i32>,
+    b_cb: Vec<i32>,
+}
+
+impl YuvTables {
+    fn new(color: ColorInfo, ten_bit: bool) -> Self {
+        let m = YuvMatrix::new(color, ten_bit);
+        let kg = 1.0 - m.kr - m.kb;
+        let codes = if ten_bit { 1024 } else { 256 };
+        let table = |f: &dyn Fn(f32) -> f32| (0..codes).map(|code| (f(code as f32) * 255.0 * 256.0).round() as i32).collect();
+        let chroma = |code: f32| (code - m.c_offset) / m.c_scale;
+        Self {
+            luma: table(&|code| (code - m.y_offset) / m.y_scale),
+            r_cr: table(&|code| 2.0 * (1.0 - m.kr) * chroma(code)),
+            g_cb: table(&|code| 2.0 * m.kb * (1.0 - m.kb) / kg * chroma(code)),
+            g_cr: table(&|code| 2.0 * m.kr * (1.0 - m.kr) / kg * chroma(code)),
+            b_cb: table(&|code| 2.0 * (1.0 - m.kb) * chroma(code)),
+        }
+    }
+
+    fn bgra(&self, y: usize, cb: usize, cr: usize) -> [u8; 4] {
+        let luma = self.luma[y];
+        let level = |v: i32| ((v + 128) >> 8).clamp(0, 255) as u8;
+        [
+            level(luma + self.b_cb[cb]),
+            level(luma - self.g_cb[cb] - self.g_cr[cr]),
+            level(luma + self.r_cr[cr]),
+            255,
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A frame with every plane filled from a hash of the byte offset, so the codes cover
+    /// the whole range, out-of-range ones included.
+    fn noisy(format: PixelFormat, color: ColorInfo) -> Frame {
+        let mut frame = Frame::new(format, 34, 6);
+        frame.set_color(color);
+        for plane in 0..frame.planes() {
+            for (i, byte) in frame.plane_mut(plane).iter_mut().enumerate() {
+                *byte = (i as u32).wrapping_mul(0x9E37_79B1).rotate_left(plane as u32 * 7 + 5) as u8;
+            }
+        }
+        frame
+    }
+
+    #[test]
+    fn fast_conversion_matches_the_reference() {
+        for format in [PixelFormat::Nv12, PixelFormat::Yuv420p, PixelFormat::P010, PixelFormat::Bgra] {
+            for space in [ColorSpace::Bt601, ColorSpace::Bt709, ColorSpace::Bt2020] {
+                for range in [ColorRange::Limited, ColorRange::Full] {
+                    let src = noisy(format, ColorInfo { space, range });
+                    let (mut reference, mut fast) = (Frame::new(PixelFormat::Bgra, 1, 1), Frame::new(PixelFormat::Bgra, 1, 1));
+                    to_bgra(&src, &mut reference).unwrap();
+                    to_bgra_fast(&src, &mut fast).unwrap();
+                    for y in 0..src.height() as usize {
+                        let row = |frame: &Frame| frame.plane(0)[y * frame.stride(0)..][..src.width() as usize * 4].to_vec();
+                        let (expected, actual) = (row(&reference), row(&fast));
+                        let off = expected.iter().zip(&actual).position(|(e, a)| e.abs_diff(*a) > 1);
+                        assert!(off.is_none(), "{:?} {:?} {:?} row {}: {:?} vs {:?}", format, space, range, y, expected, actual);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use crate::config::settings::DayCycleSettings;
+use chrono::{NaiveTime, Timelike};
+use std::time::Duration;
+
+/// A day-cycle wallpaper only changes this often; everything in between is one still frame.
+pub const DAY_CYCLE_INTERVAL: Duration = Duration::from_secs(60);
+
+const SECS_PER_DAY: f64 = 86_400.0;
+
+/// Ties a video's timeline to the wall clock, for timelapses of a whole day.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DayCycle {
+    /// Time of day the video's first frame shows, in seconds after midnight.
+    pub offset_secs: f64,
+    /// Real seconds per second of video; zero spreads the video over exactly one day.
+    pub stretch: f64,
+}
+
+impl DayCycle {
+    pub fn from_settings(settings: &DayCycleSettings) -> Self {
+        Self {
+            offset_secs: settings.offset_minutes as f64 * 60.0,
+            stretch: settings.stretch.max(0.0),
+        }
+    }
+
+    /// The position, in seconds, that should be on screen at `time` for a video of `duration`.
+    /// A stretch covering less than a day repeats; one covering more wraps at the video's end.
+    /// A zero or unusable duration stays on the first frame.
+    pub fn position(&self, time: NaiveTime, duration: f64) -> f64 {
+        if !duration.is_finite() || duration <= 0.0 {
+            return 0.0;
+        }
+        let seconds = time.num_seconds_from_midnight() as f64 + time.nanosecond() as f64 / 1e9;
+        let since_start = (seconds - self.offset_secs).rem_euclid(SECS_PER_DAY);
+        let span = if self.stretch > 0.0 { duration * self.stretch } else { SECS_PER_DAY };
+        (since_start.rem_euclid(span) / span * duration).min(duration)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cycle(offset_minutes: i32, stretch: f64) -> DayCycle {
+        DayCycle::from_settings(&DayCycleSettings { path: "day.mp4".into(), offset_minutes, stretch })
+    }
+
+    fn at(h: u32, m: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(h, m, 0).unwrap()
+    }
+
+    fn assert_near(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-6, "{} != {}", actual, expected);
+    }
+
+    #[test]
+    fn zero_stretch_fits_the_video_to_one_day() {
+        let cycle = cycle(0, 0.0);
+        assert_near(cycle.position(at(0, 0), 240.0), 0.0);
+        assert_near(cycle.position(at(6, 0), 240.0), 60.0);
+        assert_near(cycle.position(at(12, 0), 240.0), 120.0);
+        assert!(cycle.position(NaiveTime::from_hms_opt(23, 59, 59).unwrap(), 240.0) < 240.0);
+    }
+
+    #[test]
+    fn an_explicit_stretch_repeats_within_the_day() {
+        // A minute of video at 60x covers an hour, so every hour looks the same
+        let cycle = cycle(0, 60.0);
+        assert_near(cycle.position(at(0, 30), 60.0), 30.0);
+        assert_near(cycle.position(at(13, 30), 60.0), 30.0);
+        assert_near(cycle.position(at(14, 0), 60.0), 0.0);
+    }
+
+    #[test]
+    fn a_negative_stretch_counts_as_zero() {
+        assert_eq!(cycle(0, -5.0), cycle(0, 0.0));
+    }
+
+    #[test]
+    fn the_offset_wraps_across_midnight() {
+        // The first frame at 22:00, so 02:00 is four hours in and 21:00 nearly the end
+        let cycle = cycle(22 * 60, 0.0);
+        assert_near(cycle.position(at(22, 0), 240.0), 0.0);
+        assert_near(cycle.position(at(2, 0), 240.0), 40.0);
+        assert_near(cycle.position(at(21, 0), 240.0), 230.0);
+    }
+
+    #[test]
+    fn a_negative_offset_starts_the_day_before() {
+        for time in [at(0, 0), at(2, 0), at(21, 0), at(22, 0)] {
+            assert_near(cycle(-2 * 60, 0.0).position(time, 240.0), cycle(22 * 60, 0.0).position(time, 240.0));
+        }
+    }
+
+    #[test]
+    fn zero_or_unknown_duration_stays_on_the_first_frame() {
+        for duration in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert_eq!(cycle(0, 0.0).position(at(12, 0), duration), 0.0, "{}", duration);
+            assert_eq!(cycle(90, 10.0).position(at(12, 0), duration), 0.0, "{}", duration);
+        }
+    }
+}
@@ -1,14 +1,91 @@
+use crate::wallpaper::audio::{AudioSink, AudioTrack};
+use crate::wallpaper::frame::{ColorInfo, Frame, PixelFormat};
+use crate::wallpaper::stream::{self, StreamOptions};
 use anyhow::{Context, Result};
 use ffmpeg_next as ffmpeg;
 use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Give up on a file once this many video packets in a row fail to decode.
+const MAX_CONSECUTIVE_CORRUPT: u32 = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// Damage local to one packet/frame; decoding can continue with the next one.
+    Recoverable,
+    /// The input can't be decoded any further, retrying won't help.
+    Fatal,
+}
+
+/// Only damaged input is worth skipping; internal FFmpeg errors (`Bug`, `Unknown`) would just
+/// repeat on the next packet. EAGAIN isn't damage at all and never gets here, see `decode_next`.
+pub fn classify(err: &ffmpeg::Error) -> ErrorClass {
+    use ffmpeg::Error;
+    match err {
+        Error::InvalidData | Error::PatchWelcome => ErrorClass::Recoverable,
+        Error::Other { errno } if *errno == ffmpeg::error::EINVAL => ErrorClass::Recoverable,
+        _ => ErrorClass::Fatal,
+    }
+}
+
+/// The decoder wants its output drained before it takes more input (or, from `receive_frame`,
+/// more input before it has output).
+fn is_again(err: &ffmpeg::Error) -> bool {
+    matches!(err, ffmpeg::Error::Other { errno } if *errno == ffmpeg::error::EAGAIN)
+}
+
+/// Pick what the scaler should produce for a surface that takes `accepted`: the source's own
+/// layout when possible, otherwise the cheapest YUV repack, and BGRA only as a last resort.
+pub fn choose_output_format(source: ffmpeg::format::Pixel, width: u32, height: u32, accepted: &[PixelFormat]) -> PixelFormat {
+    use ffmpeg::format::Pixel;
+    // 4:2:0 chroma needs even dimensions.
+    if !width.is_multiple_of(2) || !height.is_multiple_of(2) {
+        return PixelFormat::Bgra;
+    }
+    if let Some(native) = PixelFormat::from_ffmpeg(source) {
+        if accepted.contains(&native) {
+            return native;
+        }
+    }
+    let candidates: &[PixelFormat] = match source {
+        Pixel::YUV420P10LE | Pixel::P010LE => &[PixelFormat::P010],
+        Pixel::YUV420P | Pixel::NV12 => &[PixelFormat::Nv12, PixelFormat::Yuv420p],
+        _ => &[],
+    };
+    candidates
+        .iter()
+        .copied()
+        .find(|format| accepted.contains(format))
+        .unwrap_or(PixelFormat::Bgra)
+}
 
 pub struct VideoDecoder {
     ictx: ffmpeg::format::context::Input,
     video_stream_index: usize,
+    time_base: f64,
+    /// Nominal frames per second, 30 if the container doesn't say.
+    frame_rate: f64,
     decoder: ffmpeg::decoder::Video,
+    /// Reused for every packet so decoding doesn't allocate a frame each time.
+    decoded: ffmpeg::util::frame::Video,
     scaler: ffmpeg::software::scaling::Context,
     target_width: u32,
     target_height: u32,
+    output_format: PixelFormat,
+    /// `decoded` holds the frame a seek landed on, not yet handed out.
+    pending: bool,
+    /// A packet the decoder turned away until its frames were drained, to be sent again.
+    held: Option<ffmpeg::Packet>,
+    /// The input ran out and the decoder was told so; cleared by seeking.
+    draining: bool,
+    audio: Option<AudioTrack>,
+    position: f64,
+    is_network: bool,
+    corrupt_packets: u64,
+    consecutive_corrupt: u32,
+    /// How long the last `next_frame` spent decoding and scaling.
+    decode_time: Duration,
+    scale_time: Duration,
 }
 
 // Safety: FFmpeg contexts are moveable between threads.
@@ -17,16 +94,27 @@ unsafe impl Send for VideoDecoder {}
 unsafe impl Sync for VideoDecoder {}
 
 impl VideoDecoder {
-    pub fn new<P: AsRef<Path>>(path: P, target_width: u32, target_height: u32) -> Result<Self> {
+    /// `path` may be a local file or a URL (http, HLS, rtsp, ...); URLs are opened with `options`.
+    pub fn new<P: AsRef<Path>>(path: P, target_width: u32, target_height: u32, options: &StreamOptions) -> Result<Self> {
         ffmpeg::init().context("Failed to initialize FFmpeg")?;
 
-        let ictx = ffmpeg::format::input(&path).context("Failed to open input file")?;
+        let path_str = path.as_ref().to_string_lossy().to_string();
+        let is_network = stream::is_network_source(&path_str);
+        let ictx = if is_network {
+            ffmpeg::format::input_with_dictionary(&path_str, options.to_dictionary(&path_str))
+                .context("Failed to open network stream")?
+        } else {
+            ffmpeg::format::input(&path).context("Failed to open input file")?
+        };
         let input = ictx
             .streams()
             .best(ffmpeg::media::Type::Video)
             .ok_or_else(|| anyhow::anyhow!("No video stream found"))?;
         
         let video_stream_index = input.index();
+        let time_base = f64::from(input.time_base());
+        let frame_rate = f64::from(input.avg_frame_rate());
+        let frame_rate = if frame_rate.is_finite() && frame_rate > 0.0 { frame_rate } else { 30.0 };
         let context_decoder = ffmpeg::codec::context::Context::from_parameters(input.parameters())?;
         let decoder = context_decoder.decoder().video()?;
 
@@ -44,41 +132,281 @@ impl VideoDecoder {
         Ok(Self {
             ictx,
             video_stream_index,
+            time_base,
+            frame_rate,
             decoder,
+            decoded: ffmpeg::util::frame::Video::empty(),
             scaler,
             target_width,
             target_height,
+            output_format: PixelFormat::Bgra,
+            pending: false,
+            held: None,
+            draining: false,
+            audio: None,
+            position: 0.0,
+            is_network,
+            corrupt_packets: 0,
+            consecutive_corrupt: 0,
+            decode_time: Duration::ZERO,
+            scale_time: Duration::ZERO,
         })
     }
 
-    pub fn next_frame(&mut self, output_frame: &mut ffmpeg::util::frame::Video) -> Result<bool> {
-        let mut total_scanned = 0;
-        while let Some((stream, packet)) = self.ictx.packets().next() {
-            total_scanned += 1;
-            if total_scanned > 500 {
-                tracing::warn!("Decoder scanned 500 packets without finding a video frame.");
-                return Ok(false);
+    /// Hand frames over in a format the surface converts itself, instead of BGRA from the scaler.
+    pub fn set_accepted_formats(&mut self, accepted: &[PixelFormat]) -> Result<PixelFormat> {
+        let format = choose_output_format(self.decoder.format(), self.target_width, self.target_height, accepted);
+        if format != self.output_format {
+            self.scaler = ffmpeg::software::scaling::context::Context::get(
+                self.decoder.format(),
+                self.decoder.width(),
+                self.decoder.height(),
+                format.to_ffmpeg(),
+                self.target_width,
+                self.target_height,
+                ffmpeg::software::scaling::flag::Flags::BILINEAR,
+            )?;
+            self.output_format = format;
+            tracing::info!("Decoder output format: {:?} (source {:?})", format, self.decoder.format());
+        }
+        Ok(format)
+    }
+
+    pub fn output_format(&self) -> PixelFormat {
+        self.output_format
+    }
+
+    /// Opens the audio track (if any) and routes it to `sink`. Returns false when the file has no audio.
+    pub fn enable_audio(&mut self, sink: Box<dyn AudioSink>, volume: u32, muted: bool) -> Result<bool> {
+        self.audio = AudioTrack::open(&self.ictx, sink, volume, muted)?;
+        if self.audio.is_some() {
+            tracing::info!("Audio track enabled (volume {}%, muted: {})", volume, muted);
+        }
+        Ok(self.audio.is_some())
+    }
+
+    pub fn set_volume(&mut self, volume: u32, muted: bool) {
+        if let Some(audio) = self.audio.as_mut() {
+            audio.output_mut().set_volume(volume);
+            audio.output_mut().set_muted(muted);
+        }
+    }
+
+    pub fn set_audio_paused(&mut self, paused: bool) {
+        if let Some(audio) = self.audio.as_mut() {
+            audio.output_mut().set_paused(paused);
+        }
+    }
+
+    /// Time the last `next_frame` spent decoding, and converting/scaling to the output.
+    pub fn last_timings(&self) -> (Duration, Duration) {
+        (self.decode_time, self.scale_time)
+    }
+
+    /// Decoded frames waiting to be handed out. Decoding is pulled one frame at a time, so
+    /// this is only ever the frame a seek landed on.
+    pub fn queued_frames(&self) -> usize {
+        self.pending as usize
+    }
+
+    /// Recoverable damage is skipped and counted; an `Err` means the source can't be played any further.
+    pub fn next_frame(&mut self, output_frame: &mut Frame) -> Result<bool> {
+        // A seek leaves the frame it landed on waiting in `decoded`.
+        let started = Instant::now();
+        self.decode_time = Duration::ZERO;
+        self.scale_time = Duration::ZERO;
+        if !std::mem::take(&mut self.pending) && !self.decode_next()? {
+            return Ok(false);
+        }
+        self.decode_time = started.elapsed();
+
+        let started = Instant::now();
+        output_frame.prepare(self.output_format, self.target_width, self.target_height);
+        self.scaler
+            .run(&self.decoded, output_frame.as_ffmpeg_mut())
+            .map_err(|e| anyhow::anyhow!("Scaler failed: {}", e))?;
+        self.scale_time = started.elapsed();
+        output_frame.set_pts(Some(self.position));
+        output_frame.set_color(ColorInfo::from_ffmpeg(self.decoded.color_space(), self.decoded.color_range()));
+        if let Some(audio) = self.audio.as_mut() {
+            if let Err(e) = audio.output_mut().sync(self.position) {
+                tracing::warn!("Audio output error: {}", e);
             }
+        }
+        Ok(true)
+    }
 
-            if stream.index() == self.video_stream_index {
-                self.decoder.send_packet(&packet)?;
-                let mut frame = ffmpeg::util::frame::Video::empty();
-                if self.decoder.receive_frame(&mut frame).is_ok() {
-                    self.scaler.run(&frame, output_frame)?;
+    /// Decode the next video frame into `self.decoded` and update `position`, without scaling it.
+    fn decode_next(&mut self) -> Result<bool> {
+        let mut total_scanned = 0;
+        loop {
+            // Frames the decoder already holds go out before it's given more input
+            match self.decoder.receive_frame(&mut self.decoded) {
+                Ok(()) => {
+                    if self.decoded.is_corrupt() {
+                        self.skip_corrupt("decoded frame flagged corrupt")?;
+                        continue;
+                    }
+                    self.consecutive_corrupt = 0;
+                    if let Some(pts) = self.decoded.timestamp().or(self.decoded.pts()) {
+                        self.position = pts as f64 * self.time_base;
+                    }
                     return Ok(true);
                 }
+                Err(e) if is_again(&e) => {}
+                Err(ffmpeg::Error::Eof) => return Ok(false),
+                Err(e) => match classify(&e) {
+                    ErrorClass::Recoverable => {
+                        self.skip_corrupt(&e.to_string())?;
+                        continue;
+                    }
+                    ErrorClass::Fatal => return Err(anyhow::anyhow!("Decoder failed: {}", e)),
+                },
+            }
+
+            // Drained, so a packet turned away last time goes in again before anything new
+            let resend = self.held.is_some();
+            let packet = match self.held.take() {
+                Some(packet) => packet,
+                None => {
+                    let Some((stream, packet)) = self.ictx.packets().next() else {
+                        // Out of input: the decoder gives up the frames it's still holding, then Eof
+                        if std::mem::replace(&mut self.draining, true) {
+                            return Ok(false);
+                        }
+                        self.decoder.send_eof().map_err(|e| anyhow::anyhow!("Decoder failed to drain: {}", e))?;
+                        continue;
+                    };
+                    total_scanned += 1;
+                    if total_scanned > 500 {
+                        tracing::warn!("Decoder scanned 500 packets without finding a video frame.");
+                        return Ok(false);
+                    }
+                    if stream.index() != self.video_stream_index {
+                        if let Some(audio) = self.audio.as_mut() {
+                            if stream.index() == audio.stream_index() {
+                                if let Err(e) = audio.decode_packet(&packet) {
+                                    tracing::warn!("Audio decode error: {}", e);
+                                }
+                            }
+                        }
+                        continue;
+                    }
+                    if packet.is_corrupt() {
+                        self.skip_corrupt("packet flagged corrupt by demuxer")?;
+                        continue;
+                    }
+                    packet
+                }
+            };
+
+            if let Err(e) = self.decoder.send_packet(&packet) {
+                if is_again(&e) && !resend {
+                    self.held = Some(packet);
+                    continue;
+                }
+                // Refused even with nothing left to drain, it would never go in
+                let class = if is_again(&e) { ErrorClass::Recoverable } else { classify(&e) };
+                match class {
+                    ErrorClass::Recoverable => self.skip_corrupt(&e.to_string())?,
+                    ErrorClass::Fatal => return Err(anyhow::anyhow!("Decoder rejected packet: {}", e)),
+                }
+            }
+        }
+    }
+
+    /// Seek to `timestamp` seconds, landing on the exact frame rather than the keyframe before it:
+    /// the demuxer jumps to the keyframe, then frames are decoded (but not scaled) up to the target.
+    /// The frame at the target is what the next `next_frame` returns.
+    pub fn seek(&mut self, timestamp: f64) -> Result<()> {
+        if self.is_live() {
+            return Err(anyhow::anyhow!("Live streams can't be seeked"));
+        }
+        let target = match self.duration() {
+            Some(duration) => timestamp.clamp(0.0, duration),
+            None => timestamp.max(0.0),
+        };
+        let target_us = (target * 1_000_000.0) as i64;
+        self.ictx.seek(target_us, ..target_us)?;
+        self.decoder.flush();
+        self.pending = false;
+        self.held = None;
+        self.draining = false;
+
+        // Half a frame of slack so rounding in pts doesn't make us skip the frame we asked for.
+        let tolerance = 0.5 / self.frame_rate;
+        let mut skipped = 0u32;
+        while self.decode_next()? {
+            if self.position + tolerance >= target {
+                self.pending = true;
+                break;
             }
+            skipped += 1;
+        }
+        if !self.pending {
+            // Ran off the end (target at or past the last frame); the next read loops as usual.
+            self.position = target;
         }
+        if let Some(audio) = self.audio.as_mut() {
+            // Audio decoded on the way belongs before the target.
+            audio.flush();
+        }
+        tracing::debug!("Seeked to {:.3}s ({} frames decoded past the keyframe)", self.position, skipped);
+        Ok(())
+    }
 
-        Ok(false)
+    /// Presentation time of the last frame handed out, in seconds.
+    pub fn position(&self) -> f64 {
+        self.position
+    }
+
+    /// Count a damaged packet/frame and keep going, unless the stream is damaged beyond use.
+    fn skip_corrupt(&mut self, reason: &str) -> Result<()> {
+        self.corrupt_packets += 1;
+        self.consecutive_corrupt += 1;
+        tracing::debug!("Skipping corrupt video packet ({} total): {}", self.corrupt_packets, reason);
+        if self.consecutive_corrupt > MAX_CONSECUTIVE_CORRUPT {
+            return Err(anyhow::anyhow!(
+                "{} corrupt packets in a row (last: {})",
+                self.consecutive_corrupt, reason
+            ));
+        }
+        Ok(())
+    }
+
+    /// Packets skipped because they could not be decoded.
+    pub fn corrupt_packets(&self) -> u64 {
+        self.corrupt_packets
     }
 
     pub fn seek_to_start(&mut self) -> Result<()> {
         self.ictx.seek(0, ..0)?;
         self.decoder.flush();
+        self.pending = false;
+        self.held = None;
+        self.draining = false;
+        if let Some(audio) = self.audio.as_mut() {
+            audio.flush();
+        }
+        self.position = 0.0;
         Ok(())
     }
 
+    /// Container duration in seconds, if known.
+    pub fn duration(&self) -> Option<f64> {
+        let duration = self.ictx.duration();
+        (duration > 0).then(|| duration as f64 / 1_000_000.0)
+    }
+
+    pub fn is_network(&self) -> bool {
+        self.is_network
+    }
+
+    /// Live feeds (camera, live HLS) report no duration and can't be looped by seeking.
+    pub fn is_live(&self) -> bool {
+        self.is_network && self.ictx.duration() <= 0
+    }
+
     pub fn width(&self) -> u32 {
         self.target_width
     }
@@ -87,3 +415,78 @@ impl VideoDecoder {
         self.target_height
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wallpaper::fixtures::{self, HEIGHT, WIDTH};
+
+    /// MPEG-TS packets are 188 bytes, starting with a sync byte and a 4-byte header.
+    const TS_PACKET: usize = 188;
+
+    #[test]
+    fn damaged_data_is_recoverable() {
+        assert_eq!(classify(&ffmpeg::Error::InvalidData), ErrorClass::Recoverable);
+        assert_eq!(classify(&ffmpeg::Error::Other { errno: ffmpeg::error::EINVAL }), ErrorClass::Recoverable);
+    }
+
+    #[test]
+    fn internal_errors_are_fatal() {
+        for err in [ffmpeg::Error::Bug, ffmpeg::Error::Bug2, ffmpeg::Error::Unknown, ffmpeg::Error::Eof, ffmpeg::Error::Other { errno: ffmpeg::error::ENOMEM }] {
+            assert_eq!(classify(&err), ErrorClass::Fatal, "{:?}", err);
+        }
+    }
+
+    #[test]
+    fn eagain_is_backpressure_not_damage() {
+        assert!(is_again(&ffmpeg::Error::Other { errno: ffmpeg::error::EAGAIN }));
+        assert!(!is_again(&ffmpeg::Error::InvalidData));
+    }
+
+    fn open(path: &std::path::Path) -> VideoDecoder {
+        VideoDecoder::new(path, WIDTH, HEIGHT, &StreamOptions::default()).unwrap()
+    }
+
+    #[test]
+    fn intact_clip_decodes_every_frame() {
+        let clip = fixtures::clip("intact.ts", 0, 50);
+        let mut decoder = open(&clip);
+        assert_eq!(fixtures::read_frames(&mut decoder, 100).unwrap(), 50);
+        assert_eq!(decoder.corrupt_packets(), 0);
+    }
+
+    #[test]
+    fn truncated_clip_plays_what_is_there() {
+        let path = fixtures::clip("truncated.ts", 0, 50);
+        let bytes = std::fs::read(&path).unwrap();
+        // Cut mid-packet, as an interrupted download would
+        std::fs::write(&path, &bytes[..bytes.len() * 3 / 5 + TS_PACKET / 2]).unwrap();
+
+        let mut decoder = open(&path);
+        let frames = fixtures::read_frames(&mut decoder, 100).unwrap();
+        assert!(frames > 10 && frames < 50, "{} frames", frames);
+
+        // And it still loops
+        decoder.seek_to_start().unwrap();
+        assert!(fixtures::read_frames(&mut decoder, 5).unwrap() > 0);
+    }
+
+    #[test]
+    fn bit_flipped_clip_skips_the_damage() {
+        let path = fixtures::clip("flipped.ts", 0, 50);
+        let mut bytes = std::fs::read(&path).unwrap();
+        // Flip payload bits in every seventh TS packet past the first few, which carry the
+        // tables and sequence header the file is probed from
+        for packet in (8..bytes.len() / TS_PACKET).step_by(7) {
+            bytes[packet * TS_PACKET + 100] ^= 0x5a;
+        }
+        std::fs::write(&path, &bytes).unwrap();
+
+        let mut decoder = open(&path);
+        let frames = fixtures::read_frames(&mut decoder, 100).unwrap();
+        assert!(frames > 25 && frames <= 50, "{} frames", frames);
+
+        decoder.seek_to_start().unwrap();
+        assert!(fixtures::read_frames(&mut decoder, 5).unwrap() > 0);
+    }
+}
@@ -0,0 +1,120 @@
+//! Clips encoded on the fly for tests, so no sample media has to live in the repo.
+
+use crate::wallpaper::frame::{Frame, PixelFormat};
+use crate::wallpaper::VideoDecoder;
+use ffmpeg_next as ffmpeg;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+pub const WIDTH: u32 = 64;
+pub const HEIGHT: u32 = 48;
+pub const FPS: i32 = 25;
+
+/// A scratch file for one test; the pid keeps concurrent test runs apart.
+pub fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("mew-{}-{}", std::process::id(), name))
+}
+
+/// A scratch file at `temp_path(name)` that's removed when dropped, whether the test passed or not.
+pub struct TempFile(PathBuf);
+
+impl TempFile {
+    pub fn new(name: &str) -> Self {
+        Self(temp_path(name))
+    }
+}
+
+impl Deref for TempFile {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempFile {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Encode frames `first..first + frames` of a moving gradient as MPEG-2 in MPEG-TS, with a
+/// keyframe every 5 frames. Timestamps carry on from `first`, so consecutive ranges make
+/// consecutive HLS segments. The clip is removed when the returned file is dropped.
+pub fn clip(name: &str, first: i64, frames: i64) -> TempFile {
+    use ffmpeg::format::Pixel;
+    ffmpeg::init().unwrap();
+    let path = TempFile::new(name);
+    let codec = ffmpeg::encoder::find(ffmpeg::codec::Id::MPEG2VIDEO).expect("no MPEG-2 encoder in this FFmpeg build");
+    let mut octx = ffmpeg::format::output(&*path).unwrap();
+
+    let rate = ffmpeg::Rational::new(FPS, 1);
+    let time_base = rate.invert();
+    let mut encoder_ctx = ffmpeg::codec::context::Context::new_with_codec(codec).encoder().video().unwrap();
+    encoder_ctx.set_width(WIDTH);
+    encoder_ctx.set_height(HEIGHT);
+    encoder_ctx.set_format(Pixel::YUV420P);
+    encoder_ctx.set_time_base(time_base);
+    encoder_ctx.set_frame_rate(Some(rate));
+    encoder_ctx.set_gop(5);
+    encoder_ctx.set_bit_rate(400_000);
+    let mut encoder = encoder_ctx.open_as(codec).unwrap();
+    let stream = {
+        let mut ost = octx.add_stream(codec).unwrap();
+        ost.set_parameters(&encoder);
+        ost.index()
+    };
+    octx.write_header().unwrap();
+
+    let mut frame = ffmpeg::util::frame::Video::new(Pixel::YUV420P, WIDTH, HEIGHT);
+    for index in first..first + frames {
+        for plane in 0..3 {
+            let (width, height) = if plane == 0 { (WIDTH, HEIGHT) } else { (WIDTH / 2, HEIGHT / 2) };
+            let stride = frame.stride(plane);
+            let data = frame.data_mut(plane);
+            for y in 0..height as usize {
+                for x in 0..width as usize {
+                    data[y * stride + x] = if plane == 0 { (16 + (x + y + index as usize * 4) % 220) as u8 } else { 128 };
+                }
+            }
+        }
+        frame.set_pts(Some(index));
+        encoder.send_frame(&frame).unwrap();
+        write_packets(&mut encoder, &mut octx, stream, time_base);
+    }
+    encoder.send_eof().unwrap();
+    write_packets(&mut encoder, &mut octx, stream, time_base);
+    octx.write_trailer().unwrap();
+    path
+}
+
+fn write_packets(
+    encoder: &mut ffmpeg::encoder::video::Encoder,
+    octx: &mut ffmpeg::format::context::Output,
+    stream: usize,
+    time_base: ffmpeg::Rational,
+) {
+    let out_tb = octx.stream(stream).map(|s| s.time_base()).unwrap_or(time_base);
+    let mut packet = ffmpeg::Packet::empty();
+    while encoder.receive_packet(&mut packet).is_ok() {
+        packet.set_stream(stream);
+        packet.rescale_ts(time_base, out_tb);
+        packet.write_interleaved(octx).unwrap();
+    }
+}
+
+/// Read frames until the decoder runs out, stopping early after `limit`.
+pub fn read_frames(decoder: &mut VideoDecoder, limit: usize) -> anyhow::Result<usize> {
+    let mut frame = Frame::new(PixelFormat::Bgra, WIDTH, HEIGHT);
+    let mut count = 0;
+    while count < limit && decoder.next_frame(&mut frame)? {
+        count += 1;
+    }
+    Ok(count)
+}
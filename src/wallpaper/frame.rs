@@ -0,0 +1,223 @@
+use ffmpeg_next as ffmpeg;
+use std::sync::{Arc, Mutex};
+
+/// Frames kept around for reuse; anything beyond this is freed on return.
+const POOL_CAPACITY: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// Packed 8-bit B, G, R, A.
+    Bgra,
+    /// 8-bit 4:2:0, Y plane followed by an interleaved UV plane.
+    Nv12,
+    /// 8-bit 4:2:0, separate Y, U and V planes.
+    Yuv420p,
+    /// 10-bit 4:2:0 in 16-bit little-endian words (value in the high bits), Y + interleaved UV.
+    P010,
+}
+
+impl PixelFormat {
+    pub fn to_ffmpeg(self) -> ffmpeg::format::Pixel {
+        match self {
+            PixelFormat::Bgra => ffmpeg::format::Pixel::BGRA,
+            PixelFormat::Nv12 => ffmpeg::format::Pixel::NV12,
+            PixelFormat::Yuv420p => ffmpeg::format::Pixel::YUV420P,
+            PixelFormat::P010 => ffmpeg::format::Pixel::P010LE,
+        }
+    }
+
+    pub fn from_ffmpeg(format: ffmpeg::format::Pixel) -> Option<Self> {
+        match format {
+            ffmpeg::format::Pixel::BGRA => Some(PixelFormat::Bgra),
+            ffmpeg::format::Pixel::NV12 => Some(PixelFormat::Nv12),
+            ffmpeg::format::Pixel::YUV420P => Some(PixelFormat::Yuv420p),
+            ffmpeg::format::Pixel::P010LE => Some(PixelFormat::P010),
+            _ => None,
+        }
+    }
+
+    pub fn is_yuv(self) -> bool {
+        self != PixelFormat::Bgra
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorSpace {
+    Bt601,
+    #[default]
+    Bt709,
+    Bt2020,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorRange {
+    /// 16-235 luma (broadcast / most video).
+    #[default]
+    Limited,
+    /// 0-255.
+    Full,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ColorInfo {
+    pub space: ColorSpace,
+    pub range: ColorRange,
+}
+
+impl ColorInfo {
+    pub fn from_ffmpeg(space: ffmpeg::color::Space, range: ffmpeg::color::Range) -> Self {
+        let space = match space {
+            ffmpeg::color::Space::BT470BG | ffmpeg::color::Space::SMPTE170M | ffmpeg::color::Space::FCC => ColorSpace::Bt601,
+            ffmpeg::color::Space::BT2020NCL | ffmpeg::color::Space::BT2020CL => ColorSpace::Bt2020,
+            _ => ColorSpace::Bt709,
+        };
+        let range = match range {
+            ffmpeg::color::Range::JPEG => ColorRange::Full,
+            _ => ColorRange::Limited,
+        };
+        Self { space, range }
+    }
+}
+
+/// A decoded picture on its way to the surface: planes with their real strides
+/// (FFmpeg pads lines), pixel format, presentation time and colour description.
+/// Dropping a pooled frame hands its buffers back to the pool.
+pub struct Frame {
+    video: Option<ffmpeg::util::frame::Video>,
+    format: PixelFormat,
+    pts: Option<f64>,
+    color: ColorInfo,
+    pool: Option<FramePool>,
+}
+
+impl Frame {
+    /// Standalone frame with its own buffers, not tied to a pool.
+    pub fn new(format: PixelFormat, width: u32, height: u32) -> Self {
+        Self {
+            video: Some(ffmpeg::util::frame::Video::new(format.to_ffmpeg(), width, height)),
+            format,
+            pts: None,
+            color: ColorInfo::default(),
+            pool: None,
+        }
+    }
+
+    fn video(&self) -> &ffmpeg::util::frame::Video {
+        self.video.as_ref().expect("frame buffer already returned to pool")
+    }
+
+    /// Raw FFmpeg frame, for the decoder/scaler to write into.
+    pub fn as_ffmpeg_mut(&mut self) -> &mut ffmpeg::util::frame::Video {
+        self.video.as_mut().expect("frame buffer already returned to pool")
+    }
+
+    pub fn format(&self) -> PixelFormat {
+        self.format
+    }
+
+    /// Make sure the buffers match `format` and size, reallocating if a recycled frame was
+    /// last used for something else.
+    pub fn prepare(&mut self, format: PixelFormat, width: u32, height: u32) {
+        let video = self.as_ffmpeg_mut();
+        let matches = video.format() == format.to_ffmpeg() && video.width() == width && video.height() == height;
+        if !matches {
+            *video = ffmpeg::util::frame::Video::new(format.to_ffmpeg(), width, height);
+        }
+        self.format = format;
+    }
+
+    pub fn width(&self) -> u32 {
+        self.video().width()
+    }
+
+    pub fn height(&self) -> u32 {
+        self.video().height()
+    }
+
+    pub fn planes(&self) -> usize {
+        self.video().planes()
+    }
+
+    /// Bytes per row of `plane`, including padding.
+    pub fn stride(&self, plane: usize) -> usize {
+        self.video().stride(plane)
+    }
+
+    pub fn plane(&self, plane: usize) -> &[u8] {
+        self.video().data(plane)
+    }
+
+    pub fn plane_mut(&mut self, plane: usize) -> &mut [u8] {
+        self.as_ffmpeg_mut().data_mut(plane)
+    }
+
+    #[allow(dead_code)]
+    pub fn plane_ptr(&self, plane: usize) -> *const u8 {
+        self.plane(plane).as_ptr()
+    }
+
+    /// Presentation time in seconds.
+    #[allow(dead_code)]
+    pub fn pts(&self) -> Option<f64> {
+        self.pts
+    }
+
+    pub fn set_pts(&mut self, pts: Option<f64>) {
+        self.pts = pts;
+    }
+
+    pub fn color(&self) -> ColorInfo {
+        self.color
+    }
+
+    pub fn set_color(&mut self, color: ColorInfo) {
+        self.color = color;
+    }
+
+    /// Bytes held by all planes, padding included.
+    pub fn byte_size(&self) -> usize {
+        (0..self.planes()).map(|plane| self.plane(plane).len()).sum()
+    }
+}
+
+impl Drop for Frame {
+    fn drop(&mut self) {
+        if let (Some(pool), Some(video)) = (self.pool.take(), self.video.take()) {
+            pool.recycle(video);
+        }
+    }
+}
+
+/// Recycles frame buffers between the decoder and the surface so steady-state
+/// playback doesn't allocate.
+#[derive(Clone)]
+pub struct FramePool {
+    free: Arc<Mutex<Vec<ffmpeg::util::frame::Video>>>,
+}
+
+impl FramePool {
+    pub fn new() -> Self {
+        Self {
+            free: Arc::new(Mutex::new(Vec::with_capacity(POOL_CAPACITY))),
+        }
+    }
+
+    /// A recycled frame if one is free, otherwise an empty one. The decoder sizes it with `prepare`.
+    pub fn acquire(&self) -> Frame {
+        let video = self.free.lock().unwrap().pop().unwrap_or_else(ffmpeg::util::frame::Video::empty);
+        Frame {
+            video: Some(video),
+            format: PixelFormat::Bgra,
+            pts: None,
+            color: ColorInfo::default(),
+            pool: Some(self.clone()),
+        }
+    }
+
+    fn recycle(&self, video: ffmpeg::util::frame::Video) {
+        let mut free = self.free.lock().unwrap();
+        if free.len() < POOL_CAPACITY {
+            free.push(video);
+        }
+    }
+}
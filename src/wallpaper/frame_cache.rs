@@ -0,0 +1,313 @@
+use crate::wallpaper::frame::{Frame, PixelFormat};
+use crate::wallpaper::VideoDecoder;
+use anyhow::Result;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use std::io::{Read, Write};
+
+#[derive(Debug, Clone)]
+pub struct FrameCacheOptions {
+    pub enabled: bool,
+    /// Only clips at most this long are cached.
+    pub max_clip_secs: f64,
+    /// Upper bound on cached bytes; larger clips keep streaming from the decoder.
+    pub memory_budget: usize,
+    /// Deflate each frame. Fits more in the budget at the cost of some CPU per frame.
+    pub compress: bool,
+}
+
+impl Default for FrameCacheOptions {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_clip_secs: 5.0,
+            memory_budget: 512 * 1024 * 1024,
+            compress: false,
+        }
+    }
+}
+
+/// What the cache decodes a clip from: a `VideoDecoder`, or a synthetic clip in tests.
+pub trait ClipSource {
+    fn duration(&self) -> Option<f64>;
+    fn output_format(&self) -> PixelFormat;
+    fn width(&self) -> u32;
+    fn height(&self) -> u32;
+    fn next_frame(&mut self, output: &mut Frame) -> Result<bool>;
+    fn seek_to_start(&mut self) -> Result<()>;
+}
+
+impl ClipSource for VideoDecoder {
+    fn duration(&self) -> Option<f64> {
+        VideoDecoder::duration(self)
+    }
+
+    fn output_format(&self) -> PixelFormat {
+        VideoDecoder::output_format(self)
+    }
+
+    fn width(&self) -> u32 {
+        VideoDecoder::width(self)
+    }
+
+    fn height(&self) -> u32 {
+        VideoDecoder::height(self)
+    }
+
+    fn next_frame(&mut self, output: &mut Frame) -> Result<bool> {
+        VideoDecoder::next_frame(self, output)
+    }
+
+    fn seek_to_start(&mut self) -> Result<()> {
+        VideoDecoder::seek_to_start(self)
+    }
+}
+
+enum CachedFrame {
+    Raw(Frame),
+    /// Every plane back to back, line padding included, deflated.
+    Deflated(Vec<u8>),
+}
+
+/// A short loop decoded and scaled once, then replayed from RAM.
+pub struct FrameCache {
+    frames: Vec<CachedFrame>,
+    duration: f64,
+    bytes: usize,
+    cursor: usize,
+    /// Frame the position refers to: the last one shown, or the one a seek landed on.
+    current: usize,
+    scratch: Option<Frame>,
+}
+
+impl FrameCache {
+    /// Decode the whole clip into memory. Returns `Ok(None)` (with the decoder rewound) when the
+    /// clip is too long or doesn't fit in the budget, so the caller keeps streaming.
+    pub fn build(decoder: &mut impl ClipSource, options: &FrameCacheOptions) -> Result<Option<Self>> {
+        let duration = match decoder.duration() {
+            Some(d) if d <= options.max_clip_secs => d,
+            _ => return Ok(None),
+        };
+
+        let (format, width, height) = (decoder.output_format(), decoder.width(), decoder.height());
+        let mut cache = Self {
+            frames: Vec::new(),
+            duration,
+            bytes: 0,
+            cursor: 0,
+            current: 0,
+            scratch: None,
+        };
+
+        let mut output = Frame::new(format, width, height);
+        while decoder.next_frame(&mut output)? {
+            let frame = if options.compress {
+                let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+                for plane in 0..output.planes() {
+                    encoder.write_all(output.plane(plane))?;
+                }
+                let data = encoder.finish()?;
+                cache.bytes += data.len();
+                CachedFrame::Deflated(data)
+            } else {
+                let frame = std::mem::replace(&mut output, Frame::new(format, width, height));
+                cache.bytes += frame.byte_size();
+                CachedFrame::Raw(frame)
+            };
+            cache.frames.push(frame);
+
+            if cache.bytes > options.memory_budget {
+                tracing::info!(
+                    "Clip exceeds frame cache budget ({} MB after {} frames), streaming instead",
+                    options.memory_budget / (1024 * 1024),
+                    cache.frames.len()
+                );
+                decoder.seek_to_start()?;
+                return Ok(None);
+            }
+        }
+        decoder.seek_to_start()?;
+
+        if cache.frames.is_empty() {
+            return Ok(None);
+        }
+        if options.compress {
+            // Same format and size allocate the same strides, so planes inflate straight back in.
+            cache.scratch = Some(output);
+        }
+        tracing::info!(
+            "Cached {:.1}s {:?} loop in memory: {} frames, {} MB{}",
+            duration,
+            format,
+            cache.frames.len(),
+            cache.bytes / (1024 * 1024),
+            if options.compress { " (compressed)" } else { "" }
+        );
+        Ok(Some(cache))
+    }
+
+    /// The next frame of the loop, wrapping at the end.
+    pub fn next_frame(&mut self) -> Result<&Frame> {
+        let index = self.cursor;
+        self.current = index;
+        self.cursor = (self.cursor + 1) % self.frames.len();
+        match &self.frames[index] {
+            CachedFrame::Raw(frame) => Ok(frame),
+            CachedFrame::Deflated(data) => {
+                let scratch = self.scratch.as_mut().ok_or_else(|| anyhow::anyhow!("Frame cache has no scratch frame"))?;
+                let mut inflater = DeflateDecoder::new(&data[..]);
+                for plane in 0..scratch.planes() {
+                    inflater.read_exact(scratch.plane_mut(plane))?;
+                }
+                Ok(scratch)
+            }
+        }
+    }
+
+    /// Continue the loop from the frame at `timestamp` seconds.
+    pub fn seek(&mut self, timestamp: f64) {
+        let fraction = (timestamp / self.duration).clamp(0.0, 1.0);
+        self.cursor = ((fraction * self.frames.len() as f64) as usize).min(self.frames.len() - 1);
+        self.current = self.cursor;
+    }
+
+    /// The frame `next_frame` last returned, for snapshotting what's on screen.
+    pub fn current_frame(&self) -> Option<&Frame> {
+        match self.frames.get(self.current)? {
+            CachedFrame::Raw(frame) => Some(frame),
+            CachedFrame::Deflated(_) => self.scratch.as_ref(),
+        }
+    }
+
+    /// Loop time of the current frame, in seconds.
+    pub fn position(&self) -> f64 {
+        self.current as f64 / self.frames.len() as f64 * self.duration
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTH: u32 = 64;
+    const HEIGHT: u32 = 48;
+
+    /// `frames` BGRA frames over `duration`, either flat grey or noise that won't deflate.
+    struct SyntheticClip {
+        frames: usize,
+        duration: Option<f64>,
+        noisy: bool,
+        emitted: usize,
+        rewound: bool,
+    }
+
+    impl SyntheticClip {
+        fn new(frames: usize, duration: Option<f64>, noisy: bool) -> Self {
+            Self { frames, duration, noisy, emitted: 0, rewound: false }
+        }
+    }
+
+    impl ClipSource for SyntheticClip {
+        fn duration(&self) -> Option<f64> {
+            self.duration
+        }
+
+        fn output_format(&self) -> PixelFormat {
+            PixelFormat::Bgra
+        }
+
+        fn width(&self) -> u32 {
+            WIDTH
+        }
+
+        fn height(&self) -> u32 {
+            HEIGHT
+        }
+
+        fn next_frame(&mut self, output: &mut Frame) -> Result<bool> {
+            if self.emitted == self.frames {
+                return Ok(false);
+            }
+            let mut seed = (self.emitted as u32).wrapping_mul(2_654_435_761) | 1;
+            for byte in output.plane_mut(0) {
+                *byte = if self.noisy {
+                    seed ^= seed << 13;
+                    seed ^= seed >> 17;
+                    seed ^= seed << 5;
+                    seed as u8
+                } else {
+                    self.emitted as u8
+                };
+            }
+            self.emitted += 1;
+            Ok(true)
+        }
+
+        fn seek_to_start(&mut self) -> Result<()> {
+            self.emitted = 0;
+            self.rewound = true;
+            Ok(())
+        }
+    }
+
+    fn frame_bytes() -> usize {
+        Frame::new(PixelFormat::Bgra, WIDTH, HEIGHT).byte_size()
+    }
+
+    fn options(budget: usize, compress: bool) -> FrameCacheOptions {
+        FrameCacheOptions { memory_budget: budget, compress, ..Default::default() }
+    }
+
+    #[test]
+    fn a_clip_within_budget_is_kept_and_rewound() {
+        let mut clip = SyntheticClip::new(50, Some(2.0), false);
+        let mut cache = FrameCache::build(&mut clip, &options(50 * frame_bytes(), false)).unwrap().unwrap();
+        assert_eq!(cache.frames.len(), 50);
+        assert_eq!(cache.bytes, 50 * frame_bytes());
+        assert!(clip.rewound && clip.emitted == 0);
+
+        cache.seek(1.0);
+        assert_eq!(cache.next_frame().unwrap().plane(0)[0], 25);
+        assert!((cache.position() - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn a_clip_over_budget_is_refused_and_rewound() {
+        let mut clip = SyntheticClip::new(50, Some(2.0), false);
+        assert!(FrameCache::build(&mut clip, &options(50 * frame_bytes() - 1, false)).unwrap().is_none());
+        assert!(clip.rewound && clip.emitted == 0);
+    }
+
+    #[test]
+    fn long_or_endless_clips_are_never_decoded() {
+        for duration in [Some(5.5), None] {
+            let mut clip = SyntheticClip::new(50, duration, false);
+            assert!(FrameCache::build(&mut clip, &FrameCacheOptions::default()).unwrap().is_none());
+            assert_eq!(clip.emitted, 0, "{:?}", duration);
+        }
+        let mut clip = SyntheticClip::new(50, Some(5.0), false);
+        assert!(FrameCache::build(&mut clip, &FrameCacheOptions::default()).unwrap().is_some());
+    }
+
+    #[test]
+    fn an_empty_clip_is_refused() {
+        let mut clip = SyntheticClip::new(0, Some(1.0), false);
+        assert!(FrameCache::build(&mut clip, &FrameCacheOptions::default()).unwrap().is_none());
+    }
+
+    #[test]
+    fn compression_counts_deflated_bytes() {
+        // Flat frames deflate to almost nothing, so a budget too small for them raw holds them compressed
+        let budget = 10 * frame_bytes();
+        assert!(FrameCache::build(&mut SyntheticClip::new(50, Some(2.0), false), &options(budget, false)).unwrap().is_none());
+        let mut cache = FrameCache::build(&mut SyntheticClip::new(50, Some(2.0), false), &options(budget, true)).unwrap().unwrap();
+        assert!(cache.bytes < 50 * frame_bytes() / 20, "{} bytes", cache.bytes);
+        for index in 0..50 {
+            assert!(cache.next_frame().unwrap().plane(0).iter().all(|&b| b == index as u8));
+        }
+
+        // Noise doesn't shrink, so it's refused either way
+        assert!(FrameCache::build(&mut SyntheticClip::new(50, Some(2.0), true), &options(budget, true)).unwrap().is_none());
+    }
+}
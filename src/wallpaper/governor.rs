@@ -0,0 +1,301 @@
+use crate::config::settings::QualitySettings;
+use std::time::{Duration, Instant};
+use sysinfo::System;
+
+/// Decode (plus scaling) above this share of the frame budget means we're about to run late.
+const OVER_BUDGET: f64 = 0.8;
+/// A step up is only taken if the next level is predicted to stay under this share.
+const UNDER_BUDGET: f64 = 0.5;
+/// Whole-system CPU above this steps down, and it must be below `CPU_CALM` to step up.
+const CPU_BUSY: f32 = 0.9;
+const CPU_CALM: f32 = 0.6;
+/// How long a trend has to last before acting on it. Stepping down is quick, stepping up
+/// slow, so the quality doesn't bounce between two levels.
+const STEP_DOWN_AFTER: Duration = Duration::from_secs(4);
+const STEP_UP_AFTER: Duration = Duration::from_secs(30);
+/// Measurements straight after a change still cover the old level; ignore them for a while.
+const SETTLE: Duration = Duration::from_secs(10);
+
+/// Frame rate and internal resolution the governor has settled on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quality {
+    pub fps: u32,
+    /// Fraction of the screen size frames are decoded at.
+    pub scale: f32,
+}
+
+/// How far the governor may go below the user's settings.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QualityBounds {
+    pub adaptive: bool,
+    pub min_fps: u32,
+    pub min_scale: f32,
+}
+
+impl Default for QualityBounds {
+    fn default() -> Self {
+        Self {
+            adaptive: true,
+            min_fps: 10,
+            min_scale: 0.5,
+        }
+    }
+}
+
+impl QualityBounds {
+    pub fn from_settings(settings: &QualitySettings) -> Self {
+        Self {
+            adaptive: settings.adaptive,
+            min_fps: settings.min_fps.max(1),
+            min_scale: settings.min_scale.clamp(0.1, 1.0),
+        }
+    }
+}
+
+/// One measurement of how hard the pipeline is working.
+#[derive(Debug, Clone, Copy)]
+pub struct Load {
+    /// Typical (p95) decode plus scale time per frame.
+    pub work: Duration,
+    /// Whole-system CPU use, 0 to 1, if known.
+    pub cpu: Option<f32>,
+}
+
+/// Steps the frame rate, then the internal resolution, down while decoding can't keep up,
+/// and back up once there's headroom again. Driven purely by the loads and times it's
+/// given, so a recorded or made-up trace plays back the same way every time.
+pub struct Governor {
+    ladder: Vec<Quality>,
+    level: usize,
+    over_since: Option<Instant>,
+    under_since: Option<Instant>,
+    settle_until: Option<Instant>,
+}
+
+impl Governor {
+    /// Start at full quality, `max_fps` at the screen's size.
+    pub fn new(max_fps: u32, bounds: &QualityBounds) -> Self {
+        Self {
+            ladder: ladder(max_fps.max(1), bounds),
+            level: 0,
+            over_since: None,
+            under_since: None,
+            settle_until: None,
+        }
+    }
+
+    pub fn quality(&self) -> Quality {
+        self.ladder[self.level]
+    }
+
+    /// The frame rate this governor was built for.
+    pub fn max_fps(&self) -> u32 {
+        self.ladder[0].fps
+    }
+
+    /// Forget any trend, e.g. while paused, so it doesn't carry across the gap.
+    pub fn interrupt(&mut self) {
+        self.over_since = None;
+        self.under_since = None;
+    }
+
+    /// Take a measurement made at `now`. Returns the new quality if it changed.
+    pub fn observe(&mut self, now: Instant, load: &Load) -> Option<Quality> {
+        if self.settle_until.is_some_and(|until| now < until) {
+            return None;
+        }
+        let current = self.quality();
+        let budget = 1.0 / current.fps as f64;
+        let share = load.work.as_secs_f64() / budget;
+        let cpu = load.cpu.unwrap_or(0.0);
+
+        let over = share > OVER_BUDGET || cpu > CPU_BUSY;
+        let under = self.level.checked_sub(1).map(|up| self.ladder[up]).is_some_and(|next| {
+            // Decode cost follows the pixel count
+            let ratio = (next.scale / current.scale) as f64;
+            let predicted = load.work.as_secs_f64() * ratio * ratio;
+            predicted * next.fps as f64 <= UNDER_BUDGET && cpu < CPU_CALM
+        });
+
+        if over {
+            self.under_since = None;
+            let since = *self.over_since.get_or_insert(now);
+            if now.duration_since(since) >= STEP_DOWN_AFTER && self.level + 1 < self.ladder.len() {
+                return Some(self.step(now, self.level + 1, share, load.cpu));
+            }
+        } else if under {
+            self.over_since = None;
+            let since = *self.under_since.get_or_insert(now);
+            if now.duration_since(since) >= STEP_UP_AFTER {
+                return Some(self.step(now, self.level - 1, share, load.cpu));
+            }
+        } else {
+            self.interrupt();
+        }
+        None
+    }
+
+    fn step(&mut self, now: Instant, level: usize, share: f64, cpu: Option<f32>) -> Quality {
+        let (from, to) = (self.quality(), self.ladder[level]);
+        tracing::info!(
+            "Quality governor: {} fps at {:.0}% -> {} fps at {:.0}% (decode at {:.0}% of the frame budget, CPU {})",
+            from.fps,
+            from.scale * 100.0,
+            to.fps,
+            to.scale * 100.0,
+            share * 100.0,
+            cpu.map_or("unknown".to_string(), |cpu| format!("{:.0}%", cpu * 100.0))
+        );
+        self.level = level;
+        self.settle_until = Some(now + SETTLE);
+        self.interrupt();
+        to
+    }
+}
+
+/// Quality levels, best first: the frame rate in steps of a third down to `min_fps`, then
+/// the resolution in quarters down to `min_scale`. A single level when not adaptive.
+fn ladder(max_fps: u32, bounds: &QualityBounds) -> Vec<Quality> {
+    let mut levels = vec![Quality { fps: max_fps, scale: 1.0 }];
+    if !bounds.adaptive {
+        return levels;
+    }
+    let min_fps = bounds.min_fps.min(max_fps);
+    let mut fps = max_fps;
+    while fps > min_fps {
+        fps = (fps * 2 / 3).max(min_fps);
+        levels.push(Quality { fps, scale: 1.0 });
+    }
+    let mut scale = 1.0f32;
+    while scale - bounds.min_scale > 0.01 {
+        scale = (scale - 0.25).max(bounds.min_scale);
+        levels.push(Quality { fps, scale });
+    }
+    levels
+}
+
+/// Whole-system CPU use, sampled between calls.
+pub struct CpuLoad {
+    system: System,
+}
+
+impl CpuLoad {
+    pub fn new() -> Self {
+        let mut system = System::new();
+        system.refresh_cpu_usage();
+        Self { system }
+    }
+
+    /// Average use since the previous call, 0 to 1.
+    pub fn sample(&mut self) -> f32 {
+        self.system.refresh_cpu_usage();
+        self.system.global_cpu_info().cpu_usage() / 100.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(work_ms: f64) -> Load {
+        Load { work: Duration::from_secs_f64(work_ms / 1000.0), cpu: None }
+    }
+
+    /// Feed `load` once a second from `from` up to `to` seconds, returning when the quality
+    /// changed and to what.
+    fn trace(governor: &mut Governor, start: Instant, from: u64, to: u64, load: Load) -> Vec<(u64, Quality)> {
+        (from..to)
+            .filter_map(|second| governor.observe(start + Duration::from_secs(second), &load).map(|q| (second, q)))
+            .collect()
+    }
+
+    fn at(fps: u32, scale: f32) -> Quality {
+        Quality { fps, scale }
+    }
+
+    #[test]
+    fn ladder_steps_rate_then_resolution_within_bounds() {
+        let levels = ladder(60, &QualityBounds::default());
+        let expected = [at(60, 1.0), at(40, 1.0), at(26, 1.0), at(17, 1.0), at(11, 1.0), at(10, 1.0), at(10, 0.75), at(10, 0.5)];
+        assert_eq!(levels, expected);
+
+        let bounds = QualityBounds { adaptive: true, min_fps: 20, min_scale: 0.6 };
+        let levels = ladder(30, &bounds);
+        assert_eq!(levels, [at(30, 1.0), at(20, 1.0), at(20, 0.75), at(20, 0.6)]);
+        // A minimum above the setting leaves the rate alone
+        assert_eq!(ladder(15, &bounds)[1], at(15, 0.75));
+    }
+
+    #[test]
+    fn steps_down_after_four_seconds_over_budget() {
+        let start = Instant::now();
+        let mut governor = Governor::new(60, &QualityBounds::default());
+        // 15 ms of a 16.7 ms budget
+        assert_eq!(trace(&mut governor, start, 0, 5, load(15.0)), [(4, at(40, 1.0))]);
+    }
+
+    #[test]
+    fn a_short_spike_is_ignored() {
+        let start = Instant::now();
+        let mut governor = Governor::new(60, &QualityBounds::default());
+        assert!(trace(&mut governor, start, 0, 3, load(15.0)).is_empty());
+        // Back within budget resets the trend, so the next spike starts counting afresh
+        assert!(trace(&mut governor, start, 3, 4, load(10.0)).is_empty());
+        assert_eq!(trace(&mut governor, start, 4, 10, load(15.0)), [(8, at(40, 1.0))]);
+    }
+
+    #[test]
+    fn busy_cpu_steps_down_on_its_own() {
+        let start = Instant::now();
+        let mut governor = Governor::new(30, &QualityBounds::default());
+        let busy = Load { work: Duration::from_millis(1), cpu: Some(0.95) };
+        assert_eq!(trace(&mut governor, start, 0, 5, busy), [(4, at(20, 1.0))]);
+    }
+
+    #[test]
+    fn waits_out_the_settle_time_after_a_change() {
+        let start = Instant::now();
+        let mut governor = Governor::new(60, &QualityBounds::default());
+        // Still over at 40 fps: the stale window runs to 14 s, then 4 more seconds over
+        assert_eq!(trace(&mut governor, start, 0, 19, load(22.0)), [(4, at(40, 1.0)), (18, at(26, 1.0))]);
+    }
+
+    #[test]
+    fn steps_up_only_after_thirty_seconds_of_headroom() {
+        let start = Instant::now();
+        let mut governor = Governor::new(60, &QualityBounds::default());
+        trace(&mut governor, start, 0, 5, load(15.0));
+        assert_eq!(governor.quality(), at(40, 1.0));
+        // Settled at 14 s, then 30 s of headroom
+        assert!(trace(&mut governor, start, 5, 44, load(2.0)).is_empty());
+        assert_eq!(trace(&mut governor, start, 44, 45, load(2.0)), [(44, at(60, 1.0))]);
+    }
+
+    #[test]
+    fn no_step_up_when_the_next_level_would_be_tight() {
+        let start = Instant::now();
+        let mut governor = Governor::new(60, &QualityBounds::default());
+        trace(&mut governor, start, 0, 5, load(15.0));
+        // 10 ms is fine at 40 fps but would take 60% of the budget at 60
+        assert!(trace(&mut governor, start, 5, 120, load(10.0)).is_empty());
+        assert_eq!(governor.quality(), at(40, 1.0));
+    }
+
+    #[test]
+    fn never_goes_below_the_bounds() {
+        let start = Instant::now();
+        let bounds = QualityBounds::default();
+        let mut governor = Governor::new(60, &bounds);
+        let changes = trace(&mut governor, start, 0, 600, load(500.0));
+        assert_eq!(changes.len(), 7);
+        assert_eq!(governor.quality(), at(bounds.min_fps, bounds.min_scale));
+    }
+
+    #[test]
+    fn fixed_quality_when_not_adaptive() {
+        let start = Instant::now();
+        let mut governor = Governor::new(60, &QualityBounds { adaptive: false, ..Default::default() });
+        assert!(trace(&mut governor, start, 0, 600, load(500.0)).is_empty());
+        assert_eq!(governor.quality(), at(60, 1.0));
+    }
+}
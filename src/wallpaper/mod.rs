@@ -1,6 +1,24 @@
 pub mod decoder;
 pub mod renderer;
 pub mod player;
+pub mod command;
+pub mod pause;
+pub mod playlist;
+pub mod audio;
+pub mod stream;
+pub mod proxy;
+pub mod frame_cache;
+pub mod stats;
+pub mod governor;
+pub mod watchdog;
+pub mod frame;
+pub mod convert;
+pub mod day_cycle;
+pub mod transition;
+pub mod surface;
+pub mod video_processor;
+#[cfg(test)]
+pub mod fixtures;
 
 pub use decoder::VideoDecoder;
 pub use renderer::WallpaperRenderer;
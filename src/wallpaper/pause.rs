@@ -0,0 +1,112 @@
+use std::collections::BTreeSet;
+use std::fmt;
+
+/// Why playback is held. Each source sets and clears its own reason, so e.g. unplugging
+/// the charger can't resume a wallpaper the user paused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PauseReason {
+    User,
+    /// A power policy tier keyed on the battery wants a still or hidden wallpaper.
+    Battery,
+    Fullscreen,
+    /// No keyboard or mouse input for the configured idle time.
+    Idle,
+    /// The lock screen covers the desktop.
+    Locked,
+    Schedule,
+    AppRule,
+    /// Any other power policy tier wants a still or hidden wallpaper.
+    Power,
+}
+
+impl PauseReason {
+    pub fn label(self) -> &'static str {
+        match self {
+            PauseReason::User => "paused by you",
+            PauseReason::Battery => "on battery",
+            PauseReason::Fullscreen => "fullscreen app",
+            PauseReason::Idle => "idle",
+            PauseReason::Locked => "session locked",
+            PauseReason::Schedule => "schedule",
+            PauseReason::AppRule => "app rule",
+            PauseReason::Power => "power saving",
+        }
+    }
+}
+
+/// The active pause reasons. Playback runs only while this is empty.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PauseReasons(BTreeSet<PauseReason>);
+
+impl PauseReasons {
+    /// Returns true if the set changed.
+    pub fn insert(&mut self, reason: PauseReason) -> bool {
+        self.0.insert(reason)
+    }
+
+    /// Returns true if the set changed.
+    pub fn remove(&mut self, reason: PauseReason) -> bool {
+        self.0.remove(&reason)
+    }
+
+    pub fn contains(&self, reason: PauseReason) -> bool {
+        self.0.contains(&reason)
+    }
+
+    pub fn is_paused(&self) -> bool {
+        !self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = PauseReason> + '_ {
+        self.0.iter().copied()
+    }
+}
+
+impl fmt::Display for PauseReasons {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let labels: Vec<&str> = self.iter().map(PauseReason::label).collect();
+        write!(f, "{}", labels.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reasons_are_set_and_cleared_independently() {
+        let mut reasons = PauseReasons::default();
+        assert!(reasons.insert(PauseReason::User));
+        assert!(reasons.insert(PauseReason::Battery));
+        assert!(!reasons.insert(PauseReason::Battery));
+
+        assert!(reasons.remove(PauseReason::Battery));
+        assert!(!reasons.remove(PauseReason::Battery));
+        assert!(!reasons.remove(PauseReason::Locked));
+        assert!(reasons.contains(PauseReason::User));
+        assert!(!reasons.contains(PauseReason::Battery));
+    }
+
+    #[test]
+    fn resumes_only_once_every_reason_is_cleared() {
+        let mut reasons = PauseReasons::default();
+        assert!(!reasons.is_paused());
+        reasons.insert(PauseReason::Idle);
+        reasons.insert(PauseReason::Locked);
+
+        reasons.remove(PauseReason::Idle);
+        assert!(reasons.is_paused());
+        reasons.remove(PauseReason::Locked);
+        assert!(!reasons.is_paused());
+    }
+
+    #[test]
+    fn lists_labels_in_a_stable_order() {
+        let mut reasons = PauseReasons::default();
+        assert_eq!(reasons.to_string(), "");
+        reasons.insert(PauseReason::Locked);
+        reasons.insert(PauseReason::User);
+        reasons.insert(PauseReason::Idle);
+        assert_eq!(reasons.to_string(), "paused by you, idle, session locked");
+    }
+}
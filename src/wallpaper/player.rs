@@ -1,143 +1,1457 @@
-use crate::wallpaper::{VideoDecoder, WallpaperRenderer};
+use crate::clock::Clock;
+use crate::config::PlaybackPositions;
+use crate::performance::policy::PowerMode;
+use crate::wallpaper::audio::{NullSink, WaveOutSink};
+use crate::wallpaper::command::{self, MediaInfo, PlaybackStatus, PlayerCommand, PlayerEvent, PlayerHandle, PlayerStats};
+use crate::wallpaper::day_cycle::{DayCycle, DAY_CYCLE_INTERVAL};
+use crate::wallpaper::frame::{Frame, FramePool, PixelFormat};
+use crate::wallpaper::frame_cache::{FrameCache, FrameCacheOptions};
+use crate::wallpaper::governor::{CpuLoad, Governor, Load, QualityBounds};
+use crate::wallpaper::pause::{PauseReason, PauseReasons};
+use crate::wallpaper::playlist::Rotation;
+use crate::wallpaper::proxy::{ProxyManager, ProxyState};
+use crate::wallpaper::stats::{FrameTiming, StatsCollector};
+use crate::wallpaper::stream::{self, Backoff, StreamOptions};
+use crate::wallpaper::transition::{Direction, Transition, TransitionKind};
+use crate::wallpaper::watchdog::{Component, Progress, Recovery};
+use crate::wallpaper::surface::Surface;
+use crate::wallpaper::VideoDecoder;
 use anyhow::Result;
-use ffmpeg_next as ffmpeg;
-use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
-use tokio::time::sleep;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::task::JoinHandle;
 
+/// Loops in a row that produced no frame before the source is considered broken.
+const MAX_EMPTY_LOOPS: u32 = 5;
+
+/// How often the current position is noted in memory, and how often that is written to disk.
+const POSITION_RECORD_INTERVAL: Duration = Duration::from_secs(5);
+const POSITION_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often position and stats events go out while playing.
+const POSITION_EVENT_INTERVAL: Duration = Duration::from_secs(1);
+const STATS_EVENT_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Everything the player task owns. Seeded from settings before the task starts, then only
+/// changed through `PlayerCommand`s.
 pub struct PlayerState {
-    pub is_paused: bool,
+    /// Playback is held while any reason is set.
+    pub pause_reasons: PauseReasons,
     pub fps: u32,
     pub path: String,
     pub resolution: String,
+    pub audio_enabled: bool,
+    pub volume: u32,
+    pub muted: bool,
+    pub stream_options: StreamOptions,
+    pub max_reconnect_delay: Duration,
+    /// Played when `path` can't be decoded; a solid colour is used if this fails too.
+    pub fallback_path: String,
+    pub fallback_color: [u8; 3],
+    /// Why the current wallpaper isn't playing.
+    pub last_error: Option<String>,
+    /// Play heavy local sources through a screen-sized proxy once one has been built.
+    pub proxy_enabled: bool,
+    pub proxies: ProxyManager,
+    pub frame_cache: FrameCacheOptions,
+    /// Time of the frame on screen, and the media length if known.
+    pub position: f64,
+    pub duration: Option<f64>,
+    /// Resume each wallpaper where it was left, except those listed in `always_from_start`.
+    pub resume: bool,
+    pub always_from_start: Vec<String>,
+    pub positions: PlaybackPositions,
+    /// Blend used when the wallpaper changes; pausing and resuming crossfade over the same
+    /// duration when `fade_on_pause` is set.
+    pub transition: TransitionKind,
+    pub transition_duration: Duration,
+    pub fade_on_pause: bool,
+    /// Picks `path` when a playlist is playing.
+    pub playlist: Option<Rotation>,
+    /// Wallpapers that show the frame for the time of day rather than playing, by path.
+    pub day_cycles: HashMap<String, DayCycle>,
+    /// Set by the power policy; the reduced modes cap the frame rate at `reduced_fps` and
+    /// decode at `reduced_scale` of the screen size.
+    pub power_mode: PowerMode,
+    pub reduced_fps: u32,
+    pub reduced_scale: f32,
+    /// Close the decoder after the wallpaper has been held this long, reopening it at the
+    /// same position on resume. Zero keeps it open.
+    pub release_after: Duration,
+    /// Limits for the quality governor, which trades frame rate and resolution for headroom.
+    pub quality: QualityBounds,
+    /// Set by the app rules: a frame rate cap, and muting regardless of `muted`.
+    pub app_fps_cap: Option<u32>,
+    pub app_muted: bool,
+    /// Set by the app rules: plays instead of `path`, which stays what to go back to.
+    pub app_wallpaper: Option<String>,
+}
+
+impl Default for PlayerState {
+    fn default() -> Self {
+        Self {
+            pause_reasons: PauseReasons::default(),
+            fps: 30,
+            path: String::new(),
+            resolution: "1080p".to_string(),
+            audio_enabled: false,
+            volume: 50,
+            muted: false,
+            stream_options: StreamOptions::default(),
+            max_reconnect_delay: Duration::from_secs(30),
+            fallback_path: String::new(),
+            fallback_color: [0, 0, 0],
+            last_error: None,
+            proxy_enabled: false,
+            proxies: ProxyManager::new(),
+            frame_cache: FrameCacheOptions::default(),
+            position: 0.0,
+            duration: None,
+            resume: true,
+            always_from_start: Vec::new(),
+            positions: PlaybackPositions::default(),
+            transition: TransitionKind::Crossfade,
+            transition_duration: Duration::from_millis(800),
+            fade_on_pause: true,
+            playlist: None,
+            day_cycles: HashMap::new(),
+            power_mode: PowerMode::Full,
+            reduced_fps: 15,
+            reduced_scale: 0.5,
+            release_after: Duration::from_secs(120),
+            quality: QualityBounds::default(),
+            app_fps_cap: None,
+            app_muted: false,
+            app_wallpaper: None,
+        }
+    }
+}
+
+impl PlayerState {
+    /// What's on screen: an app rule's wallpaper, or else `path`.
+    pub fn playing_path(&self) -> &str {
+        self.app_wallpaper.as_deref().unwrap_or(&self.path)
+    }
+
+    /// Note the current position against `path` so it can resume there later.
+    pub fn record_position(&mut self, path: &str) {
+        // An error means a fallback (or nothing) is on screen, so the position isn't this wallpaper's
+        if !self.resume || self.last_error.is_some() || path.is_empty() || stream::is_network_source(path) {
+            return;
+        }
+        // The clock decides where a day-cycle wallpaper is
+        if self.day_cycles.contains_key(path) {
+            return;
+        }
+        if self.always_from_start.iter().any(|p| p == path) {
+            self.positions.forget(path);
+            return;
+        }
+        if let Some(duration) = self.duration {
+            self.positions.record(path, self.position, Some(duration));
+        }
+    }
+
+    /// Where `path` should start playing, if anywhere other than the beginning.
+    pub fn resume_position(&self, path: &str) -> Option<f64> {
+        if !self.resume || self.always_from_start.iter().any(|p| p == path) || self.day_cycles.contains_key(path) {
+            return None;
+        }
+        self.positions.resume_position(path)
+    }
+
+    fn save_positions(&mut self) {
+        if let Err(e) = self.positions.save() {
+            tracing::warn!("Failed to save playback positions: {}", e);
+        }
+    }
 }
 
 pub struct WallpaperPlayer {
-    state: Arc<Mutex<PlayerState>>,
+    state: PlayerState,
+    status: PlaybackStatus,
+    handle: PlayerHandle,
+    commands: mpsc::UnboundedReceiver<PlayerCommand>,
+    /// Commands picked up while sleeping, applied at the top of the next loop.
+    pending: VecDeque<PlayerCommand>,
+    events: broadcast::Sender<PlayerEvent>,
+    stats: watch::Sender<PlayerStats>,
+    /// Paces playback and drives every interval; swapped for a simulated one in tests.
+    clock: Arc<dyn Clock>,
+    /// Stamped as playback makes progress, for the watchdog.
+    progress: Progress,
+    /// Asked for by the watchdog, carried out at the top of the next loop.
+    recover: Option<Recovery>,
+    /// Set when the playlist moves on, even to an entry with the same path, so the new
+    /// entry's play time and loops start from zero.
+    item_changed: bool,
+    shutting_down: bool,
 }
 
 impl WallpaperPlayer {
-    pub fn new() -> Self {
+    pub fn new(state: PlayerState, clock: Arc<dyn Clock>) -> Self {
+        let (handle, ends) = command::channel();
         Self {
-            state: Arc::new(Mutex::new(PlayerState {
-                is_paused: false,
-                fps: 30,
-                path: String::new(),
-                resolution: "1080p".to_string(),
-            })),
+            state,
+            status: PlaybackStatus::Idle,
+            handle,
+            commands: ends.commands,
+            pending: VecDeque::new(),
+            events: ends.events,
+            stats: ends.stats,
+            progress: Progress::new(clock.clone()),
+            clock,
+            recover: None,
+            item_changed: false,
+            shutting_down: false,
+        }
+    }
+
+    /// What the watchdog reads to spot a stall.
+    pub fn progress(&self) -> Progress {
+        self.progress.clone()
+    }
+
+    /// Send commands and subscribe to events through this.
+    pub fn handle(&self) -> PlayerHandle {
+        self.handle.clone()
+    }
+
+    fn emit(&self, event: PlayerEvent) {
+        // No subscribers is fine
+        let _ = self.events.send(event);
+    }
+
+    fn set_status(&mut self, status: PlaybackStatus) {
+        if status != self.status {
+            self.status = status;
+            self.emit(PlayerEvent::Status(status));
         }
     }
 
-    pub fn get_state(&self) -> Arc<Mutex<PlayerState>> {
-        self.state.clone()
+    fn fail(&mut self, reason: String) {
+        self.state.last_error = Some(reason.clone());
+        self.emit(PlayerEvent::Error(reason));
     }
 
-    pub async fn run(&self) -> Result<()> {
-        let mut renderer = match WallpaperRenderer::new() {
-            Ok(r) => r,
-            Err(e) => {
-                tracing::error!("Failed to initialize initial renderer: {}", e);
-                // We'll try to recover in the loop
-                return Err(e); // First init is still critical for task startup
+    fn pause_reasons_changed(&self) {
+        let reasons = &self.state.pause_reasons;
+        if reasons.is_paused() {
+            tracing::info!("Playback held: {}", reasons);
+        } else {
+            tracing::info!("All pause reasons cleared, playing.");
+        }
+        self.emit(PlayerEvent::PauseReasons(reasons.clone()));
+    }
+
+    /// Sleep, but wake as soon as a command arrives.
+    async fn idle(&mut self, duration: Duration) {
+        let clock = self.clock.clone();
+        self.progress.idle(true);
+        tokio::select! {
+            Some(command) = self.commands.recv() => self.pending.push_back(command),
+            _ = clock.sleep(duration) => {}
+        }
+        self.progress.idle(false);
+    }
+
+    /// Run `work` on the decoder on the blocking pool, so a read stuck on a dead file handle or
+    /// network share holds up only that thread. If the watchdog asks for the decoder back
+    /// meanwhile, the thread is left to finish on its own and drop the decoder, `decoder` is
+    /// left empty and this returns `None`.
+    async fn on_decoder<T: Send + 'static>(
+        &mut self,
+        decoder: &mut Option<VideoDecoder>,
+        work: impl FnOnce(&mut VideoDecoder) -> T + Send + 'static,
+    ) -> Option<T> {
+        let mut dec = decoder.take()?;
+        let mut task = tokio::task::spawn_blocking(move || {
+            let out = work(&mut dec);
+            (dec, out)
+        });
+        loop {
+            tokio::select! {
+                done = &mut task => match done {
+                    Ok((dec, out)) => {
+                        *decoder = Some(dec);
+                        return Some(out);
+                    }
+                    Err(e) => {
+                        tracing::error!("Decoder thread failed: {}", e);
+                        return None;
+                    }
+                },
+                Some(command) = self.commands.recv() => {
+                    let reclaim = matches!(command, PlayerCommand::Recover(recovery) if recovery.includes_decoder());
+                    self.pending.push_back(command);
+                    if reclaim {
+                        return None;
+                    }
+                }
             }
-        };
+        }
+    }
+
+    /// After `on_decoder` gave up on a stuck read: the position the decoder should be reopened
+    /// at, by the reload at the top of the loop.
+    fn abandon_decoder(&mut self, path: &str, fallback_active: bool) -> Option<f64> {
+        tracing::error!("Left a stuck decoder behind, reopening {} at {:.1}s", path, self.state.position);
+        if !fallback_active {
+            self.state.record_position(path);
+        }
+        Some(self.state.position)
+    }
+
+    /// Apply one command to the state. `Shutdown` only sets a flag; the loop handles it itself.
+    fn apply(&mut self, command: PlayerCommand, seek_to: &mut Option<f64>) {
+        tracing::debug!("Player command: {:?}", command);
+        match command {
+            PlayerCommand::Load(path) => {
+                // The outgoing wallpaper's proxy isn't wanted any more
+                self.state.proxies.cancel_except(&path);
+                self.state.playlist = None;
+                self.state.path = path;
+            }
+            PlayerCommand::SetPlaylist(playlist) => {
+                self.state.playlist = playlist.and_then(|playlist| {
+                    let name = playlist.name.clone();
+                    let rotation = Rotation::new(playlist);
+                    if rotation.is_none() {
+                        tracing::warn!("Playlist {} is empty, ignoring it.", name);
+                    }
+                    rotation
+                });
+                if let Some(ref rotation) = self.state.playlist {
+                    tracing::info!("Playing playlist {} ({} items)", rotation.name(), rotation.len());
+                    self.state.path = rotation.current_path().to_string();
+                    self.item_changed = true;
+                }
+            }
+            PlayerCommand::Next => {
+                if let Some(ref mut rotation) = self.state.playlist {
+                    self.state.path = rotation.next().to_string();
+                    self.item_changed = true;
+                }
+            }
+            PlayerCommand::Previous => {
+                if let Some(ref mut rotation) = self.state.playlist {
+                    self.state.path = rotation.previous().to_string();
+                    self.item_changed = true;
+                }
+            }
+            PlayerCommand::Pause(reason) => {
+                if self.state.pause_reasons.insert(reason) {
+                    self.pause_reasons_changed();
+                }
+            }
+            PlayerCommand::Resume(reason) => {
+                if self.state.pause_reasons.remove(reason) {
+                    self.pause_reasons_changed();
+                }
+            }
+            PlayerCommand::Seek(position) => *seek_to = Some(position),
+            PlayerCommand::SetFps(fps) => self.state.fps = fps.max(1),
+            PlayerCommand::SetResolution(resolution) => self.state.resolution = resolution,
+            PlayerCommand::SetAudio { enabled, volume, muted } => {
+                self.state.audio_enabled = enabled;
+                self.state.volume = volume.min(100);
+                self.state.muted = muted;
+            }
+            PlayerCommand::SetProxyEnabled(enabled) => self.state.proxy_enabled = enabled,
+            PlayerCommand::SetAlwaysFromStart { path, enabled } => {
+                self.state.always_from_start.retain(|p| *p != path);
+                if enabled {
+                    self.state.always_from_start.push(path);
+                }
+            }
+            PlayerCommand::SetDayCycle { path, cycle } => match cycle {
+                Some(cycle) => {
+                    self.state.day_cycles.insert(path, cycle);
+                }
+                None => {
+                    self.state.day_cycles.remove(&path);
+                }
+            },
+            PlayerCommand::SetTransition { kind, duration, fade_on_pause } => {
+                self.state.transition = kind;
+                self.state.transition_duration = duration;
+                self.state.fade_on_pause = fade_on_pause;
+            }
+            PlayerCommand::SetPowerMode { mode, reason } => {
+                if mode != self.state.power_mode {
+                    tracing::info!("Power mode: {} -> {}", self.state.power_mode.label(), mode.label());
+                    self.state.power_mode = mode;
+                }
+                let held = mode.is_held().then_some(reason);
+                let mut changed = false;
+                for power_reason in [PauseReason::Battery, PauseReason::Power] {
+                    changed |= if held == Some(power_reason) {
+                        self.state.pause_reasons.insert(power_reason)
+                    } else {
+                        self.state.pause_reasons.remove(power_reason)
+                    };
+                }
+                if changed {
+                    self.pause_reasons_changed();
+                }
+            }
+            PlayerCommand::SetAppLimits { fps_cap, mute } => {
+                self.state.app_fps_cap = fps_cap.map(|fps| fps.max(1));
+                self.state.app_muted = mute;
+            }
+            PlayerCommand::SetAppWallpaper(path) => {
+                if path != self.state.app_wallpaper {
+                    match &path {
+                        Some(path) => tracing::info!("App rule wallpaper: {}", path),
+                        None => tracing::info!("App rule wallpaper cleared, back to {}", self.state.path),
+                    }
+                    self.state.app_wallpaper = path;
+                    // Whatever comes on screen starts its playlist time afresh
+                    self.item_changed = true;
+                }
+            }
+            PlayerCommand::Recover(recovery) => {
+                // A pending restart covers any rebuild
+                if self.recover != Some(Recovery::Restart) {
+                    self.recover = Some(recovery);
+                }
+            }
+            PlayerCommand::Shutdown => self.shutting_down = true,
+        }
+    }
+
+    /// Play onto `renderer` until shut down.
+    pub async fn run(&mut self, mut renderer: impl Surface + Send) -> Result<()> {
+        let clock = self.clock.clone();
+        let progress = self.progress.clone();
+        progress.watch_window(renderer.window_beat());
 
         let mut decoder: Option<VideoDecoder> = None;
         let mut last_path = String::new();
         let mut last_resolution = String::new();
-        let mut next_frame_target_time = Instant::now();
-        let mut last_heartbeat = Instant::now();
+        let mut last_audio_enabled = false;
+        let mut last_proxy_enabled = false;
+        let mut last_paused = false;
+        let mut reconnect: Option<Backoff> = None;
+        let mut fallback_active = false;
+        let mut empty_loops = 0u32;
+        let mut frames_this_loop = 0u64;
+        let mut proxy_pending = false;
+        let mut last_proxy_state: Option<ProxyState> = None;
+        let mut frame_cache: Option<FrameCache> = None;
+        // A cache being built in the background while the decoder streams
+        let mut cache_build: Option<JoinHandle<Result<Option<FrameCache>>>> = None;
+        let mut playing_proxy = false;
+        let frame_pool = FramePool::new();
+        let mut last_proxy_check = clock.now();
+        let mut next_frame_target_time = clock.now();
+        let mut last_heartbeat = clock.now();
+        let mut last_position_record = clock.now();
+        let mut last_position_save = clock.now();
+        let mut last_position_event = clock.now();
+        let mut last_stats_event = clock.now();
+        let mut collector = StatsCollector::new();
+        let mut transition: Option<Transition> = None;
+        // Last frame streamed from the decoder, kept as the still for the next transition
+        let mut last_frame: Option<Frame> = None;
+        // How long the current wallpaper has actually played, and how often it looped, for playlist rotation
+        let mut item_played = Duration::ZERO;
+        let mut item_loops = 0u32;
+        let mut last_day_cycle_frame: Option<Instant> = None;
+        let mut last_scale = 1.0f32;
+        let mut hidden = false;
+        // When playback was last held, and the position to reopen at once the decoder was released for it
+        let mut paused_since: Option<Instant> = None;
+        let mut released: Option<f64> = None;
+        let mut governor = Governor::new(self.state.fps, &self.state.quality);
+        let mut cpu = CpuLoad::new();
 
         loop {
+            progress.turn();
             // Heartbeat every 10 seconds to confirm the thread is alive
-            if last_heartbeat.elapsed() > Duration::from_secs(10) {
-                tracing::info!("Player Heartbeat: Engine Healthy (State: {})", 
-                    if decoder.is_some() { "Streaming" } else { "Idle" });
-                last_heartbeat = Instant::now();
+            if clock.elapsed(last_heartbeat) > Duration::from_secs(10) {
+                tracing::info!("Player Heartbeat: Engine Healthy (State: {}) {}",
+                    if decoder.is_some() { "Streaming" } else { "Idle" }, *self.stats.borrow());
+                last_heartbeat = clock.now();
             }
 
-            let (paused, fps, path, resolution) = {
-                let s = self.state.lock().unwrap();
-                (s.is_paused, s.fps, s.path.clone(), s.resolution.clone())
-            };
+            while let Ok(command) = self.commands.try_recv() {
+                self.pending.push_back(command);
+            }
+            let mut seek_to: Option<f64> = None;
+            while let Some(command) = self.pending.pop_front() {
+                self.apply(command, &mut seek_to);
+            }
+            if std::mem::take(&mut self.item_changed) {
+                item_played = Duration::ZERO;
+                item_loops = 0;
+            }
+
+            if self.shutting_down {
+                tracing::info!("Stopping playback of {}", last_path);
+                if !fallback_active {
+                    self.state.record_position(&last_path);
+                }
+                self.state.save_positions();
+                // Close the decoder (and its audio) before the window goes
+                drop(decoder);
+                drop(frame_cache);
+                renderer.close();
+                tracing::info!("Player shut down.");
+                return Ok(());
+            }
+
+            if let Some(recovery) = self.recover.take() {
+                tracing::warn!("Recovering from a stall: {}", recovery);
+                let decoder_too = recovery.includes_decoder();
+                let renderer_too = !matches!(recovery, Recovery::Rebuild(Component::Decoder));
+                if decoder_too && (decoder.is_some() || frame_cache.is_some()) {
+                    // Reopened by the reload below, at the same position
+                    if !fallback_active {
+                        self.state.record_position(&last_path);
+                    }
+                    released = Some(self.state.position);
+                    decoder = None;
+                    frame_cache = None;
+                    cache_build = None;
+                }
+                if renderer_too {
+                    match renderer.rebuild() {
+                        Ok(()) => {
+                            renderer.set_visible(!hidden);
+                            progress.watch_window(renderer.window_beat());
+                        }
+                        Err(e) => tracing::error!("Rebuilding the renderer failed: {}", e),
+                    }
+                }
+                if recovery == Recovery::Restart {
+                    transition = None;
+                    last_frame = None;
+                    collector.reset();
+                }
+            }
+
+            let paused = self.state.pause_reasons.is_paused();
+            if !paused {
+                paused_since = None;
+            }
+            let power_mode = self.state.power_mode;
+            if governor.max_fps() != self.state.fps {
+                governor = Governor::new(self.state.fps, &self.state.quality);
+            }
+            let quality = governor.quality();
+            let fps = if power_mode >= PowerMode::ReducedFps { quality.fps.min(self.state.reduced_fps.max(1)) } else { quality.fps };
+            let fps = self.state.app_fps_cap.map_or(fps, |cap| fps.min(cap));
+            let scale = if power_mode >= PowerMode::ReducedResolution { quality.scale.min(self.state.reduced_scale.clamp(0.1, 1.0)) } else { quality.scale };
+            if (power_mode == PowerMode::Hidden) != hidden {
+                hidden = power_mode == PowerMode::Hidden;
+                renderer.set_visible(!hidden);
+            }
+            let path = self.state.playing_path().to_string();
+            let resolution = self.state.resolution.clone();
+            let (audio_enabled, volume, muted) = (self.state.audio_enabled, self.state.volume, self.state.muted || self.state.app_muted);
+            let stream_options = self.state.stream_options.clone();
+            let max_reconnect_delay = self.state.max_reconnect_delay;
+            let fallback_path = self.state.fallback_path.clone();
+            let fallback_color = self.state.fallback_color;
+            let proxy_enabled = self.state.proxy_enabled;
+            let proxies = self.state.proxies.clone();
+            let cache_options = self.state.frame_cache.clone();
+            let mut failure: Option<String> = None;
+            let mut drop_cache = false;
+            let day_cycle = self.state.day_cycles.get(&path).copied();
+            // Audio makes no sense for a frame a minute
+            let audio = (audio_enabled && day_cycle.is_none()).then_some((volume, muted));
 
             if path.is_empty() {
-                sleep(Duration::from_millis(500)).await;
+                progress.expect_frames(false);
+                self.idle(Duration::from_millis(500)).await;
                 continue;
             }
 
-            if path != last_path || resolution != last_resolution {
+            if path != last_path
+                || resolution != last_resolution
+                || audio_enabled != last_audio_enabled
+                || proxy_enabled != last_proxy_enabled
+                || scale != last_scale
+                || (released.is_some() && !paused)
+            {
                 tracing::info!("Reloading wallpaper: {} (Target: {})", path, resolution);
-                
+                if !fallback_active {
+                    // Same path (resolution/audio change) records too, so the reload resumes in place
+                    self.state.record_position(&last_path);
+                    if path != last_path {
+                        self.state.save_positions();
+                    }
+                }
+
+                // Keep the outgoing picture so the new wallpaper blends in from it once its first frame is ready
+                transition = None;
+                if self.state.transition != TransitionKind::Cut {
+                    if let Some(still) = on_screen(&last_frame, &frame_cache) {
+                        transition = Transition::new(self.state.transition, Direction::FromStill, self.state.transition_duration, still)
+                            .map_err(|e| tracing::warn!("Cannot start transition: {}", e))
+                            .ok();
+                    }
+                }
+                last_frame = None;
+                if path != last_path {
+                    item_played = Duration::ZERO;
+                    item_loops = 0;
+                    collector.reset();
+                }
+                last_day_cycle_frame = None;
+                // Pick up where the released or rescaled decoder left off
+                let resume_at = released.take().or((scale != last_scale).then_some(self.state.position));
+                if let Some(position) = resume_at {
+                    if path == last_path && seek_to.is_none() && !stream::is_network_source(&path) {
+                        seek_to = Some(position);
+                    }
+                }
+
                 // Logical Scaling Fix: Always target the PHYSICAL screen size to avoid "invisible" mismatch
-                let (screen_w, screen_h) = renderer.screen_size();
-                tracing::info!("Logical Decoder Target: {}x{}", screen_w, screen_h);
+                let (screen_w, screen_h) = renderer.size();
+                let (decode_w, decode_h) = decode_size(&renderer, scale);
+                tracing::info!("Logical Decoder Target: {}x{}", decode_w, decode_h);
 
-                decoder = match VideoDecoder::new(&path, screen_w, screen_h) {
-                    Ok(d) => Some(d),
+                reconnect = None;
+                fallback_active = false;
+                empty_loops = 0;
+                frames_this_loop = 0;
+                progress.enter(Component::Decoder);
+                let proxy = if proxy_enabled && !stream::is_network_source(&path) {
+                    proxies.resolve(&path, (screen_w, screen_h)).await
+                } else {
+                    None
+                };
+                let proxy_decoder = match proxy {
+                    Some(proxy) => match open_decoder(&proxy.to_string_lossy(), (decode_w, decode_h), renderer.accepted_formats(), &stream_options, audio).await {
+                        Ok(d) => {
+                            tracing::info!("Playing proxy {:?}", proxy);
+                            Some((d, proxy))
+                        }
+                        Err(e) => {
+                            tracing::warn!("Proxy unusable, playing source: {}", e);
+                            None
+                        }
+                    },
+                    None => None,
+                };
+                playing_proxy = proxy_decoder.is_some();
+                proxy_pending = proxy_enabled && !playing_proxy && !stream::is_network_source(&path);
+                last_proxy_state = None;
+
+                let opened_from = proxy_decoder.as_ref().map_or(path.clone(), |(_, proxy)| proxy.to_string_lossy().into_owned());
+                let opened = match proxy_decoder {
+                    Some((d, _)) => Ok(d),
+                    None => open_decoder(&path, (decode_w, decode_h), renderer.accepted_formats(), &stream_options, audio).await,
+                };
+                decoder = match opened {
+                    Ok(d) => {
+                        self.state.last_error = None;
+                        Some(d)
+                    }
                     Err(e) => {
                         tracing::error!("Failed to load wallpaper: {}", e);
+                        if stream::is_network_source(&path) {
+                            let mut backoff = Backoff::new(max_reconnect_delay, clock.now());
+                            let delay = backoff.fail(clock.now());
+                            tracing::warn!("Stream unavailable, retrying in {:?}", delay);
+                            self.fail(format!("Stream unavailable: {}", e));
+                            self.set_status(PlaybackStatus::Reconnecting);
+                            reconnect = Some(backoff);
+                        } else {
+                            failure = Some(format!("Could not open {}: {:#}", path, e));
+                        }
                         None
                     }
                 };
+                frame_cache = None;
+                cache_build = None;
+                if let Some(ref dec) = decoder {
+                    // Audio and live streams need the decoder running, so only silent files are cached
+                    let short = dec.duration().is_some_and(|d| d <= cache_options.max_clip_secs);
+                    if cache_options.enabled && audio.is_none() && !dec.is_network() && short {
+                        cache_build = Some(build_cache(&opened_from, (decode_w, decode_h), renderer.accepted_formats(), &stream_options, &cache_options));
+                    }
+                }
+                self.state.position = 0.0;
+                self.state.duration = decoder.as_ref().and_then(|d| d.duration());
+                if let Some(ref dec) = decoder {
+                    if seek_to.is_none() {
+                        seek_to = self.state.resume_position(&path);
+                        if let Some(position) = seek_to {
+                            tracing::info!("Resuming {} at {:.1}s", path, position);
+                        }
+                    }
+                    self.emit(PlayerEvent::Loaded(media_info(&path, dec, playing_proxy, false)));
+                    self.set_status(if paused { PlaybackStatus::Paused } else { PlaybackStatus::Playing });
+                }
                 last_path = path;
                 last_resolution = resolution;
+                last_audio_enabled = audio_enabled;
+                last_proxy_enabled = proxy_enabled;
+                last_scale = scale;
+                last_paused = false;
+                next_frame_target_time = clock.now();
             }
 
-            if paused {
-                sleep(Duration::from_millis(200)).await;
-                continue;
+            // Swap to the proxy as soon as its background transcode finishes
+            if proxy_pending && !fallback_active && decoder.is_some()
+                && clock.elapsed(last_proxy_check) > Duration::from_secs(1)
+            {
+                last_proxy_check = clock.now();
+                if let Some(progress) = proxies.job(&last_path) {
+                    if matches!(progress.state, ProxyState::Failed(_)) {
+                        // Starts the transcode again once its retry is due
+                        proxies.resolve(&last_path, renderer.size()).await;
+                    }
+                    if last_proxy_state.as_ref() != Some(&progress.state) {
+                        last_proxy_state = Some(progress.state.clone());
+                        self.emit(PlayerEvent::ProxyProgress(progress));
+                    }
+                }
+                if let Some(proxy) = proxies.ready(&last_path) {
+                    match open_decoder(&proxy.to_string_lossy(), decode_size(&renderer, scale), renderer.accepted_formats(), &stream_options, audio).await {
+                        Ok(d) => {
+                            tracing::info!("Switched to proxy {:?}", proxy);
+                            self.emit(PlayerEvent::Loaded(media_info(&last_path, &d, true, false)));
+                            decoder = Some(d);
+                            frame_cache = None;
+                            cache_build = None;
+                            playing_proxy = true;
+                            last_paused = false;
+                        }
+                        Err(e) => tracing::warn!("Proxy unusable, staying on source: {}", e),
+                    }
+                    proxy_pending = false;
+                }
+            }
+
+            // Switch to the cached loop once it's built, carrying on from the frame on screen
+            if let Some(build) = cache_build.take_if(|build| build.is_finished()) {
+                match build.await {
+                    Ok(Ok(Some(mut cache))) => {
+                        cache.seek(self.state.position);
+                        if let Some(ref dec) = decoder {
+                            self.emit(PlayerEvent::Loaded(media_info(&last_path, dec, playing_proxy, true)));
+                        }
+                        frame_cache = Some(cache);
+                    }
+                    Ok(Ok(None)) => {}
+                    Ok(Err(e)) => tracing::warn!("Frame cache build failed: {}. Streaming instead.", e),
+                    Err(e) => tracing::warn!("Frame cache build did not finish: {}. Streaming instead.", e),
+                }
             }
 
             if let Some(ref mut dec) = decoder {
-                let frame_time = Duration::from_secs_f64(1.0 / fps as f64);
-                let mut rgb_frame = ffmpeg::util::frame::Video::empty();
+                dec.set_volume(volume, muted);
+                if paused != last_paused {
+                    dec.set_audio_paused(paused);
+                    last_paused = paused;
+                    self.set_status(if paused { PlaybackStatus::Paused } else { PlaybackStatus::Playing });
+                    // A wallpaper change that hasn't finished blending takes priority
+                    let changing = transition.as_ref().is_some_and(|t| t.direction() == Direction::FromStill && !t.is_finished());
+                    if self.state.fade_on_pause && self.state.transition != TransitionKind::Cut && !changing {
+                        transition = pause_transition(paused, transition.take(), &last_frame, &frame_cache, self.state.transition_duration);
+                    }
+                }
+            }
+
+            if let Some(target) = seek_to {
+                let seeked = if let Some(ref mut cache) = frame_cache {
+                    cache.seek(target);
+                    Some(cache.position())
+                } else if let Some(ref mut dec) = decoder {
+                    match dec.seek(target) {
+                        Ok(()) => Some(dec.position()),
+                        Err(e) => {
+                            tracing::warn!("Seek to {:.2}s failed: {}", target, e);
+                            None
+                        }
+                    }
+                } else {
+                    None
+                };
+                if let Some(position) = seeked {
+                    tracing::info!("Seeked to {:.2}s", position);
+                    transition = None;
+                    self.state.position = position;
+                    self.emit(PlayerEvent::Position { position, duration: self.state.duration });
+                    next_frame_target_time = clock.now();
+                    // Show the new frame straight away, even while paused (scrubbing).
+                    if paused {
+                        if let Some(ref mut cache) = frame_cache {
+                            if let Ok(frame) = cache.next_frame() {
+                                present(clock.as_ref(), &progress, &mut renderer, frame).await;
+                            }
+                        } else if let Some(ref mut dec) = decoder {
+                            let mut frame = frame_pool.acquire();
+                            if let Ok(true) = dec.next_frame(&mut frame) {
+                                present(clock.as_ref(), &progress, &mut renderer, &frame).await;
+                            }
+                        }
+                    }
+                }
+            }
 
-                if next_frame_target_time < Instant::now() {
-                    next_frame_target_time = Instant::now();
+            if !fallback_active && clock.elapsed(last_position_record) > POSITION_RECORD_INTERVAL {
+                last_position_record = clock.now();
+                self.state.record_position(&last_path);
+                if clock.elapsed(last_position_save) > POSITION_SAVE_INTERVAL {
+                    last_position_save = clock.now();
+                    self.state.save_positions();
                 }
+            }
 
-                let sleep_duration = next_frame_target_time.saturating_duration_since(Instant::now());
-                if sleep_duration > Duration::ZERO {
-                    sleep(sleep_duration).await;
+            if clock.elapsed(last_position_event) > POSITION_EVENT_INTERVAL && !paused && (decoder.is_some() || frame_cache.is_some()) {
+                last_position_event = clock.now();
+                self.emit(PlayerEvent::Position { position: self.state.position, duration: self.state.duration });
+            }
+            if clock.elapsed(last_stats_event) > STATS_EVENT_INTERVAL {
+                last_stats_event = clock.now();
+                let stats = PlayerStats {
+                    queue_depth: decoder.as_ref().map_or(0, |d| d.queued_frames()),
+                    loops: item_loops,
+                    corrupt_packets: decoder.as_ref().map_or(0, |d| d.corrupt_packets()),
+                    ..collector.snapshot(last_stats_event)
+                };
+                // Only steady playback says anything about decode headroom
+                let streaming = (decoder.is_some() || frame_cache.is_some()) && day_cycle.is_none();
+                if streaming && !paused && stats.presented_fps > 0.0 {
+                    let load = Load {
+                        work: Duration::from_secs_f64((stats.decode_ms.p95 + stats.scale_ms.p95).max(0.0) / 1000.0),
+                        cpu: Some(cpu.sample()),
+                    };
+                    governor.observe(last_stats_event, &load);
+                } else {
+                    governor.interrupt();
                 }
+                self.stats.send_replace(stats.clone());
+                self.emit(PlayerEvent::Stats(stats));
+            }
 
-                // Zero-allocation frame fetch
-                match dec.next_frame(&mut rgb_frame) {
-                    Ok(true) => {
-                        if let Err(e) = renderer.render_frame(rgb_frame.data(0), dec.width(), dec.height()) {
-                            tracing::error!("Render error: {}. Recovering...", e);
-                            match WallpaperRenderer::new() {
-                                Ok(new_renderer) => {
-                                    renderer = new_renderer;
-                                    tracing::info!("Renderer recovered.");
-                                }
-                                Err(re_err) => {
-                                    tracing::error!("Recovery failed: {}. Retrying next frame.", re_err);
-                                    sleep(Duration::from_millis(100)).await;
-                                }
+            // Day-cycle wallpapers show the frame for the time of day, once a minute
+            if let (Some(cycle), Some(duration)) = (day_cycle.filter(|_| !fallback_active), self.state.duration) {
+                transition = None;
+                let due = last_day_cycle_frame.is_none_or(|t| clock.elapsed(t) >= DAY_CYCLE_INTERVAL);
+                if !paused && due {
+                    last_day_cycle_frame = Some(clock.now());
+                    let target = cycle.position(clock.local_now().time(), duration);
+                    if let Some(ref mut cache) = frame_cache {
+                        cache.seek(target);
+                        match cache.next_frame() {
+                            Ok(frame) => {
+                                let started = Instant::now();
+                                present(clock.as_ref(), &progress, &mut renderer, frame).await;
+                                let timing = FrameTiming { present: started.elapsed(), ..Default::default() };
+                                collector.record_frame(clock.now(), timing, DAY_CYCLE_INTERVAL);
                             }
+                            Err(e) => tracing::warn!("Day-cycle frame at {:.1}s failed: {}", target, e),
                         }
+                        self.state.position = cache.position();
+                    } else if decoder.is_some() {
+                        let mut frame = frame_pool.acquire();
+                        progress.enter(Component::Decoder);
+                        let decoded = self
+                            .on_decoder(&mut decoder, move |dec| {
+                                let result = dec.seek(target).and_then(|_| dec.next_frame(&mut frame));
+                                (frame, result)
+                            })
+                            .await;
+                        progress.enter(Component::Player);
+                        match (decoded, decoder.as_ref()) {
+                            (Some((frame, Ok(true))), Some(dec)) => {
+                                let (decode, scale) = dec.last_timings();
+                                self.state.position = dec.position();
+                                let started = Instant::now();
+                                present(clock.as_ref(), &progress, &mut renderer, &frame).await;
+                                let timing = FrameTiming { decode, scale, present: started.elapsed(), ..Default::default() };
+                                collector.record_frame(clock.now(), timing, DAY_CYCLE_INTERVAL);
+                            }
+                            (Some((_, Err(e))), _) => tracing::warn!("Day-cycle frame at {:.1}s failed: {}", target, e),
+                            (Some(_), _) => {}
+                            (None, _) => released = self.abandon_decoder(&last_path, fallback_active),
+                        }
+                    }
+                }
+                progress.expect_frames(false);
+                let wait = last_day_cycle_frame.map_or(DAY_CYCLE_INTERVAL, |t| DAY_CYCLE_INTERVAL.saturating_sub(clock.elapsed(t)));
+                self.idle(wait.max(Duration::from_millis(200))).await;
+                next_frame_target_time = clock.now();
+                continue;
+            }
+
+            // After a pause, frames keep coming until the motion has settled onto the held picture
+            let settling = paused && transition.as_ref().is_some_and(|t| t.direction() == Direction::ToStill && !t.is_finished());
+            if paused && !settling {
+                // A still wallpaper doesn't need a decoder; free it until playback resumes
+                let since = *paused_since.get_or_insert_with(|| clock.now());
+                let release_after = self.state.release_after;
+                if decoder.is_some() && !fallback_active && !release_after.is_zero() && clock.elapsed(since) >= release_after {
+                    tracing::info!("Held for {:?}, releasing the decoder for {} at {:.1}s", release_after, last_path, self.state.position);
+                    self.state.record_position(&last_path);
+                    released = Some(self.state.position);
+                    decoder = None;
+                    frame_cache = None;
+                    cache_build = None;
+                }
+                progress.expect_frames(false);
+                self.idle(Duration::from_millis(200)).await;
+                // Time spent paused isn't time the player fell behind
+                next_frame_target_time = clock.now();
+                continue;
+            }
+
+            progress.expect_frames(decoder.is_some() || frame_cache.is_some());
+            let mut stream_lost = false;
+            if let Some(ref mut cache) = frame_cache {
+                let frame_time = Duration::from_secs_f64(1.0 / fps as f64);
+                let behind = wait_until(clock.as_ref(), &mut next_frame_target_time).await;
+                collector.record_behind(clock.now(), behind, frame_time);
+                let slot = next_frame_target_time;
+
+                let before = cache.position();
+                let started = Instant::now();
+                progress.enter(Component::Decoder);
+                match cache.next_frame() {
+                    Ok(frame) => {
+                        progress.decoded();
+                        // Inflating a compressed frame is the cache's decode step
+                        let decode = started.elapsed();
+                        let late = clock.elapsed(slot);
+                        let started = Instant::now();
+                        show(clock.as_ref(), &progress, &mut renderer, &mut transition, frame).await;
+                        let timing = FrameTiming { decode, present: started.elapsed(), late, ..Default::default() };
+                        collector.record_frame(clock.now(), timing, frame_time);
+                        item_played += frame_time;
+                        self.state.position = cache.position();
+                        if self.state.position < before {
+                            item_loops += 1;
+                        }
+                    }
+                    Err(e) => {
+                        tracing::warn!("Frame cache read failed: {}. Streaming instead.", e);
+                        drop_cache = true;
+                    }
+                }
+
+                next_frame_target_time += frame_time;
+            } else if decoder.is_some() {
+                let frame_time = Duration::from_secs_f64(1.0 / fps as f64);
+                let rgb_frame = frame_pool.acquire();
+
+                let behind = wait_until(clock.as_ref(), &mut next_frame_target_time).await;
+                collector.record_behind(clock.now(), behind, frame_time);
+                let slot = next_frame_target_time;
+
+                // Zero-allocation frame fetch
+                let mut shown = false;
+                progress.enter(Component::Decoder);
+                let decoded = self.on_decoder(&mut decoder, |dec| next_frame(dec, rgb_frame)).await;
+                let (Some((mut rgb_frame, result)), Some(dec)) = (decoded, decoder.as_ref()) else {
+                    released = self.abandon_decoder(&last_path, fallback_active);
+                    continue;
+                };
+                match result {
+                    Ok(true) => {
+                        progress.decoded();
+                        empty_loops = 0;
+                        frames_this_loop += 1;
+                        let (decode, scale) = dec.last_timings();
+                        let late = clock.elapsed(slot);
+                        let started = Instant::now();
+                        show(clock.as_ref(), &progress, &mut renderer, &mut transition, &rgb_frame).await;
+                        let timing = FrameTiming { decode, scale, present: started.elapsed(), late };
+                        collector.record_frame(clock.now(), timing, frame_time);
+                        item_played += frame_time;
+                        self.state.position = dec.position();
+                        shown = true;
+                    }
+                    Ok(false) if dec.is_live() => {
+                        tracing::warn!("Live stream ended.");
+                        stream_lost = true;
                     }
                     Ok(false) => {
                         // Loop
-                        let _ = dec.seek_to_start();
-                        if let Ok(true) = dec.next_frame(&mut rgb_frame) {
-                            let _ = renderer.render_frame(rgb_frame.data(0), dec.width(), dec.height());
+                        if frames_this_loop == 0 {
+                            empty_loops += 1;
+                        }
+                        frames_this_loop = 0;
+                        item_loops += 1;
+                        if empty_loops >= MAX_EMPTY_LOOPS {
+                            failure = Some(format!(
+                                "No decodable video frames after {} attempts ({} corrupt packets skipped)",
+                                empty_loops,
+                                dec.corrupt_packets()
+                            ));
+                        } else {
+                            let looped = self
+                                .on_decoder(&mut decoder, |dec| match dec.seek_to_start() {
+                                    Ok(()) => {
+                                        let (frame, result) = next_frame(dec, rgb_frame);
+                                        (frame, Ok(result))
+                                    }
+                                    Err(e) => (rgb_frame, Err(e)),
+                                })
+                                .await;
+                            let (Some((frame, seeked)), Some(dec)) = (looped, decoder.as_ref()) else {
+                                released = self.abandon_decoder(&last_path, fallback_active);
+                                continue;
+                            };
+                            rgb_frame = frame;
+                            match seeked {
+                                Err(e) => failure = Some(format!("Cannot loop, seek failed: {}", e)),
+                                Ok(Ok(true)) => {
+                                    progress.decoded();
+                                    frames_this_loop += 1;
+                                    let (decode, scale) = dec.last_timings();
+                                    let late = clock.elapsed(slot);
+                                    let started = Instant::now();
+                                    show(clock.as_ref(), &progress, &mut renderer, &mut transition, &rgb_frame).await;
+                                    let timing = FrameTiming { decode, scale, present: started.elapsed(), late };
+                                    collector.record_frame(clock.now(), timing, frame_time);
+                                    shown = true;
+                                }
+                                Ok(_) => {}
+                            }
                         }
                     }
+                    Err(e) if dec.is_network() => {
+                        tracing::warn!("Stream read error: {}", e);
+                        stream_lost = true;
+                    }
                     Err(e) => {
-                        tracing::error!("Decode error: {}. Retrying...", e);
-                        sleep(Duration::from_millis(100)).await;
+                        failure = Some(format!("Decode error: {}", e));
                     }
                 }
 
                 next_frame_target_time += frame_time;
+                if shown {
+                    last_frame = Some(rgb_frame);
+                }
 
+            } else if let Some(ref mut backoff) = reconnect {
+                if backoff.is_due(clock.now()) {
+                    progress.enter(Component::Decoder);
+                    match open_decoder(&last_path, decode_size(&renderer, scale), renderer.accepted_formats(), &stream_options, audio).await {
+                        Ok(d) => {
+                            tracing::info!("Stream reconnected after {} attempt(s).", backoff.attempt() + 1);
+                            self.state.last_error = None;
+                            self.emit(PlayerEvent::Loaded(media_info(&last_path, &d, false, false)));
+                            self.set_status(PlaybackStatus::Playing);
+                            decoder = Some(d);
+                            reconnect = None;
+                        }
+                        Err(e) => {
+                            let delay = backoff.fail(clock.now());
+                            tracing::warn!("Reconnect failed: {}. Next attempt in {:?}", e, delay);
+                        }
+                    }
+                }
+                self.idle(Duration::from_millis(250)).await;
+                next_frame_target_time = clock.now();
             } else {
-                sleep(Duration::from_millis(500)).await;
-                next_frame_target_time = Instant::now();
+                self.idle(Duration::from_millis(500)).await;
+                next_frame_target_time = clock.now();
+            }
+
+            if let Some(ref mut rotation) = self.state.playlist {
+                // An app rule's wallpaper doesn't count towards the playlist item it's covering
+                if !fallback_active && self.state.app_wallpaper.is_none() && rotation.is_due(item_played, item_loops) {
+                    self.state.path = rotation.next().to_string();
+                    tracing::info!("Playlist {}: moving on to {}", rotation.name(), self.state.path);
+                    item_played = Duration::ZERO;
+                    item_loops = 0;
+                }
+            }
+
+            if drop_cache || failure.is_some() || stream_lost {
+                frame_cache = None;
+                cache_build = None;
+            }
+
+            if let Some(reason) = failure {
+                tracing::error!("Wallpaper failed: {}", reason);
+                self.fail(reason);
+                self.set_status(PlaybackStatus::Failed);
+                decoder = None;
+                empty_loops = 0;
+                frames_this_loop = 0;
+
+                if !fallback_active && !fallback_path.is_empty() && fallback_path != last_path {
+                    tracing::warn!("Switching to fallback wallpaper: {}", fallback_path);
+                    decoder = match open_decoder(&fallback_path, decode_size(&renderer, scale), renderer.accepted_formats(), &stream_options, audio).await {
+                        Ok(d) => Some(d),
+                        Err(e) => {
+                            tracing::error!("Fallback wallpaper failed too: {}", e);
+                            None
+                        }
+                    };
+                }
+                fallback_active = true;
+
+                if decoder.is_none() {
+                    tracing::warn!("Showing solid colour {:?}", fallback_color);
+                    if let Err(e) = renderer.present_solid(fallback_color) {
+                        tracing::error!("Failed to draw fallback colour: {}", e);
+                    }
+                }
+            }
+
+            if stream_lost {
+                // The swapchain keeps presenting the last frame until the stream is back.
+                tracing::warn!("Lost stream {}, keeping last frame and reconnecting.", last_path);
+                self.set_status(PlaybackStatus::Reconnecting);
+                decoder = None;
+                reconnect = Some(Backoff::new(max_reconnect_delay, clock.now()));
+            }
+        }
+    }
+}
+
+fn media_info(path: &str, decoder: &VideoDecoder, proxy: bool, cached: bool) -> MediaInfo {
+    MediaInfo {
+        path: path.to_string(),
+        width: decoder.width(),
+        height: decoder.height(),
+        duration: decoder.duration(),
+        is_live: decoder.is_live(),
+        output_format: decoder.output_format(),
+        proxy,
+        cached,
+    }
+}
+
+/// Size frames are decoded at: the screen, shrunk by `scale` for reduced resolution.
+/// Even dimensions keep the YUV formats happy.
+fn decode_size(renderer: &impl Surface, scale: f32) -> (u32, u32) {
+    let (width, height) = renderer.size();
+    if scale >= 1.0 {
+        return (width, height);
+    }
+    let shrink = |v: u32| (((v as f32 * scale) as u32) & !1).max(2);
+    (shrink(width), shrink(height))
+}
+
+/// Decode into `frame`, handing it back with the result.
+fn next_frame(decoder: &mut VideoDecoder, mut frame: Frame) -> (Frame, Result<bool>) {
+    let result = decoder.next_frame(&mut frame);
+    (frame, result)
+}
+
+/// Sleep until the frame's presentation time, or present straight away if we're running late.
+/// Returns how far behind schedule we were; those slots are skipped.
+async fn wait_until(clock: &dyn Clock, target: &mut Instant) -> Duration {
+    let now = clock.now();
+    let behind = now.saturating_duration_since(*target);
+    if *target < now {
+        *target = now;
+    }
+
+    let sleep_duration = target.saturating_duration_since(clock.now());
+    if sleep_duration > Duration::ZERO {
+        clock.sleep(sleep_duration).await;
+    }
+    behind
+}
+
+/// What's on screen now: the cached loop's current frame, or the last one streamed.
+fn on_screen<'a>(last_frame: &'a Option<Frame>, frame_cache: &'a Option<FrameCache>) -> Option<&'a Frame> {
+    match frame_cache {
+        Some(cache) => cache.current_frame(),
+        None => last_frame.as_ref(),
+    }
+}
+
+/// Crossfade for a pause or resume. Pausing settles the motion onto the frame on screen;
+/// resuming blends from that held frame back into playback.
+fn pause_transition(
+    paused: bool,
+    previous: Option<Transition>,
+    last_frame: &Option<Frame>,
+    frame_cache: &Option<FrameCache>,
+    duration: Duration,
+) -> Option<Transition> {
+    let held = previous.as_ref().filter(|t| t.direction() == Direction::ToStill).map(|t| t.still());
+    let (direction, still) = if paused {
+        (Direction::ToStill, on_screen(last_frame, frame_cache))
+    } else {
+        (Direction::FromStill, held.or_else(|| on_screen(last_frame, frame_cache)))
+    };
+    Transition::new(TransitionKind::Crossfade, direction, duration, still?)
+        .map_err(|e| tracing::warn!("Cannot start pause transition: {}", e))
+        .ok()
+}
+
+/// Present `frame` through the running transition, if any. A finished `ToStill` keeps
+/// showing its still, since that's the picture held while paused.
+async fn show(clock: &dyn Clock, progress: &Progress, renderer: &mut impl Surface, transition: &mut Option<Transition>, frame: &Frame) {
+    if let Some(t) = transition.as_mut() {
+        match t.apply(frame, clock.now()) {
+            Ok(Some(blended)) => return present(clock, progress, renderer, blended).await,
+            Ok(None) => {}
+            Err(e) => tracing::warn!("Transition failed, cutting instead: {}", e),
+        }
+        if t.direction() == Direction::ToStill && t.is_finished() {
+            return present(clock, progress, renderer, t.still()).await;
+        }
+        *transition = None;
+    }
+    present(clock, progress, renderer, frame).await;
+}
+
+/// Render a frame, rebuilding the renderer if the shell took our window away.
+async fn present(clock: &dyn Clock, progress: &Progress, renderer: &mut impl Surface, frame: &Frame) {
+    progress.enter(Component::Presenter);
+    let result = renderer.present(frame);
+    progress.enter(Component::Player);
+    if let Err(e) = result {
+        tracing::error!("Render error: {}. Recovering...", e);
+        match renderer.rebuild() {
+            Ok(()) => {
+                progress.watch_window(renderer.window_beat());
+                tracing::info!("Renderer recovered.");
+            }
+            Err(re_err) => {
+                tracing::error!("Recovery failed: {}. Retrying next frame.", re_err);
+                clock.sleep(Duration::from_millis(100)).await;
             }
         }
+    } else {
+        progress.presented();
+    }
+}
+
+/// Open `path` on the blocking pool. A URL can take the whole connect timeout to answer, and
+/// the runtime's other tasks (monitor, scheduler, watchdog) have to keep running meanwhile.
+async fn open_decoder(
+    path: &str,
+    target: (u32, u32),
+    formats: &[PixelFormat],
+    options: &StreamOptions,
+    audio: Option<(u32, bool)>,
+) -> Result<VideoDecoder> {
+    let (path, formats, options) = (path.to_string(), formats.to_vec(), options.clone());
+    tokio::task::spawn_blocking(move || open_decoder_blocking(&path, target, &formats, &options, audio)).await?
+}
+
+fn open_decoder_blocking(
+    path: &str,
+    target: (u32, u32),
+    formats: &[PixelFormat],
+    options: &StreamOptions,
+    audio: Option<(u32, bool)>,
+) -> Result<VideoDecoder> {
+    let mut decoder = VideoDecoder::new(path, target.0, target.1, options)?;
+    decoder.set_accepted_formats(formats)?;
+    if let Some((volume, muted)) = audio {
+        enable_audio(&mut decoder, volume, muted);
+    }
+    Ok(decoder)
+}
+
+/// Decode a short clip into a `FrameCache` on the blocking pool, through a decoder of its own
+/// so the one on screen keeps streaming until the cache is ready.
+fn build_cache(
+    path: &str,
+    target: (u32, u32),
+    formats: &[PixelFormat],
+    options: &StreamOptions,
+    cache_options: &FrameCacheOptions,
+) -> JoinHandle<Result<Option<FrameCache>>> {
+    let (path, formats, options, cache_options) = (path.to_string(), formats.to_vec(), options.clone(), cache_options.clone());
+    tokio::task::spawn_blocking(move || {
+        let mut decoder = open_decoder_blocking(&path, target, &formats, &options, None)?;
+        FrameCache::build(&mut decoder, &cache_options)
+    })
+}
+
+/// Prefer the default output device, but keep decoding into a null sink if it can't be opened
+/// so the audio clock still behaves the same.
+fn enable_audio(decoder: &mut VideoDecoder, volume: u32, muted: bool) {
+    match decoder.enable_audio(Box::new(WaveOutSink::new()), volume, muted) {
+        Ok(true) => {}
+        Ok(false) => tracing::info!("Wallpaper has no audio track."),
+        Err(e) => {
+            tracing::warn!("Audio device unavailable: {}. Falling back to null sink.", e);
+            if let Err(e) = decoder.enable_audio(Box::new(NullSink::new()), volume, muted) {
+                tracing::error!("Failed to enable audio: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SimulatedClock;
+    use crate::wallpaper::fixtures;
+    use crate::wallpaper::surface::HeadlessSurface;
+    use chrono::DateTime;
+    use std::sync::Mutex;
+
+    /// When each picture went up, and its top-left pixel as BGRA.
+    type Timeline = Arc<Mutex<Vec<(Duration, [u8; 4])>>>;
+
+    /// A headless surface that notes the virtual time of every present.
+    struct Recording {
+        surface: HeadlessSurface,
+        clock: Arc<SimulatedClock>,
+        timeline: Timeline,
+    }
+
+    impl Surface for Recording {
+        fn accepted_formats(&self) -> &[PixelFormat] {
+            self.surface.accepted_formats()
+        }
+
+        fn present(&mut self, frame: &Frame) -> Result<()> {
+            self.surface.present(frame)?;
+            let picture = self.surface.last_frame().unwrap();
+            let pixel = picture.plane(0)[..4].try_into().unwrap();
+            self.timeline.lock().unwrap().push((self.clock.elapsed_total(), pixel));
+            Ok(())
+        }
+
+        fn size(&self) -> (u32, u32) {
+            self.surface.size()
+        }
+
+        fn present_solid(&mut self, rgb: [u8; 3]) -> Result<()> {
+            let mut frame = Frame::new(PixelFormat::Bgra, fixtures::WIDTH, fixtures::HEIGHT);
+            for dst in frame.plane_mut(0).chunks_exact_mut(4) {
+                dst.copy_from_slice(&[rgb[2], rgb[1], rgb[0], 255]);
+            }
+            self.present(&frame)
+        }
+
+        fn set_visible(&mut self, visible: bool) {
+            self.surface.set_visible(visible)
+        }
+
+        fn rebuild(&mut self) -> Result<()> {
+            self.surface.rebuild()
+        }
+
+        fn close(self) {}
+    }
+
+    fn state(path: &str) -> PlayerState {
+        PlayerState {
+            path: path.to_string(),
+            fps: fixtures::FPS as u32,
+            resume: false,
+            transition: TransitionKind::Cut,
+            fade_on_pause: false,
+            quality: QualityBounds { adaptive: false, ..Default::default() },
+            ..Default::default()
+        }
+    }
+
+    /// Play `state` for `duration` of virtual time on a paused runtime, then shut down.
+    async fn play(state: PlayerState, duration: Duration) -> (Vec<(Duration, [u8; 4])>, PlayerStats) {
+        let clock = Arc::new(SimulatedClock::new(DateTime::parse_from_rfc3339("2024-03-10T12:00:00+01:00").unwrap()));
+        let timeline = Timeline::default();
+        let surface = Recording {
+            surface: HeadlessSurface::new(fixtures::WIDTH, fixtures::HEIGHT),
+            clock: clock.clone(),
+            timeline: timeline.clone(),
+        };
+        let mut player = WallpaperPlayer::new(state, clock.clone());
+        let handle = player.handle();
+        let task = tokio::spawn(async move { player.run(surface).await });
+
+        clock.sleep(duration).await;
+        let stats = handle.stats();
+        handle.send(PlayerCommand::Shutdown);
+        task.await.unwrap().unwrap();
+        let timeline = std::mem::take(&mut *timeline.lock().unwrap());
+        (timeline, stats)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn plays_an_hour_on_the_frame_grid() {
+        let clip = fixtures::clip("hour.ts", 0, 50);
+        let (timeline, stats) = play(state(&clip.to_string_lossy()), Duration::from_secs(3600)).await;
+
+        // Every frame in its 40 ms slot from the first one on, with none dropped or doubled
+        let frame_time = Duration::from_millis(40);
+        let first = timeline[0].0;
+        assert!(first < Duration::from_secs(1), "first frame at {:?}", first);
+        for (index, (at, _)) in timeline.iter().enumerate() {
+            assert_eq!(*at, first + frame_time * index as u32, "frame {}", index);
+        }
+        let expected = ((Duration::from_secs(3600) - first).as_millis() / 40) as usize;
+        assert!((expected..=expected + 1).contains(&timeline.len()), "{} frames, expected {}", timeline.len(), expected);
+        // The 2 s clip looped all the way through
+        assert!((1798..=1800).contains(&stats.loops), "{} loops", stats.loops);
+        assert_eq!(stats.dropped_frames, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn missing_wallpaper_shows_the_fallback_colour() {
+        let path = fixtures::temp_path("missing.mp4");
+        let state = PlayerState { fallback_color: [200, 100, 50], ..state(&path.to_string_lossy()) };
+        let (timeline, _) = play(state, Duration::from_secs(5)).await;
+        assert!(!timeline.is_empty());
+        assert!(timeline.iter().all(|(_, pixel)| *pixel == [50, 100, 200, 255]), "{:?}", timeline.first());
+    }
+
+    #[test]
+    fn held_power_modes_pause_for_their_tier() {
+        let clock = Arc::new(SimulatedClock::new(DateTime::parse_from_rfc3339("2024-03-10T12:00:00+01:00").unwrap()));
+        let mut player = WallpaperPlayer::new(PlayerState::default(), clock);
+        let mut power = |mode, reason| {
+            player.apply(PlayerCommand::SetPowerMode { mode, reason }, &mut None);
+            (player.state.power_mode, player.state.pause_reasons.iter().collect::<Vec<_>>())
+        };
+        assert_eq!(power(PowerMode::Static, PauseReason::Battery), (PowerMode::Static, vec![PauseReason::Battery]));
+        assert_eq!(power(PowerMode::Hidden, PauseReason::Power), (PowerMode::Hidden, vec![PauseReason::Power]));
+        assert_eq!(power(PowerMode::ReducedFps, PauseReason::Battery), (PowerMode::ReducedFps, vec![]));
+    }
+
+    #[test]
+    fn an_app_rule_wallpaper_gives_way_to_whatever_was_loaded_under_it() {
+        let clock = Arc::new(SimulatedClock::new(DateTime::parse_from_rfc3339("2024-03-10T12:00:00+01:00").unwrap()));
+        let mut player = WallpaperPlayer::new(state("home.mp4"), clock);
+        let mut send = |command| {
+            player.apply(command, &mut None);
+            player.state.playing_path().to_string()
+        };
+        assert_eq!(send(PlayerCommand::SetAppWallpaper(Some("game.mp4".to_string()))), "game.mp4");
+        // The schedule moves on while the rule holds
+        assert_eq!(send(PlayerCommand::Load("evening.mp4".to_string())), "game.mp4");
+        assert_eq!(send(PlayerCommand::SetAppWallpaper(None)), "evening.mp4");
+    }
+
+    #[test]
+    fn resume_skips_always_from_start_and_day_cycle_wallpapers() {
+        let clock = Arc::new(SimulatedClock::new(DateTime::parse_from_rfc3339("2024-03-10T12:00:00+01:00").unwrap()));
+        let state = PlayerState { resume: true, position: 30.0, duration: Some(60.0), ..state("a.mp4") };
+        let mut player = WallpaperPlayer::new(state, clock);
+        player.state.record_position("a.mp4");
+        assert_eq!(player.state.resume_position("a.mp4"), Some(30.0));
+
+        // Switching it on hides the saved position, and the next record drops it for good
+        player.apply(PlayerCommand::SetAlwaysFromStart { path: "a.mp4".into(), enabled: true }, &mut None);
+        assert_eq!(player.state.resume_position("a.mp4"), None);
+        player.state.record_position("a.mp4");
+        player.apply(PlayerCommand::SetAlwaysFromStart { path: "a.mp4".into(), enabled: false }, &mut None);
+        assert_eq!(player.state.resume_position("a.mp4"), None);
+
+        let cycle = DayCycle { offset_secs: 0.0, stretch: 0.0 };
+        player.apply(PlayerCommand::SetDayCycle { path: "day.mp4".into(), cycle: Some(cycle) }, &mut None);
+        player.state.record_position("day.mp4");
+        assert_eq!(player.state.resume_position("day.mp4"), None);
+        // Even a position saved before it became a day cycle is ignored
+        player.state.positions.record("day.mp4", 30.0, Some(60.0));
+        assert_eq!(player.state.resume_position("day.mp4"), None);
+
+        player.state.record_position("b.mp4");
+        player.state.resume = false;
+        assert_eq!(player.state.resume_position("b.mp4"), None);
     }
 }
@@ -0,0 +1,324 @@
+use crate::config::settings::PlaylistSettings;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaylistOrder {
+    Sequential,
+    /// Every item once, in random order, before any repeats.
+    Shuffle,
+    /// Random, favouring items with a higher weight.
+    Weighted,
+}
+
+impl PlaylistOrder {
+    pub fn from_name(name: &str) -> Self {
+        match name.to_ascii_lowercase().as_str() {
+            "shuffle" => PlaylistOrder::Shuffle,
+            "weighted" => PlaylistOrder::Weighted,
+            _ => PlaylistOrder::Sequential,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PlaylistEntry {
+    pub path: String,
+    /// Overrides the playlist's interval for this item.
+    pub interval: Option<Duration>,
+    pub weight: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Playlist {
+    pub name: String,
+    pub entries: Vec<PlaylistEntry>,
+    pub order: PlaylistOrder,
+    /// Time each item plays for; zero stays on an item until told to move.
+    pub interval: Duration,
+    /// Move on after this many loops instead of on a timer. Zero uses the interval.
+    pub loops: u32,
+}
+
+impl Playlist {
+    pub fn from_settings(settings: &PlaylistSettings) -> Self {
+        Self {
+            name: settings.name.clone(),
+            entries: settings
+                .items
+                .iter()
+                .map(|item| PlaylistEntry {
+                    path: item.path.clone(),
+                    interval: item.interval_secs.map(|secs| Duration::from_secs(secs as u64)),
+                    weight: item.weight,
+                })
+                .collect(),
+            order: PlaylistOrder::from_name(&settings.order),
+            interval: Duration::from_secs(settings.interval_secs as u64),
+            loops: settings.loops,
+        }
+    }
+}
+
+/// Walks a playlist: picks what plays next, remembers what played for `previous`, and
+/// decides when the current item has had its turn.
+pub struct Rotation {
+    playlist: Playlist,
+    current: usize,
+    history: Vec<usize>,
+    /// Items not yet played in this shuffle pass.
+    bag: Vec<usize>,
+    rng: u64,
+}
+
+/// Items remembered for `previous`.
+const HISTORY_LEN: usize = 50;
+
+impl Rotation {
+    /// Start at the first item in play order. `None` for an empty playlist.
+    pub fn new(playlist: Playlist) -> Option<Self> {
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0);
+        Self::with_seed(playlist, seed)
+    }
+
+    /// Like `new`, with a fixed seed so the shuffle and weighted orders are repeatable.
+    pub fn with_seed(playlist: Playlist, seed: u64) -> Option<Self> {
+        if playlist.entries.is_empty() {
+            return None;
+        }
+        let mut rotation = Self {
+            playlist,
+            current: 0,
+            history: Vec::new(),
+            bag: Vec::new(),
+            // xorshift gets stuck on zero
+            rng: seed | 1,
+        };
+        rotation.current = match rotation.playlist.order {
+            PlaylistOrder::Sequential => 0,
+            PlaylistOrder::Shuffle => {
+                rotation.bag = (0..rotation.len()).collect();
+                rotation.draw_from_bag()
+            }
+            PlaylistOrder::Weighted => rotation.pick_weighted(None),
+        };
+        Some(rotation)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.playlist.name
+    }
+
+    pub fn current_path(&self) -> &str {
+        &self.playlist.entries[self.current].path
+    }
+
+    pub fn len(&self) -> usize {
+        self.playlist.entries.len()
+    }
+
+    /// Move on to the next item in play order and return its path.
+    pub fn next(&mut self) -> &str {
+        self.history.push(self.current);
+        if self.history.len() > HISTORY_LEN {
+            self.history.remove(0);
+        }
+        self.current = match self.playlist.order {
+            PlaylistOrder::Sequential => (self.current + 1) % self.len(),
+            PlaylistOrder::Shuffle => self.draw_from_bag(),
+            PlaylistOrder::Weighted => self.pick_weighted(Some(self.current)),
+        };
+        self.current_path()
+    }
+
+    /// Go back to what played before. With no history, sequential steps backwards and the
+    /// random orders stay put.
+    pub fn previous(&mut self) -> &str {
+        match self.history.pop() {
+            Some(index) => self.current = index,
+            None if self.playlist.order == PlaylistOrder::Sequential => {
+                self.current = (self.current + self.len() - 1) % self.len();
+            }
+            None => {}
+        }
+        self.current_path()
+    }
+
+    /// Whether the current item has played long enough, counting only time spent playing.
+    pub fn is_due(&self, played: Duration, loops: u32) -> bool {
+        if self.len() < 2 {
+            return false;
+        }
+        if self.playlist.loops > 0 {
+            return loops >= self.playlist.loops;
+        }
+        let interval = self.playlist.entries[self.current].interval.unwrap_or(self.playlist.interval);
+        !interval.is_zero() && played >= interval
+    }
+
+    fn draw_from_bag(&mut self) -> usize {
+        if self.bag.is_empty() {
+            // Refill without the item on screen so a new pass never starts with a repeat
+            self.bag = (0..self.len()).filter(|&i| i != self.current || self.len() == 1).collect();
+        }
+        let slot = (self.next_random() % self.bag.len() as u64) as usize;
+        self.bag.swap_remove(slot)
+    }
+
+    /// Pick by weight, never `avoid` (the item on screen) when there's a choice.
+    fn pick_weighted(&mut self, avoid: Option<usize>) -> usize {
+        let avoid = avoid.filter(|_| self.len() > 1);
+        let weights: Vec<u64> = self
+            .playlist
+            .entries
+            .iter()
+            .enumerate()
+            .map(|(i, e)| if Some(i) == avoid { 0 } else { e.weight as u64 })
+            .collect();
+        let total: u64 = weights.iter().sum();
+        if total == 0 {
+            return (self.current + 1) % self.len();
+        }
+        let mut target = self.next_random() % total;
+        for (i, weight) in weights.iter().enumerate() {
+            if target < *weight {
+                return i;
+            }
+            target -= weight;
+        }
+        self.len() - 1
+    }
+
+    fn next_random(&mut self) -> u64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn playlist(order: PlaylistOrder, weights: &[u32]) -> Playlist {
+        Playlist {
+            name: "test".into(),
+            entries: weights
+                .iter()
+                .enumerate()
+                .map(|(i, &weight)| PlaylistEntry { path: format!("{}.mp4", i), interval: None, weight })
+                .collect(),
+            order,
+            interval: Duration::from_secs(300),
+            loops: 0,
+        }
+    }
+
+    fn index(rotation: &Rotation) -> usize {
+        rotation.current_path().trim_end_matches(".mp4").parse().unwrap()
+    }
+
+    #[test]
+    fn sequential_wraps_both_ways() {
+        let mut rotation = Rotation::with_seed(playlist(PlaylistOrder::Sequential, &[1, 1, 1]), 7).unwrap();
+        assert_eq!(rotation.current_path(), "0.mp4");
+        let order: Vec<String> = (0..4).map(|_| rotation.next().to_string()).collect();
+        assert_eq!(order, ["1.mp4", "2.mp4", "0.mp4", "1.mp4"]);
+
+        // Back through the history, then stepping backwards past its start
+        let mut fresh = Rotation::with_seed(playlist(PlaylistOrder::Sequential, &[1, 1, 1]), 7).unwrap();
+        fresh.next();
+        assert_eq!(fresh.previous(), "0.mp4");
+        assert_eq!(fresh.previous(), "2.mp4");
+    }
+
+    #[test]
+    fn shuffle_covers_everything_once_per_pass_and_never_repeats_the_current_item() {
+        for seed in 0..50 {
+            let mut rotation = Rotation::with_seed(playlist(PlaylistOrder::Shuffle, &[1; 5]), seed).unwrap();
+            let mut pass = vec![index(&rotation)];
+            for _ in 0..4 {
+                rotation.next();
+                pass.push(index(&rotation));
+            }
+            pass.sort();
+            assert_eq!(pass, [0, 1, 2, 3, 4], "seed {}", seed);
+
+            let mut last = index(&rotation);
+            for _ in 0..100 {
+                rotation.next();
+                assert_ne!(index(&rotation), last, "seed {}", seed);
+                last = index(&rotation);
+            }
+        }
+    }
+
+    #[test]
+    fn weighted_follows_the_weights_and_skips_weight_zero() {
+        let mut rotation = Rotation::with_seed(playlist(PlaylistOrder::Weighted, &[1, 3, 0, 6]), 42).unwrap();
+        let mut counts = [0u32; 4];
+        let mut last = index(&rotation);
+        for _ in 0..20_000 {
+            rotation.next();
+            let current = index(&rotation);
+            assert_ne!(current, last);
+            counts[current] += 1;
+            last = current;
+        }
+        assert_eq!(counts[2], 0);
+        // Never repeating the item on screen flattens the weights a little, so only check the order
+        assert!(counts[3] > counts[1] && counts[1] > counts[0], "{:?}", counts);
+        assert!(counts[0] > 0);
+    }
+
+    #[test]
+    fn weighted_with_nothing_else_to_pick_moves_on_in_order() {
+        let mut rotation = Rotation::with_seed(playlist(PlaylistOrder::Weighted, &[0, 0, 0]), 3).unwrap();
+        let start = index(&rotation);
+        rotation.next();
+        assert_eq!(index(&rotation), (start + 1) % 3);
+    }
+
+    #[test]
+    fn an_item_interval_overrides_the_playlist_interval() {
+        let mut list = playlist(PlaylistOrder::Sequential, &[1, 1]);
+        list.entries[1].interval = Some(Duration::from_secs(30));
+        let mut rotation = Rotation::with_seed(list, 1).unwrap();
+        assert!(!rotation.is_due(Duration::from_secs(299), 0));
+        assert!(rotation.is_due(Duration::from_secs(300), 0));
+
+        rotation.next();
+        assert!(!rotation.is_due(Duration::from_secs(29), 0));
+        assert!(rotation.is_due(Duration::from_secs(30), 0));
+    }
+
+    #[test]
+    fn a_zero_interval_waits_to_be_told() {
+        let mut list = playlist(PlaylistOrder::Sequential, &[1, 1]);
+        list.interval = Duration::ZERO;
+        let rotation = Rotation::with_seed(list, 1).unwrap();
+        assert!(!rotation.is_due(Duration::from_secs(86_400), 0));
+    }
+
+    #[test]
+    fn loops_take_precedence_over_the_interval() {
+        let mut list = playlist(PlaylistOrder::Sequential, &[1, 1]);
+        list.loops = 3;
+        list.entries[0].interval = Some(Duration::from_secs(10));
+        let rotation = Rotation::with_seed(list, 1).unwrap();
+        assert!(!rotation.is_due(Duration::from_secs(3600), 2));
+        assert!(rotation.is_due(Duration::ZERO, 3));
+    }
+
+    #[test]
+    fn empty_and_single_item_playlists() {
+        assert!(Rotation::with_seed(playlist(PlaylistOrder::Sequential, &[]), 1).is_none());
+
+        for order in [PlaylistOrder::Sequential, PlaylistOrder::Shuffle, PlaylistOrder::Weighted] {
+            let mut rotation = Rotation::with_seed(playlist(order, &[1]), 1).unwrap();
+            assert_eq!(rotation.next(), "0.mp4");
+            assert_eq!(rotation.previous(), "0.mp4");
+            assert!(!rotation.is_due(Duration::from_secs(3600), 100), "{:?}", order);
+        }
+    }
+}
@@ -0,0 +1,457 @@
+use crate::wallpaper::stream::Backoff;
+use anyhow::{Context, Result};
+use ffmpeg_next as ffmpeg;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use windows::Win32::System::Threading::{GetCurrentThread, SetThreadPriority, THREAD_MODE_BACKGROUND_BEGIN};
+
+/// Encoders tried in order: x264 if the FFmpeg build has it, then Media Foundation, then the built-in MPEG-4.
+const PROXY_ENCODERS: &[&str] = &["libx264", "h264_mf", "mpeg4"];
+
+/// A failed transcode is tried again after 1s, 2s, 4s ... up to this.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProxyState {
+    Running(f32),
+    Ready(PathBuf),
+    Failed(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct ProxyProgress {
+    pub source: String,
+    pub state: ProxyState,
+}
+
+struct Job {
+    progress: ProxyProgress,
+    /// Set to stop the transcode at its next packet.
+    cancel: Arc<AtomicBool>,
+    /// When a failed job may run again; carried over to the retry so the delay keeps growing.
+    retry: Option<Backoff>,
+}
+
+/// Background transcoder for sources too heavy to decode at full size on every frame.
+/// Proxies live in the cache dir and are keyed on the source file and target size,
+/// so editing the source or changing resolution produces a fresh one.
+#[derive(Clone)]
+pub struct ProxyManager {
+    cache_dir: PathBuf,
+    jobs: Arc<Mutex<HashMap<PathBuf, Job>>>,
+}
+
+impl ProxyManager {
+    pub fn new() -> Self {
+        let mut cache_dir = dirs::cache_dir().unwrap_or_else(std::env::temp_dir);
+        cache_dir.push("Mew");
+        cache_dir.push("proxies");
+        Self {
+            cache_dir,
+            jobs: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Returns the proxy to play for `source`, or None to play the source itself.
+    /// Starts a background transcode the first time a heavy source is seen, and again once
+    /// a failed one's retry is due.
+    pub async fn resolve(&self, source: &str, target: (u32, u32)) -> Option<PathBuf> {
+        let proxy = match self.proxy_path(Path::new(source), target) {
+            Ok(p) => p,
+            Err(e) => {
+                tracing::warn!("Proxy lookup failed for {}: {}", source, e);
+                return None;
+            }
+        };
+        if proxy.exists() {
+            return Some(proxy);
+        }
+
+        let retry = {
+            let mut jobs = self.jobs.lock().unwrap();
+            match jobs.get(&proxy) {
+                Some(job) if !matches!(job.progress.state, ProxyState::Failed(_)) => return None,
+                Some(job) if !job.retry.as_ref().is_some_and(|retry| retry.is_due(Instant::now())) => return None,
+                _ => jobs.remove(&proxy).and_then(|job| job.retry),
+            }
+        };
+
+        // Probing opens the file and its decoder, so keep it off the async threads
+        let probed = source.to_string();
+        let heavy = tokio::task::spawn_blocking(move || is_heavy(Path::new(&probed), target))
+            .await
+            .unwrap_or_else(|e| Err(e.into()));
+        match heavy {
+            Ok(true) => self.spawn(source.to_string(), proxy, target, retry),
+            Ok(false) => {}
+            Err(e) => tracing::debug!("Could not probe {} for proxying: {}", source, e),
+        }
+        None
+    }
+
+    /// The finished proxy for `source`, once its job completes.
+    pub fn ready(&self, source: &str) -> Option<PathBuf> {
+        self.jobs.lock().unwrap().values().find_map(|job| match &job.progress.state {
+            ProxyState::Ready(path) if job.progress.source == source => Some(path.clone()),
+            _ => None,
+        })
+    }
+
+    /// The job for `source` in whatever state it's in.
+    pub fn job(&self, source: &str) -> Option<ProxyProgress> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .values()
+            .find(|job| job.progress.source == source)
+            .map(|job| job.progress.clone())
+    }
+
+    /// Stop transcoding anything but `keep`, e.g. when another wallpaper is loaded. Stopped
+    /// jobs are forgotten, so the source starts over if it comes back.
+    pub fn cancel_except(&self, keep: &str) {
+        self.jobs.lock().unwrap().retain(|_, job| {
+            let running = matches!(job.progress.state, ProxyState::Running(_));
+            if running && job.progress.source != keep {
+                tracing::info!("Cancelling proxy transcode for {}", job.progress.source);
+                job.cancel.store(true, Ordering::Relaxed);
+                return false;
+            }
+            true
+        });
+    }
+
+    fn proxy_path(&self, source: &Path, target: (u32, u32)) -> Result<PathBuf> {
+        let meta = std::fs::metadata(source).context("Source not found")?;
+        let modified = meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        let source_key = fnv1a(source.to_string_lossy().as_bytes());
+        let version_key = fnv1a(format!("{}:{}:{}x{}", meta.len(), modified, target.0, target.1).as_bytes());
+        Ok(self.cache_dir.join(format!("{:016x}-{:016x}.mkv", source_key, version_key)))
+    }
+
+    fn spawn(&self, source: String, proxy: PathBuf, target: (u32, u32), retry: Option<Backoff>) {
+        tracing::info!("Building {}x{} proxy for {} -> {:?}", target.0, target.1, source, proxy);
+        let cancel = Arc::new(AtomicBool::new(false));
+        self.jobs.lock().unwrap().insert(
+            proxy.clone(),
+            Job {
+                progress: ProxyProgress { source: source.clone(), state: ProxyState::Running(0.0) },
+                cancel: cancel.clone(),
+                retry,
+            },
+        );
+
+        let jobs = self.jobs.clone();
+        let cache_dir = self.cache_dir.clone();
+        std::thread::spawn(move || {
+            // Background mode also lowers I/O and memory priority, so playback never waits on us
+            unsafe {
+                let _ = SetThreadPriority(GetCurrentThread(), THREAD_MODE_BACKGROUND_BEGIN);
+            }
+
+            // A cancelled job may already have been replaced by a new one for the same proxy
+            let update = |f: &mut dyn FnMut(&mut Job)| {
+                if let Some(job) = jobs.lock().unwrap().get_mut(&proxy).filter(|job| Arc::ptr_eq(&job.cancel, &cancel)) {
+                    f(job);
+                }
+            };
+            let report = |fraction: f32| update(&mut |job| job.progress.state = ProxyState::Running(fraction.clamp(0.0, 1.0)));
+
+            let result = std::fs::create_dir_all(&cache_dir)
+                .map_err(anyhow::Error::from)
+                .and_then(|_| transcode(Path::new(&source), &proxy, target, &report, &cancel));
+
+            match result {
+                Ok(()) => {
+                    tracing::info!("Proxy ready: {:?}", proxy);
+                    remove_stale(&cache_dir, &proxy);
+                    update(&mut |job| job.progress.state = ProxyState::Ready(proxy.clone()));
+                }
+                Err(_) if cancel.load(Ordering::Relaxed) => tracing::info!("Proxy transcode for {} cancelled", source),
+                Err(e) => update(&mut |job| {
+                    let now = Instant::now();
+                    let delay = job.retry.get_or_insert_with(|| Backoff::new(MAX_RETRY_DELAY, now)).fail(now);
+                    tracing::error!("Proxy transcode failed for {}: {:#}. Trying again in {:?}", source, e, delay);
+                    job.progress.state = ProxyState::Failed(e.to_string());
+                }),
+            }
+        });
+    }
+}
+
+/// Stable across builds (unlike `DefaultHasher`), so cache names survive upgrades.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325u64, |hash, b| (hash ^ *b as u64).wrapping_mul(0x100000001b3))
+}
+
+/// Delete older proxies of the same source (previous source version or resolution).
+fn remove_stale(cache_dir: &Path, keep: &Path) {
+    let Some(name) = keep.file_name().and_then(|n| n.to_str()) else { return };
+    let Some((source_key, _)) = name.split_once('-') else { return };
+
+    if let Ok(entries) = std::fs::read_dir(cache_dir) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let stale = path != keep
+                && path
+                    .file_name()
+                    .and_then(|n| n.to_str())
+                    .is_some_and(|n| n.starts_with(&format!("{}-", source_key)));
+            if stale {
+                tracing::info!("Removing stale proxy {:?}", path);
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+}
+
+/// Worth proxying when decode+downscale does far more work than the screen needs.
+fn is_heavy(source: &Path, target: (u32, u32)) -> Result<bool> {
+    ffmpeg::init()?;
+    let ictx = ffmpeg::format::input(&source)?;
+    let stream = ictx
+        .streams()
+        .best(ffmpeg::media::Type::Video)
+        .ok_or_else(|| anyhow::anyhow!("No video stream found"))?;
+    let decoder = ffmpeg::codec::context::Context::from_parameters(stream.parameters())?
+        .decoder()
+        .video()?;
+
+    let source_pixels = decoder.width() as u64 * decoder.height() as u64;
+    let target_pixels = target.0 as u64 * target.1 as u64;
+    let intra_heavy = matches!(decoder.id(), ffmpeg::codec::Id::PRORES | ffmpeg::codec::Id::HEVC);
+
+    Ok(source_pixels > target_pixels * 2 || (intra_heavy && source_pixels > target_pixels))
+}
+
+fn transcode(source: &Path, dest: &Path, target: (u32, u32), progress: &dyn Fn(f32), cancel: &AtomicBool) -> Result<()> {
+    ffmpeg::init()?;
+    let mut ictx = ffmpeg::format::input(&source).context("Failed to open source")?;
+    let total_secs = ictx.duration() as f64 / 1_000_000.0;
+
+    let (video_index, video_tb, frame_rate, video_params) = {
+        let ist = ictx
+            .streams()
+            .best(ffmpeg::media::Type::Video)
+            .ok_or_else(|| anyhow::anyhow!("No video stream found"))?;
+        (ist.index(), ist.time_base(), ist.avg_frame_rate(), ist.parameters())
+    };
+    let audio_in = ictx
+        .streams()
+        .best(ffmpeg::media::Type::Audio)
+        .map(|ist| (ist.index(), ist.time_base(), ist.parameters()));
+
+    let mut decoder = ffmpeg::codec::context::Context::from_parameters(video_params)?
+        .decoder()
+        .video()?;
+
+    // Even dimensions keep 4:2:0 encoders happy.
+    let (width, height) = (target.0 & !1, target.1 & !1);
+    let rate = if frame_rate.numerator() > 0 && frame_rate.denominator() > 0 {
+        frame_rate
+    } else {
+        ffmpeg::Rational::new(30, 1)
+    };
+    let encoder_tb = rate.invert();
+
+    let codec = PROXY_ENCODERS
+        .iter()
+        .find_map(|name| ffmpeg::encoder::find_by_name(name))
+        .ok_or_else(|| anyhow::anyhow!("No suitable video encoder in this FFmpeg build"))?;
+
+    let tmp = dest.with_extension("part.mkv");
+    let mut octx = ffmpeg::format::output(&tmp).context("Failed to create proxy file")?;
+    let global_header = octx.format().flags().contains(ffmpeg::format::Flags::GLOBAL_HEADER);
+
+    let mut encoder_ctx = ffmpeg::codec::context::Context::new_with_codec(codec).encoder().video()?;
+    encoder_ctx.set_width(width);
+    encoder_ctx.set_height(height);
+    encoder_ctx.set_format(ffmpeg::format::Pixel::YUV420P);
+    encoder_ctx.set_time_base(encoder_tb);
+    encoder_ctx.set_frame_rate(Some(rate));
+    encoder_ctx.set_gop((f64::from(rate) * 2.0).round().max(1.0) as u32);
+    if global_header {
+        encoder_ctx.set_flags(ffmpeg::codec::Flags::GLOBAL_HEADER);
+    }
+
+    let mut options = ffmpeg::Dictionary::new();
+    if codec.name() == "libx264" {
+        options.set("preset", "veryfast");
+        options.set("crf", "20");
+    } else {
+        // Roughly 0.15 bits per pixel, plenty for a screen-sized loop.
+        encoder_ctx.set_bit_rate((width as f64 * height as f64 * f64::from(rate) * 0.15) as usize);
+    }
+    let mut encoder = encoder_ctx.open_as_with(codec, options).context("Failed to open proxy encoder")?;
+
+    let video_out = {
+        let mut ost = octx.add_stream(codec)?;
+        ost.set_parameters(&encoder);
+        ost.index()
+    };
+    // Audio is stream-copied so proxies keep their soundtrack.
+    let audio_out = match audio_in {
+        Some((index, tb, params)) => {
+            let mut ost = octx.add_stream(ffmpeg::encoder::find(ffmpeg::codec::Id::None))?;
+            ost.set_parameters(params);
+            Some((index, tb, ost.index()))
+        }
+        None => None,
+    };
+
+    octx.write_header()?;
+
+    let mut scaler = ffmpeg::software::scaling::Context::get(
+        decoder.format(),
+        decoder.width(),
+        decoder.height(),
+        ffmpeg::format::Pixel::YUV420P,
+        width,
+        height,
+        ffmpeg::software::scaling::Flags::AREA,
+    )?;
+
+    let mut frame_index = 0i64;
+    let mut decoded = ffmpeg::util::frame::Video::empty();
+    let mut scaled = ffmpeg::util::frame::Video::empty();
+
+    for (stream, mut packet) in ictx.packets() {
+        if cancel.load(Ordering::Relaxed) {
+            drop(octx);
+            let _ = std::fs::remove_file(&tmp);
+            anyhow::bail!("Cancelled");
+        }
+        if stream.index() == video_index {
+            if decoder.send_packet(&packet).is_err() {
+                // Damaged packet: skip it, the proxy just loses that frame.
+                continue;
+            }
+            while decoder.receive_frame(&mut decoded).is_ok() {
+                scaler.run(&decoded, &mut scaled)?;
+                scaled.set_pts(Some(frame_index));
+                frame_index += 1;
+                encoder.send_frame(&scaled)?;
+                write_encoded(&mut encoder, &mut octx, video_out, encoder_tb)?;
+
+                if total_secs > 0.0 {
+                    let secs = decoded.timestamp().unwrap_or(0) as f64 * f64::from(video_tb);
+                    progress((secs / total_secs) as f32);
+                }
+            }
+        } else if let Some((index, tb, out_index)) = audio_out {
+            if stream.index() == index {
+                let out_tb = octx.stream(out_index).map(|s| s.time_base()).unwrap_or(tb);
+                packet.rescale_ts(tb, out_tb);
+                packet.set_position(-1);
+                packet.set_stream(out_index);
+                packet.write_interleaved(&mut octx)?;
+            }
+        }
+    }
+
+    decoder.send_eof()?;
+    while decoder.receive_frame(&mut decoded).is_ok() {
+        scaler.run(&decoded, &mut scaled)?;
+        scaled.set_pts(Some(frame_index));
+        frame_index += 1;
+        encoder.send_frame(&scaled)?;
+        write_encoded(&mut encoder, &mut octx, video_out, encoder_tb)?;
+    }
+    encoder.send_eof()?;
+    write_encoded(&mut encoder, &mut octx, video_out, encoder_tb)?;
+    octx.write_trailer()?;
+    drop(octx);
+
+    if frame_index == 0 {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(anyhow::anyhow!("Source produced no frames"));
+    }
+    std::fs::rename(&tmp, dest).context("Failed to move proxy into place")?;
+    progress(1.0);
+    Ok(())
+}
+
+fn write_encoded(
+    encoder: &mut ffmpeg::encoder::video::Encoder,
+    octx: &mut ffmpeg::format::context::Output,
+    stream_index: usize,
+    encoder_tb: ffmpeg::Rational,
+) -> Result<()> {
+    let out_tb = octx.stream(stream_index).map(|s| s.time_base()).unwrap_or(encoder_tb);
+    let mut packet = ffmpeg::Packet::empty();
+    while encoder.receive_packet(&mut packet).is_ok() {
+        packet.set_stream(stream_index);
+        packet.rescale_ts(encoder_tb, out_tb);
+        packet.write_interleaved(octx)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wallpaper::fixtures::TempFile;
+
+    fn manager() -> ProxyManager {
+        ProxyManager {
+            cache_dir: std::env::temp_dir().join(format!("mew-{}-proxies", std::process::id())),
+            jobs: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn job(source: &str, state: ProxyState, retry: Option<Backoff>) -> Job {
+        Job {
+            progress: ProxyProgress { source: source.to_string(), state },
+            cancel: Arc::new(AtomicBool::new(false)),
+            retry,
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_job_waits_for_its_retry() {
+        let file = TempFile::new("heavy.mp4");
+        std::fs::write(&file, b"not really a video").unwrap();
+        let source = file.to_string_lossy().into_owned();
+        let manager = manager();
+        let proxy = manager.proxy_path(Path::new(&source), (1920, 1080)).unwrap();
+
+        let mut retry = Backoff::new(MAX_RETRY_DELAY, Instant::now());
+        retry.fail(Instant::now());
+        manager.jobs.lock().unwrap().insert(proxy, job(&source, ProxyState::Failed("no encoder".into()), Some(retry)));
+
+        // Not due for another second, so nothing is started and the failure stays visible
+        assert_eq!(manager.resolve(&source, (1920, 1080)).await, None);
+        let progress = manager.job(&source).unwrap();
+        assert_eq!(progress.state, ProxyState::Failed("no encoder".into()));
+        assert_eq!(manager.jobs.lock().unwrap().values().next().unwrap().retry.as_ref().unwrap().attempt(), 1);
+    }
+
+    #[test]
+    fn loading_another_wallpaper_cancels_its_transcode() {
+        let manager = manager();
+        let outgoing = job("old.mp4", ProxyState::Running(0.4), None);
+        let cancelled = outgoing.cancel.clone();
+        {
+            let mut jobs = manager.jobs.lock().unwrap();
+            jobs.insert("old.mkv".into(), outgoing);
+            jobs.insert("new.mkv".into(), job("new.mp4", ProxyState::Running(0.1), None));
+            jobs.insert("done.mkv".into(), job("done.mp4", ProxyState::Ready("done.mkv".into()), None));
+        }
+
+        manager.cancel_except("new.mp4");
+        assert!(cancelled.load(Ordering::Relaxed));
+        assert!(manager.job("old.mp4").is_none());
+        assert!(manager.job("new.mp4").is_some());
+        // Finished proxies stay usable
+        assert_eq!(manager.ready("done.mp4"), Some(PathBuf::from("done.mkv")));
+    }
+}
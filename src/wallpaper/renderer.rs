@@ -7,10 +7,23 @@ use windows::Win32::System::LibraryLoader::*;
 use windows::core::{PCWSTR, Interface};
 use anyhow::Result;
 use ffmpeg_next as ffmpeg;
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc, OnceLock};
+use crate::clock::Clock;
+use crate::wallpaper::frame::{Frame, PixelFormat};
+use crate::wallpaper::surface::Surface;
+use crate::wallpaper::video_processor::{self, VideoProcessor};
+use crate::wallpaper::watchdog::Beat;
+
+/// The icon layer's background and text background colours before we made them
+/// transparent, captured once so a recreated renderer doesn't record its own change.
+static ICON_LAYER_COLOURS: OnceLock<(isize, isize)> = OnceLock::new();
+
+const LVM_GETBKCOLOR: u32 = 0x1000;
+const LVM_SETBKCOLOR: u32 = 0x1001;
+const LVM_GETTEXTBKCOLOR: u32 = 0x1025;
+const LVM_SETTEXTBKCOLOR: u32 = 0x1026;
 
 pub struct WallpaperRenderer {
-    #[allow(dead_code)]
     hwnd: HWND,
     parent_workerw: HWND,
     device: ID3D11Device,
@@ -19,6 +32,12 @@ pub struct WallpaperRenderer {
     texture_cache: Option<ID3D11Texture2D>,
     texture_size: (u32, u32),
     physical_size: (u32, u32),
+    accepted_formats: Vec<PixelFormat>,
+    video_processor: Option<VideoProcessor>,
+    /// Bumped by the window thread on every message, so a dead or hung thread shows up.
+    window_beat: Beat,
+    /// What the window thread stamps its beat with, kept for rebuilding.
+    clock: Arc<dyn Clock>,
 }
 
 // Safety: HWND is a handle that can be passed between threads on Windows.
@@ -27,8 +46,11 @@ unsafe impl Send for WallpaperRenderer {}
 unsafe impl Sync for WallpaperRenderer {}
 
 impl WallpaperRenderer {
-    pub fn new() -> Result<Self> {
+    pub fn new(clock: Arc<dyn Clock>) -> Result<Self> {
         let (tx, rx) = mpsc::channel::<Result<(isize, isize, i32, i32)>>();
+        let window_beat = Beat::default();
+        let thread_beat = window_beat.clone();
+        let thread_clock = clock.clone();
 
         // Spawn a dedicated thread for the window and its message loop
         // This ensures interactions never block the high-precision render loop
@@ -93,8 +115,13 @@ impl WallpaperRenderer {
                 // CRITICAL: Make SysListView32 background transparent so icons float over wallpaper
                 if !syslistview.0.is_null() {
                     unsafe {
-                        SendMessageW(syslistview, 0x1026, WPARAM(0), LPARAM(0xFFFFFFFF)); // LVM_SETTEXTBKCOLOR = CLR_NONE
-                        SendMessageW(syslistview, 0x1001, WPARAM(0), LPARAM(0xFFFFFFFF)); // LVM_SETBKCOLOR = CLR_NONE
+                        ICON_LAYER_COLOURS.get_or_init(|| {
+                            let bk = SendMessageW(syslistview, LVM_GETBKCOLOR, WPARAM(0), LPARAM(0)).0;
+                            let text_bk = SendMessageW(syslistview, LVM_GETTEXTBKCOLOR, WPARAM(0), LPARAM(0)).0;
+                            (bk, text_bk)
+                        });
+                        SendMessageW(syslistview, LVM_SETTEXTBKCOLOR, WPARAM(0), LPARAM(0xFFFFFFFF)); // CLR_NONE
+                        SendMessageW(syslistview, LVM_SETBKCOLOR, WPARAM(0), LPARAM(0xFFFFFFFF)); // CLR_NONE
                         tracing::info!("Set SysListView32 background to CLR_NONE (transparent)");
                     }
                 }
@@ -154,8 +181,8 @@ impl WallpaperRenderer {
                 // Ensure DefView doesn't have an opaque background
                 if !syslistview.0.is_null() {
                     unsafe {
-                        SendMessageW(syslistview, 0x1026, WPARAM(0), LPARAM(0xFFFFFFFF)); // LVM_SETTEXTBKCOLOR = CLR_NONE
-                        SendMessageW(syslistview, 0x1001, WPARAM(0), LPARAM(0xFFFFFFFF)); // LVM_SETBKCOLOR = CLR_NONE
+                        SendMessageW(syslistview, LVM_SETTEXTBKCOLOR, WPARAM(0), LPARAM(0xFFFFFFFF)); // CLR_NONE
+                        SendMessageW(syslistview, LVM_SETBKCOLOR, WPARAM(0), LPARAM(0xFFFFFFFF)); // CLR_NONE
                         
                         // Force Redraw of the desktop
                         let _ = windows::Win32::Graphics::Gdi::InvalidateRect(syslistview, None, BOOL(1));
@@ -175,11 +202,14 @@ impl WallpaperRenderer {
             })();
 
             match res {
-                Ok(_) => {
-                    // Message loop for our window
+                Ok(hwnd_val) => {
+                    // Message loop for our window. The timer keeps messages coming so the beat never goes quiet while healthy.
                     unsafe {
+                        SetTimer(HWND(hwnd_val as *mut _), 1, 1000, None);
                         let mut msg = MSG::default();
+                        thread_beat.tick(thread_clock.now());
                         while GetMessageW(&mut msg, HWND::default(), 0, 0).as_bool() {
+                            thread_beat.tick(thread_clock.now());
                             let _ = TranslateMessage(&msg);
                             DispatchMessageW(&msg);
                         }
@@ -237,6 +267,10 @@ impl WallpaperRenderer {
         let context = context.ok_or_else(|| anyhow::anyhow!("Failed to create D3D11 context"))?;
         let swapchain = swapchain.ok_or_else(|| anyhow::anyhow!("Failed to create D3D11 swapchain"))?;
 
+        let mut accepted_formats = video_processor::supported_formats(&device, (width as u32, height as u32));
+        accepted_formats.push(PixelFormat::Bgra);
+        tracing::info!("Renderer accepts {:?}", accepted_formats);
+
         Ok(Self { 
             hwnd, 
             parent_workerw, 
@@ -246,6 +280,10 @@ impl WallpaperRenderer {
             texture_cache: None, 
             texture_size: (0, 0),
             physical_size: (width as u32, height as u32),
+            accepted_formats,
+            video_processor: None,
+            window_beat,
+            clock,
         })
     }
 
@@ -255,7 +293,26 @@ impl WallpaperRenderer {
         self.physical_size
     }
 
-    pub fn render_frame(&mut self, data: &[u8], width: u32, height: u32) -> Result<()> {
+    /// Pixel formats `render_frame` takes, best first.
+    pub fn accepted_formats(&self) -> &[PixelFormat] {
+        &self.accepted_formats
+    }
+
+    pub fn render_frame(&mut self, frame: &Frame) -> Result<()> {
+        let (width, height) = (frame.width(), frame.height());
+        if !self.accepted_formats.contains(&frame.format()) {
+            return Err(anyhow::anyhow!("Unsupported frame format {:?}", frame.format()));
+        }
+        if frame.format().is_yuv() {
+            return self.render_yuv(frame);
+        }
+        // Rows are `stride` apart, not `width * 4`: FFmpeg pads lines for SIMD alignment.
+        let stride = frame.stride(0);
+        let data = frame.plane(0);
+        if height == 0 || stride < width as usize * 4 || data.len() < stride * (height as usize - 1) + width as usize * 4 {
+            return Err(anyhow::anyhow!("Frame buffer too small for {}x{} (stride {})", width, height, stride));
+        }
+
         unsafe {
             // Verify parent still exists (Shell might have restarted)
             if !IsWindow(self.parent_workerw).as_bool() {
@@ -300,7 +357,7 @@ impl WallpaperRenderer {
 
             let texture = self.texture_cache.as_ref().unwrap();
             let resource: ID3D11Resource = texture.cast()?;
-            self.context.UpdateSubresource(&resource, 0, None, data.as_ptr() as *const _, width * 4, 0);
+            self.context.UpdateSubresource(&resource, 0, None, data.as_ptr() as *const _, stride as u32, 0);
 
             let back_buffer: ID3D11Texture2D = self.swapchain.GetBuffer(0)?;
             self.context.CopyResource(&back_buffer, texture);
@@ -311,6 +368,73 @@ impl WallpaperRenderer {
         }
         Ok(())
     }
+
+    /// NV12/P010 go through the GPU video processor, which converts and scales straight into the back buffer.
+    fn render_yuv(&mut self, frame: &Frame) -> Result<()> {
+        unsafe {
+            if !IsWindow(self.parent_workerw).as_bool() {
+                return Err(anyhow::anyhow!("Parent WorkerW was lost. Shell may have restarted."));
+            }
+        }
+
+        let input_size = (frame.width(), frame.height());
+        let output_size = self.physical_size;
+        if !self.video_processor.as_ref().is_some_and(|vp| vp.matches(input_size, output_size)) {
+            unsafe {
+                let sc_desc = self.swapchain.GetDesc()?;
+                if (sc_desc.BufferDesc.Width, sc_desc.BufferDesc.Height) != output_size {
+                    self.context.ClearState();
+                    self.context.Flush();
+                    self.swapchain.ResizeBuffers(0, output_size.0, output_size.1, windows::Win32::Graphics::Dxgi::Common::DXGI_FORMAT_UNKNOWN, DXGI_SWAP_CHAIN_FLAG(0))?;
+                }
+            }
+            self.video_processor = Some(VideoProcessor::new(&self.device, &self.context, input_size, output_size)?);
+            tracing::info!("Created video processor: {}x{} -> {}x{}", input_size.0, input_size.1, output_size.0, output_size.1);
+        }
+
+        unsafe {
+            let back_buffer: ID3D11Texture2D = self.swapchain.GetBuffer(0)?;
+            self.video_processor.as_mut().unwrap().process(&self.context, frame, &back_buffer)?;
+            let _ = self.swapchain.Present(1, windows::Win32::Graphics::Dxgi::DXGI_PRESENT(0));
+        }
+        Ok(())
+    }
+
+    pub fn window_beat(&self) -> Beat {
+        self.window_beat.clone()
+    }
+
+    /// Show or hide the wallpaper window, uncovering the plain desktop behind it.
+    pub fn set_visible(&self, visible: bool) {
+        unsafe {
+            let _ = ShowWindowAsync(self.hwnd, if visible { SW_SHOWNOACTIVATE } else { SW_HIDE });
+        }
+    }
+
+    /// Take the wallpaper window down and put the desktop back the way it was.
+    pub fn close(self) {
+        self.close_window();
+        restore_desktop();
+    }
+
+    /// Ask the window thread to destroy the window; its message loop ends on WM_DESTROY.
+    fn close_window(&self) {
+        unsafe {
+            let _ = PostMessageW(self.hwnd, WM_CLOSE, WPARAM(0), LPARAM(0));
+        }
+    }
+
+    /// Fill the wallpaper with a single RGB colour (fallback when nothing can be decoded).
+    pub fn render_solid(&mut self, rgb: [u8; 3]) -> Result<()> {
+        let (width, height) = self.physical_size;
+        let pixel = [rgb[2], rgb[1], rgb[0], 255];
+        let mut frame = Frame::new(PixelFormat::Bgra, width, height);
+        // Row padding gets painted too, which is harmless.
+        for dst in frame.plane_mut(0).chunks_exact_mut(4) {
+            dst.copy_from_slice(&pixel);
+        }
+        self.render_frame(&frame)
+    }
     
 }
 
@@ -320,10 +444,49 @@ unsafe extern "system" fn wnd_proc(hwnd: HWND, msg: u32, wparam: WPARAM, lparam:
         WM_MOUSEACTIVATE => LRESULT(MA_NOACTIVATE as isize), // Don't activate, let input go to shell
         WM_SETCURSOR => LRESULT(1), // Handle cursor ourselves (hidden/pass-through)
         WM_ERASEBKGND => LRESULT(1), // Don't erase, we handle painting
+        WM_DESTROY => {
+            PostQuitMessage(0);
+            LRESULT(0)
+        }
         _ => DefWindowProcW(hwnd, msg, wparam, lparam),
     }
 }
 
+/// Undo what creating a renderer did to the shell: give the icon layer its own background
+/// back and have Explorer repaint the user's wallpaper. Safe to call with no renderer.
+pub fn restore_desktop() {
+    unsafe {
+        let syslistview = find_icon_layer();
+        if let (false, Some(&(bk, text_bk))) = (syslistview.0.is_null(), ICON_LAYER_COLOURS.get()) {
+            SendMessageW(syslistview, LVM_SETBKCOLOR, WPARAM(0), LPARAM(bk));
+            SendMessageW(syslistview, LVM_SETTEXTBKCOLOR, WPARAM(0), LPARAM(text_bk));
+            let _ = windows::Win32::Graphics::Gdi::InvalidateRect(syslistview, None, BOOL(1));
+        }
+
+        // Setting the wallpaper to itself makes Explorer redraw it over whatever we left behind
+        let mut wallpaper = [0u16; 260];
+        if SystemParametersInfoW(SPI_GETDESKWALLPAPER, wallpaper.len() as u32, Some(wallpaper.as_mut_ptr() as *mut _), SYSTEM_PARAMETERS_INFO_UPDATE_FLAGS(0)).is_ok() {
+            let _ = SystemParametersInfoW(SPI_SETDESKWALLPAPER, 0, Some(wallpaper.as_mut_ptr() as *mut _), SPIF_SENDCHANGE);
+        }
+        tracing::info!("Desktop restored.");
+    }
+}
+
+/// SysListView32, the window holding the desktop icons, under Progman or a WorkerW.
+unsafe fn find_icon_layer() -> HWND {
+    let progman = FindWindowW(windows::core::w!("Progman"), None).unwrap_or_default();
+    let mut defview = FindWindowExW(progman, HWND::default(), windows::core::w!("SHELLDLL_DefView"), PCWSTR::null()).unwrap_or_default();
+    let mut current = HWND::default();
+    while defview.0.is_null() {
+        current = FindWindowExW(HWND::default(), current, windows::core::w!("WorkerW"), PCWSTR::null()).unwrap_or_default();
+        if current.0.is_null() {
+            return HWND::default();
+        }
+        defview = FindWindowExW(current, HWND::default(), windows::core::w!("SHELLDLL_DefView"), PCWSTR::null()).unwrap_or_default();
+    }
+    FindWindowExW(defview, HWND::default(), windows::core::w!("SysListView32"), PCWSTR::null()).unwrap_or_default()
+}
+
 unsafe fn find_correct_workerw_layer(result: &mut HWND) {
     EnumWindows(Some(find_workerw_enum_proc), LPARAM(result as *mut _ as isize));
 }
@@ -356,3 +519,41 @@ unsafe extern "system" fn find_workerw_enum_proc(hwnd: HWND, lparam: LPARAM) ->
     
     BOOL(1)
 }
+
+impl Surface for WallpaperRenderer {
+    fn accepted_formats(&self) -> &[PixelFormat] {
+        WallpaperRenderer::accepted_formats(self)
+    }
+
+    fn present(&mut self, frame: &Frame) -> Result<()> {
+        self.render_frame(frame)
+    }
+
+    fn size(&self) -> (u32, u32) {
+        self.screen_size()
+    }
+
+    fn present_solid(&mut self, rgb: [u8; 3]) -> Result<()> {
+        self.render_solid(rgb)
+    }
+
+    fn set_visible(&mut self, visible: bool) {
+        WallpaperRenderer::set_visible(self, visible)
+    }
+
+    fn window_beat(&self) -> Beat {
+        WallpaperRenderer::window_beat(self)
+    }
+
+    fn rebuild(&mut self) -> Result<()> {
+        let fresh = WallpaperRenderer::new(self.clock.clone())?;
+        // The stalled window would otherwise stay under WorkerW, possibly on top of the new one
+        self.close_window();
+        *self = fresh;
+        Ok(())
+    }
+
+    fn close(self) {
+        WallpaperRenderer::close(self)
+    }
+}
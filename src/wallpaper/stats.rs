@@ -0,0 +1,192 @@
+use crate::wallpaper::command::{Percentiles, PlayerStats};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Metrics cover this much recent playback.
+const WINDOW: Duration = Duration::from_secs(10);
+
+/// What one presented frame cost at each stage.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FrameTiming {
+    pub decode: Duration,
+    pub scale: Duration,
+    pub present: Duration,
+    /// From the frame's slot to it going up.
+    pub late: Duration,
+}
+
+struct FrameSample {
+    at: Instant,
+    decode: Duration,
+    scale: Duration,
+    present: Duration,
+    late: bool,
+}
+
+/// Rolling record of what the pipeline did over the last few seconds, summarised into
+/// `PlayerStats` on demand.
+pub struct StatsCollector {
+    frames: VecDeque<FrameSample>,
+    /// Presentation slots skipped because a frame came too late, with when it happened.
+    dropped: VecDeque<(Instant, u32)>,
+}
+
+impl StatsCollector {
+    pub fn new() -> Self {
+        Self {
+            frames: VecDeque::new(),
+            dropped: VecDeque::new(),
+        }
+    }
+
+    /// Note a presented frame, with slots `frame_time` long.
+    pub fn record_frame(&mut self, now: Instant, timing: FrameTiming, frame_time: Duration) {
+        self.frames.push_back(FrameSample {
+            at: now,
+            decode: timing.decode,
+            scale: timing.scale,
+            present: timing.present,
+            // Up to a quarter of a frame is normal timer jitter
+            late: timing.late > frame_time / 4,
+        });
+        self.expire(now);
+    }
+
+    /// Note that the loop fell `behind` schedule and skipped the slots it missed.
+    pub fn record_behind(&mut self, now: Instant, behind: Duration, frame_time: Duration) {
+        let missed = (behind.as_secs_f64() / frame_time.as_secs_f64().max(f64::EPSILON)) as u32;
+        if missed > 0 {
+            self.dropped.push_back((now, missed));
+        }
+    }
+
+    /// Forget everything, e.g. when a different wallpaper starts.
+    pub fn reset(&mut self) {
+        self.frames.clear();
+        self.dropped.clear();
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while self.frames.front().is_some_and(|f| now.duration_since(f.at) > WINDOW) {
+            self.frames.pop_front();
+        }
+        while self.dropped.front().is_some_and(|(at, _)| now.duration_since(*at) > WINDOW) {
+            self.dropped.pop_front();
+        }
+    }
+
+    /// Summary of the window. Counters the collector doesn't see are filled in by the player.
+    pub fn snapshot(&mut self, now: Instant) -> PlayerStats {
+        self.expire(now);
+        let span = self
+            .frames
+            .front()
+            .map_or(Duration::ZERO, |first| now.duration_since(first.at))
+            .max(Duration::from_secs(1));
+        PlayerStats {
+            presented_fps: self.frames.len() as f64 / span.as_secs_f64(),
+            dropped_frames: self.dropped.iter().map(|(_, n)| *n as u64).sum(),
+            late_frames: self.frames.iter().filter(|f| f.late).count() as u64,
+            decode_ms: percentiles(self.frames.iter().map(|f| f.decode)),
+            scale_ms: percentiles(self.frames.iter().map(|f| f.scale)),
+            present_ms: percentiles(self.frames.iter().map(|f| f.present)),
+            ..Default::default()
+        }
+    }
+}
+
+fn percentiles(samples: impl Iterator<Item = Duration>) -> Percentiles {
+    let mut ms: Vec<f64> = samples.map(|d| d.as_secs_f64() * 1000.0).collect();
+    if ms.is_empty() {
+        return Percentiles::default();
+    }
+    ms.sort_by(|a, b| a.total_cmp(b));
+    let at = |p: f64| ms[((ms.len() - 1) as f64 * p).round() as usize];
+    Percentiles {
+        p50: at(0.50),
+        p95: at(0.95),
+        p99: at(0.99),
+        max: ms[ms.len() - 1],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME: Duration = Duration::from_millis(40);
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    fn timing(decode: Duration, late: Duration) -> FrameTiming {
+        FrameTiming { decode, scale: ms(1), present: ms(2), late }
+    }
+
+    #[test]
+    fn percentiles_pick_the_nearest_rank() {
+        let mut collector = StatsCollector::new();
+        let start = Instant::now();
+        // Shuffled, so the order they arrive in doesn't matter
+        for i in 0..100u64 {
+            let decode = ms((i * 37) % 100 + 1);
+            collector.record_frame(start + ms(i * 40), timing(decode, Duration::ZERO), FRAME);
+        }
+        let stats = collector.snapshot(start + ms(99 * 40));
+        assert_eq!(stats.decode_ms, Percentiles { p50: 51.0, p95: 95.0, p99: 99.0, max: 100.0 });
+        assert_eq!(stats.scale_ms, Percentiles { p50: 1.0, p95: 1.0, p99: 1.0, max: 1.0 });
+        assert!((stats.presented_fps - 100.0 / 3.96).abs() < 0.01, "{}", stats.presented_fps);
+    }
+
+    #[test]
+    fn empty_window_reports_zeroes() {
+        let stats = StatsCollector::new().snapshot(Instant::now());
+        assert_eq!(stats.presented_fps, 0.0);
+        assert_eq!(stats.decode_ms, Percentiles::default());
+    }
+
+    #[test]
+    fn old_samples_fall_out_of_the_window() {
+        let mut collector = StatsCollector::new();
+        let start = Instant::now();
+        collector.record_frame(start, timing(ms(50), ms(30)), FRAME);
+        collector.record_behind(start, ms(200), FRAME);
+        for i in 1..=25u64 {
+            collector.record_frame(start + ms(5000 + i * 40), timing(ms(5), Duration::ZERO), FRAME);
+        }
+
+        let stats = collector.snapshot(start + ms(6000));
+        assert_eq!(stats.decode_ms.max, 50.0);
+        assert_eq!((stats.late_frames, stats.dropped_frames), (1, 5));
+
+        let stats = collector.snapshot(start + WINDOW + ms(1));
+        assert_eq!(stats.decode_ms.max, 5.0);
+        assert_eq!((stats.late_frames, stats.dropped_frames), (0, 0));
+        // 25 frames over the span since the oldest one left in the window
+        assert!((stats.presented_fps - 25.0 / 4.961).abs() < 0.01, "{}", stats.presented_fps);
+    }
+
+    #[test]
+    fn counts_whole_missed_slots() {
+        let mut collector = StatsCollector::new();
+        let now = Instant::now();
+        collector.record_behind(now, ms(39), FRAME);
+        assert_eq!(collector.snapshot(now).dropped_frames, 0);
+        collector.record_behind(now, ms(40), FRAME);
+        collector.record_behind(now, ms(130), FRAME);
+        assert_eq!(collector.snapshot(now).dropped_frames, 4);
+        collector.reset();
+        assert_eq!(collector.snapshot(now).dropped_frames, 0);
+    }
+
+    #[test]
+    fn late_allows_a_quarter_frame_of_jitter() {
+        let mut collector = StatsCollector::new();
+        let now = Instant::now();
+        collector.record_frame(now, timing(ms(1), ms(10)), FRAME);
+        collector.record_frame(now, timing(ms(1), ms(11)), FRAME);
+        collector.record_frame(now, timing(ms(1), ms(80)), FRAME);
+        assert_eq!(collector.snapshot(now).late_frames, 2);
+    }
+}
@@ -0,0 +1,271 @@
+use ffmpeg_next as ffmpeg;
+use std::time::{Duration, Instant};
+
+const NETWORK_SCHEMES: &[&str] = &["http://", "https://", "rtsp://", "rtsps://", "rtmp://", "rtmps://", "udp://", "rtp://", "srt://", "tcp://"];
+
+pub fn is_network_source(path: &str) -> bool {
+    let lower = path.trim().to_ascii_lowercase();
+    NETWORK_SCHEMES.iter().any(|scheme| lower.starts_with(scheme))
+}
+
+/// Connection options applied when the wallpaper source is a URL.
+#[derive(Debug, Clone)]
+pub struct StreamOptions {
+    /// Socket open/read timeout.
+    pub timeout: Duration,
+    /// Network receive buffer in KiB (UDP/RTP/SRT).
+    pub buffer_kb: u32,
+    /// RTSP over TCP avoids packet loss on lossy Wi-Fi at the cost of a bit of latency.
+    pub rtsp_over_tcp: bool,
+}
+
+impl Default for StreamOptions {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(10),
+            buffer_kb: 1024,
+            rtsp_over_tcp: true,
+        }
+    }
+}
+
+impl StreamOptions {
+    pub fn to_dictionary(&self, path: &str) -> ffmpeg::Dictionary<'static> {
+        let timeout_us = self.timeout.as_micros().to_string();
+        let mut opts = ffmpeg::Dictionary::new();
+
+        // rw_timeout covers every protocol read; `timeout` is the socket/listen timeout for http and rtsp.
+        opts.set("rw_timeout", &timeout_us);
+        opts.set("timeout", &timeout_us);
+        opts.set("buffer_size", &(self.buffer_kb as u64 * 1024).to_string());
+
+        let lower = path.to_ascii_lowercase();
+        if lower.starts_with("http") {
+            // Let the http protocol paper over short drops before we tear the stream down.
+            opts.set("reconnect", "1");
+            opts.set("reconnect_streamed", "1");
+            opts.set("reconnect_delay_max", "5");
+        }
+        if lower.starts_with("rtsp") && self.rtsp_over_tcp {
+            opts.set("rtsp_transport", "tcp");
+        }
+        opts
+    }
+}
+
+/// Exponential reconnect delay: 1s, 2s, 4s ... capped at `max_delay`.
+#[derive(Debug)]
+pub struct Backoff {
+    attempt: u32,
+    max_delay: Duration,
+    next_attempt: Instant,
+}
+
+impl Backoff {
+    /// First attempt is due straight away (`now`).
+    pub fn new(max_delay: Duration, now: Instant) -> Self {
+        Self {
+            attempt: 0,
+            max_delay,
+            next_attempt: now,
+        }
+    }
+
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    pub fn is_due(&self, now: Instant) -> bool {
+        now >= self.next_attempt
+    }
+
+    /// Record a failed attempt and schedule the next one. Returns the delay used.
+    pub fn fail(&mut self, now: Instant) -> Duration {
+        let delay = Duration::from_secs(1u64 << self.attempt.min(16)).min(self.max_delay);
+        self.attempt += 1;
+        self.next_attempt = now + delay;
+        delay
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wallpaper::fixtures::{self, HEIGHT, WIDTH};
+    use crate::wallpaper::VideoDecoder;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let start = Instant::now();
+        let mut backoff = Backoff::new(Duration::from_secs(10), start);
+        assert!(backoff.is_due(start));
+
+        let delays: Vec<u64> = (0..6).map(|_| backoff.fail(start).as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 10, 10]);
+        assert_eq!(backoff.attempt(), 6);
+    }
+
+    #[test]
+    fn backoff_waits_for_the_delay() {
+        let start = Instant::now();
+        let mut backoff = Backoff::new(Duration::from_secs(60), start);
+        backoff.fail(start);
+        let failed = start + Duration::from_secs(5);
+        assert_eq!(backoff.fail(failed), Duration::from_secs(2));
+        assert!(!backoff.is_due(failed + Duration::from_millis(1999)));
+        assert!(backoff.is_due(failed + Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn backoff_survives_many_failures() {
+        let start = Instant::now();
+        let mut backoff = Backoff::new(Duration::from_secs(300), start);
+        for _ in 0..100 {
+            backoff.fail(start);
+        }
+        assert_eq!(backoff.fail(start), Duration::from_secs(300));
+    }
+
+    #[test]
+    fn recognises_network_sources() {
+        for url in ["http://host/a.mp4", "HTTPS://host/live.m3u8", "  rtsp://cam/stream", "srt://host:9000", "udp://239.0.0.1:1234"] {
+            assert!(is_network_source(url), "{}", url);
+        }
+        for path in ["C:\\Videos\\http.mp4", "/home/me/https.mkv", "http_clip.mp4", "file:///tmp/a.mp4", ""] {
+            assert!(!is_network_source(path), "{}", path);
+        }
+    }
+
+    #[test]
+    fn http_options() {
+        let options = StreamOptions { timeout: Duration::from_millis(2500), buffer_kb: 64, rtsp_over_tcp: true };
+        let opts = options.to_dictionary("https://host/live.m3u8");
+        assert_eq!(opts.get("rw_timeout"), Some("2500000"));
+        assert_eq!(opts.get("timeout"), Some("2500000"));
+        assert_eq!(opts.get("buffer_size"), Some("65536"));
+        assert_eq!(opts.get("reconnect"), Some("1"));
+        assert_eq!(opts.get("reconnect_streamed"), Some("1"));
+        assert_eq!(opts.get("rtsp_transport"), None);
+    }
+
+    #[test]
+    fn rtsp_options() {
+        let tcp = StreamOptions::default().to_dictionary("RTSP://cam/stream");
+        assert_eq!(tcp.get("rtsp_transport"), Some("tcp"));
+        assert_eq!(tcp.get("reconnect"), None);
+
+        let udp = StreamOptions { rtsp_over_tcp: false, ..Default::default() }.to_dictionary("rtsp://cam/stream");
+        assert_eq!(udp.get("rtsp_transport"), None);
+    }
+
+    /// Serve `files` over HTTP on a free local port, a thread per connection, for the rest of the test run.
+    fn serve(files: Vec<(String, Vec<u8>)>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let files = Arc::new(files);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { continue };
+                let files = files.clone();
+                std::thread::spawn(move || {
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    let mut request = String::new();
+                    reader.read_line(&mut request).unwrap();
+                    let mut header = String::new();
+                    while reader.read_line(&mut header).is_ok_and(|n| n > 2) {
+                        header.clear();
+                    }
+                    let path = request.split_whitespace().nth(1).unwrap_or("/").trim_start_matches('/');
+                    let response = match files.iter().find(|(name, _)| name == path) {
+                        Some((_, body)) => {
+                            let mut response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len()).into_bytes();
+                            response.extend_from_slice(body);
+                            response
+                        }
+                        None => b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec(),
+                    };
+                    let _ = stream.write_all(&response);
+                });
+            }
+        });
+        base
+    }
+
+    fn playlist(segments: &[&str], ended: bool) -> Vec<u8> {
+        let mut m3u8 = String::from("#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:1\n#EXT-X-MEDIA-SEQUENCE:0\n");
+        for segment in segments {
+            m3u8.push_str(&format!("#EXTINF:1.0,\n{}\n", segment));
+        }
+        if ended {
+            m3u8.push_str("#EXT-X-ENDLIST\n");
+        }
+        m3u8.into_bytes()
+    }
+
+    #[test]
+    fn plays_a_file_over_http() {
+        let clip = std::fs::read(fixtures::clip("http.ts", 0, 25)).unwrap();
+        let base = serve(vec![("clip.ts".into(), clip)]);
+
+        let mut decoder = VideoDecoder::new(format!("{}/clip.ts", base), WIDTH, HEIGHT, &StreamOptions::default()).unwrap();
+        assert!(decoder.is_network());
+        assert!(fixtures::read_frames(&mut decoder, 100).unwrap() >= 20);
+    }
+
+    #[test]
+    fn plays_an_hls_playlist_through_every_segment() {
+        let first = std::fs::read(fixtures::clip("hls-0.ts", 0, 25)).unwrap();
+        let second = std::fs::read(fixtures::clip("hls-1.ts", 25, 25)).unwrap();
+        let base = serve(vec![
+            ("index.m3u8".into(), playlist(&["0.ts", "1.ts"], true)),
+            ("0.ts".into(), first),
+            ("1.ts".into(), second),
+        ]);
+
+        let mut decoder = VideoDecoder::new(format!("{}/index.m3u8", base), WIDTH, HEIGHT, &StreamOptions::default()).unwrap();
+        // A finished playlist has a length, so it loops like a file
+        assert!(!decoder.is_live());
+        assert!(decoder.duration().is_some_and(|d| (d - 2.0).abs() < 0.1));
+        let frames = fixtures::read_frames(&mut decoder, 100).unwrap();
+        assert!(frames >= 45, "{} frames", frames);
+        assert!(decoder.position() > 1.5);
+    }
+
+    #[test]
+    fn open_hls_playlist_is_live() {
+        let segment = std::fs::read(fixtures::clip("live-0.ts", 0, 25)).unwrap();
+        let base = serve(vec![("live.m3u8".into(), playlist(&["0.ts"], false)), ("0.ts".into(), segment)]);
+
+        let mut decoder = VideoDecoder::new(format!("{}/live.m3u8", base), WIDTH, HEIGHT, &StreamOptions::default()).unwrap();
+        assert!(decoder.is_live());
+        assert!(decoder.seek(1.0).is_err());
+        assert!(fixtures::read_frames(&mut decoder, 5).unwrap() > 0);
+    }
+
+    #[test]
+    fn missing_stream_fails_to_open() {
+        let base = serve(Vec::new());
+        assert!(VideoDecoder::new(format!("{}/gone.m3u8", base), WIDTH, HEIGHT, &StreamOptions::default()).is_err());
+    }
+
+    #[test]
+    fn silent_server_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/stalled.ts", listener.local_addr().unwrap());
+        // Accept and hold connections without ever answering
+        std::thread::spawn(move || {
+            let mut held = Vec::new();
+            for stream in listener.incoming() {
+                held.push(stream);
+            }
+        });
+
+        let options = StreamOptions { timeout: Duration::from_millis(500), ..Default::default() };
+        let started = Instant::now();
+        assert!(VideoDecoder::new(url, WIDTH, HEIGHT, &options).is_err());
+        assert!(started.elapsed() < Duration::from_secs(5), "took {:?}", started.elapsed());
+    }
+}
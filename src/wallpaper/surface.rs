@@ -0,0 +1,314 @@
+#[cfg(test)]
+use crate::wallpaper::convert;
+use crate::wallpaper::frame::{Frame, PixelFormat};
+use crate::wallpaper::watchdog::Beat;
+use anyhow::Result;
+#[cfg(test)]
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(test)]
+use std::sync::Arc;
+
+/// Something frames can be presented on.
+pub trait Surface {
+    /// Pixel formats `present` takes directly, best first. BGRA is always accepted.
+    fn accepted_formats(&self) -> &[PixelFormat];
+    fn present(&mut self, frame: &Frame) -> Result<()>;
+    fn size(&self) -> (u32, u32);
+    /// Fill with a single RGB colour, for when nothing can be decoded.
+    fn present_solid(&mut self, rgb: [u8; 3]) -> Result<()>;
+    fn set_visible(&mut self, visible: bool);
+    /// Stamped by the thread behind the surface's window, if it has one, for the watchdog.
+    fn window_beat(&self) -> Beat {
+        Beat::default()
+    }
+    /// Replace a surface that failed or stalled with a fresh one.
+    fn rebuild(&mut self) -> Result<()>;
+    /// Take the surface down for good.
+    fn close(self);
+}
+
+/// Off-screen surface that converts everything to BGRA on the CPU and keeps the
+/// last picture, for running the pipeline in tests without a desktop.
+#[cfg(test)]
+pub struct HeadlessSurface {
+    width: u32,
+    height: u32,
+    last: Option<Frame>,
+    presented: u64,
+    /// Surfaces made by this one and its rebuilds that haven't been taken down yet.
+    open: Arc<AtomicUsize>,
+}
+
+#[cfg(test)]
+impl HeadlessSurface {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            last: None,
+            presented: 0,
+            open: Arc::new(AtomicUsize::new(1)),
+        }
+    }
+
+    /// The most recently presented picture, as BGRA.
+    pub fn last_frame(&self) -> Option<&Frame> {
+        self.last.as_ref()
+    }
+
+    pub fn presented(&self) -> u64 {
+        self.presented
+    }
+
+    /// How many surfaces are still up; stays readable after `close`.
+    pub fn open_surfaces(&self) -> Arc<AtomicUsize> {
+        self.open.clone()
+    }
+}
+
+#[cfg(test)]
+impl Surface for HeadlessSurface {
+    fn accepted_formats(&self) -> &[PixelFormat] {
+        &[PixelFormat::Nv12, PixelFormat::P010, PixelFormat::Yuv420p, PixelFormat::Bgra]
+    }
+
+    fn present(&mut self, frame: &Frame) -> Result<()> {
+        let out = self.last.get_or_insert_with(|| Frame::new(PixelFormat::Bgra, frame.width(), frame.height()));
+        convert::to_bgra(frame, out)?;
+        self.width = frame.width();
+        self.height = frame.height();
+        self.presented += 1;
+        Ok(())
+    }
+
+    fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    fn present_solid(&mut self, rgb: [u8; 3]) -> Result<()> {
+        let mut frame = Frame::new(PixelFormat::Bgra, self.width, self.height);
+        for dst in frame.plane_mut(0).chunks_exact_mut(4) {
+            dst.copy_from_slice(&[rgb[2], rgb[1], rgb[0], 255]);
+        }
+        self.present(&frame)
+    }
+
+    fn set_visible(&mut self, _visible: bool) {}
+
+    fn rebuild(&mut self) -> Result<()> {
+        // Up with the new surface before the old one goes, like the renderer
+        self.open.fetch_add(1, Ordering::SeqCst);
+        self.open.fetch_sub(1, Ordering::SeqCst);
+        self.last = None;
+        Ok(())
+    }
+
+    fn close(self) {
+        self.open.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wallpaper::frame::{ColorInfo, ColorRange, ColorSpace};
+
+    const SPACES: [ColorSpace; 2] = [ColorSpace::Bt601, ColorSpace::Bt709];
+    const RANGES: [ColorRange; 2] = [ColorRange::Limited, ColorRange::Full];
+    const YUV: [PixelFormat; 3] = [PixelFormat::Nv12, PixelFormat::Yuv420p, PixelFormat::P010];
+
+    /// Y, Cb, Cr code values for an RGB colour, worked out from the standard's definitions
+    /// rather than by inverting `convert`.
+    fn encode(rgb: [u8; 3], color: ColorInfo, ten_bit: bool) -> (u16, u16, u16) {
+        let (kr, kb) = match color.space {
+            ColorSpace::Bt601 => (0.299, 0.114),
+            _ => (0.2126, 0.0722),
+        };
+        let [r, g, b] = rgb.map(|c| c as f64 / 255.0);
+        let y = kr * r + (1.0 - kr - kb) * g + kb * b;
+        let cb = (b - y) / (2.0 * (1.0 - kb));
+        let cr = (r - y) / (2.0 * (1.0 - kr));
+        let bits = if ten_bit { 4.0 } else { 1.0 };
+        let max = if ten_bit { 1023.0 } else { 255.0 };
+        let (y_offset, y_scale, c_scale) = match color.range {
+            ColorRange::Limited => (16.0 * bits, 219.0 * bits, 224.0 * bits),
+            ColorRange::Full => (0.0, max, max),
+        };
+        let code = |v: f64| v.round().clamp(0.0, max) as u16;
+        (code(y_offset + y * y_scale), code(128.0 * bits + cb * c_scale), code(128.0 * bits + cr * c_scale))
+    }
+
+    /// A `format` frame where each 2x2 block has the colour `pixel` gives its top-left corner.
+    fn frame(format: PixelFormat, color: ColorInfo, width: u32, height: u32, pixel: impl Fn(usize, usize) -> [u8; 3]) -> Frame {
+        let mut frame = Frame::new(format, width, height);
+        frame.set_color(color);
+        let ten_bit = format == PixelFormat::P010;
+        let put = |frame: &mut Frame, plane: usize, offset: usize, value: u16| {
+            if ten_bit {
+                frame.plane_mut(plane)[offset..offset + 2].copy_from_slice(&(value << 6).to_le_bytes());
+            } else {
+                frame.plane_mut(plane)[offset] = value as u8;
+            }
+        };
+        let sample = if ten_bit { 2 } else { 1 };
+        for y in 0..height as usize {
+            for x in 0..width as usize {
+                let (luma, cb, cr) = encode(pixel(x & !1, y & !1), color, ten_bit);
+                let offset = y * frame.stride(0) + x * sample;
+                put(&mut frame, 0, offset, luma);
+                let (cx, cy) = (x / 2, y / 2);
+                match format {
+                    PixelFormat::Yuv420p => {
+                        let (cb_at, cr_at) = (cy * frame.stride(1) + cx, cy * frame.stride(2) + cx);
+                        put(&mut frame, 1, cb_at, cb);
+                        put(&mut frame, 2, cr_at, cr);
+                    }
+                    _ => {
+                        let at = cy * frame.stride(1) + cx * 2 * sample;
+                        put(&mut frame, 1, at, cb);
+                        put(&mut frame, 1, at + sample, cr);
+                    }
+                }
+            }
+        }
+        frame
+    }
+
+    fn rgb_at(surface: &HeadlessSurface, x: usize, y: usize) -> [u8; 3] {
+        let frame = surface.last_frame().unwrap();
+        let at = y * frame.stride(0) + x * 4;
+        let [b, g, r, a] = frame.plane(0)[at..at + 4] else { unreachable!() };
+        assert_eq!(a, 255);
+        [r, g, b]
+    }
+
+    fn assert_close(actual: [u8; 3], expected: [u8; 3], tolerance: u8, what: &str) {
+        let off = actual.iter().zip(expected).any(|(a, e)| a.abs_diff(e) > tolerance);
+        assert!(!off, "{}: got {:?}, expected {:?}", what, actual, expected);
+    }
+
+    fn present(frame: &Frame) -> HeadlessSurface {
+        let mut surface = HeadlessSurface::new(1, 1);
+        surface.present(frame).unwrap();
+        surface
+    }
+
+    #[test]
+    fn limited_range_black_and_white() {
+        let color = ColorInfo { space: ColorSpace::Bt709, range: ColorRange::Limited };
+        for (luma, expected) in [(16, 0), (235, 255), (0, 0), (255, 255)] {
+            let mut frame = frame(PixelFormat::Nv12, color, 2, 2, |_, _| [0, 0, 0]);
+            frame.plane_mut(0)[0] = luma;
+            assert_eq!(rgb_at(&present(&frame), 0, 0), [expected; 3], "Y={}", luma);
+        }
+    }
+
+    #[test]
+    fn full_range_uses_every_code() {
+        let color = ColorInfo { space: ColorSpace::Bt709, range: ColorRange::Full };
+        for (luma, expected) in [(0, 0), (16, 16), (128, 128), (255, 255)] {
+            let mut frame = frame(PixelFormat::Yuv420p, color, 2, 2, |_, _| [0, 0, 0]);
+            frame.plane_mut(0)[0] = luma;
+            assert_eq!(rgb_at(&present(&frame), 0, 0), [expected; 3], "Y={}", luma);
+        }
+    }
+
+    #[test]
+    fn matrix_follows_the_frame() {
+        // Pure green as a BT.601 and a BT.709 encoder write it
+        let bt601 = ColorInfo { space: ColorSpace::Bt601, range: ColorRange::Limited };
+        let bt709 = ColorInfo { space: ColorSpace::Bt709, range: ColorRange::Limited };
+        let green = |color: ColorInfo, (y, cb, cr): (u8, u8, u8)| {
+            let mut frame = Frame::new(PixelFormat::Nv12, 2, 2);
+            frame.set_color(color);
+            frame.plane_mut(0)[..2].fill(y);
+            let stride = frame.stride(0);
+            frame.plane_mut(0)[stride..stride + 2].fill(y);
+            frame.plane_mut(1)[..2].copy_from_slice(&[cb, cr]);
+            rgb_at(&present(&frame), 1, 1)
+        };
+        assert_close(green(bt601, (145, 54, 34)), [0, 255, 0], 2, "BT.601 green");
+        assert_close(green(bt709, (173, 42, 26)), [0, 255, 0], 2, "BT.709 green");
+        // Reading BT.601 codes with the BT.709 matrix visibly shifts the colour
+        assert!(green(bt709, (145, 54, 34))[1] < 230);
+    }
+
+    #[test]
+    fn every_format_space_and_range_round_trips() {
+        let colours = [[255, 0, 0], [0, 255, 0], [0, 0, 255], [128, 128, 128], [224, 172, 105], [20, 40, 60]];
+        for format in YUV {
+            // Ten bits leave only the final rounding to 8
+            let tolerance = if format == PixelFormat::P010 { 1 } else { 3 };
+            for space in SPACES {
+                for range in RANGES {
+                    let color = ColorInfo { space, range };
+                    for rgb in colours {
+                        let surface = present(&frame(format, color, 4, 2, |_, _| rgb));
+                        let what = format!("{:?} {:?} {:?}", format, space, range);
+                        assert_close(rgb_at(&surface, 3, 1), rgb, tolerance, &what);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn chroma_is_shared_by_each_block() {
+        let color = ColorInfo { space: ColorSpace::Bt709, range: ColorRange::Limited };
+        let blocks = |x: usize, y: usize| match (x / 2, y / 2) {
+            (0, 0) => [255, 0, 0],
+            (1, 0) => [0, 0, 255],
+            (_, 0) => [0, 255, 0],
+            _ => [255, 255, 255],
+        };
+        for format in YUV {
+            let surface = present(&frame(format, color, 6, 4, blocks));
+            for (x, y) in [(0, 0), (1, 1), (2, 0), (3, 1), (4, 1), (5, 0), (0, 2), (5, 3)] {
+                assert_close(rgb_at(&surface, x, y), blocks(x & !1, y & !1), 3, &format!("{:?} at {},{}", format, x, y));
+            }
+        }
+    }
+
+    #[test]
+    fn bgra_is_copied_through() {
+        let mut source = Frame::new(PixelFormat::Bgra, 3, 2);
+        let stride = source.stride(0);
+        for y in 0..2 {
+            for x in 0..3 {
+                source.plane_mut(0)[y * stride + x * 4..][..4].copy_from_slice(&[x as u8, y as u8, 7, 255]);
+            }
+        }
+        let surface = present(&source);
+        assert_eq!(rgb_at(&surface, 2, 1), [7, 1, 2]);
+        assert_eq!(rgb_at(&surface, 0, 0), [7, 0, 0]);
+    }
+
+    #[test]
+    fn tracks_size_and_count() {
+        let mut surface = HeadlessSurface::new(1920, 1080);
+        assert_eq!(surface.size(), (1920, 1080));
+        assert!(surface.last_frame().is_none());
+        assert!(surface.accepted_formats().contains(&PixelFormat::Bgra));
+
+        let color = ColorInfo::default();
+        surface.present(&frame(PixelFormat::Nv12, color, 8, 6, |_, _| [0, 0, 0])).unwrap();
+        surface.present(&frame(PixelFormat::P010, color, 4, 2, |_, _| [0, 0, 0])).unwrap();
+        assert_eq!(surface.size(), (4, 2));
+        assert_eq!(surface.presented(), 2);
+        assert_eq!(surface.last_frame().unwrap().width(), 4);
+    }
+
+    #[test]
+    fn rebuild_takes_the_old_surface_down() {
+        let mut surface = HeadlessSurface::new(4, 2);
+        let open = surface.open_surfaces();
+        surface.present_solid([1, 2, 3]).unwrap();
+        for _ in 0..3 {
+            surface.rebuild().unwrap();
+            assert_eq!(open.load(Ordering::SeqCst), 1);
+        }
+        assert!(surface.last_frame().is_none());
+        surface.close();
+        assert_eq!(open.load(Ordering::SeqCst), 0);
+    }
+}
@@ -0,0 +1,332 @@
+use crate::wallpaper::convert;
+use crate::wallpaper::frame::{Frame, PixelFormat};
+use anyhow::Result;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransitionKind {
+    /// Switch straight to the new picture.
+    Cut,
+    /// Dip through black.
+    Fade,
+    Crossfade,
+    /// Pixels switch over in a fixed random order.
+    Dissolve,
+    /// The new picture pushes the old one out to the left.
+    Slide,
+}
+
+impl TransitionKind {
+    /// Parse the name stored in settings; anything unknown is a crossfade.
+    pub fn from_name(name: &str) -> Self {
+        match name.to_ascii_lowercase().as_str() {
+            "cut" | "none" => TransitionKind::Cut,
+            "fade" => TransitionKind::Fade,
+            "dissolve" => TransitionKind::Dissolve,
+            "slide" => TransitionKind::Slide,
+            _ => TransitionKind::Crossfade,
+        }
+    }
+}
+
+/// Which way a transition runs relative to its still picture.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// From the still to the live frames, e.g. the old wallpaper into the new one.
+    FromStill,
+    /// From the live frames onto the still, e.g. motion settling on the paused frame.
+    ToStill,
+}
+
+/// A blend between a still picture and the frames being played. The clock starts on the
+/// first live frame, so a slow-opening decoder keeps the still on screen rather than eating
+/// into the transition.
+pub struct Transition {
+    kind: TransitionKind,
+    direction: Direction,
+    duration: Duration,
+    started: Option<Instant>,
+    finished: bool,
+    still: Frame,
+    live: Frame,
+    out: Frame,
+}
+
+impl Transition {
+    /// Snapshot `still` (any format) as BGRA.
+    pub fn new(kind: TransitionKind, direction: Direction, duration: Duration, still: &Frame) -> Result<Self> {
+        let mut snapshot = Frame::new(PixelFormat::Bgra, still.width(), still.height());
+        convert::to_bgra_fast(still, &mut snapshot)?;
+        Ok(Self {
+            kind,
+            direction,
+            duration,
+            started: None,
+            finished: kind == TransitionKind::Cut,
+            still: snapshot,
+            live: Frame::new(PixelFormat::Bgra, still.width(), still.height()),
+            out: Frame::new(PixelFormat::Bgra, still.width(), still.height()),
+        })
+    }
+
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    /// The still picture, as BGRA.
+    pub fn still(&self) -> &Frame {
+        &self.still
+    }
+
+    /// True once `apply` has run past the end, i.e. the final picture is what's showing.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Blend `live` in at the current point of the transition. Returns `None` once it's
+    /// over (or can't apply, e.g. the screen size changed): then `live` should be shown as
+    /// is, or the still for `ToStill`.
+    pub fn apply(&mut self, live: &Frame, now: Instant) -> Result<Option<&Frame>> {
+        let started = *self.started.get_or_insert(now);
+        let size_changed = live.width() != self.still.width() || live.height() != self.still.height();
+        if self.finished || size_changed || now.duration_since(started) >= self.duration {
+            self.finished = true;
+            return Ok(None);
+        }
+        let linear = now.duration_since(started).as_secs_f32() / self.duration.as_secs_f32().max(f32::EPSILON);
+        let eased = ease(linear.clamp(0.0, 1.0));
+        let progress = match self.direction {
+            Direction::FromStill => eased,
+            Direction::ToStill => 1.0 - eased,
+        };
+
+        // Every live frame goes through here, so it has to keep up with the frame rate
+        convert::to_bgra_fast(live, &mut self.live)?;
+        blend(self.kind, &self.still, &self.live, progress, &mut self.out)?;
+        Ok(Some(&self.out))
+    }
+}
+
+/// Smoothstep, so the blend eases in and out instead of starting and stopping abruptly.
+fn ease(t: f32) -> f32 {
+    t * t * (3.0 - 2.0 * t)
+}
+
+/// Write the mix of two same-sized BGRA frames into `out`; `progress` 0 is all `from`, 1 all `to`.
+pub fn blend(kind: TransitionKind, from: &Frame, to: &Frame, progress: f32, out: &mut Frame) -> Result<()> {
+    if from.format() != PixelFormat::Bgra || to.format() != PixelFormat::Bgra {
+        return Err(anyhow::anyhow!("Transitions blend BGRA frames, got {:?} and {:?}", from.format(), to.format()));
+    }
+    if from.width() != to.width() || from.height() != to.height() {
+        return Err(anyhow::anyhow!(
+            "Cannot blend {}x{} with {}x{}",
+            from.width(),
+            from.height(),
+            to.width(),
+            to.height()
+        ));
+    }
+    let (width, height) = (from.width() as usize, from.height() as usize);
+    out.prepare(PixelFormat::Bgra, from.width(), from.height());
+    out.set_pts(to.pts());
+    out.set_color(to.color());
+
+    let progress = progress.clamp(0.0, 1.0);
+    // Weights in 1/256ths keep the per-pixel work in integers
+    let weight = (progress * 256.0).round() as u32;
+    let (from_stride, to_stride, out_stride) = (from.stride(0), to.stride(0), out.stride(0));
+    let (a, b) = (from.plane(0), to.plane(0));
+    let dst = out.plane_mut(0);
+
+    for y in 0..height {
+        let a_row = &a[y * from_stride..y * from_stride + width * 4];
+        let b_row = &b[y * to_stride..y * to_stride + width * 4];
+        let dst_row = &mut dst[y * out_stride..y * out_stride + width * 4];
+        match kind {
+            TransitionKind::Cut => {
+                dst_row.copy_from_slice(if progress < 1.0 { a_row } else { b_row });
+            }
+            TransitionKind::Crossfade => {
+                for ((d, &x), &z) in dst_row.iter_mut().zip(a_row).zip(b_row) {
+                    *d = mix(x, z, weight);
+                }
+            }
+            TransitionKind::Fade => {
+                // First half darkens the old picture, second half brings up the new one
+                let (row, level) = if progress < 0.5 { (a_row, 256 - weight * 2) } else { (b_row, weight * 2 - 256) };
+                for (d, &x) in dst_row.iter_mut().zip(row) {
+                    *d = mix(0, x, level);
+                }
+                for alpha in dst_row.iter_mut().skip(3).step_by(4) {
+                    *alpha = 255;
+                }
+            }
+            TransitionKind::Dissolve => {
+                for x in 0..width {
+                    let take_new = (noise(x as u32, y as u32) as u32) < weight;
+                    let src = if take_new { b_row } else { a_row };
+                    dst_row[x * 4..x * 4 + 4].copy_from_slice(&src[x * 4..x * 4 + 4]);
+                }
+            }
+            TransitionKind::Slide => {
+                let shift = ((width as f32 * progress).round() as usize).min(width);
+                let kept = width - shift;
+                dst_row[..kept * 4].copy_from_slice(&a_row[shift * 4..]);
+                dst_row[kept * 4..].copy_from_slice(&b_row[..shift * 4]);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `a` moved `weight`/256 of the way towards `b`.
+fn mix(a: u8, b: u8, weight: u32) -> u8 {
+    ((a as u32 * (256 - weight) + b as u32 * weight + 128) >> 8) as u8
+}
+
+/// Cheap per-pixel hash giving each pixel a fixed threshold in 0..256.
+fn noise(x: u32, y: u32) -> u8 {
+    let mut h = x.wrapping_mul(0x9E37_79B1) ^ y.wrapping_mul(0x85EB_CA77);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0xC2B2_AE3D);
+    h ^= h >> 13;
+    (h >> 24) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PADDING: u8 = 0xAA;
+
+    /// A BGRA frame where pixel (x, y) is `pixel(x, y)`, with garbage in the row padding.
+    fn bgra(width: u32, height: u32, pixel: impl Fn(usize, usize) -> [u8; 4]) -> Frame {
+        let mut frame = Frame::new(PixelFormat::Bgra, width, height);
+        let stride = frame.stride(0);
+        frame.plane_mut(0).fill(PADDING);
+        for y in 0..height as usize {
+            for x in 0..width as usize {
+                frame.plane_mut(0)[y * stride + x * 4..][..4].copy_from_slice(&pixel(x, y));
+            }
+        }
+        frame
+    }
+
+    fn solid(value: [u8; 4]) -> Frame {
+        bgra(8, 4, |_, _| value)
+    }
+
+    fn at(frame: &Frame, x: usize, y: usize) -> [u8; 4] {
+        frame.plane(0)[y * frame.stride(0) + x * 4..][..4].try_into().unwrap()
+    }
+
+    fn blended(kind: TransitionKind, from: &Frame, to: &Frame, progress: f32) -> Frame {
+        let mut out = Frame::new(PixelFormat::Bgra, 1, 1);
+        blend(kind, from, to, progress, &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn crossfade_endpoints_and_midpoint() {
+        let (from, to) = (solid([200, 0, 100, 255]), solid([100, 250, 0, 255]));
+        assert_eq!(at(&blended(TransitionKind::Crossfade, &from, &to, 0.0), 3, 2), [200, 0, 100, 255]);
+        assert_eq!(at(&blended(TransitionKind::Crossfade, &from, &to, 1.0), 3, 2), [100, 250, 0, 255]);
+        assert_eq!(at(&blended(TransitionKind::Crossfade, &from, &to, 0.5), 3, 2), [150, 125, 50, 255]);
+        // Out of range progress is clamped
+        assert_eq!(at(&blended(TransitionKind::Crossfade, &from, &to, 1.5), 0, 0), [100, 250, 0, 255]);
+    }
+
+    #[test]
+    fn fade_dips_through_black() {
+        let (from, to) = (solid([200, 100, 50, 255]), solid([40, 80, 160, 255]));
+        let fade = |progress| at(&blended(TransitionKind::Fade, &from, &to, progress), 5, 1);
+        assert_eq!(fade(0.0), [200, 100, 50, 255]);
+        assert_eq!(fade(0.25), [100, 50, 25, 255]);
+        assert_eq!(fade(0.5), [0, 0, 0, 255]);
+        assert_eq!(fade(0.75), [20, 40, 80, 255]);
+        assert_eq!(fade(1.0), [40, 80, 160, 255]);
+    }
+
+    #[test]
+    fn cut_switches_at_the_end() {
+        let (from, to) = (solid([1, 1, 1, 255]), solid([2, 2, 2, 255]));
+        assert_eq!(at(&blended(TransitionKind::Cut, &from, &to, 0.99), 0, 0), [1, 1, 1, 255]);
+        assert_eq!(at(&blended(TransitionKind::Cut, &from, &to, 1.0), 0, 0), [2, 2, 2, 255]);
+    }
+
+    #[test]
+    fn slide_pushes_the_old_picture_left() {
+        // Blue holds the column, green which picture it came from
+        let from = bgra(8, 4, |x, _| [x as u8, 1, 0, 255]);
+        let to = bgra(8, 4, |x, _| [x as u8, 2, 0, 255]);
+        let columns = |progress| {
+            let out = blended(TransitionKind::Slide, &from, &to, progress);
+            (0..8).map(|x| at(&out, x, 3)[..2].to_vec()).collect::<Vec<_>>()
+        };
+        assert_eq!(columns(0.0), (0..8).map(|x| vec![x, 1]).collect::<Vec<_>>());
+        // A quarter in: old columns 2-7 on the left, then the new picture's first two
+        let quarter = columns(0.25);
+        assert_eq!(quarter[..6], (2..8).map(|x| vec![x, 1]).collect::<Vec<_>>()[..]);
+        assert_eq!(quarter[6..], [vec![0, 2], vec![1, 2]]);
+        assert_eq!(columns(1.0), (0..8).map(|x| vec![x, 2]).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn dissolve_only_ever_adds_new_pixels() {
+        let (from, to) = (bgra(64, 64, |_, _| [0, 0, 0, 255]), bgra(64, 64, |_, _| [255, 255, 255, 255]));
+        let mut shown = vec![false; 64 * 64];
+        let mut counts = Vec::new();
+        for step in 0..=20 {
+            let out = blended(TransitionKind::Dissolve, &from, &to, step as f32 / 20.0);
+            for (i, was_new) in shown.iter_mut().enumerate() {
+                let is_new = at(&out, i % 64, i / 64)[0] == 255;
+                assert!(is_new || !*was_new, "pixel {} went back at step {}", i, step);
+                *was_new = is_new;
+            }
+            counts.push(shown.iter().filter(|&&new| new).count());
+        }
+        assert_eq!(counts[0], 0);
+        assert_eq!(counts[20], 64 * 64);
+        // Spread evenly rather than sweeping across
+        assert!((1600..2500).contains(&counts[10]), "{} new at the midpoint", counts[10]);
+    }
+
+    #[test]
+    fn padding_is_neither_read_nor_written() {
+        let (from, to) = (solid([10, 20, 30, 255]), solid([30, 20, 10, 255]));
+        for kind in [TransitionKind::Crossfade, TransitionKind::Fade, TransitionKind::Dissolve, TransitionKind::Slide, TransitionKind::Cut] {
+            let mut out = Frame::new(PixelFormat::Bgra, 8, 4);
+            out.plane_mut(0).fill(0x55);
+            blend(kind, &from, &to, 0.5, &mut out).unwrap();
+            let stride = out.stride(0);
+            for y in 0..4 {
+                let row = &out.plane(0)[y * stride..(y + 1) * stride];
+                assert!(row[..32].chunks(4).all(|px| px[3] == 255 && !px.contains(&PADDING)), "{:?} row {}", kind, y);
+                assert!(row[32..].iter().all(|&b| b == 0x55), "{:?} wrote into padding", kind);
+            }
+        }
+    }
+
+    #[test]
+    fn mismatched_frames_are_refused() {
+        let mut out = Frame::new(PixelFormat::Bgra, 8, 4);
+        let nv12 = Frame::new(PixelFormat::Nv12, 8, 4);
+        assert!(blend(TransitionKind::Crossfade, &solid([0; 4]), &nv12, 0.5, &mut out).is_err());
+        assert!(blend(TransitionKind::Crossfade, &nv12, &solid([0; 4]), 0.5, &mut out).is_err());
+        let small = bgra(4, 4, |_, _| [0; 4]);
+        assert!(blend(TransitionKind::Slide, &solid([0; 4]), &small, 0.5, &mut out).is_err());
+    }
+
+    #[test]
+    fn clock_starts_on_the_first_live_frame() {
+        let still = solid([0, 0, 0, 255]);
+        let live = solid([255, 255, 255, 255]);
+        let mut transition = Transition::new(TransitionKind::Crossfade, Direction::FromStill, Duration::from_secs(1), &still).unwrap();
+        let opened = Instant::now() + Duration::from_secs(10);
+        assert_eq!(at(transition.apply(&live, opened).unwrap().unwrap(), 0, 0), [0, 0, 0, 255]);
+        let halfway = at(transition.apply(&live, opened + Duration::from_millis(500)).unwrap().unwrap(), 0, 0);
+        assert_eq!(halfway, [128, 128, 128, 255]);
+        assert!(transition.apply(&live, opened + Duration::from_secs(1)).unwrap().is_none());
+        assert!(transition.is_finished());
+    }
+}
@@ -0,0 +1,202 @@
+use crate::wallpaper::frame::{ColorRange, ColorSpace, Frame, PixelFormat};
+use anyhow::Result;
+use std::mem::ManuallyDrop;
+use windows::core::Interface;
+use windows::Win32::Foundation::BOOL;
+use windows::Win32::Graphics::Direct3D11::*;
+use windows::Win32::Graphics::Dxgi::Common::*;
+
+/// YUV formats we can upload, and the DXGI texture format each one maps to.
+const YUV_FORMATS: &[(PixelFormat, DXGI_FORMAT)] = &[(PixelFormat::Nv12, DXGI_FORMAT_NV12), (PixelFormat::P010, DXGI_FORMAT_P010)];
+
+fn dxgi_format(format: PixelFormat) -> Option<DXGI_FORMAT> {
+    YUV_FORMATS.iter().find(|(f, _)| *f == format).map(|(_, dxgi)| *dxgi)
+}
+
+fn content_desc(input: (u32, u32), output: (u32, u32)) -> D3D11_VIDEO_PROCESSOR_CONTENT_DESC {
+    D3D11_VIDEO_PROCESSOR_CONTENT_DESC {
+        InputFrameFormat: D3D11_VIDEO_FRAME_FORMAT_PROGRESSIVE,
+        InputWidth: input.0,
+        InputHeight: input.1,
+        OutputWidth: output.0,
+        OutputHeight: output.1,
+        Usage: D3D11_VIDEO_USAGE_PLAYBACK_NORMAL,
+        ..Default::default()
+    }
+}
+
+/// Which YUV formats this GPU's video processor takes as input.
+pub fn supported_formats(device: &ID3D11Device, size: (u32, u32)) -> Vec<PixelFormat> {
+    let probe = || -> Result<Vec<PixelFormat>> {
+        let video_device: ID3D11VideoDevice = device.cast()?;
+        let enumerator = unsafe { video_device.CreateVideoProcessorEnumerator(&content_desc(size, size))? };
+        Ok(YUV_FORMATS
+            .iter()
+            .filter(|(_, dxgi)| {
+                unsafe { enumerator.CheckVideoProcessorFormat(*dxgi) }
+                    .is_ok_and(|flags| flags & D3D11_VIDEO_PROCESSOR_FORMAT_SUPPORT_INPUT.0 as u32 != 0)
+            })
+            .map(|(format, _)| *format)
+            .collect())
+    };
+    probe().unwrap_or_else(|e| {
+        tracing::info!("No D3D11 video processor ({}), using CPU colour conversion.", e);
+        Vec::new()
+    })
+}
+
+struct InputTextures {
+    format: PixelFormat,
+    /// CPU-writable copy the planes are uploaded into.
+    upload: ID3D11Texture2D,
+    texture: ID3D11Texture2D,
+    view: ID3D11VideoProcessorInputView,
+}
+
+/// Converts and scales NV12/P010 frames to the BGRA back buffer on the GPU.
+pub struct VideoProcessor {
+    device: ID3D11Device,
+    video_device: ID3D11VideoDevice,
+    video_context: ID3D11VideoContext,
+    enumerator: ID3D11VideoProcessorEnumerator,
+    processor: ID3D11VideoProcessor,
+    input_size: (u32, u32),
+    output_size: (u32, u32),
+    input: Option<InputTextures>,
+}
+
+impl VideoProcessor {
+    pub fn new(device: &ID3D11Device, context: &ID3D11DeviceContext, input_size: (u32, u32), output_size: (u32, u32)) -> Result<Self> {
+        let video_device: ID3D11VideoDevice = device.cast()?;
+        let video_context: ID3D11VideoContext = context.cast()?;
+        unsafe {
+            let enumerator = video_device.CreateVideoProcessorEnumerator(&content_desc(input_size, output_size))?;
+            let processor = video_device.CreateVideoProcessor(&enumerator, 0)?;
+            video_context.VideoProcessorSetStreamFrameFormat(&processor, 0, D3D11_VIDEO_FRAME_FORMAT_PROGRESSIVE);
+            // Output is full-range RGB for playback.
+            video_context.VideoProcessorSetOutputColorSpace(&processor, &D3D11_VIDEO_PROCESSOR_COLOR_SPACE { _bitfield: 0 });
+            Ok(Self {
+                device: device.clone(),
+                video_device,
+                video_context,
+                enumerator,
+                processor,
+                input_size,
+                output_size,
+                input: None,
+            })
+        }
+    }
+
+    pub fn matches(&self, input_size: (u32, u32), output_size: (u32, u32)) -> bool {
+        self.input_size == input_size && self.output_size == output_size
+    }
+
+    fn ensure_input(&mut self, format: PixelFormat) -> Result<()> {
+        if self.input.as_ref().is_some_and(|input| input.format == format) {
+            return Ok(());
+        }
+        let dxgi = dxgi_format(format).ok_or_else(|| anyhow::anyhow!("{:?} is not a GPU input format", format))?;
+        let mut desc = D3D11_TEXTURE2D_DESC {
+            Width: self.input_size.0,
+            Height: self.input_size.1,
+            MipLevels: 1,
+            ArraySize: 1,
+            Format: dxgi,
+            SampleDesc: DXGI_SAMPLE_DESC { Count: 1, Quality: 0 },
+            Usage: D3D11_USAGE_DYNAMIC,
+            BindFlags: D3D11_BIND_SHADER_RESOURCE.0 as u32,
+            CPUAccessFlags: D3D11_CPU_ACCESS_WRITE.0 as u32,
+            ..Default::default()
+        };
+        unsafe {
+            let mut upload = None;
+            self.device.CreateTexture2D(&desc, None, Some(&mut upload))?;
+            desc.Usage = D3D11_USAGE_DEFAULT;
+            desc.CPUAccessFlags = 0;
+            let mut texture = None;
+            self.device.CreateTexture2D(&desc, None, Some(&mut texture))?;
+            let upload = upload.ok_or_else(|| anyhow::anyhow!("Failed to create upload texture"))?;
+            let texture: ID3D11Texture2D = texture.ok_or_else(|| anyhow::anyhow!("Failed to create video texture"))?;
+
+            let view_desc = D3D11_VIDEO_PROCESSOR_INPUT_VIEW_DESC {
+                FourCC: 0,
+                ViewDimension: D3D11_VPIV_DIMENSION_TEXTURE2D,
+                Anonymous: D3D11_VIDEO_PROCESSOR_INPUT_VIEW_DESC_0 { Texture2D: D3D11_TEX2D_VPIV { MipSlice: 0, ArraySlice: 0 } },
+            };
+            let mut view = None;
+            self.video_device.CreateVideoProcessorInputView(&texture, &self.enumerator, &view_desc, Some(&mut view))?;
+            let view = view.ok_or_else(|| anyhow::anyhow!("Failed to create video processor input view"))?;
+
+            tracing::info!("Created {:?} video texture: {}x{}", format, self.input_size.0, self.input_size.1);
+            self.input = Some(InputTextures { format, upload, texture, view });
+        }
+        Ok(())
+    }
+
+    /// Upload `frame` and blit it, colour converted and scaled, into `target`.
+    pub fn process(&mut self, context: &ID3D11DeviceContext, frame: &Frame, target: &ID3D11Texture2D) -> Result<()> {
+        self.ensure_input(frame.format())?;
+        let input = self.input.as_ref().unwrap();
+        let bytes_per_sample = if frame.format() == PixelFormat::P010 { 2 } else { 1 };
+        let row_bytes = frame.width() as usize * bytes_per_sample;
+        let luma_rows = frame.height() as usize;
+        let chroma_rows = luma_rows.div_ceil(2);
+
+        unsafe {
+            let mut mapped = D3D11_MAPPED_SUBRESOURCE::default();
+            context.Map(&input.upload, 0, D3D11_MAP_WRITE_DISCARD, 0, Some(&mut mapped))?;
+            let pitch = mapped.RowPitch as usize;
+            let dst = std::slice::from_raw_parts_mut(mapped.pData as *mut u8, pitch * (luma_rows + chroma_rows));
+            // NV12/P010 textures are the luma rows followed directly by the interleaved chroma rows,
+            // both at the texture's pitch. Chroma rows hold width/2 UV pairs, i.e. the same byte count.
+            for (plane, rows, dst_offset) in [(0, luma_rows, 0), (1, chroma_rows, pitch * luma_rows)] {
+                let stride = frame.stride(plane);
+                let src = frame.plane(plane);
+                for row in 0..rows {
+                    dst[dst_offset + row * pitch..][..row_bytes].copy_from_slice(&src[row * stride..][..row_bytes]);
+                }
+            }
+            context.Unmap(&input.upload, 0);
+            context.CopyResource(&input.texture, &input.upload);
+
+            self.video_context.VideoProcessorSetStreamColorSpace(&self.processor, 0, &stream_color_space(frame));
+
+            let output_desc = D3D11_VIDEO_PROCESSOR_OUTPUT_VIEW_DESC {
+                ViewDimension: D3D11_VPOV_DIMENSION_TEXTURE2D,
+                Anonymous: D3D11_VIDEO_PROCESSOR_OUTPUT_VIEW_DESC_0 { Texture2D: D3D11_TEX2D_VPOV { MipSlice: 0 } },
+            };
+            // The flip-model back buffer rotates, so the output view is made per frame rather than cached.
+            let mut output_view = None;
+            self.video_device.CreateVideoProcessorOutputView(target, &self.enumerator, &output_desc, Some(&mut output_view))?;
+            let output_view = output_view.ok_or_else(|| anyhow::anyhow!("Failed to create video processor output view"))?;
+
+            let stream = D3D11_VIDEO_PROCESSOR_STREAM {
+                Enable: BOOL(1),
+                pInputSurface: ManuallyDrop::new(Some(input.view.clone())),
+                ..Default::default()
+            };
+            let streams = [stream];
+            let result = self.video_context.VideoProcessorBlt(&self.processor, &output_view, 0, &streams);
+            let [stream] = streams;
+            drop(ManuallyDrop::into_inner(stream.pInputSurface));
+            result?;
+        }
+        Ok(())
+    }
+}
+
+/// Legacy D3D11 colour space bitfield: bit 2 selects the BT.709 matrix, bits 4-5 the nominal range.
+/// BT.2020 has no encoding here, so it goes through the 709 matrix.
+fn stream_color_space(frame: &Frame) -> D3D11_VIDEO_PROCESSOR_COLOR_SPACE {
+    let color = frame.color();
+    let matrix = match color.space {
+        ColorSpace::Bt601 => 0,
+        ColorSpace::Bt709 | ColorSpace::Bt2020 => 1,
+    };
+    let range = match color.range {
+        ColorRange::Limited => 1,
+        ColorRange::Full => 2,
+    };
+    D3D11_VIDEO_PROCESSOR_COLOR_SPACE { _bitfield: (matrix << 2) | (range << 4) }
+}
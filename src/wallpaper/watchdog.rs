@@ -0,0 +1,477 @@
+use crate::clock::Clock;
+use crate::wallpaper::command::{PlayerCommand, PlayerHandle};
+use chrono::{DateTime, FixedOffset};
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// No progress for this long counts as a stall. Generous, since opening a slow network
+/// stream or building a frame cache legitimately takes a few seconds.
+const STALL_AFTER: Duration = Duration::from_secs(15);
+/// After acting, give the component this long to come back before judging it again.
+const GRACE: Duration = Duration::from_secs(20);
+/// A second stall of the same component within this window restarts the whole player.
+const ESCALATE_WITHIN: Duration = Duration::from_secs(300);
+/// Incidents kept in memory for diagnostics.
+const MAX_INCIDENTS: usize = 50;
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The parts of playback the watchdog keeps an eye on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Component {
+    /// The player loop itself, outside any one stage.
+    Player,
+    Decoder,
+    Presenter,
+    /// The wallpaper window's message thread.
+    Window,
+}
+
+impl Component {
+    pub fn label(self) -> &'static str {
+        match self {
+            Component::Player => "player loop",
+            Component::Decoder => "decoder",
+            Component::Presenter => "presenter",
+            Component::Window => "window thread",
+        }
+    }
+
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Component::Decoder,
+            2 => Component::Presenter,
+            3 => Component::Window,
+            _ => Component::Player,
+        }
+    }
+}
+
+/// What the player is asked to do about a stall.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recovery {
+    /// Tear down and recreate one component.
+    Rebuild(Component),
+    /// Drop the decoder, renderer and everything cached, and start over from the state.
+    Restart,
+}
+
+impl Recovery {
+    /// The decoder is torn down too, not just the surface.
+    pub fn includes_decoder(self) -> bool {
+        !matches!(self, Recovery::Rebuild(Component::Presenter | Component::Window))
+    }
+}
+
+impl fmt::Display for Recovery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Recovery::Rebuild(component) => write!(f, "rebuilt the {}", component.label()),
+            Recovery::Restart => write!(f, "restarted the player"),
+        }
+    }
+}
+
+/// The last time one thread made progress, read by another. Stamped with whatever clock
+/// the writer goes by, so readers must use the same one.
+#[derive(Debug, Clone, Default)]
+pub struct Beat(Arc<Mutex<Option<Instant>>>);
+
+impl Beat {
+    pub fn tick(&self, now: Instant) {
+        *self.0.lock().unwrap() = Some(now);
+    }
+
+    /// Time between the last tick and `now`; `None` before the first.
+    pub fn age(&self, now: Instant) -> Option<Duration> {
+        self.0.lock().unwrap().map(|at| now.saturating_duration_since(at))
+    }
+}
+
+/// Progress stamps the player publishes for the watchdog, on the player's clock.
+#[derive(Clone)]
+pub struct Progress {
+    clock: Arc<dyn Clock>,
+    /// Each pass of the player loop.
+    turn: Beat,
+    /// Which component the loop is in right now.
+    stage: Arc<AtomicU8>,
+    /// The loop is sleeping on purpose, e.g. a day-cycle wallpaper waiting for its next minute.
+    idle: Arc<AtomicBool>,
+    /// Frames should be flowing: playing, with a decoder or cache open.
+    expecting: Arc<AtomicBool>,
+    decoded: Beat,
+    presented: Beat,
+    /// The current renderer's window thread.
+    window: Arc<Mutex<Beat>>,
+}
+
+impl Progress {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            clock,
+            turn: Beat::default(),
+            stage: Arc::default(),
+            idle: Arc::default(),
+            expecting: Arc::default(),
+            decoded: Beat::default(),
+            presented: Beat::default(),
+            window: Arc::default(),
+        }
+    }
+
+    pub fn turn(&self) {
+        self.turn.tick(self.clock.now());
+        self.enter(Component::Player);
+    }
+
+    pub fn idle(&self, idle: bool) {
+        self.idle.store(idle, Ordering::Relaxed);
+    }
+
+    pub fn enter(&self, component: Component) {
+        self.stage.store(component as u8, Ordering::Relaxed);
+    }
+
+    pub fn expect_frames(&self, expecting: bool) {
+        // Frames only count as overdue from the moment they're expected
+        if expecting && !self.expecting.swap(true, Ordering::Relaxed) {
+            self.decoded.tick(self.clock.now());
+            self.presented.tick(self.clock.now());
+        } else if !expecting {
+            self.expecting.store(false, Ordering::Relaxed);
+        }
+    }
+
+    pub fn decoded(&self) {
+        self.decoded.tick(self.clock.now());
+    }
+
+    pub fn presented(&self) {
+        self.presented.tick(self.clock.now());
+    }
+
+    /// Follow a new renderer's window thread.
+    pub fn watch_window(&self, beat: Beat) {
+        *self.window.lock().unwrap() = beat;
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let now = self.clock.now();
+        Snapshot {
+            turn: self.turn.age(now),
+            stage: Component::from_u8(self.stage.load(Ordering::Relaxed)),
+            idle: self.idle.load(Ordering::Relaxed),
+            expecting: self.expecting.load(Ordering::Relaxed),
+            decoded: self.decoded.age(now),
+            presented: self.presented.age(now),
+            window: self.window.lock().unwrap().age(now),
+        }
+    }
+}
+
+/// How long since each kind of progress, at one moment.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Snapshot {
+    pub turn: Option<Duration>,
+    pub stage: Component,
+    pub idle: bool,
+    pub expecting: bool,
+    pub decoded: Option<Duration>,
+    pub presented: Option<Duration>,
+    pub window: Option<Duration>,
+}
+
+impl Snapshot {
+    /// The stalled component and how long it's been stuck, if any. A stuck loop is blamed
+    /// on the stage it's stuck in; a running loop that isn't getting frames out on the
+    /// decoder or the presenter, whichever stopped first.
+    pub fn stall(&self) -> Option<(Component, Duration)> {
+        let stale = |age: Option<Duration>| age.filter(|age| *age >= STALL_AFTER);
+        if let Some(age) = stale(self.turn).filter(|_| !self.idle) {
+            return Some((self.stage, age));
+        }
+        if let Some(age) = stale(self.window) {
+            return Some((Component::Window, age));
+        }
+        if !self.expecting {
+            return None;
+        }
+        match (stale(self.decoded), stale(self.presented)) {
+            (Some(age), _) => Some((Component::Decoder, age)),
+            (None, Some(age)) => Some((Component::Presenter, age)),
+            (None, None) => None,
+        }
+    }
+}
+
+/// One stall and what was done about it.
+#[derive(Debug, Clone)]
+pub struct Incident {
+    /// On the watchdog's clock, like the stall itself.
+    pub at: DateTime<FixedOffset>,
+    pub component: Component,
+    pub stalled_for: Duration,
+    pub recovery: Recovery,
+}
+
+impl fmt::Display for Incident {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} stalled for {:.1}s, {}",
+            self.at.format("%Y-%m-%d %H:%M:%S"),
+            self.component.label(),
+            self.stalled_for.as_secs_f64(),
+            self.recovery
+        )
+    }
+}
+
+/// Decides what to do about stalls: rebuild the component the first time, restart the
+/// player if the same one stalls again soon after.
+#[derive(Debug, Default)]
+pub struct Escalation {
+    recent: VecDeque<(Instant, Component)>,
+    quiet_until: Option<Instant>,
+}
+
+impl Escalation {
+    pub fn decide(&mut self, now: Instant, component: Component) -> Option<Recovery> {
+        if self.quiet_until.is_some_and(|until| now < until) {
+            return None;
+        }
+        while self.recent.front().is_some_and(|(at, _)| now.duration_since(*at) > ESCALATE_WITHIN) {
+            self.recent.pop_front();
+        }
+        let repeat = self.recent.iter().any(|(_, c)| *c == component);
+        self.recent.push_back((now, component));
+        self.quiet_until = Some(now + GRACE);
+        Some(if repeat || component == Component::Player { Recovery::Restart } else { Recovery::Rebuild(component) })
+    }
+}
+
+/// Watches the player's progress stamps and asks it to recover when something stalls.
+pub struct Watchdog {
+    progress: Progress,
+    player: PlayerHandle,
+    clock: Arc<dyn Clock>,
+    incidents: Arc<Mutex<VecDeque<Incident>>>,
+    /// Where incidents are appended, `incidents.log` next to the settings.
+    log: Option<PathBuf>,
+}
+
+impl Watchdog {
+    pub fn new(progress: Progress, player: PlayerHandle, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            progress,
+            player,
+            clock,
+            incidents: Arc::new(Mutex::new(VecDeque::new())),
+            log: incident_log_path().inspect_err(|e| tracing::warn!("Incidents won't be logged: {}", e)).ok(),
+        }
+    }
+
+    /// Recent incidents, oldest first.
+    pub fn incidents(&self) -> Arc<Mutex<VecDeque<Incident>>> {
+        self.incidents.clone()
+    }
+
+    pub async fn run(&self) -> anyhow::Result<()> {
+        let mut escalation = Escalation::default();
+        loop {
+            self.clock.sleep(CHECK_INTERVAL).await;
+            let Some((component, stalled_for)) = self.progress.snapshot().stall() else {
+                continue;
+            };
+            let Some(recovery) = escalation.decide(self.clock.now(), component) else {
+                continue;
+            };
+            let incident = Incident { at: self.clock.local_now(), component, stalled_for, recovery };
+            tracing::error!("Watchdog: {}", incident);
+            // A player waiting on a stuck decode gives up on it as soon as this arrives
+            self.player.send(PlayerCommand::Recover(recovery));
+            self.record(incident);
+        }
+    }
+
+    fn record(&self, incident: Incident) {
+        if let Some(path) = &self.log {
+            if let Err(e) = append_incident(path, &incident) {
+                tracing::warn!("Failed to write incident log: {}", e);
+            }
+        }
+        let mut incidents = self.incidents.lock().unwrap();
+        incidents.push_back(incident);
+        if incidents.len() > MAX_INCIDENTS {
+            incidents.pop_front();
+        }
+    }
+}
+
+fn append_incident(path: &PathBuf, incident: &Incident) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", incident)?;
+    Ok(())
+}
+
+fn incident_log_path() -> anyhow::Result<PathBuf> {
+    let mut path = dirs::config_dir().ok_or_else(|| anyhow::anyhow!("Could not find config directory"))?;
+    path.push("Mew");
+    path.push("incidents.log");
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SimulatedClock;
+    use crate::wallpaper::command::{self, PlayerEnds};
+
+    /// A watchdog on a paused clock that logs nowhere, with the player's end of the command
+    /// channel to see what it asks for.
+    fn watchdog() -> (Watchdog, Progress, PlayerEnds, Arc<SimulatedClock>) {
+        let clock = Arc::new(SimulatedClock::new(DateTime::parse_from_rfc3339("2024-03-10T12:00:00+01:00").unwrap()));
+        let (handle, ends) = command::channel();
+        let progress = Progress::new(clock.clone());
+        let mut watchdog = Watchdog::new(progress.clone(), handle, clock.clone());
+        watchdog.log = None;
+        (watchdog, progress, ends, clock)
+    }
+
+    /// A player loop that keeps turning every 40 ms, stamping only the progress it's given.
+    fn turning(progress: &Progress, clock: Arc<SimulatedClock>, decodes: bool, presents: bool) {
+        let progress = progress.clone();
+        progress.expect_frames(true);
+        tokio::spawn(async move {
+            loop {
+                clock.sleep(Duration::from_millis(40)).await;
+                progress.turn();
+                if decodes {
+                    progress.decoded();
+                }
+                if presents {
+                    progress.presented();
+                }
+            }
+        });
+    }
+
+    /// The next recovery asked for, and when.
+    async fn next_recovery(ends: &mut PlayerEnds, clock: &SimulatedClock) -> (Duration, Recovery) {
+        match ends.commands.recv().await {
+            Some(PlayerCommand::Recover(recovery)) => (clock.elapsed_total(), recovery),
+            other => panic!("expected a recovery, got {:?}", other),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_loop_stuck_in_the_decoder_rebuilds_it() {
+        let (watchdog, progress, mut ends, clock) = watchdog();
+        progress.turn();
+        progress.enter(Component::Decoder);
+        tokio::spawn(async move { watchdog.run().await });
+
+        assert_eq!(next_recovery(&mut ends, &clock).await, (STALL_AFTER, Recovery::Rebuild(Component::Decoder)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_decoder_without_frames_is_rebuilt() {
+        let (watchdog, progress, mut ends, clock) = watchdog();
+        turning(&progress, clock.clone(), false, true);
+        tokio::spawn(async move { watchdog.run().await });
+
+        assert_eq!(next_recovery(&mut ends, &clock).await, (STALL_AFTER, Recovery::Rebuild(Component::Decoder)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_presenter_without_frames_is_rebuilt() {
+        let (watchdog, progress, mut ends, clock) = watchdog();
+        turning(&progress, clock.clone(), true, false);
+        tokio::spawn(async move { watchdog.run().await });
+
+        assert_eq!(next_recovery(&mut ends, &clock).await, (STALL_AFTER, Recovery::Rebuild(Component::Presenter)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_silent_window_thread_is_rebuilt_even_while_idle() {
+        let (watchdog, progress, mut ends, clock) = watchdog();
+        let beat = Beat::default();
+        beat.tick(clock.now());
+        progress.watch_window(beat);
+        progress.turn();
+        progress.idle(true);
+        tokio::spawn(async move { watchdog.run().await });
+
+        assert_eq!(next_recovery(&mut ends, &clock).await, (STALL_AFTER, Recovery::Rebuild(Component::Window)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn an_idle_player_is_left_alone() {
+        let (watchdog, progress, mut ends, _clock) = watchdog();
+        progress.turn();
+        progress.idle(true);
+        tokio::spawn(async move { watchdog.run().await });
+
+        tokio::time::sleep(Duration::from_secs(600)).await;
+        assert!(ends.commands.try_recv().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_same_stall_again_restarts_the_player() {
+        let (watchdog, progress, mut ends, clock) = watchdog();
+        turning(&progress, clock.clone(), false, true);
+        tokio::spawn(async move { watchdog.run().await });
+
+        assert_eq!(next_recovery(&mut ends, &clock).await, (STALL_AFTER, Recovery::Rebuild(Component::Decoder)));
+        // Still no frames once the grace period is up
+        assert_eq!(next_recovery(&mut ends, &clock).await, (STALL_AFTER + GRACE, Recovery::Restart));
+        assert_eq!(next_recovery(&mut ends, &clock).await, (STALL_AFTER + GRACE * 2, Recovery::Restart));
+    }
+
+    #[test]
+    fn escalation_forgets_old_stalls_and_tells_components_apart() {
+        let start = Instant::now();
+        let mut escalation = Escalation::default();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert_eq!(escalation.decide(at(0), Component::Decoder), Some(Recovery::Rebuild(Component::Decoder)));
+        assert_eq!(escalation.decide(at(10), Component::Presenter), None, "within the grace period");
+        assert_eq!(escalation.decide(at(30), Component::Presenter), Some(Recovery::Rebuild(Component::Presenter)));
+        assert_eq!(escalation.decide(at(60), Component::Decoder), Some(Recovery::Restart));
+        // Long after the last decoder stall, a new one only rebuilds again
+        assert_eq!(escalation.decide(at(400), Component::Decoder), Some(Recovery::Rebuild(Component::Decoder)));
+        // A stuck loop outside any stage can only be restarted
+        assert_eq!(escalation.decide(at(1000), Component::Player), Some(Recovery::Restart));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn incidents_are_kept_and_logged() {
+        let (mut watchdog, progress, mut ends, clock) = watchdog();
+        let log = std::env::temp_dir().join(format!("mew-{}-incidents.log", std::process::id()));
+        let _ = std::fs::remove_file(&log);
+        watchdog.log = Some(log.clone());
+        let incidents = watchdog.incidents();
+        turning(&progress, clock.clone(), true, false);
+        tokio::spawn(async move { watchdog.run().await });
+
+        next_recovery(&mut ends, &clock).await;
+        tokio::task::yield_now().await;
+        let incident = incidents.lock().unwrap().back().cloned().unwrap();
+        assert_eq!(incident.at.to_rfc3339(), "2024-03-10T12:00:15+01:00");
+        assert_eq!(incident.component, Component::Presenter);
+        assert_eq!(incident.stalled_for, STALL_AFTER);
+        assert_eq!(incident.recovery, Recovery::Rebuild(Component::Presenter));
+
+        let written = std::fs::read_to_string(&log).unwrap();
+        let _ = std::fs::remove_file(&log);
+        assert_eq!(written, "2024-03-10 12:00:15 presenter stalled for 15.0s, rebuilt the presenter\n");
+    }
+}